@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS locations (
+    id UUID PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    latitude DOUBLE PRECISION NOT NULL,
+    longitude DOUBLE PRECISION NOT NULL,
+    altitude DOUBLE PRECISION,
+    accuracy DOUBLE PRECISION,
+    speed DOUBLE PRECISION,
+    heading DOUBLE PRECISION,
+    battery REAL,
+    timestamp TIMESTAMPTZ NOT NULL
+);
+
+-- Databases created before speed, heading and battery were tracked.
+ALTER TABLE locations
+    ADD COLUMN IF NOT EXISTS speed DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS heading DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS battery REAL;
+
+CREATE INDEX IF NOT EXISTS idx_locations_user_timestamp ON locations (user_id, timestamp DESC);
@@ -0,0 +1,31 @@
+CREATE TABLE IF NOT EXISTS geofences (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    geofence_type TEXT NOT NULL DEFAULT 'circle',
+    center_latitude DOUBLE PRECISION,
+    center_longitude DOUBLE PRECISION,
+    radius_meters DOUBLE PRECISION,
+    polygon JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Databases created when only circular geofences were supported.
+ALTER TABLE geofences
+    ADD COLUMN IF NOT EXISTS geofence_type TEXT NOT NULL DEFAULT 'circle',
+    ADD COLUMN IF NOT EXISTS polygon JSONB,
+    ALTER COLUMN center_latitude DROP NOT NULL,
+    ALTER COLUMN center_longitude DROP NOT NULL,
+    ALTER COLUMN radius_meters DROP NOT NULL;
+
+CREATE TABLE IF NOT EXISTS geofence_events (
+    id UUID PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    geofence_id UUID NOT NULL,
+    event_type TEXT NOT NULL,
+    latitude DOUBLE PRECISION NOT NULL,
+    longitude DOUBLE PRECISION NOT NULL,
+    occurred_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_geofence_events_user_occurred ON geofence_events (user_id, occurred_at DESC);
+CREATE INDEX IF NOT EXISTS idx_geofence_events_geofence_occurred ON geofence_events (geofence_id, occurred_at DESC);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS routes (
+    id UUID PRIMARY KEY,
+    waypoints JSONB NOT NULL,
+    waypoint_order JSONB NOT NULL,
+    total_distance_meters DOUBLE PRECISION NOT NULL,
+    two_opt_iterations INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS daily_stats (
+    user_id TEXT NOT NULL,
+    date DATE NOT NULL,
+    distance_meters DOUBLE PRECISION NOT NULL,
+    point_count BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, date)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE locations ADD COLUMN IF NOT EXISTS geohash TEXT;
+
+-- text_pattern_ops lets `geohash LIKE 'u4pruy%'` use the index regardless of collation.
+CREATE INDEX IF NOT EXISTS idx_locations_geohash ON locations (geohash text_pattern_ops);
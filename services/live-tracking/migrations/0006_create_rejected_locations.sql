@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS rejected_locations (
+    id UUID PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    latitude DOUBLE PRECISION NOT NULL,
+    longitude DOUBLE PRECISION NOT NULL,
+    accuracy DOUBLE PRECISION,
+    timestamp TIMESTAMPTZ NOT NULL,
+    reason TEXT NOT NULL,
+    implied_speed_kmh DOUBLE PRECISION,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_rejected_locations_user_timestamp ON rejected_locations (user_id, timestamp DESC);
@@ -0,0 +1 @@
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
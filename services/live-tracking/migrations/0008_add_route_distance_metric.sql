@@ -0,0 +1 @@
+ALTER TABLE routes ADD COLUMN IF NOT EXISTS distance_metric TEXT NOT NULL DEFAULT 'haversine';
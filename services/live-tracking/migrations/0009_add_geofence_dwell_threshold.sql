@@ -0,0 +1,2 @@
+-- Seconds a user must stay inside before a DWELL event fires; NULL disables dwell alerts.
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS dwell_threshold_secs BIGINT;
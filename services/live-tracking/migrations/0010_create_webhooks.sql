@@ -0,0 +1,15 @@
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS webhook_url TEXT;
+
+-- Geofence event deliveries that still failed after the last retry.
+CREATE TABLE IF NOT EXISTS webhook_dead_letters (
+    id UUID PRIMARY KEY,
+    geofence_id UUID NOT NULL,
+    event_id UUID NOT NULL,
+    url TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT NOT NULL,
+    failed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhook_dead_letters_geofence_failed ON webhook_dead_letters (geofence_id, failed_at DESC);
@@ -0,0 +1,3 @@
+-- Lets the retention purge find expired fixes without scanning every user's history.
+CREATE INDEX IF NOT EXISTS idx_locations_timestamp ON locations (timestamp);
+CREATE INDEX IF NOT EXISTS idx_rejected_locations_timestamp ON rejected_locations (timestamp);
@@ -0,0 +1,3 @@
+-- Optional vertical extent in meters; NULL leaves that side of the band open.
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS min_altitude DOUBLE PRECISION;
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS max_altitude DOUBLE PRECISION;
@@ -0,0 +1,18 @@
+-- Last presence state per user, so transitions survive restarts and are emitted once.
+CREATE TABLE IF NOT EXISTS user_presence (
+    user_id TEXT PRIMARY KEY,
+    online BOOLEAN NOT NULL,
+    last_seen TIMESTAMPTZ NOT NULL,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS presence_events (
+    id UUID PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    last_seen TIMESTAMPTZ NOT NULL,
+    occurred_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_presence_events_user_occurred ON presence_events (user_id, occurred_at DESC, id DESC);
+CREATE INDEX IF NOT EXISTS idx_presence_events_occurred ON presence_events (occurred_at DESC, id DESC);
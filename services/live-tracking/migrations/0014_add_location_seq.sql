@@ -0,0 +1,5 @@
+-- Per-device sequence number supplied by the SDK; NULL for clients that do not send one.
+ALTER TABLE locations ADD COLUMN IF NOT EXISTS seq BIGINT;
+
+-- A repeated sequence number is a retransmission of a fix already stored.
+CREATE UNIQUE INDEX IF NOT EXISTS idx_locations_user_seq ON locations (user_id, seq) WHERE seq IS NOT NULL;
@@ -0,0 +1,3 @@
+-- How each fix's timestamp was arrived at: 'device', 'server', 'substituted' or 'suspect'. Rows
+-- stored before it was tracked took the device's word.
+ALTER TABLE locations ADD COLUMN IF NOT EXISTS timestamp_status TEXT NOT NULL DEFAULT 'device';
@@ -0,0 +1,31 @@
+-- Organization each row belongs to, taken from the `tenant` claim of the caller's token. Rows
+-- stored before tenants existed, and anonymous callers, belong to the 'default' tenant.
+ALTER TABLE locations ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE rejected_locations ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE geofence_events ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE routes ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE daily_stats ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE user_presence ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE presence_events ADD COLUMN IF NOT EXISTS tenant_id TEXT NOT NULL DEFAULT 'default';
+
+-- The same user id may exist in several tenants.
+ALTER TABLE daily_stats DROP CONSTRAINT IF EXISTS daily_stats_pkey;
+ALTER TABLE daily_stats ADD PRIMARY KEY (tenant_id, user_id, date);
+ALTER TABLE user_presence DROP CONSTRAINT IF EXISTS user_presence_pkey;
+ALTER TABLE user_presence ADD PRIMARY KEY (tenant_id, user_id);
+
+DROP INDEX IF EXISTS idx_locations_user_seq;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_locations_tenant_user_seq ON locations (tenant_id, user_id, seq) WHERE seq IS NOT NULL;
+
+DROP INDEX IF EXISTS idx_locations_user_timestamp;
+CREATE INDEX IF NOT EXISTS idx_locations_tenant_user_timestamp ON locations (tenant_id, user_id, timestamp DESC);
+DROP INDEX IF EXISTS idx_rejected_locations_user_timestamp;
+CREATE INDEX IF NOT EXISTS idx_rejected_locations_tenant_user_timestamp ON rejected_locations (tenant_id, user_id, timestamp DESC);
+DROP INDEX IF EXISTS idx_geofence_events_user_occurred;
+CREATE INDEX IF NOT EXISTS idx_geofence_events_tenant_user_occurred ON geofence_events (tenant_id, user_id, occurred_at DESC);
+DROP INDEX IF EXISTS idx_presence_events_user_occurred;
+DROP INDEX IF EXISTS idx_presence_events_occurred;
+CREATE INDEX IF NOT EXISTS idx_presence_events_tenant_user_occurred ON presence_events (tenant_id, user_id, occurred_at DESC, id DESC);
+CREATE INDEX IF NOT EXISTS idx_presence_events_tenant_occurred ON presence_events (tenant_id, occurred_at DESC, id DESC);
+CREATE INDEX IF NOT EXISTS idx_geofences_tenant_created ON geofences (tenant_id, created_at, id) WHERE deleted_at IS NULL;
@@ -0,0 +1,5 @@
+-- Speed in m/s above which a fix inside the geofence raises a SPEEDING event; NULL disables it.
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS speed_limit DOUBLE PRECISION;
+-- Only set on SPEEDING events.
+ALTER TABLE geofence_events ADD COLUMN IF NOT EXISTS observed_speed DOUBLE PRECISION;
+ALTER TABLE geofence_events ADD COLUMN IF NOT EXISTS speed_limit DOUBLE PRECISION;
@@ -0,0 +1,9 @@
+-- Accepted fixes per tenant and calendar month (UTC), for billing. Counted in Redis as fixes are
+-- stored and added here by the periodic flush, so the most recent counts may still be in Redis.
+CREATE TABLE IF NOT EXISTS usage (
+    tenant_id TEXT NOT NULL,
+    month DATE NOT NULL,
+    fixes BIGINT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (tenant_id, month)
+);
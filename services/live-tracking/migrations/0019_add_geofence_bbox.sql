@@ -0,0 +1,44 @@
+-- Bounding box of each geofence, written with its geometry, so point lookups only fetch the
+-- geofences whose box holds the point. NULL where no simple box exists (a circle reaching a pole
+-- or the antimeridian); such geofences are always candidates.
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS min_latitude DOUBLE PRECISION;
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS max_latitude DOUBLE PRECISION;
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS min_longitude DOUBLE PRECISION;
+ALTER TABLE geofences ADD COLUMN IF NOT EXISTS max_longitude DOUBLE PRECISION;
+
+-- Polygons: extent of the outer ring, whose vertices are [lon, lat].
+UPDATE geofences g
+SET min_latitude = e.min_latitude, max_latitude = e.max_latitude,
+    min_longitude = e.min_longitude, max_longitude = e.max_longitude
+FROM (
+    SELECT id,
+           MIN((vertex->>1)::DOUBLE PRECISION) AS min_latitude, MAX((vertex->>1)::DOUBLE PRECISION) AS max_latitude,
+           MIN((vertex->>0)::DOUBLE PRECISION) AS min_longitude, MAX((vertex->>0)::DOUBLE PRECISION) AS max_longitude
+    FROM geofences, jsonb_array_elements(polygon->0) AS vertex
+    WHERE geofence_type = 'polygon' AND min_latitude IS NULL
+    GROUP BY id
+) e
+WHERE g.id = e.id;
+
+-- Circles: the same slightly generous box the service computes.
+WITH circles AS (
+    SELECT id, center_latitude AS lat, center_longitude AS lon,
+           radius_meters / (6371000.0 * pi() / 180.0) * 1.01 AS lat_delta
+    FROM geofences
+    WHERE geofence_type <> 'polygon' AND min_latitude IS NULL
+      AND center_latitude IS NOT NULL AND center_longitude IS NOT NULL AND radius_meters IS NOT NULL
+), boxes AS (
+    SELECT id, lat, lon, lat_delta,
+           lat_delta / LEAST(cos(radians(lat - lat_delta)), cos(radians(lat + lat_delta))) AS lon_delta
+    FROM circles
+    WHERE lat - lat_delta > -90 AND lat + lat_delta < 90
+)
+UPDATE geofences g
+SET min_latitude = b.lat - b.lat_delta, max_latitude = b.lat + b.lat_delta,
+    min_longitude = b.lon - b.lon_delta, max_longitude = b.lon + b.lon_delta
+FROM boxes b
+WHERE g.id = b.id AND b.lon - b.lon_delta >= -180 AND b.lon + b.lon_delta <= 180;
+
+CREATE INDEX IF NOT EXISTS idx_geofences_tenant_bbox
+    ON geofences (tenant_id, min_latitude, max_latitude, min_longitude, max_longitude)
+    WHERE deleted_at IS NULL;
@@ -0,0 +1,16 @@
+-- Rolling-aggregate buckets of each user's recent fixes. Maintained in Redis as fixes are stored
+-- and copied here by the periodic flush; rows that leave the rolling window are deleted.
+CREATE TABLE IF NOT EXISTS rolling_stats (
+    tenant_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    bucket_start TIMESTAMPTZ NOT NULL,
+    distance_meters DOUBLE PRECISION NOT NULL DEFAULT 0,
+    speed_sum DOUBLE PRECISION NOT NULL DEFAULT 0,
+    speed_count BIGINT NOT NULL DEFAULT 0,
+    max_speed DOUBLE PRECISION,
+    point_count BIGINT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (tenant_id, user_id, bucket_start)
+);
+
+CREATE INDEX IF NOT EXISTS idx_rolling_stats_bucket_start ON rolling_stats (bucket_start);
@@ -0,0 +1,19 @@
+-- One row per stay of a user inside a geofence, from the ENTER to the EXIT the monitor recorded.
+-- Opened on ENTER with a NULL exit; closed on EXIT with the time and distance covered inside.
+CREATE TABLE IF NOT EXISTS geofence_visits (
+    id UUID PRIMARY KEY,
+    tenant_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    geofence_id UUID NOT NULL,
+    entered_at TIMESTAMPTZ NOT NULL,
+    exited_at TIMESTAMPTZ,
+    dwell_secs BIGINT,
+    distance_meters DOUBLE PRECISION,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- At most one open visit per user and geofence.
+CREATE UNIQUE INDEX IF NOT EXISTS idx_geofence_visits_open
+    ON geofence_visits (tenant_id, user_id, geofence_id) WHERE exited_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS idx_geofence_visits_geofence_entered ON geofence_visits (geofence_id, entered_at);
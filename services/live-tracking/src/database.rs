@@ -1,10 +1,237 @@
-use sqlx::{Pool, Postgres, PgPool};
+use std::future::Future;
+use std::time::Duration;
+use sqlx::{
+    migrate::MigrateError,
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Connection, Executor, Pool, Postgres,
+};
+use rand::Rng;
+use tracing::{info, warn};
+use crate::config::Config;
 
-pub async fn create_pool(database_url: &str) -> Result<Pool<Postgres>, sqlx::Error> {
-    PgPool::connect(database_url).await
+/// Versioned schema migrations from `migrations/`, embedded at compile time. Every statement is
+/// idempotent so databases created before migrations were tracked upgrade cleanly.
+static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("./migrations");
+
+pub async fn create_pool(config: &Config) -> Result<Pool<Postgres>, sqlx::Error> {
+    info!(
+        "Database pool: max_connections={}, min_connections={}, acquire_timeout={}s, idle_timeout={}s, statement_timeout={}ms",
+        config.db_max_connections,
+        config.db_min_connections,
+        config.db_acquire_timeout_secs,
+        config.db_idle_timeout_secs,
+        config.db_statement_timeout_ms
+    );
+
+    // A startup option, so it is the session default of every pooled connection. A request that
+    // times out mid-query drops its connection; the server still stops the query at this limit,
+    // after which the connection goes back to the pool.
+    let mut options: PgConnectOptions = config.database_url.parse()?;
+    if config.db_statement_timeout_ms > 0 {
+        options = options.options([("statement_timeout", format!("{}ms", config.db_statement_timeout_ms))]);
+    }
+
+    PgPoolOptions::new()
+        .max_connections(config.db_max_connections)
+        .min_connections(config.db_min_connections)
+        .acquire_timeout(Duration::from_secs(config.db_acquire_timeout_secs))
+        .idle_timeout(Duration::from_secs(config.db_idle_timeout_secs))
+        .connect_with(options)
+        .await
+}
+
+/// Migrations run on a connection taken out of the pool for good, with no statement timeout:
+/// backfills and index builds may legitimately take longer than any request.
+pub async fn run_migrations(pool: &Pool<Postgres>) -> Result<(), MigrateError> {
+    let mut conn = pool.acquire().await?.detach();
+    conn.execute("SET statement_timeout = 0").await?;
+    let result = MIGRATOR.run(&mut conn).await;
+    if let Err(e) = conn.close().await {
+        warn!("Failed to close the migration connection: {}", e);
+    }
+    result
 }
 
-pub async fn run_migrations(_pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
-    // Placeholder for database migrations
-    Ok(())
-}
\ No newline at end of file
+/// Longest wait between two attempts of a read, however many retries are configured.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Whether `error` means the query never got a fair hearing: the connection broke, the server
+/// is restarting, or it gave up on a serialization conflict. Errors the database answered on
+/// the merits (constraint violations, syntax errors, missing rows) are not, and neither is
+/// `PoolTimedOut`, which only repeats once the pool is already saturated.
+pub fn is_transient(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::WorkerCrashed => true,
+        sqlx::Error::Database(db) => db.code().is_some_and(|code| {
+            // 08: connection exception; 57P01-57P03: shutdown or not yet accepting connections;
+            // 40001/40P01: serialization failure and deadlock, which clear on a second try.
+            code.starts_with("08") || matches!(code.as_ref(), "57P01" | "57P02" | "57P03" | "40001" | "40P01")
+        }),
+        _ => false,
+    }
+}
+
+/// Runs `query`, running it again up to `config.db_read_retries` times while it fails with a
+/// [transient](is_transient) error. Only for idempotent queries; anything else fails at once.
+pub async fn with_retry<T, F, Fut>(config: &Config, mut query: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut backoff = Duration::from_millis(config.db_retry_initial_backoff_ms);
+    let mut retries = 0;
+    loop {
+        match query().await {
+            Err(e) if retries < config.db_read_retries && is_transient(&e) => {
+                retries += 1;
+                let delay = jittered(backoff);
+                warn!("Transient database error, retry {} of {} in {:?}: {}", retries, config.db_read_retries, delay, e);
+                tokio::time::sleep(delay).await;
+                backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A random delay between half of `backoff` and all of it, so requests that failed together do
+/// not retry together.
+fn jittered(backoff: Duration) -> Duration {
+    backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::error::Error as StdError;
+    use std::fmt;
+    use std::io;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use super::*;
+    use crate::test_support;
+
+    /// A database error carrying only a SQLSTATE code.
+    #[derive(Debug)]
+    struct SqlState(&'static str);
+
+    impl fmt::Display for SqlState {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "SQLSTATE {}", self.0)
+        }
+    }
+
+    impl StdError for SqlState {}
+
+    impl DatabaseError for SqlState {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn database_error(code: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(SqlState(code)))
+    }
+
+    fn connection_reset() -> sqlx::Error {
+        sqlx::Error::Io(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer"))
+    }
+
+    #[test]
+    fn broken_connections_and_restarts_are_transient() {
+        assert!(is_transient(&connection_reset()));
+        assert!(is_transient(&sqlx::Error::WorkerCrashed));
+        for code in ["08000", "08006", "57P01", "57P03", "40001", "40P01"] {
+            assert!(is_transient(&database_error(code)), "{}", code);
+        }
+    }
+
+    #[test]
+    fn answers_on_the_merits_are_not_transient() {
+        // Unique and foreign key violations, a syntax error and a missing table.
+        for code in ["23505", "23503", "42601", "42P01"] {
+            assert!(!is_transient(&database_error(code)), "{}", code);
+        }
+        assert!(!is_transient(&sqlx::Error::RowNotFound));
+        assert!(!is_transient(&sqlx::Error::PoolTimedOut));
+    }
+
+    /// Runs `with_retry` over a query failing with `errors` in turn, then succeeding; returns the
+    /// result and how many times the query ran.
+    async fn run(retries: u32, errors: Vec<fn() -> sqlx::Error>) -> (Result<u32, sqlx::Error>, u32) {
+        let mut config = test_support::config();
+        config.db_read_retries = retries;
+        let calls = AtomicU32::new(0);
+        let result = with_retry(&config, || {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            let outcome = errors.get(call as usize).map_or(Ok(call), |error| Err(error()));
+            async move { outcome }
+        })
+        .await;
+        (result, calls.into_inner())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_errors_are_retried_until_the_query_succeeds() {
+        let (result, calls) = run(2, vec![connection_reset, || database_error("57P01")]).await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_stop_at_the_configured_count() {
+        let (result, calls) = run(1, vec![connection_reset, connection_reset, connection_reset]).await;
+        assert!(matches!(result, Err(sqlx::Error::Io(_))));
+        assert_eq!(calls, 2);
+
+        let (result, calls) = run(0, vec![connection_reset]).await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_errors_pass_through_at_once() {
+        let (result, calls) = run(2, vec![|| database_error("23505")]).await;
+        assert_eq!(result.unwrap_err().as_database_error().and_then(|e| e.code()).as_deref(), Some("23505"));
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_doubles_from_the_initial_delay() {
+        let started = tokio::time::Instant::now();
+        let (result, _) = run(3, vec![connection_reset, connection_reset, connection_reset]).await;
+        assert!(result.is_ok());
+        // 50, 100 and 200 ms at the defaults, each jittered down to no less than half.
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(175) && waited <= Duration::from_millis(350), "{:?}", waited);
+    }
+
+    #[test]
+    fn jitter_keeps_at_least_half_of_the_backoff() {
+        let backoff = Duration::from_millis(100);
+        for _ in 0..1000 {
+            let delay = jittered(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff, "{:?}", delay);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::convert::Infallible;
+use std::error::Error as _;
+use tracing::error;
+use serde::Serialize;
+use utoipa::ToSchema;
+use warp::{
+    http::StatusCode,
+    reject::Reject,
+    reply::{json, with_header, with_status},
+    Rejection, Reply,
+};
+use crate::models::ValidationError;
+
+/// Every error a handler or filter can reject a request with. Rendered by [`handle_rejection`] as
+/// `{"error": {"code": .., "message": ..}}`.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest { code: &'static str, message: String },
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound { code: &'static str, message: String },
+    Conflict { code: &'static str, message: String },
+    Unprocessable { code: &'static str, message: String },
+    RateLimited { retry_after_secs: u64 },
+    PayloadTooLarge { limit_bytes: usize },
+    UnsupportedMediaType(String),
+    Unavailable(String),
+    /// The request ran past its time limit and was abandoned.
+    Timeout { limit_ms: u128 },
+    /// The detail is logged but never sent to the client.
+    Internal(String),
+}
+
+impl Reject for ApiError {}
+
+impl From<ValidationError> for ApiError {
+    fn from(e: ValidationError) -> Self {
+        ApiError::BadRequest { code: e.code, message: e.message }
+    }
+}
+
+impl ApiError {
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        ApiError::NotFound { code, message: message.into() }
+    }
+
+    /// A failed storage call: unavailable when no pooled connection could be acquired within the
+    /// acquire timeout, internal otherwise.
+    pub fn storage(context: &str, e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::PoolTimedOut => ApiError::Unavailable("database is overloaded, retry later".to_string()),
+            e => ApiError::Internal(format!("{}: {}", context, e)),
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::Conflict { .. } => StatusCode::CONFLICT,
+            ApiError::Unprocessable { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest { code, .. }
+            | ApiError::NotFound { code, .. }
+            | ApiError::Conflict { code, .. }
+            | ApiError::Unprocessable { code, .. } => code,
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::PayloadTooLarge { .. } => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::Unavailable(_) => "service_unavailable",
+            ApiError::Timeout { .. } => "timeout",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::BadRequest { message, .. }
+            | ApiError::NotFound { message, .. }
+            | ApiError::Conflict { message, .. }
+            | ApiError::Unprocessable { message, .. }
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::UnsupportedMediaType(message)
+            | ApiError::Unavailable(message) => message.clone(),
+            ApiError::RateLimited { .. } => "too many requests".to_string(),
+            ApiError::PayloadTooLarge { limit_bytes } => format!("request body exceeds the limit of {} bytes", limit_bytes),
+            ApiError::Timeout { limit_ms } => format!("request did not complete within {} ms", limit_ms),
+            ApiError::Internal(_) => "internal server error".to_string(),
+        }
+    }
+
+    pub fn to_response(&self) -> warp::reply::Response {
+        if let ApiError::Internal(detail) = self {
+            error!("Internal error: {}", detail);
+        }
+        let response = render(self.status(), self.code(), &self.message());
+        match self {
+            ApiError::RateLimited { retry_after_secs } => {
+                with_header(response, "retry-after", retry_after_secs.to_string()).into_response()
+            }
+            _ => response,
+        }
+    }
+}
+
+/// Renders any rejection that reaches the top of the route tree, including warp's own.
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let response = if let Some(e) = err.find::<ApiError>() {
+        e.to_response()
+    } else if err.is_not_found() {
+        render(StatusCode::NOT_FOUND, "not_found", "no such route")
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        let message = e.source().map(|s| s.to_string()).unwrap_or_else(|| e.to_string());
+        render(StatusCode::BAD_REQUEST, "invalid_body", &message)
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        render(StatusCode::BAD_REQUEST, "invalid_query", &e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        render(StatusCode::BAD_REQUEST, "invalid_header", &e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        render(StatusCode::BAD_REQUEST, "missing_header", &e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        render(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", &e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+        render(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", &e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::LengthRequired>() {
+        render(StatusCode::LENGTH_REQUIRED, "length_required", &e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+        render(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", &e.to_string())
+    } else {
+        ApiError::Internal(format!("unhandled rejection: {:?}", err)).to_response()
+    };
+    Ok(response)
+}
+
+/// The body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = Error)]
+pub struct ErrorBody<'a> {
+    pub error: ErrorDetail<'a>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorDetail<'a> {
+    /// Stable, machine-readable reason, e.g. `invalid_parameter`.
+    pub code: &'a str,
+    pub message: &'a str,
+}
+
+fn render(status: StatusCode, code: &str, message: &str) -> warp::reply::Response {
+    with_status(json(&ErrorBody { error: ErrorDetail { code, message } }), status).into_response()
+}
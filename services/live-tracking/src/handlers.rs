@@ -1,87 +1,2043 @@
 pub mod health {
-    use warp::{Reply, Rejection, reply::json};
+    use std::future::Future;
+    use std::time::{Duration, Instant};
+    use serde::Serialize;
+    use utoipa::ToSchema;
+    use warp::{Reply, Rejection, http::StatusCode, reply::{json, with_status}};
     use crate::AppState;
 
-    pub async fn health_check() -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({
-            "status": "healthy",
-            "service": "live-tracking"
-        })))
+    const DEPENDENCY_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+    const SERVICE: &str = "live-tracking";
+
+    #[utoipa::path(
+        get,
+        path = "/",
+        summary = "Service banner",
+        responses((status = 200, description = "Service name, version and features.", body = Object)),
+    )]
+    pub fn banner() -> impl Reply {
+        json(&serde_json::json!({
+            "service": "Suuupra Live Tracking Service",
+            "version": "1.0.0",
+            "status": "running",
+            "features": [
+                "Real-time GPS tracking",
+                "Route optimization",
+                "Geofencing",
+                "Analytics and reporting",
+                "WebSocket real-time updates",
+                "Liveness probe: GET /health/live (alias /health), no dependency checks",
+                "Readiness probe: GET /health/ready, checks Postgres and Redis",
+                "OpenAPI document: GET /openapi.json"
+            ]
+        }))
     }
 
-    pub async fn readiness_check(_state: AppState) -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({
-            "status": "ready",
-            "service": "live-tracking"
-        })))
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct Liveness {
+        /// Always `healthy`.
+        pub status: &'static str,
+        pub service: &'static str,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct Readiness {
+        /// `ready`, or `not_ready` when either dependency is down.
+        pub status: &'static str,
+        pub service: &'static str,
+        pub checks: DependencyChecks,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct DependencyChecks {
+        pub postgres: DependencyCheck,
+        pub redis: DependencyCheck,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub struct DependencyCheck {
+        /// `up` or `down`.
+        pub status: &'static str,
+        pub duration_ms: f64,
+        /// Why the check failed; absent when it passed.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub error: Option<String>,
+    }
+
+    /// Confirms the process is up and its runtime is still scheduling tasks. Deliberately checks
+    /// no dependencies, so an outage of Postgres or Redis never gets the process restarted.
+    #[utoipa::path(
+        get,
+        path = "/health/live",
+        summary = "Liveness probe",
+        description = "Checks no dependencies. `/health` is an alias.",
+        responses((status = 200, description = "The process is up.", body = Liveness)),
+    )]
+    pub async fn liveness_check() -> Result<impl Reply, Rejection> {
+        tokio::task::yield_now().await;
+        Ok(json(&Liveness { status: "healthy", service: SERVICE }))
+    }
+
+    /// Reports 503 until both Postgres and Redis answer, so traffic is only routed here when
+    /// requests can be served.
+    #[utoipa::path(
+        get,
+        path = "/health/ready",
+        summary = "Readiness probe",
+        description = "Checks Postgres and Redis.",
+        responses(
+            (status = 200, description = "Both dependencies answered.", body = Readiness),
+            (status = 503, description = "A dependency is down.", body = Readiness),
+        ),
+    )]
+    pub async fn readiness_check(state: AppState) -> Result<impl Reply, Rejection> {
+        let postgres = timed_check(async {
+            sqlx::query("SELECT 1")
+                .execute(&state.db_pool)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        });
+        let redis = timed_check(async {
+            let mut conn = state
+                .redis_client
+                .get_multiplexed_async_connection()
+                .await
+                .map_err(|e| e.to_string())?;
+            redis::cmd("PING")
+                .query_async::<_, String>(&mut conn)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        });
+        let (postgres, redis) = tokio::join!(postgres, redis);
+
+        let ready = postgres.error.is_none() && redis.error.is_none();
+        let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+        Ok(with_status(
+            json(&Readiness {
+                status: if ready { "ready" } else { "not_ready" },
+                service: SERVICE,
+                checks: DependencyChecks { postgres, redis },
+            }),
+            status,
+        ))
+    }
+
+    /// Runs a dependency check under a timeout and reports its outcome and duration.
+    async fn timed_check<F>(check: F) -> DependencyCheck
+    where
+        F: Future<Output = Result<(), String>>,
+    {
+        let started = Instant::now();
+        let result = match tokio::time::timeout(DEPENDENCY_CHECK_TIMEOUT, check).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("timed out after {}ms", DEPENDENCY_CHECK_TIMEOUT.as_millis())),
+        };
+        let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+        match result {
+            Ok(()) => DependencyCheck { status: "up", duration_ms, error: None },
+            Err(e) => DependencyCheck { status: "down", duration_ms, error: Some(e) },
+        }
     }
 }
 
 pub mod tracking {
-    use warp::{Reply, Rejection, reply::json};
+    use std::collections::HashMap;
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use futures_util::{Stream, StreamExt};
+    use tracing::{error, warn};
+    use warp::{Reply, Rejection, http::StatusCode, hyper::body::Buf, reply::{json, with_header, with_status}};
     use crate::AppState;
+    use crate::config::Config;
+    use crate::database::with_retry;
+    use crate::error::ApiError;
+    use crate::middleware::auth::{AuthError, Claims};
+    use crate::models::{
+        validate_time_span, BatchResult, ClusterQuery, ClusterResult, EncodedPolyline, ExportFormat, ExportQuery, GpxImportQuery, GpxImportResult,
+        HistoryQuery, Location, LocationAt, LocationAtQuery, MatchQuery, MatchedTrack, NearbyQuery, NearbyResult, Paginated, PolylineQuery,
+        SignExportRequest, SignedExportUrl, TrackLocationQuery, TrackLocationRequest, TrackedLocation,
+    };
+    use crate::openapi::SignedLink;
+    use crate::services::tracking_service::Recorded;
+    use crate::utils::{gpx, polyline, signed_url};
+
+    const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+    /// Stores a fix. With `geofences=true` the response also reports the geofences the fix falls
+    /// in and those it entered or exited; that part is dropped, with a warning, if it fails, since
+    /// the fix is already stored, and is not part of idempotent replays.
+    #[utoipa::path(
+        post,
+        path = "/api/v1/track/location",
+        summary = "Record a fix for the token's user",
+        description = "`user_id` in the body is ignored.",
+        params(
+            (
+                "idempotency-key" = Option<String>, Header, min_length = 1, max_length = 255,
+                description = "Makes retries safe: a repeated key replays the first response with `idempotent-replayed: true`."
+            ),
+            TrackLocationQuery,
+        ),
+        request_body = TrackLocationRequest,
+        responses(
+            (status = 201, description = "The stored fix.", body = TrackedLocation),
+            (status = 409),
+            (status = 422, description = "Rejected as implausible, e.g. `implausible_speed`."),
+            (status = 429),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn track_location(
+        claims: Claims,
+        idempotency_key: Option<String>,
+        query: std::collections::HashMap<String, String>,
+        mut data: TrackLocationRequest,
+        state: AppState,
+    ) -> Result<impl Reply, Rejection> {
+        let _timer = state.metrics.track_location_duration_seconds.start_timer();
+        let query = TrackLocationQuery::from_params(&query).map_err(ApiError::from)?;
+        data.tenant_id = claims.tenant_id().to_string();
+        data.user_id = claims.sub;
+        data.validate().map_err(ApiError::from)?;
+        data.resolve_timestamp(Utc::now(), &state.config.timestamp_policy()).map_err(ApiError::from)?;
+
+        let recorded = match idempotency_key {
+            Some(key) if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN => {
+                return Err(ApiError::BadRequest {
+                    code: "invalid_idempotency_key",
+                    message: format!("Idempotency-Key must be 1 to {} characters", MAX_IDEMPOTENCY_KEY_LEN),
+                }
+                .into());
+            }
+            Some(key) => state.tracking_service.record_location_once(data, &key).await,
+            None => state.tracking_service.record_location(data).await,
+        };
+
+        match recorded {
+            Ok(Recorded::Stored(location)) => {
+                state.metrics.location_updates_total.inc();
+                state.live_updates.publish(&location);
+                let reported = state.tracking_service.reported(location.clone());
+                if query.geofences {
+                    match state.geolocation_service.fix_geofence_state(&location).await {
+                        Ok(geofences) => {
+                            let tracked = TrackedLocation { location: reported, geofences };
+                            return Ok(with_status(json(&tracked), StatusCode::CREATED).into_response());
+                        }
+                        Err(e) => warn!("Failed to evaluate geofences for fix {}: {}", location.id, e),
+                    }
+                }
+                Ok(with_status(json(&reported), StatusCode::CREATED).into_response())
+            }
+            Ok(Recorded::Replayed(location)) => Ok(with_header(
+                with_status(json(&state.tracking_service.reported(location)), StatusCode::CREATED),
+                "idempotent-replayed",
+                "true",
+            )
+            .into_response()),
+            Ok(Recorded::InProgress) => Err(ApiError::Conflict {
+                code: "idempotency_key_in_use",
+                message: "a request with this Idempotency-Key is still being processed".to_string(),
+            }
+            .into()),
+            Ok(Recorded::Rejected { reason, message }) => {
+                Err(ApiError::Unprocessable { code: reason, message }.into())
+            }
+            Err(e) => Err(ApiError::storage("failed to persist location", e).into()),
+        }
+    }
+
+    #[utoipa::path(
+        post,
+        path = "/api/v1/track/locations/batch",
+        summary = "Record several fixes for the token's user in one transaction",
+        request_body = Vec<TrackLocationRequest>,
+        responses(
+            (status = 200, description = "How many fixes were stored and the indices of those rejected.", body = BatchResult),
+            (status = 429),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn track_locations_batch(claims: Claims, data: Vec<TrackLocationRequest>, state: AppState) -> Result<impl Reply, Rejection> {
+        if data.len() > state.config.max_batch_size {
+            return Err(ApiError::BadRequest {
+                code: "batch_too_large",
+                message: format!("batch of {} exceeds the maximum of {}", data.len(), state.config.max_batch_size),
+            }
+            .into());
+        }
+
+        let received = Utc::now();
+        let policy = state.config.timestamp_policy();
+        let mut accepted = Vec::with_capacity(data.len());
+        let mut accepted_indices = Vec::with_capacity(data.len());
+        let mut rejected = Vec::new();
+        for (index, mut request) in data.into_iter().enumerate() {
+            if request.validate().and_then(|_| request.resolve_timestamp(received, &policy)).is_ok() {
+                request.tenant_id = claims.tenant_id().to_string();
+                request.user_id = claims.sub.clone();
+                accepted.push(request);
+                accepted_indices.push(index);
+            } else {
+                rejected.push(index);
+            }
+        }
+
+        let batch = state
+            .tracking_service
+            .record_locations(accepted)
+            .await
+            .map_err(|e| ApiError::storage("failed to persist location batch", e))?;
+
+        state.metrics.location_updates_total.inc_by(batch.stored.len() as u64);
+        for location in &batch.stored {
+            state.live_updates.publish(location);
+        }
+        rejected.extend(batch.rejected.iter().map(|&i| accepted_indices[i]));
+        rejected.sort_unstable();
+        let duplicates: Vec<usize> = batch.duplicates.iter().map(|&i| accepted_indices[i]).collect();
+        Ok(json(&BatchResult { accepted: batch.stored.len(), rejected, duplicates }))
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/location/{user_id}",
+        summary = "A user's latest fix",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."),),
+        responses((status = 200, description = "The latest fix.", body = Location), (status = 403), (status = 404), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_current_location(user_id: String, claims: Claims, state: AppState) -> Result<impl Reply, Rejection> {
+        if claims.sub != user_id && !claims.has_role("admin") {
+            return Err(ApiError::Forbidden("cannot read another user's location".to_string()).into());
+        }
+        let tenant_id = claims.tenant_id();
+        match with_retry(&state.config, || state.tracking_service.current_location(tenant_id, &user_id)).await {
+            Ok(Some(location)) => Ok(json(&location)),
+            Ok(None) => Err(ApiError::not_found("no_location", format!("no location recorded for user {}", user_id)).into()),
+            Err(e) => Err(ApiError::storage("failed to load current location", e).into()),
+        }
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/location/nearby",
+        summary = "Users whose current location is within a radius, nearest first",
+        params(NearbyQuery),
+        responses((status = 200, description = "Nearby users.", body = NearbyResult), (status = 400), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_nearby_locations(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let query = NearbyQuery::from_params(&query).map_err(ApiError::from)?;
+
+        state
+            .tracking_service
+            .nearby_locations(claims.tenant_id(), &query)
+            .await
+            .map(|result| json(&result))
+            .map_err(|e| ApiError::storage("failed to search nearby locations", e).into())
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/location/clusters",
+        summary = "Current locations within a bounding box grouped into map-marker clusters",
+        description = "A position and count per cell, or the user's own point when alone in their cell.",
+        params(ClusterQuery),
+        responses(
+            (status = 200, description = "The clusters and lone users.", body = ClusterResult),
+            (status = 400),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_clusters(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let query = ClusterQuery::from_params(&query).map_err(ApiError::from)?;
+
+        state
+            .tracking_service
+            .clusters(claims.tenant_id(), &query)
+            .await
+            .map(|result| json(&result))
+            .map_err(|e| ApiError::storage("failed to cluster locations", e).into())
+    }
+
+    /// Users may read their own history; admins may read anyone's in their tenant.
+    pub fn authorize_history(claims: &Claims, user_id: &str) -> Result<(), ApiError> {
+        if claims.sub != user_id && !claims.has_role("admin") {
+            return Err(ApiError::Forbidden("cannot read another user's location history".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Aggregates over every user's history expose each of them, so only admins may read them.
+    pub fn authorize_tenant_history(claims: &Claims) -> Result<(), ApiError> {
+        if !claims.has_role("admin") {
+            return Err(ApiError::Forbidden("reading every user's location history requires an admin token".to_string()));
+        }
+        Ok(())
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/location/{user_id}/history",
+        summary = "A page of a user's fixes, newest first",
+        description = "Users may read their own; admins anyone's.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."), HistoryQuery),
+        responses(
+            (status = 200, description = "One page of fixes.", body = Paginated<Location>),
+            (status = 400),
+            (status = 403),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_location_history(user_id: String, claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        authorize_history(&claims, &user_id)?;
+
+        let query = HistoryQuery::from_params(&query).map_err(ApiError::from)?;
+        validate_time_span(query.from, query.to, state.config.max_query_window()).map_err(ApiError::from)?;
+
+        with_retry(&state.config, || state.tracking_service.location_history(claims.tenant_id(), &user_id, &query))
+            .await
+            .map(|page| json(&page))
+            .map_err(|e| ApiError::storage("failed to load location history", e).into())
+    }
+
+    /// Where the user was at an instant. A fix further away than the configured gap is returned
+    /// flagged as uncertain, or answers 404 with `strict=true`.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/location/{user_id}/at",
+        summary = "Where a user was at an instant",
+        description = "The fix nearest to it, or the last one before it. Same access rule as history.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."), LocationAtQuery),
+        responses(
+            (status = 200, description = "The fix and its distance in time from the instant.", body = LocationAt),
+            (status = 400),
+            (status = 403),
+            (status = 404),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_location_at(user_id: String, claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        authorize_history(&claims, &user_id)?;
+
+        let query = LocationAtQuery::from_params(&query).map_err(ApiError::from)?;
+
+        match state.tracking_service.location_at(claims.tenant_id(), &user_id, &query).await {
+            Ok(Some(at)) if at.uncertain && query.strict => Err(ApiError::not_found(
+                "no_location_near",
+                format!(
+                    "no location recorded for user {} within {} seconds of {}",
+                    user_id,
+                    state.config.location_at_max_gap_secs,
+                    query.timestamp.to_rfc3339()
+                ),
+            )
+            .into()),
+            Ok(Some(at)) => Ok(json(&at)),
+            Ok(None) => Err(ApiError::not_found("no_location", format!("no location recorded for user {}", user_id)).into()),
+            Err(e) => Err(ApiError::storage("failed to load location", e).into()),
+        }
+    }
+
+    /// The track in the window snapped to roads, or the raw track when matching is unavailable.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/location/{user_id}/matched",
+        summary = "A user's track snapped to roads",
+        description = "The raw track with `matched: false` when matching is unavailable. Same access rule as history.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."), MatchQuery),
+        responses(
+            (status = 200, description = "The matched track.", body = MatchedTrack),
+            (status = 400),
+            (status = 403),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_matched_track(user_id: String, claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        authorize_history(&claims, &user_id)?;
 
-    pub async fn track_location(_data: serde_json::Value, _state: AppState) -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({"message": "Location tracked"})))
+        let query = MatchQuery::from_params(&query).map_err(ApiError::from)?;
+        validate_time_span(Some(query.from), Some(query.to), state.config.max_query_window()).map_err(ApiError::from)?;
+
+        state
+            .route_optimizer
+            .matched_track(claims.tenant_id(), &user_id, &query)
+            .await
+            .map(|track| json(&track))
+            .map_err(|e| ApiError::storage("failed to load track", e).into())
+    }
+
+    /// The track in the window as an encoded polyline, e.g. to embed in a map. Same access rule
+    /// as history; an empty window yields an empty polyline.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/location/{user_id}/polyline",
+        summary = "Every fix in the window, oldest first, as a Google encoded polyline",
+        description = "Same access rule as history.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."), PolylineQuery),
+        responses((status = 200, description = "The encoded track.", body = EncodedPolyline), (status = 400), (status = 403)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_polyline(user_id: String, claims: Claims, query: HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        authorize_history(&claims, &user_id)?;
+        let query = PolylineQuery::from_params(&query).map_err(ApiError::from)?;
+        validate_time_span(query.export.from, query.export.to, state.config.max_query_window()).map_err(ApiError::from)?;
+
+        let mut rows = state.tracking_service.export_locations(claims.tenant_id().to_string(), user_id, query.export);
+        let mut encoder = polyline::Encoder::new(query.precision);
+        let mut points = 0;
+        while let Some(row) = rows.recv().await {
+            let location = row.map_err(|e| ApiError::storage("failed to load track", e))?;
+            encoder.push(location.latitude, location.longitude);
+            points += 1;
+        }
+
+        Ok(json(&EncodedPolyline { polyline: encoder.finish(), precision: query.precision, points }))
+    }
+
+    /// Tenant an export is read from: the caller's when they may read the history, otherwise the
+    /// one a valid signed link was minted in. A token, when sent, takes precedence over a signature.
+    fn authorize_export(
+        claims: &Option<Claims>,
+        user_id: &str,
+        format: ExportFormat,
+        params: &HashMap<String, String>,
+        config: &Config,
+    ) -> Result<String, ApiError> {
+        match claims {
+            Some(claims) => {
+                authorize_history(claims, user_id)?;
+                Ok(claims.tenant_id().to_string())
+            }
+            None if params.contains_key(signed_url::SIGNATURE) => {
+                signed_url::verify(&config.export_url_secret, user_id, format.as_str(), params, Utc::now().timestamp())
+                    .map(str::to_string)
+                    .map_err(|e| ApiError::Unauthorized(e.to_string()))
+            }
+            None => Err(AuthError::MissingToken.into()),
+        }
     }
 
-    pub async fn get_current_location(_user_id: String, _state: AppState) -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({"message": "Location retrieved"})))
+    /// Mints a link to one of the user's exports that works without a JWT until it expires. Same
+    /// access rule as history.
+    #[utoipa::path(
+        post,
+        path = "/api/v1/location/{user_id}/export/sign",
+        summary = "Mint a link to an export that works without a token until it expires",
+        description = "E.g. to hand to a mapping tool. Same access rule as history.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."),),
+        request_body = SignExportRequest,
+        responses((status = 200, description = "The link.", body = SignedExportUrl), (status = 403)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn sign_export(user_id: String, claims: Claims, request: SignExportRequest, state: AppState) -> Result<impl Reply, Rejection> {
+        authorize_history(&claims, &user_id)?;
+        request.validate(state.config.export_url_ttl_secs).map_err(ApiError::from)?;
+        validate_time_span(request.from, request.to, state.config.max_query_window()).map_err(ApiError::from)?;
+
+        let expires = Utc::now().timestamp() + request.expires_in_secs.unwrap_or(state.config.export_url_ttl_secs) as i64;
+        let mut params = HashMap::from([
+            (signed_url::TENANT.to_string(), claims.tenant_id().to_string()),
+            (signed_url::EXPIRES.to_string(), expires.to_string()),
+        ]);
+        for (name, bound) in [("from", request.from), ("to", request.to)] {
+            if let Some(bound) = bound {
+                params.insert(name.to_string(), bound.to_rfc3339_opts(SecondsFormat::Millis, true));
+            }
+        }
+        if let Some(simplify) = request.simplify {
+            params.insert("simplify".to_string(), simplify.to_string());
+        }
+
+        let signature = signed_url::sign(&state.config.export_url_secret, &user_id, request.format.as_str(), &params);
+        let query: Vec<String> = signed_url::SIGNED_PARAMS
+            .iter()
+            .filter_map(|name| params.get(*name).map(|value| format!("{}={}", name, value)))
+            .chain(std::iter::once(format!("{}={}", signed_url::SIGNATURE, signature)))
+            .collect();
+
+        Ok(json(&SignedExportUrl {
+            url: format!("/api/v1/location/{}/{}?{}", user_id, request.format.path_segment(), query.join("&")),
+            expires_at: DateTime::from_timestamp(expires, 0).ok_or_else(|| ApiError::Internal("link expiry out of range".to_string()))?,
+        }))
     }
 
-    pub async fn get_location_history(_user_id: String, _query: std::collections::HashMap<String, String>, _state: AppState) -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({"message": "Location history retrieved"})))
+    /// Streams the whole history in the window as newline-delimited JSON, oldest first. Errors
+    /// after the first row can no longer change the status, so they abort the body instead.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/location/{user_id}/export",
+        summary = "Every fix in the window, oldest first, streamed as newline-delimited JSON",
+        description = "Same access rule as history. Without a token, a link from `.../export/sign` is accepted instead.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."), ExportQuery, SignedLink),
+        responses(
+            (status = 200, description = "One `Location` object per line.", content_type = "application/x-ndjson", body = Location),
+            (status = 400),
+            (status = 403),
+        ),
+        security(("bearerAuth" = []), ()),
+    )]
+    pub async fn export_location_history(user_id: String, claims: Option<Claims>, query: HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let tenant_id = authorize_export(&claims, &user_id, ExportFormat::Ndjson, &query, &state.config)?;
+        let query = ExportQuery::from_params(&query).map_err(ApiError::from)?;
+        validate_time_span(query.from, query.to, state.config.max_query_window()).map_err(ApiError::from)?;
+
+        let rows = state.tracking_service.export_locations(tenant_id, user_id, query);
+        let lines = futures_util::stream::unfold(rows, |mut rows| async move {
+            rows.recv().await.map(|row| (row, rows))
+        })
+        .map(|row| -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            let location = row.inspect_err(|e| error!("Location export failed: {}", e))?;
+            let mut line = serde_json::to_vec(&location)?;
+            line.push(b'\n');
+            Ok(line)
+        });
+
+        Ok(with_header(
+            warp::reply::Response::new(warp::hyper::Body::wrap_stream(lines)),
+            "content-type",
+            "application/x-ndjson",
+        ))
+    }
+
+    /// Streams the history in the window as a single-track GPX 1.1 download, oldest first.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/location/{user_id}/export.gpx",
+        summary = "Every fix in the window as a single-track GPX 1.1 download",
+        description = "Same access rule as history. Without a token, a link from `.../export/sign` is accepted instead.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."), ExportQuery, SignedLink),
+        responses(
+            (status = 200, description = "GPX document.", content_type = "application/gpx+xml", body = String),
+            (status = 400),
+            (status = 403),
+        ),
+        security(("bearerAuth" = []), ()),
+    )]
+    pub async fn export_location_gpx(user_id: String, claims: Option<Claims>, query: HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let tenant_id = authorize_export(&claims, &user_id, ExportFormat::Gpx, &query, &state.config)?;
+        let query = ExportQuery::from_params(&query).map_err(ApiError::from)?;
+        validate_time_span(query.from, query.to, state.config.max_query_window()).map_err(ApiError::from)?;
+
+        let filename: String = user_id
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let header = gpx::header(&user_id);
+        let rows = state.tracking_service.export_locations(tenant_id, user_id, query);
+        let points = futures_util::stream::unfold(rows, |mut rows| async move {
+            rows.recv().await.map(|row| (row, rows))
+        })
+        .map(|row| -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+            let location = row.inspect_err(|e| error!("GPX export failed: {}", e))?;
+            Ok(gpx::track_point(&location))
+        });
+        let document = futures_util::stream::once(async move { Ok(header) })
+            .chain(points)
+            .chain(futures_util::stream::once(async { Ok(gpx::FOOTER.to_string()) }));
+
+        Ok(with_header(
+            with_header(
+                warp::reply::Response::new(warp::hyper::Body::wrap_stream(document)),
+                "content-type",
+                gpx::CONTENT_TYPE,
+            ),
+            "content-disposition",
+            format!("attachment; filename=\"{}-track.gpx\"", filename),
+        ))
+    }
+
+    /// GPX travels as `application/gpx+xml`, but generic XML types are common too.
+    fn is_xml(content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        matches!(essence.as_str(), "application/xml" | "text/xml") || essence.ends_with("+xml")
+    }
+
+    /// Imports the track points of a GPX document into the user's history, in one transaction,
+    /// parsing the body as it arrives rather than buffering it. Users may import into their own
+    /// history; admins into anyone's in their tenant. Untimed points are skipped or interpolated
+    /// as `missing_time` says, and the response counts what was imported and skipped.
+    #[utoipa::path(
+        post,
+        path = "/api/v1/location/{user_id}/import.gpx",
+        summary = "Import the track points of a GPX document into a user's history, in one transaction",
+        description = "The body is parsed as it arrives, up to `GPX_IMPORT_MAX_BYTES`. Users may import into their own \
+                       history; admins into anyone's.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."), GpxImportQuery),
+        request_body(content = String, content_type = "application/gpx+xml"),
+        responses(
+            (status = 200, description = "What was imported and skipped.", body = GpxImportResult),
+            (status = 400),
+            (status = 403),
+            (status = 413),
+            (status = 415),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn import_location_gpx(
+        user_id: String,
+        claims: Claims,
+        content_type: Option<String>,
+        length: Option<u64>,
+        query: HashMap<String, String>,
+        body: impl Stream<Item = Result<impl Buf, warp::Error>> + Send,
+        state: AppState,
+    ) -> Result<impl Reply, Rejection> {
+        if claims.sub != user_id && !claims.has_role("admin") {
+            return Err(ApiError::Forbidden("cannot import into another user's location history".to_string()).into());
+        }
+        if let Some(content_type) = content_type.filter(|content_type| !is_xml(content_type)) {
+            return Err(ApiError::UnsupportedMediaType(format!("expected a GPX document, got {}", content_type)).into());
+        }
+        let query = GpxImportQuery::from_params(&query, state.config.gpx_import_missing_time).map_err(ApiError::from)?;
+        let limit_bytes = state.config.gpx_import_max_bytes;
+        if length.is_some_and(|length| length > limit_bytes as u64) {
+            return Err(ApiError::PayloadTooLarge { limit_bytes }.into());
+        }
+
+        let storage = |e| ApiError::storage("failed to import GPX track", e);
+        let invalid_gpx = |e: gpx::ReadError| ApiError::BadRequest { code: "invalid_gpx", message: e.to_string() };
+        let mut import = state
+            .tracking_service
+            .begin_gpx_import(claims.tenant_id(), &user_id, query.missing_time)
+            .await
+            .map_err(storage)?;
+        let mut reader = gpx::TrackPointReader::default();
+        let mut received = 0;
+        let mut body = Box::pin(body);
+        // Returning early drops the import, which rolls back everything stored so far.
+        while let Some(chunk) = body.next().await {
+            let points = {
+                let chunk = chunk.map_err(|e| ApiError::BadRequest {
+                    code: "invalid_body",
+                    message: format!("failed to read request body: {}", e),
+                })?;
+                received += chunk.remaining();
+                if received > limit_bytes {
+                    return Err(ApiError::PayloadTooLarge { limit_bytes }.into());
+                }
+                reader.push(chunk.chunk()).map_err(invalid_gpx)?
+            };
+            import.push(points).await.map_err(storage)?;
+        }
+        reader.finish().map_err(invalid_gpx)?;
+
+        let result = import.commit().await.map_err(storage)?;
+        Ok(json(&result))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn claims(sub: &str, roles: &[&str]) -> Claims {
+            Claims {
+                sub: sub.to_string(),
+                roles: roles.iter().map(|role| role.to_string()).collect(),
+                tenant: Some("acme".to_string()),
+            }
+        }
+
+        #[test]
+        fn users_may_read_their_own_history() {
+            assert!(authorize_history(&claims("alice", &[]), "alice").is_ok());
+        }
+
+        #[test]
+        fn admins_may_read_anyones_history() {
+            assert!(authorize_history(&claims("alice", &["user", "admin"]), "bob").is_ok());
+        }
+
+        #[test]
+        fn other_users_history_is_forbidden() {
+            assert!(matches!(authorize_history(&claims("alice", &["user"]), "bob"), Err(ApiError::Forbidden(_))));
+            assert!(matches!(authorize_history(&claims("alice", &["administrator"]), "bob"), Err(ApiError::Forbidden(_))));
+        }
     }
 }
 
 pub mod routes {
-    use warp::{Reply, Rejection, reply::json};
+    use warp::{Reply, Rejection, http::StatusCode, reply::{json, with_status}};
+    use uuid::Uuid;
     use crate::AppState;
+    use crate::error::ApiError;
+    use crate::middleware::auth::Claims;
+    use crate::models::{OptimizeRouteRequest, OptimizedRouteResponse, Route};
+
+    #[utoipa::path(
+        post,
+        path = "/api/v1/routes/optimize",
+        summary = "Order waypoints into a short round trip from the first one and estimate arrival times",
+        request_body = OptimizeRouteRequest,
+        responses(
+            (status = 201, description = "The stored route with one ETA per stop in visiting order.", body = OptimizedRouteResponse),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn optimize_route(claims: Claims, data: OptimizeRouteRequest, state: AppState) -> Result<impl Reply, Rejection> {
+        data.validate().map_err(ApiError::from)?;
+
+        let optimized = state.route_optimizer.optimize(data.waypoints.clone(), data.metric);
+        let etas = state.route_optimizer.etas(&data, &optimized);
 
-    pub async fn optimize_route(_data: serde_json::Value, _state: AppState) -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({"message": "Route optimized"})))
+        state
+            .route_optimizer
+            .save_route(claims.tenant_id(), &data.waypoints, &optimized)
+            .await
+            .map(|route| with_status(json(&OptimizedRouteResponse { route, etas }), StatusCode::CREATED))
+            .map_err(|e| ApiError::storage("failed to persist optimized route", e).into())
     }
 
-    pub async fn get_route(_route_id: String, _state: AppState) -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({"message": "Route retrieved"})))
+    #[utoipa::path(
+        get,
+        path = "/api/v1/routes/{route_id}",
+        summary = "A stored route",
+        params(("route_id" = Uuid, Path, description = "Route id.")),
+        responses((status = 200, description = "The route.", body = Route), (status = 404), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_route(route_id: String, claims: Claims, state: AppState) -> Result<impl Reply, Rejection> {
+        let not_found = || ApiError::not_found("route_not_found", format!("no route with id {}", route_id));
+
+        let id = Uuid::parse_str(&route_id).map_err(|_| not_found())?;
+
+        match state.route_optimizer.get_route(claims.tenant_id(), id).await {
+            Ok(Some(route)) => Ok(json(&route)),
+            Ok(None) => Err(not_found().into()),
+            Err(e) => Err(ApiError::storage("failed to load route", e).into()),
+        }
     }
 }
 
 pub mod analytics {
     use warp::{Reply, Rejection, reply::json};
+    use tracing::warn;
     use crate::AppState;
+    use crate::database::with_retry;
+    use crate::error::ApiError;
+    use crate::middleware::auth::Claims;
+    use crate::models::{
+        validate_time_span, ActiveUsersQuery, ActiveUsersResult, AnalyticsQuery, AnalyticsSummary, DistanceResult, HeatmapQuery,
+        HeatmapResult, StopsResult, TripsResult,
+    };
+    use super::tracking::{authorize_history, authorize_tenant_history};
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/analytics",
+        summary = "Distance, speed and activity of a user over a window",
+        params(AnalyticsQuery),
+        responses(
+            (status = 200, description = "The summary.", body = AnalyticsSummary),
+            (status = 400),
+            (status = 403),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_analytics(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let query = AnalyticsQuery::from_params(&query).map_err(ApiError::from)?;
+        authorize_history(&claims, &query.user_id)?;
+        validate_time_span(Some(query.from), Some(query.to), state.config.max_query_window()).map_err(ApiError::from)?;
+
+        let tenant_id = claims.tenant_id();
+        with_retry(&state.config, || state.analytics_service.user_summary(tenant_id, &query))
+            .await
+            .map(|summary| json(&summary))
+            .map_err(|e| ApiError::storage("failed to compute analytics", e).into())
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/analytics/active-users",
+        summary = "How many distinct users reported recently",
+        params(ActiveUsersQuery),
+        responses((status = 200, description = "The count.", body = ActiveUsersResult), (status = 400), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_active_users(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let query = ActiveUsersQuery::from_params(&query).map_err(ApiError::from)?;
+
+        state
+            .analytics_service
+            .active_users(Some(claims.tenant_id()), query.window_minutes)
+            .await
+            .map(|result| json(&result))
+            .map_err(|e| {
+                warn!("Failed to count active users: {}", e);
+                ApiError::Unavailable("active user counts are temporarily unavailable".to_string()).into()
+            })
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/analytics/heatmap",
+        summary = "Fix counts per grid cell within a bounding box, densest first",
+        description = "Admins only.",
+        params(HeatmapQuery),
+        responses(
+            (status = 200, description = "The cells.", body = HeatmapResult),
+            (status = 400),
+            (status = 403),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_heatmap(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        authorize_tenant_history(&claims)?;
+        let query = HeatmapQuery::from_params(&query).map_err(ApiError::from)?;
+        validate_time_span(Some(query.from), Some(query.to), state.config.max_query_window()).map_err(ApiError::from)?;
 
-    pub async fn get_analytics(_query: std::collections::HashMap<String, String>, _state: AppState) -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({"message": "Analytics retrieved"})))
+        let tenant_id = claims.tenant_id();
+        with_retry(&state.config, || state.analytics_service.heatmap(tenant_id, &query))
+            .await
+            .map(|heatmap| json(&heatmap))
+            .map_err(|e| ApiError::storage("failed to build heatmap", e).into())
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/analytics/distance",
+        summary = "Distance a user travelled over a bounded window",
+        description = "The window may not exceed `DISTANCE_MAX_WINDOW_HOURS`.",
+        params(
+            AnalyticsQuery,
+            ("smooth" = Option<bool>, Query, description = "Kalman-smooth the track before measuring."),
+        ),
+        responses(
+            (status = 200, description = "The distance.", body = DistanceResult),
+            (status = 400),
+            (status = 403),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_distance(claims: Claims, params: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let query = AnalyticsQuery::from_params(&params).map_err(ApiError::from)?;
+        authorize_history(&claims, &query.user_id)?;
+
+        let max_window = chrono::Duration::hours(state.config.distance_max_window_hours).min(state.config.max_query_window());
+        validate_time_span(Some(query.from), Some(query.to), max_window).map_err(ApiError::from)?;
+
+        let smooth = params.get("smooth").is_some_and(|value| value == "true");
+        let tenant_id = claims.tenant_id();
+        with_retry(&state.config, || state.analytics_service.user_distance(tenant_id, &query, smooth))
+            .await
+            .map(|distance| json(&distance))
+            .map_err(|e| ApiError::storage("failed to compute distance", e).into())
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/analytics/stops",
+        summary = "Places a user stayed at over a window",
+        params(AnalyticsQuery),
+        responses(
+            (status = 200, description = "The stops, oldest first.", body = StopsResult),
+            (status = 400),
+            (status = 403),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_stops(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let query = AnalyticsQuery::from_params(&query).map_err(ApiError::from)?;
+        authorize_history(&claims, &query.user_id)?;
+        validate_time_span(Some(query.from), Some(query.to), state.config.max_query_window()).map_err(ApiError::from)?;
+
+        let tenant_id = claims.tenant_id();
+        with_retry(&state.config, || state.analytics_service.user_stops(tenant_id, &query))
+            .await
+            .map(|stops| json(&stops))
+            .map_err(|e| ApiError::storage("failed to detect stops", e).into())
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/analytics/trips",
+        summary = "Movement between stops over a window",
+        params(AnalyticsQuery),
+        responses(
+            (status = 200, description = "The trips, oldest first.", body = TripsResult),
+            (status = 400),
+            (status = 403),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_trips(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let query = AnalyticsQuery::from_params(&query).map_err(ApiError::from)?;
+        authorize_history(&claims, &query.user_id)?;
+        validate_time_span(Some(query.from), Some(query.to), state.config.max_query_window()).map_err(ApiError::from)?;
+
+        let tenant_id = claims.tenant_id();
+        with_retry(&state.config, || state.analytics_service.user_trips(tenant_id, &query))
+            .await
+            .map(|trips| json(&trips))
+            .map_err(|e| ApiError::storage("failed to detect trips", e).into())
     }
 }
 
 pub mod geofencing {
+    use warp::{Reply, Rejection, http::StatusCode, reply::{json, with_status}};
+    use uuid::Uuid;
+    use crate::AppState;
+    use crate::error::ApiError;
+    use crate::middleware::auth::Claims;
+    use crate::models::{
+        CreateGeofenceRequest, EvaluateGeofencesRequest, FeatureImportResult, FeatureImportStatus, Geofence, GeofenceDistance,
+        GeofenceEvaluation, GeofenceImportQuery, GeofenceImportReport, GeofenceImportRequest, GeofenceQuery, GeofenceVisitQuery,
+        GeofenceVisits, NearestGeofenceQuery, NearestGeofences, Paginated, PointQuery, validate_time_span,
+    };
+
+    /// Also the answer for another tenant's geofence, so its existence is not disclosed.
+    fn geofence_not_found(id: Uuid) -> ApiError {
+        ApiError::not_found("not_found", format!("no geofence with id {}", id))
+    }
+
+    #[utoipa::path(
+        post,
+        path = "/api/v1/geofences",
+        summary = "Create a geofence",
+        request_body = CreateGeofenceRequest,
+        responses((status = 201, description = "The geofence.", body = Geofence), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn create_geofence(claims: Claims, data: CreateGeofenceRequest, state: AppState) -> Result<impl Reply, Rejection> {
+        data.validate().map_err(ApiError::from)?;
+
+        state
+            .geolocation_service
+            .create_geofence(claims.tenant_id(), data)
+            .await
+            .map(|geofence| with_status(json(&geofence), StatusCode::CREATED))
+            .map_err(|e| ApiError::storage("failed to create geofence", e).into())
+    }
+
+    /// Creates a geofence per feature of a GeoJSON FeatureCollection and reports each feature's
+    /// outcome. Invalid features are skipped, unless `atomic=true`, in which case nothing is
+    /// created and the report comes back as 422.
+    #[utoipa::path(
+        post,
+        path = "/api/v1/geofences/import",
+        summary = "Create a geofence per feature of a GeoJSON FeatureCollection",
+        description = "A Point with a `radius` property (meters) becomes a circle, a Polygon a polygon. The other \
+                       properties are those of `CreateGeofenceRequest`.",
+        params(GeofenceImportQuery),
+        request_body = GeofenceImportRequest,
+        responses(
+            (status = 200, description = "The outcome of each feature.", body = GeofenceImportReport),
+            (
+                status = 422,
+                description = "`atomic=true` and some features were invalid; nothing was created.",
+                body = GeofenceImportReport
+            ),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn import_geofences(
+        claims: Claims,
+        query: std::collections::HashMap<String, String>,
+        data: GeofenceImportRequest,
+        state: AppState,
+    ) -> Result<impl Reply, Rejection> {
+        let query = GeofenceImportQuery::from_params(&query).map_err(ApiError::from)?;
+        data.validate().map_err(ApiError::from)?;
+
+        let mut requests = Vec::with_capacity(data.features.len());
+        let mut features = Vec::with_capacity(data.features.len());
+        for (index, feature) in data.features.into_iter().enumerate() {
+            let (status, error) = match CreateGeofenceRequest::from_feature(feature) {
+                Ok(request) => {
+                    requests.push(request);
+                    (FeatureImportStatus::Skipped, None)
+                }
+                Err(e) => (FeatureImportStatus::Invalid, Some(e)),
+            };
+            features.push(FeatureImportResult { index, status, geofence: None, error });
+        }
+
+        let invalid = features.len() - requests.len();
+        let rolled_back = query.atomic && invalid > 0;
+        if rolled_back {
+            requests.clear();
+        }
+        let created = state
+            .geolocation_service
+            .import_geofences(claims.tenant_id(), requests)
+            .await
+            .map_err(|e| ApiError::storage("failed to import geofences", e))?;
+
+        let mut report = GeofenceImportReport { atomic: query.atomic, created: created.len(), invalid, features };
+        let valid = report.features.iter_mut().filter(|feature| feature.status == FeatureImportStatus::Skipped);
+        for (feature, geofence) in valid.zip(created) {
+            feature.status = FeatureImportStatus::Created;
+            feature.geofence = Some(geofence);
+        }
+        let status = if rolled_back { StatusCode::UNPROCESSABLE_ENTITY } else { StatusCode::OK };
+        Ok(with_status(json(&report), status))
+    }
+
+    #[utoipa::path(
+        put,
+        path = "/api/v1/geofences/{geofence_id}",
+        summary = "Replace a geofence",
+        params(("geofence_id" = Uuid, Path, description = "Geofence id."),),
+        request_body = CreateGeofenceRequest,
+        responses((status = 200, description = "The geofence.", body = Geofence), (status = 404), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn update_geofence(id: Uuid, claims: Claims, data: CreateGeofenceRequest, state: AppState) -> Result<impl Reply, Rejection> {
+        data.validate().map_err(ApiError::from)?;
+
+        match state.geolocation_service.update_geofence(claims.tenant_id(), id, data).await {
+            Ok(Some(geofence)) => Ok(json(&geofence)),
+            Ok(None) => Err(geofence_not_found(id).into()),
+            Err(e) => Err(ApiError::storage("failed to update geofence", e).into()),
+        }
+    }
+
+    #[utoipa::path(
+        delete,
+        path = "/api/v1/geofences/{geofence_id}",
+        summary = "Delete a geofence",
+        params(("geofence_id" = Uuid, Path, description = "Geofence id."),),
+        responses((status = 204, description = "Deleted."), (status = 404), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn delete_geofence(id: Uuid, claims: Claims, state: AppState) -> Result<impl Reply, Rejection> {
+        match state.geolocation_service.delete_geofence(claims.tenant_id(), id).await {
+            Ok(true) => Ok(StatusCode::NO_CONTENT),
+            Ok(false) => Err(geofence_not_found(id).into()),
+            Err(e) => Err(ApiError::storage("failed to delete geofence", e).into()),
+        }
+    }
+
+    #[utoipa::path(
+        post,
+        path = "/api/v1/geofences/evaluate",
+        summary = "Which geofences contain a point, and whether a user is currently recorded inside each",
+        request_body = EvaluateGeofencesRequest,
+        responses((status = 200, description = "The containing geofences.", body = GeofenceEvaluation), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn evaluate_geofences(claims: Claims, data: EvaluateGeofencesRequest, state: AppState) -> Result<impl Reply, Rejection> {
+        data.validate().map_err(ApiError::from)?;
+
+        state
+            .geolocation_service
+            .evaluate_point(claims.tenant_id(), data)
+            .await
+            .map(|evaluation| json(&evaluation))
+            .map_err(|e| ApiError::storage("failed to evaluate geofences", e).into())
+    }
+
+    /// Stays of users inside the geofence that overlap the window, including ones still open.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/geofences/{geofence_id}/visits",
+        summary = "Stays of users inside a geofence that overlap the window, oldest entry first",
+        description = "A visit the user has not left yet has null `exited_at`, `dwell_secs` and `distance_meters`.",
+        params(("geofence_id" = Uuid, Path, description = "Geofence id."), GeofenceVisitQuery),
+        responses(
+            (status = 200, description = "The visits.", body = GeofenceVisits),
+            (status = 400),
+            (status = 404),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_geofence_visits(
+        id: Uuid,
+        claims: Claims,
+        query: std::collections::HashMap<String, String>,
+        state: AppState,
+    ) -> Result<impl Reply, Rejection> {
+        let query = GeofenceVisitQuery::from_params(&query).map_err(ApiError::from)?;
+        validate_time_span(query.from, query.to, state.config.max_query_window()).map_err(ApiError::from)?;
+
+        match state.geolocation_service.visits(claims.tenant_id(), id, &query).await {
+            Ok(Some(visits)) => Ok(json(&visits)),
+            Ok(None) => Err(geofence_not_found(id).into()),
+            Err(e) => Err(ApiError::storage("failed to load geofence visits", e).into()),
+        }
+    }
+
+    /// Signed distance from `lat`,`lon` to the geofence's boundary, for warning users as they
+    /// approach it.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/geofences/{geofence_id}/distance",
+        summary = "Signed distance from a point to a geofence's boundary, negative inside",
+        description = "For warning users as they approach. Altitude bands are ignored.",
+        params(("geofence_id" = Uuid, Path, description = "Geofence id."), PointQuery),
+        responses(
+            (status = 200, description = "The distance.", body = GeofenceDistance),
+            (status = 400),
+            (status = 404),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_geofence_distance(
+        id: Uuid,
+        claims: Claims,
+        query: std::collections::HashMap<String, String>,
+        state: AppState,
+    ) -> Result<impl Reply, Rejection> {
+        let point = PointQuery::from_params(&query).map_err(ApiError::from)?;
+
+        match state
+            .geolocation_service
+            .distance_to_geofence(claims.tenant_id(), id, point.latitude, point.longitude)
+            .await
+        {
+            Ok(Some(distance)) => Ok(json(&distance)),
+            Ok(None) => Err(geofence_not_found(id).into()),
+            Err(e) => Err(ApiError::storage("failed to load geofence", e).into()),
+        }
+    }
+
+    /// The geofences closest to `lat`,`lon`, for suggesting one when a stop is assigned by hand.
+    /// Unlike evaluation, geofences that don't contain the point are included.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/geofences/nearest",
+        summary = "The geofences whose boundaries are closest to a point",
+        description = "For suggesting one when assigning a stop by hand. Unlike evaluation, geofences that don't contain \
+                       the point are included. Ordered by distance to the boundary, which is 0 for containing geofences; \
+                       equal distances are ordered by name.",
+        params(NearestGeofenceQuery),
+        responses((status = 200, description = "The nearest geofences.", body = NearestGeofences), (status = 400), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_nearest_geofences(
+        claims: Claims,
+        query: std::collections::HashMap<String, String>,
+        state: AppState,
+    ) -> Result<impl Reply, Rejection> {
+        let query = NearestGeofenceQuery::from_params(&query).map_err(ApiError::from)?;
+
+        state
+            .geolocation_service
+            .nearest_geofences(claims.tenant_id(), &query)
+            .await
+            .map(|nearest| json(&nearest))
+            .map_err(|e| ApiError::storage("failed to load geofences", e).into())
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/geofences",
+        summary = "List geofences, optionally near a point or by name",
+        params(GeofenceQuery),
+        responses((status = 200, description = "One page of geofences.", body = Paginated<Geofence>), (status = 400), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_geofences(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let query = GeofenceQuery::from_params(&query).map_err(ApiError::from)?;
+
+        state
+            .geolocation_service
+            .list_geofences(claims.tenant_id(), &query)
+            .await
+            .map(|list| json(&list))
+            .map_err(|e| ApiError::storage("failed to list geofences", e).into())
+    }
+}
+
+pub mod users {
+    use tracing::{info, warn};
     use warp::{Reply, Rejection, reply::json};
     use crate::AppState;
+    use crate::error::ApiError;
+    use crate::middleware::auth::Claims;
+    use crate::models::{
+        ErasureResult, MembershipSource, Paginated, PresenceEvent, PresenceQuery, TrackedUser, TrackedUsersQuery, UserGeofences,
+        UserStatus,
+    };
+    use crate::services::tracking_service::{ErasureError, TrackedUsersError};
+
+    #[utoipa::path(
+        get,
+        path = "/api/v1/presence/events",
+        summary = "ONLINE/OFFLINE transitions, newest first",
+        params(PresenceQuery),
+        responses((status = 200, description = "One page of transitions.", body = Paginated<PresenceEvent>), (status = 400), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_presence_events(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        let query = PresenceQuery::from_params(&query).map_err(ApiError::from)?;
+
+        state
+            .presence_service
+            .events(claims.tenant_id(), &query)
+            .await
+            .map(|page| json(&page))
+            .map_err(|e| ApiError::storage("failed to load presence events", e).into())
+    }
+
+    /// Every user of the caller's tenant that has reported, by last report, with presence, battery
+    /// and latest fix. Admins only.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/admin/users",
+        summary = "Every user of the caller's tenant that has reported, by last report",
+        description = "With presence, battery and latest fix. Admins only.",
+        params(TrackedUsersQuery),
+        responses(
+            (status = 200, description = "One page of users.", body = Paginated<TrackedUser>),
+            (status = 400),
+            (status = 403),
+            (status = 503),
+        ),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn list_tracked_users(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        if !claims.has_role("admin") {
+            return Err(ApiError::Forbidden("listing users requires an admin token".to_string()).into());
+        }
+        let query = TrackedUsersQuery::from_params(&query).map_err(ApiError::from)?;
+
+        match state.tracking_service.tracked_users(claims.tenant_id(), &query).await {
+            Ok(page) => Ok(json(&page)),
+            Err(TrackedUsersError::Storage(e)) => Err(ApiError::storage("failed to load latest locations", e).into()),
+            Err(TrackedUsersError::Cache(e)) => Err(ApiError::Unavailable(format!("presence is unavailable: {}", e)).into()),
+        }
+    }
+
+    /// Sequence diagnostics are best effort: the status is still served when they fail.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/users/{user_id}/status",
+        summary = "Whether a user is online, from their last report",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."),),
+        responses((status = 200, description = "The user's presence.", body = UserStatus), (status = 403), (status = 404), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_user_status(user_id: String, claims: Claims, state: AppState) -> Result<impl Reply, Rejection> {
+        if claims.sub != user_id && !claims.has_role("admin") {
+            return Err(ApiError::Forbidden("cannot read another user's status".to_string()).into());
+        }
+        let tenant_id = claims.tenant_id();
+        match state.tracking_service.user_status(tenant_id, &user_id).await {
+            Ok(Some(mut status)) => {
+                status.diagnostics = state
+                    .tracking_service
+                    .sequence_diagnostics(tenant_id, &user_id)
+                    .await
+                    .inspect_err(|e| warn!("Failed to check sequence gaps of {}: {}", user_id, e))
+                    .ok()
+                    .flatten();
+                Ok(json(&status))
+            }
+            Ok(None) => Err(ApiError::not_found("user_not_found", format!("user {} has never reported", user_id)).into()),
+            Err(e) => Err(ApiError::Unavailable(format!("presence is unavailable: {}", e)).into()),
+        }
+    }
+
+    /// The geofences a user is inside now. When the monitor has recorded none for them, their
+    /// latest fix is tested instead; a user who never reported is inside none.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/users/{user_id}/geofences",
+        summary = "The geofences a user is inside now and for how long",
+        description = "From the monitor's membership, or from their latest fix when it has none. Empty for users who \
+                       never reported.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."),),
+        responses((status = 200, description = "The user's geofences.", body = UserGeofences), (status = 403), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_user_geofences(user_id: String, claims: Claims, state: AppState) -> Result<impl Reply, Rejection> {
+        if claims.sub != user_id && !claims.has_role("admin") {
+            return Err(ApiError::Forbidden("cannot read another user's geofences".to_string()).into());
+        }
+        let tenant_id = claims.tenant_id();
+        let recorded = state
+            .geolocation_service
+            .recorded_geofences(tenant_id, &user_id)
+            .await
+            .map_err(|e| ApiError::storage("failed to load geofences", e))?;
+
+        let (source, geofences) = match recorded {
+            Some(geofences) => (MembershipSource::Monitor, geofences),
+            None => {
+                let latest = state
+                    .tracking_service
+                    .current_location(tenant_id, &user_id)
+                    .await
+                    .map_err(|e| ApiError::storage("failed to load the latest location", e))?;
+                let geofences = match latest {
+                    Some(fix) => state
+                        .geolocation_service
+                        .geofences_containing(&fix)
+                        .await
+                        .map_err(|e| ApiError::storage("failed to load geofences", e))?,
+                    None => Vec::new(),
+                };
+                (MembershipSource::LatestFix, geofences)
+            }
+        };
 
-    pub async fn create_geofence(_data: serde_json::Value, _state: AppState) -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({"message": "Geofence created"})))
+        Ok(json(&UserGeofences { user_id, source, geofences }))
     }
 
-    pub async fn get_geofences(_query: std::collections::HashMap<String, String>, _state: AppState) -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({"message": "Geofences retrieved"})))
+    /// Erases everything stored about a user. Users may erase their own data; admins anyone's.
+    /// Repeating the call is harmless and reports zero counts.
+    #[utoipa::path(
+        delete,
+        path = "/api/v1/users/{user_id}/data",
+        summary = "Erase everything stored about a user",
+        description = "Users may erase their own data; admins anyone's. Idempotent.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."),),
+        responses((status = 200, description = "What was removed.", body = ErasureResult), (status = 403), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn erase_user_data(user_id: String, claims: Claims, state: AppState) -> Result<impl Reply, Rejection> {
+        if claims.sub != user_id && !claims.has_role("admin") {
+            return Err(ApiError::Forbidden("cannot erase another user's data".to_string()).into());
+        }
+
+        match state.tracking_service.erase_user_data(claims.tenant_id(), &user_id).await {
+            Ok(result) => {
+                info!(
+                    target: "audit",
+                    tenant_id = %claims.tenant_id(),
+                    actor = %claims.sub,
+                    user_id = %user_id,
+                    locations = result.locations,
+                    rejected_locations = result.rejected_locations,
+                    daily_stats = result.daily_stats,
+                    geofence_events = result.geofence_events,
+                    cache_keys = result.cache_keys,
+                    "User data erased"
+                );
+                Ok(json(&result))
+            }
+            Err(ErasureError::Storage(e)) => Err(ApiError::storage("failed to erase user data", e).into()),
+            Err(ErasureError::Cache(e)) => Err(ApiError::Unavailable(format!(
+                "stored data was erased but cached data could not be ({}), retry the request",
+                e
+            ))
+            .into()),
+        }
     }
 }
 
 pub mod websocket {
-    use warp::{Reply, Rejection, reply::json, ws::Ws};
+    use std::time::Duration;
+    use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+    use rand::Rng;
+    use serde::Serialize;
+    use tokio::sync::broadcast::{self, error::RecvError};
+    use tokio::time::{Instant, Interval, MissedTickBehavior};
+    use tracing::{debug, error, warn};
+    use uuid::Uuid;
+    use warp::{Reply, Rejection, reply::with_header, ws::{Message, WebSocket, Ws}};
     use crate::AppState;
+    use crate::config::Config;
+    use crate::error::ApiError;
+    use crate::middleware::auth::{WsAuth, WS_BEARER_PROTOCOL};
+    use crate::models::{
+        validate_time_span, ExportQuery, GeofenceStreamMessage, Location, PresenceEvent, ReplayQuery, TrackingStreamQuery,
+    };
+    use crate::openapi::AccessToken;
+    use crate::services::live_updates::{ConnectionPermit, ConnectionRefused};
+    use crate::utils::redis_keys;
+
+    /// "Normal closure": a replay reached the end of its window.
+    const NORMAL_CLOSE_CODE: u16 = 1000;
+    /// "Going away": the service is shutting down.
+    const SHUTDOWN_CLOSE_CODE: u16 = 1001;
+    /// Application-defined: the client stopped answering pings. Distinct from the shutdown code
+    /// so clients can tell a dead link from a deploy.
+    const UNRESPONSIVE_CLOSE_CODE: u16 = 4000;
+    /// "Internal error": the initial state could not be loaded.
+    const INTERNAL_ERROR_CLOSE_CODE: u16 = 1011;
+    /// "Try again later": the client could not keep up with the update rate, or the service is
+    /// at its connection limit.
+    const LAGGED_CLOSE_CODE: u16 = 1013;
+    /// "Policy violation": the user already has as many connections open as allowed.
+    const USER_LIMIT_CLOSE_CODE: u16 = 1008;
+
+    /// Documents the upgrade and the close codes above.
+    const UPGRADE_DESCRIPTION: &str = "Switching to the WebSocket protocol. Each text frame is one JSON message. The \
+        server pings every `WS_PING_INTERVAL_SECS` and closes with 4000 when nothing comes back within \
+        `WS_PONG_TIMEOUT_SECS`. Other close codes: 1001 when the service shuts down, 1008 when the user has too many \
+        connections open, 1011 when the initial state could not be loaded, and 1013 when the client falls behind or \
+        the service is at capacity.";
+
+    /// What [`Heartbeat::next`] asks the connection to do.
+    enum Beat {
+        Ping,
+        Unanswered,
+    }
+
+    /// Pings the client every `WS_PING_INTERVAL_SECS` and gives up on it once nothing has come back
+    /// for `WS_PONG_TIMEOUT_SECS` after a ping, so peers a load balancer dropped without a close
+    /// frame don't keep their connection slot. Any frame from the client counts as an answer.
+    struct Heartbeat {
+        ticks: Interval,
+        timeout: Duration,
+        answer_due: Option<Instant>,
+    }
+
+    impl Heartbeat {
+        fn new(config: &Config) -> Self {
+            let every = Duration::from_secs(config.ws_ping_interval_secs);
+            let mut ticks = tokio::time::interval_at(Instant::now() + every, every);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Self { ticks, timeout: Duration::from_secs(config.ws_pong_timeout_secs), answer_due: None }
+        }
+
+        /// Waits for the next ping to be due or, while one is unanswered, for its timeout.
+        async fn next(&mut self) -> Beat {
+            match self.answer_due {
+                Some(due) => {
+                    tokio::time::sleep_until(due).await;
+                    Beat::Unanswered
+                }
+                None => {
+                    self.ticks.tick().await;
+                    self.answer_due = Some(Instant::now() + self.timeout);
+                    Beat::Ping
+                }
+            }
+        }
+
+        fn heard(&mut self) {
+            self.answer_due = None;
+        }
+    }
+
+    /// Completes the handshake only to close the socket straight away with a code saying why, so
+    /// clients can tell a limit from a network failure.
+    fn refuse(ws: Ws, refused: ConnectionRefused, label: &str) -> warp::reply::Response {
+        warn!("Refusing WebSocket for {}: {:?}", label, refused);
+        let (code, reason) = match refused {
+            ConnectionRefused::UserLimit => (USER_LIMIT_CLOSE_CODE, "too many connections for this user"),
+            ConnectionRefused::GlobalLimit => (LAGGED_CLOSE_CODE, "server at connection capacity"),
+        };
+        ws.on_upgrade(move |mut socket| async move {
+            let _ = socket.send(Message::close_with(code, reason)).await;
+        })
+        .into_response()
+    }
 
-    pub async fn tracking_websocket(_user_id: String, _ws: Ws, _state: AppState) -> Result<impl Reply, Rejection> {
-        Ok(json(&serde_json::json!({"message": "WebSocket connection established"})))
+    /// Selects [`WS_BEARER_PROTOCOL`] when the token came in `Sec-WebSocket-Protocol`, as the
+    /// browser aborts the connection otherwise.
+    fn accept(reply: impl Reply, via_subprotocol: bool) -> warp::reply::Response {
+        if via_subprotocol {
+            with_header(reply, "sec-websocket-protocol", WS_BEARER_PROTOCOL).into_response()
+        } else {
+            reply.into_response()
+        }
+    }
+
+    /// Streams a user's live fixes, every one or, with `rate`, the latest per interval. Only the
+    /// user themselves or an admin of their tenant may subscribe; anyone else is turned away
+    /// before the upgrade.
+    #[utoipa::path(
+        get,
+        path = "/ws/tracking/{user_id}",
+        summary = "Live fixes of a user",
+        description = "Users may subscribe to themselves; admins to anyone.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."), TrackingStreamQuery, AccessToken),
+        responses(
+            (status = 101, description = UPGRADE_DESCRIPTION, body = Location),
+            (status = 401),
+            (status = 403),
+            (status = 404),
+        ),
+        security(("bearerAuth" = []), ()),
+    )]
+    pub async fn tracking_websocket(
+        user_id: String,
+        ws: Ws,
+        auth: WsAuth,
+        query: std::collections::HashMap<String, String>,
+        state: AppState,
+    ) -> Result<impl Reply, Rejection> {
+        if auth.claims.sub != user_id && !auth.claims.has_role("admin") {
+            return Err(ApiError::Forbidden("cannot subscribe to another user's location stream".to_string()).into());
+        }
+        let query = TrackingStreamQuery::from_params(&query).map_err(ApiError::from)?;
+
+        let tenant_id = auth.claims.tenant_id().to_string();
+        let subscriber = redis_keys::presence_member(&tenant_id, &auth.claims.sub);
+        let permit = match state.live_updates.admit(Some(&subscriber)) {
+            Ok(permit) => permit,
+            Err(refused) => return Ok(refuse(ws, refused, &subscriber)),
+        };
+
+        let reply = ws.on_upgrade(move |socket| async move {
+            let updates = state.live_updates.subscribe(&tenant_id, &user_id);
+            forward(socket, updates, None, query.rate, permit, &user_id, &state).await;
+            state.live_updates.release(&tenant_id, &user_id);
+        });
+        Ok(accept(reply, auth.via_subprotocol))
+    }
+
+    /// Replays a user's recorded fixes in the window, paced by their timestamps divided by
+    /// `speed`, then closes normally. Same access rule as [`tracking_websocket`].
+    #[utoipa::path(
+        get,
+        path = "/ws/replay/{user_id}",
+        summary = "A user's recorded fixes in the window, paced by their timestamps divided by `speed`",
+        description = "Closes with 1000 at the end. Same access rule as live tracking.",
+        params(("user_id" = String, Path, description = "User id, the `sub` of their token."), ReplayQuery, AccessToken),
+        responses(
+            (status = 101, description = UPGRADE_DESCRIPTION, body = Location),
+            (status = 401),
+            (status = 403),
+            (status = 404),
+        ),
+        security(("bearerAuth" = []), ()),
+    )]
+    pub async fn replay_websocket(
+        user_id: String,
+        ws: Ws,
+        auth: WsAuth,
+        query: std::collections::HashMap<String, String>,
+        state: AppState,
+    ) -> Result<impl Reply, Rejection> {
+        if auth.claims.sub != user_id && !auth.claims.has_role("admin") {
+            return Err(ApiError::Forbidden("cannot replay another user's location history".to_string()).into());
+        }
+        let query = ReplayQuery::from_params(&query).map_err(ApiError::from)?;
+        validate_time_span(Some(query.from), Some(query.to), state.config.max_query_window()).map_err(ApiError::from)?;
+        let tenant_id = auth.claims.tenant_id().to_string();
+        let subscriber = redis_keys::presence_member(&tenant_id, &auth.claims.sub);
+        let permit = match state.live_updates.admit(Some(&subscriber)) {
+            Ok(permit) => permit,
+            Err(refused) => return Ok(refuse(ws, refused, &subscriber)),
+        };
+
+        let reply = ws.on_upgrade(move |socket| async move {
+            // Playback sleeps for most of its life, so it gets a task of its own.
+            tokio::spawn(replay(socket, tenant_id, user_id, query, permit, state));
+        });
+        Ok(accept(reply, auth.via_subprotocol))
+    }
+
+    /// Sends each fix once its offset from the first fix, scaled by the replay speed, has elapsed.
+    /// Due times are measured from the start rather than the previous send, so delays never add up.
+    async fn replay(
+        socket: WebSocket,
+        tenant_id: String,
+        user_id: String,
+        query: ReplayQuery,
+        _permit: ConnectionPermit,
+        state: AppState,
+    ) {
+        let (mut sender, mut receiver) = socket.split();
+        let mut shutdown = state.live_updates.shutdown_signal();
+        let shutting_down = async move {
+            let _ = shutdown.wait_for(|closing| *closing).await;
+        };
+        tokio::pin!(shutting_down);
+        let mut heartbeat = Heartbeat::new(&state.config);
+
+        let mut rows = state.tracking_service.export_locations(
+            tenant_id,
+            user_id.clone(),
+            ExportQuery { from: Some(query.from), to: Some(query.to), simplify: None },
+        );
+        let started = tokio::time::Instant::now();
+        let mut first_timestamp = None;
+        // The next fix to send and when it is due; the following row is only read once it is sent.
+        let mut pending = None;
+        let close = loop {
+            let due = pending.as_ref().map_or(started, |(_, due)| *due);
+            tokio::select! {
+                _ = &mut shutting_down => break Some(Message::close_with(SHUTDOWN_CLOSE_CODE, "server shutting down")),
+                incoming = receiver.next() => match incoming {
+                    Some(Ok(message)) if message.is_close() => break None,
+                    Some(Ok(_)) => heartbeat.heard(),
+                    Some(Err(_)) | None => break None,
+                },
+                beat = heartbeat.next() => match beat {
+                    Beat::Ping => {
+                        if sender.send(Message::ping(Vec::new())).await.is_err() {
+                            break None;
+                        }
+                    }
+                    Beat::Unanswered => {
+                        debug!("Closing replay of {} after its client stopped answering pings", user_id);
+                        break Some(Message::close_with(UNRESPONSIVE_CLOSE_CODE, "ping timeout"));
+                    }
+                },
+                row = rows.recv(), if pending.is_none() => match row {
+                    Some(Ok(location)) => {
+                        let first = *first_timestamp.get_or_insert(location.timestamp);
+                        let offset = (location.timestamp - first).to_std().unwrap_or_default().div_f64(query.speed);
+                        pending = Some((location, started + offset));
+                    }
+                    Some(Err(e)) => {
+                        error!("Replay of {} failed: {}", user_id, e);
+                        break Some(Message::close_with(INTERNAL_ERROR_CLOSE_CODE, "history unavailable"));
+                    }
+                    None => break Some(Message::close_with(NORMAL_CLOSE_CODE, "replay finished")),
+                },
+                _ = tokio::time::sleep_until(due), if pending.is_some() => {
+                    let Some((location, _)) = pending.take() else { continue };
+                    let Ok(payload) = serde_json::to_string(&location) else { continue };
+                    if sender.send(Message::text(payload)).await.is_err() {
+                        break None;
+                    }
+                }
+            }
+        };
+
+        if let Some(close) = close {
+            let _ = sender.send(close).await;
+        }
+    }
+
+    /// Streams ENTER/EXIT events for a geofence, preceded by a snapshot of the users inside it.
+    /// Another tenant's geofence is reported as not found.
+    #[utoipa::path(
+        get,
+        path = "/ws/geofences/{geofence_id}",
+        summary = "A snapshot of the users inside a geofence, then its live events",
+        params(("geofence_id" = Uuid, Path, description = "Geofence id."), AccessToken),
+        responses(
+            (status = 101, description = UPGRADE_DESCRIPTION, body = GeofenceStreamMessage),
+            (status = 401),
+            (status = 403),
+            (status = 404),
+        ),
+        security(("bearerAuth" = []), ()),
+    )]
+    pub async fn geofence_websocket(geofence_id: Uuid, ws: Ws, auth: WsAuth, state: AppState) -> Result<impl Reply, Rejection> {
+        let tenant_id = auth.claims.tenant_id().to_string();
+        let exists = state
+            .geolocation_service
+            .users_inside(&tenant_id, geofence_id)
+            .await
+            .map_err(|e| ApiError::storage("failed to load geofence", e))?
+            .is_some();
+        if !exists {
+            return Err(ApiError::not_found("not_found", format!("no geofence with id {}", geofence_id)).into());
+        }
+        let permit = match state.live_updates.admit(None) {
+            Ok(permit) => permit,
+            Err(refused) => return Ok(refuse(ws, refused, &geofence_id.to_string())),
+        };
+
+        let reply = ws.on_upgrade(move |socket| async move {
+            // Subscribe before taking the snapshot so no transition falls between the two.
+            let updates = state.live_updates.subscribe_geofence(geofence_id);
+            let snapshot = match state.geolocation_service.users_inside(&tenant_id, geofence_id).await {
+                Ok(users_inside) => Message::text(
+                    serde_json::to_string(&GeofenceStreamMessage::Snapshot {
+                        geofence_id,
+                        users_inside: users_inside.unwrap_or_default(),
+                    })
+                    .unwrap_or_default(),
+                ),
+                Err(e) => {
+                    error!("Failed to load geofence snapshot: {}", e);
+                    Message::close_with(INTERNAL_ERROR_CLOSE_CODE, "snapshot unavailable")
+                }
+            };
+            forward(socket, updates, Some(snapshot), None, permit, &geofence_id.to_string(), &state).await;
+            state.live_updates.release_geofence(geofence_id);
+        });
+        Ok(accept(reply, auth.via_subprotocol))
+    }
+
+    /// Streams every ONLINE/OFFLINE transition of the caller's tenant as it is detected.
+    #[utoipa::path(
+        get,
+        path = "/ws/presence",
+        summary = "Every ONLINE/OFFLINE transition as it is detected",
+        params(AccessToken),
+        responses(
+            (status = 101, description = UPGRADE_DESCRIPTION, body = PresenceEvent),
+            (status = 401),
+            (status = 403),
+            (status = 404),
+        ),
+        security(("bearerAuth" = []), ()),
+    )]
+    pub async fn presence_websocket(ws: Ws, auth: WsAuth, state: AppState) -> Result<impl Reply, Rejection> {
+        let permit = match state.live_updates.admit(None) {
+            Ok(permit) => permit,
+            Err(refused) => return Ok(refuse(ws, refused, "presence")),
+        };
+
+        let tenant_id = auth.claims.tenant_id().to_string();
+        let reply = ws.on_upgrade(move |socket| async move {
+            let updates = state.live_updates.subscribe_presence(&tenant_id);
+            forward(socket, updates, None, None, permit, "presence", &state).await;
+            state.live_updates.release_presence(&tenant_id);
+        });
+        Ok(accept(reply, auth.via_subprotocol))
+    }
+
+    /// Up to a tenth longer than `rate`, so the coalesced streams of many clients don't fall into
+    /// step.
+    fn coalescing_window(rate: Duration) -> Duration {
+        rate.mul_f64(rand::thread_rng().gen_range(1.0..=1.1))
+    }
+
+    /// Sends `update` as JSON; `false` once the socket is gone.
+    async fn send_json<T: Serialize>(sender: &mut SplitSink<WebSocket, Message>, update: &T) -> bool {
+        match serde_json::to_string(update) {
+            Ok(payload) => sender.send(Message::text(payload)).await.is_ok(),
+            Err(_) => true,
+        }
+    }
+
+    /// Sends `initial`, then forwards broadcast messages to the socket as JSON until either side
+    /// goes away. With `coalesce`, a message arriving within that long of the previous send is held
+    /// back and replaced by any later one; the one held is sent as soon as the interval is up, so
+    /// the last message before the stream goes quiet is never lost. A client that stops answering
+    /// the [`Heartbeat`] pings is closed. The connection's slot is freed when `_permit` is dropped
+    /// on return.
+    async fn forward<T: Clone + Serialize>(
+        socket: WebSocket,
+        mut updates: broadcast::Receiver<T>,
+        initial: Option<Message>,
+        coalesce: Option<Duration>,
+        _permit: ConnectionPermit,
+        label: &str,
+        state: &AppState,
+    ) {
+        let (mut sender, mut receiver) = socket.split();
+        let mut shutdown = state.live_updates.shutdown_signal();
+
+        if let Some(message) = initial {
+            let closing = message.is_close();
+            if sender.send(message).await.is_err() || closing {
+                return;
+            }
+        }
+
+        let mut heartbeat = Heartbeat::new(&state.config);
+        let mut held: Option<T> = None;
+        let mut next_send = Instant::now();
+        loop {
+            tokio::select! {
+                _ = async { shutdown.wait_for(|closing| *closing).await.map(|_| ()) } => {
+                    if let Some(update) = held.take() {
+                        send_json(&mut sender, &update).await;
+                    }
+                    let _ = sender.send(Message::close_with(SHUTDOWN_CLOSE_CODE, "server shutting down")).await;
+                    break;
+                }
+                _ = tokio::time::sleep_until(next_send), if held.is_some() => {
+                    let Some(update) = held.take() else { continue };
+                    if !send_json(&mut sender, &update).await {
+                        break;
+                    }
+                    if let Some(rate) = coalesce {
+                        next_send = Instant::now() + coalescing_window(rate);
+                    }
+                }
+                update = updates.recv() => match update {
+                    Ok(update) => match coalesce {
+                        Some(_) if Instant::now() < next_send => held = Some(update),
+                        _ => {
+                            if !send_json(&mut sender, &update).await {
+                                break;
+                            }
+                            if let Some(rate) = coalesce {
+                                next_send = Instant::now() + coalescing_window(rate);
+                            }
+                        }
+                    },
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Dropping WebSocket subscriber for {} after it fell {} updates behind", label, skipped);
+                        let _ = sender.send(Message::close_with(LAGGED_CLOSE_CODE, "subscriber lagging")).await;
+                        break;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                beat = heartbeat.next() => match beat {
+                    Beat::Ping => {
+                        if sender.send(Message::ping(Vec::new())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Beat::Unanswered => {
+                        debug!("Closing WebSocket for {} after it stopped answering pings", label);
+                        let _ = sender.send(Message::close_with(UNRESPONSIVE_CLOSE_CODE, "ping timeout")).await;
+                        break;
+                    }
+                },
+                incoming = receiver.next() => match incoming {
+                    Some(Ok(message)) if message.is_close() => break,
+                    Some(Ok(_)) => heartbeat.heard(),
+                    Some(Err(e)) => {
+                        debug!("WebSocket error for {}: {}", label, e);
+                        break;
+                    }
+                    None => break,
+                },
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_support;
+
+        #[test]
+        fn the_coalescing_window_stays_within_a_tenth_of_the_rate() {
+            let rate = Duration::from_millis(500);
+            let windows: Vec<_> = (0..1000).map(|_| coalescing_window(rate)).collect();
+            assert!(windows.iter().all(|window| (rate..=rate.mul_f64(1.1)).contains(window)));
+            assert!(windows.iter().any(|window| *window != windows[0]), "windows should vary");
+        }
+
+        #[test]
+        fn a_zero_rate_has_no_window() {
+            assert_eq!(coalescing_window(Duration::ZERO), Duration::ZERO);
+        }
+
+        fn heartbeat(ping_interval_secs: u64, pong_timeout_secs: u64) -> Heartbeat {
+            let mut config = test_support::config();
+            config.ws_ping_interval_secs = ping_interval_secs;
+            config.ws_pong_timeout_secs = pong_timeout_secs;
+            Heartbeat::new(&config)
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn a_client_that_never_answers_is_given_up_on_after_the_interval_and_the_timeout() {
+            let start = Instant::now();
+            let mut heartbeat = heartbeat(30, 10);
+
+            assert!(matches!(heartbeat.next().await, Beat::Ping));
+            assert_eq!(start.elapsed(), Duration::from_secs(30));
+            assert!(matches!(heartbeat.next().await, Beat::Unanswered));
+            assert_eq!(start.elapsed(), Duration::from_secs(40));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn an_answer_waits_for_the_next_ping_instead() {
+            let start = Instant::now();
+            let mut heartbeat = heartbeat(30, 10);
+
+            assert!(matches!(heartbeat.next().await, Beat::Ping));
+            tokio::time::advance(Duration::from_secs(5)).await;
+            heartbeat.heard();
+            assert!(matches!(heartbeat.next().await, Beat::Ping));
+            assert_eq!(start.elapsed(), Duration::from_secs(60));
+        }
+
+        #[test]
+        fn the_unresponsive_close_code_is_application_defined() {
+            assert!((4000..5000).contains(&UNRESPONSIVE_CLOSE_CODE));
+            assert_ne!(UNRESPONSIVE_CLOSE_CODE, SHUTDOWN_CLOSE_CODE);
+        }
+    }
+}
+
+pub mod usage {
+    use warp::{Reply, Rejection, reply::json};
+    use crate::AppState;
+    use crate::error::ApiError;
+    use crate::middleware::auth::Claims;
+    use crate::models::{Usage, UsageQuery};
+    use crate::services::usage_service::UsageError;
+
+    /// Fixes a tenant stored during a month, for billing. Admins only, and only for their own tenant.
+    #[utoipa::path(
+        get,
+        path = "/api/v1/usage",
+        summary = "Fixes a tenant stored during a calendar month (UTC), for billing",
+        description = "Rejected and duplicate fixes are not counted. Admins only, for their own tenant.",
+        params(UsageQuery),
+        responses((status = 200, description = "The month's usage.", body = Usage), (status = 400), (status = 403), (status = 503)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn get_usage(claims: Claims, query: std::collections::HashMap<String, String>, state: AppState) -> Result<impl Reply, Rejection> {
+        if !claims.has_role("admin") {
+            return Err(ApiError::Forbidden("usage requires an admin token".to_string()).into());
+        }
+        let query = UsageQuery::from_params(&query).map_err(ApiError::from)?;
+        let tenant_id = query.tenant_id.as_deref().unwrap_or(claims.tenant_id());
+        if tenant_id != claims.tenant_id() {
+            return Err(ApiError::Forbidden("cannot read another tenant's usage".to_string()).into());
+        }
+
+        match state.usage_service.usage(tenant_id, query.month).await {
+            Ok(usage) => Ok(json(&usage)),
+            Err(UsageError::Storage(e)) => Err(ApiError::storage("failed to read usage", e).into()),
+            Err(UsageError::Cache(e)) => Err(ApiError::Unavailable(format!("usage is temporarily unavailable ({})", e)).into()),
+        }
     }
 }
 
 pub mod metrics {
-    use warp::{Reply, Rejection};
+    use warp::{Reply, Rejection, reply::with_header};
+    use crate::AppState;
+    use crate::error::ApiError;
+
+    #[utoipa::path(
+        get,
+        path = "/metrics",
+        summary = "Prometheus metrics",
+        responses((status = 200, description = "Text exposition format.", content_type = "text/plain", body = String)),
+    )]
+    pub async fn prometheus_metrics(state: AppState) -> Result<impl Reply, Rejection> {
+        state
+            .metrics
+            .render()
+            .map(|body| with_header(body, "content-type", prometheus::TEXT_FORMAT))
+            .map_err(|e| ApiError::Internal(format!("failed to encode metrics: {}", e)).into())
+    }
+}
 
-    pub async fn prometheus_metrics() -> Result<impl Reply, Rejection> {
-        Ok("# Prometheus metrics placeholder")
+pub mod debug {
+    use warp::{Reply, Rejection, reply::json};
+    use crate::AppState;
+    use crate::error::ApiError;
+    use crate::middleware::auth::Claims;
+
+    /// A human-readable snapshot of the process for admins: connection pool, WebSockets, uptime
+    /// and the effective configuration with its secrets redacted.
+    #[utoipa::path(
+        get,
+        path = "/debug/stats",
+        summary = "A snapshot of the process for admins",
+        description = "Connection pool, WebSockets, uptime and the effective configuration with its secrets redacted. The \
+                       shape is meant for people and may change.",
+        responses((status = 200, description = "The snapshot.", body = Object), (status = 403)),
+        security(("bearerAuth" = [])),
+    )]
+    pub async fn stats(claims: Claims, state: AppState) -> Result<impl Reply, Rejection> {
+        if !claims.has_role("admin") {
+            return Err(ApiError::Forbidden("debug stats require an admin token".to_string()).into());
+        }
+
+        Ok(json(&serde_json::json!({
+            "service": "live-tracking",
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime_secs": state.started_at.elapsed().as_secs(),
+            "db_pool": {
+                "size": state.db_pool.size(),
+                "idle": state.db_pool.num_idle(),
+                "max_connections": state.config.db_max_connections
+            },
+            "websockets": state.live_updates.stats(),
+            "config": state.config.redacted()
+        })))
     }
 }
\ No newline at end of file
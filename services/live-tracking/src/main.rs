@@ -1,28 +1,40 @@
 // Live Tracking Service - Real-time GPS and activity tracking
+// The composed warp route tree is deep enough to exceed the default limit when checking `Send`.
+#![recursion_limit = "256"]
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use warp::{Filter, Rejection, Reply};
-use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-use chrono::{DateTime, Utc};
-use redis::Client as RedisClient;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use warp::{http::HeaderValue, Filter, Rejection, Reply};
 use sqlx::{Pool, Postgres};
-use tracing::{info, error, warn, instrument};
+use tracing::{info, warn};
+use uuid::Uuid;
 
 mod config;
 mod database;
+mod error;
 mod models;
 mod services;
 mod handlers;
 mod middleware;
+mod metrics;
+mod openapi;
+mod redis_client;
 mod utils;
+#[cfg(test)]
+mod test_support;
 
-use config::Config;
+use config::{Config, LogFormat};
+use metrics::Metrics;
+use redis_client::RedisClient;
 use services::{
     tracking_service::TrackingService,
     geolocation_service::GeolocationService,
     route_optimization::RouteOptimizer,
     analytics_service::AnalyticsService,
+    live_updates::LiveUpdates,
+    presence_service::PresenceService,
+    usage_service::UsageService,
+    webhooks::WebhookDispatcher,
 };
 
 #[derive(Debug, Clone)]
@@ -34,43 +46,71 @@ pub struct AppState {
     pub geolocation_service: Arc<GeolocationService>,
     pub route_optimizer: Arc<RouteOptimizer>,
     pub analytics_service: Arc<AnalyticsService>,
+    pub presence_service: Arc<PresenceService>,
+    pub usage_service: Arc<UsageService>,
+    pub metrics: Arc<Metrics>,
+    pub live_updates: Arc<LiveUpdates>,
+    pub started_at: Instant,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Load configuration; it picks the log format, so it comes before tracing
+    let config = Arc::new(Config::from_env()?);
+
     // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+    let subscriber = tracing_subscriber::fmt().with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    match config.log_format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
 
     info!("Starting Live Tracking Service v1.0.0");
-
-    // Load configuration
-    let config = Arc::new(Config::from_env()?);
-    info!("Configuration loaded for environment: {}", config.environment);
+    info!("Configuration loaded for environment: {} (log format {:?})", config.environment, config.log_format);
 
     // Initialize database pool
-    let db_pool = database::create_pool(&config.database_url).await?;
+    let db_pool = database::create_pool(&config).await?;
     info!("Database connection pool created");
 
     // Run database migrations
-    database::run_migrations(&db_pool).await?;
+    database::run_migrations(&db_pool)
+        .await
+        .map_err(|e| format!("database migrations failed: {}", e))?;
     info!("Database migrations completed");
 
+    // Initialize metrics registry
+    let metrics = Arc::new(Metrics::new()?);
+
     // Initialize Redis client
-    let redis_client = redis::Client::open(config.redis_url.as_str())?;
+    let redis_client = RedisClient::new(redis::Client::open(config.redis_url.as_str())?, &config, metrics.clone());
     info!("Redis client initialized");
 
     // Initialize services
+    let live_updates = Arc::new(LiveUpdates::new(&config, metrics.clone()));
+
+    let usage_service = Arc::new(UsageService::new(
+        db_pool.clone(),
+        redis_client.clone(),
+        config.clone(),
+    ));
+
     let tracking_service = Arc::new(TrackingService::new(
         db_pool.clone(),
         redis_client.clone(),
         config.clone(),
+        metrics.clone(),
+        usage_service.clone(),
     ));
 
+    let webhooks = Arc::new(WebhookDispatcher::new(db_pool.clone(), config.clone()));
+
     let geolocation_service = Arc::new(GeolocationService::new(
         db_pool.clone(),
+        redis_client.clone(),
         config.clone(),
+        metrics.clone(),
+        live_updates.clone(),
+        webhooks,
     ));
 
     let route_optimizer = Arc::new(RouteOptimizer::new(
@@ -82,6 +122,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         db_pool.clone(),
         redis_client.clone(),
         config.clone(),
+        metrics.clone(),
+        live_updates.clone(),
+    ));
+
+    let presence_service = Arc::new(PresenceService::new(
+        db_pool.clone(),
+        redis_client.clone(),
+        config.clone(),
+        live_updates.clone(),
     ));
 
     // Create application state
@@ -93,6 +142,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         geolocation_service,
         route_optimizer,
         analytics_service,
+        presence_service,
+        usage_service,
+        metrics,
+        live_updates,
+        started_at: Instant::now(),
     };
 
     // Start background services
@@ -102,128 +156,560 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let api_routes = setup_routes(app_state.clone());
 
     // Start HTTP server
-    let port = config.port;
-    info!("Starting HTTP server on port {}", port);
+    let stop_accepting = Arc::new(Notify::new());
+    let (addr, server) = warp::serve(api_routes).try_bind_with_graceful_shutdown((config.bind_address, config.port), {
+        let stop_accepting = stop_accepting.clone();
+        async move { stop_accepting.notified().await }
+    })?;
+    info!("HTTP server listening on {}", addr);
+    let server = tokio::spawn(server);
 
-    warp::serve(api_routes)
-        .run(([0, 0, 0, 0], port))
-        .await;
+    shutdown_signal().await;
+    info!("Shutdown signal received, draining connections");
+    stop_accepting.notify_one();
 
+    let drain = async {
+        app_state.live_updates.close_all().await;
+        let _ = server.await;
+        app_state.db_pool.close().await;
+    };
+    let drain_timeout = Duration::from_secs(config.shutdown_drain_timeout_secs);
+    if tokio::time::timeout(drain_timeout, drain).await.is_err() {
+        warn!("Connection draining did not finish within {}s, forcing exit", drain_timeout.as_secs());
+        std::process::exit(1);
+    }
+
+    info!("Live Tracking Service stopped");
     Ok(())
 }
 
+/// Resolves on the first SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
 fn setup_routes(
     app_state: AppState,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    // Requests from origins outside the allowlist are refused without any CORS headers.
+    let config = &app_state.config;
     let cors = warp::cors()
-        .allow_any_origin()
-        .allow_headers(vec!["content-type", "authorization"])
-        .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"]);
+        .allow_headers(config.cors_allowed_headers.iter().map(String::as_str))
+        .allow_header(middleware::request_id::HEADER)
+        .expose_headers(vec![middleware::request_id::HEADER, models::COORDINATE_PRECISION_HEADER])
+        .allow_methods(config.cors_allowed_methods.iter().map(String::as_str));
+    let cors = if config.cors_allowed_origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(config.cors_allowed_origins.iter().map(String::as_str))
+    };
+
+    // Handlers are awaited through `deadline::run`, which answers 504 once the route's time limit
+    // runs out. WebSocket upgrades are exempt: the socket outlives the handler anyway.
+    let deadline = middleware::deadline::limit(app_state.config.clone());
 
     // Health check routes
-    let health = warp::path("health")
+    // Liveness never touches Postgres or Redis; `/health` is kept as an alias of `/health/live`.
+    let live = warp::path!("health" / "live")
+        .or(warp::path!("health"))
+        .unify()
         .and(warp::get())
-        .and_then(handlers::health::health_check);
+        .map(handlers::health::liveness_check)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
 
     let ready = warp::path!("health" / "ready")
         .and(warp::get())
         .and(with_app_state(app_state.clone()))
-        .and_then(handlers::health::readiness_check);
+        .map(handlers::health::readiness_check)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
 
     // Tracking routes
+    let rate_limit = middleware::rate_limit::per_client(app_state.config.clone(), app_state.redis_client.clone());
+
     let track_location = warp::path!("api" / "v1" / "track" / "location")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(rate_limit.clone())
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(warp::query())
+        .and(middleware::body::json(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::track_location)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let track_locations_batch = warp::path!("api" / "v1" / "track" / "locations" / "batch")
+        .and(warp::post())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(rate_limit)
+        .and(middleware::body::json(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::track_locations_batch)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    // Must be matched before `get_location`, which would otherwise take "nearby" as a user id.
+    let get_nearby_locations = warp::path!("api" / "v1" / "location" / "nearby")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
         .and(with_app_state(app_state.clone()))
-        .and_then(handlers::tracking::track_location);
+        .map(handlers::tracking::get_nearby_locations)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    // Likewise matched before `get_location`.
+    let get_clusters = warp::path!("api" / "v1" / "location" / "clusters")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::get_clusters)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
 
     let get_location = warp::path!("api" / "v1" / "location" / String)
         .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
         .and(with_app_state(app_state.clone()))
-        .and_then(handlers::tracking::get_current_location);
+        .map(handlers::tracking::get_current_location)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
 
     let get_location_history = warp::path!("api" / "v1" / "location" / String / "history")
         .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::get_location_history)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_location_at = warp::path!("api" / "v1" / "location" / String / "at")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::get_location_at)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_matched_track = warp::path!("api" / "v1" / "location" / String / "matched")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::get_matched_track)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_polyline = warp::path!("api" / "v1" / "location" / String / "polyline")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::get_polyline)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    // Exports also accept a signed link instead of a token, checked in the handler.
+    let export_location_history = warp::path!("api" / "v1" / "location" / String / "export")
+        .and(warp::get())
+        .and(middleware::auth::optional_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::export_location_history)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let sign_export = warp::path!("api" / "v1" / "location" / String / "export" / "sign")
+        .and(warp::post())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(middleware::body::json(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::sign_export)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let export_location_gpx = warp::path!("api" / "v1" / "location" / String / "export.gpx")
+        .and(warp::get())
+        .and(middleware::auth::optional_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::export_location_gpx)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let import_location_gpx = warp::path!("api" / "v1" / "location" / String / "import.gpx")
+        .and(warp::post())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::query())
+        .and(warp::body::stream())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::tracking::import_location_gpx)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_user_status = warp::path!("api" / "v1" / "users" / String / "status")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::users::get_user_status)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_user_geofences = warp::path!("api" / "v1" / "users" / String / "geofences")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::users::get_user_geofences)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let list_tracked_users = warp::path!("api" / "v1" / "admin" / "users")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
         .and(warp::query())
         .and(with_app_state(app_state.clone()))
-        .and_then(handlers::tracking::get_location_history);
+        .map(handlers::users::list_tracked_users)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_presence_events = warp::path!("api" / "v1" / "presence" / "events")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::users::get_presence_events)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let erase_user_data = warp::path!("api" / "v1" / "users" / String / "data")
+        .and(warp::delete())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::users::erase_user_data)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
 
     // Route optimization routes
     let optimize_route = warp::path!("api" / "v1" / "routes" / "optimize")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(middleware::body::json(app_state.config.clone()))
         .and(with_app_state(app_state.clone()))
-        .and_then(handlers::routes::optimize_route);
+        .map(handlers::routes::optimize_route)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
 
     let get_route = warp::path!("api" / "v1" / "routes" / String)
         .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
         .and(with_app_state(app_state.clone()))
-        .and_then(handlers::routes::get_route);
+        .map(handlers::routes::get_route)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
 
     // Analytics routes
     let get_analytics = warp::path!("api" / "v1" / "analytics")
         .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::analytics::get_analytics)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_active_users = warp::path!("api" / "v1" / "analytics" / "active-users")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::analytics::get_active_users)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_heatmap = warp::path!("api" / "v1" / "analytics" / "heatmap")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::analytics::get_heatmap)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_distance = warp::path!("api" / "v1" / "analytics" / "distance")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
         .and(warp::query())
         .and(with_app_state(app_state.clone()))
-        .and_then(handlers::analytics::get_analytics);
+        .map(handlers::analytics::get_distance)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_stops = warp::path!("api" / "v1" / "analytics" / "stops")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::analytics::get_stops)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_trips = warp::path!("api" / "v1" / "analytics" / "trips")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::analytics::get_trips)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
 
     // Geofencing routes
     let create_geofence = warp::path!("api" / "v1" / "geofences")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(middleware::body::json(app_state.config.clone()))
         .and(with_app_state(app_state.clone()))
-        .and_then(handlers::geofencing::create_geofence);
+        .map(handlers::geofencing::create_geofence)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
 
     let get_geofences = warp::path!("api" / "v1" / "geofences")
         .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::geofencing::get_geofences)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let import_geofences = warp::path!("api" / "v1" / "geofences" / "import")
+        .and(warp::post())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(middleware::body::json(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::geofencing::import_geofences)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_nearest_geofences = warp::path!("api" / "v1" / "geofences" / "nearest")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::geofencing::get_nearest_geofences)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let evaluate_geofences = warp::path!("api" / "v1" / "geofences" / "evaluate")
+        .and(warp::post())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(middleware::body::json(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::geofencing::evaluate_geofences)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let update_geofence = warp::path!("api" / "v1" / "geofences" / Uuid)
+        .and(warp::put())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(middleware::body::json(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::geofencing::update_geofence)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let delete_geofence = warp::path!("api" / "v1" / "geofences" / Uuid)
+        .and(warp::delete())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::geofencing::delete_geofence)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_geofence_distance = warp::path!("api" / "v1" / "geofences" / Uuid / "distance")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::geofencing::get_geofence_distance)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_geofence_visits = warp::path!("api" / "v1" / "geofences" / Uuid / "visits")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
         .and(warp::query())
         .and(with_app_state(app_state.clone()))
-        .and_then(handlers::geofencing::get_geofences);
+        .map(handlers::geofencing::get_geofence_visits)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
 
     // WebSocket for real-time tracking
     let ws_tracking = warp::path!("ws" / "tracking" / String)
         .and(warp::ws())
+        .and(middleware::auth::require_ws_jwt(app_state.config.clone()))
+        .and(warp::query())
         .and(with_app_state(app_state.clone()))
         .and_then(handlers::websocket::tracking_websocket);
 
+    let ws_replay = warp::path!("ws" / "replay" / String)
+        .and(warp::ws())
+        .and(middleware::auth::require_ws_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::websocket::replay_websocket);
+
+    let ws_presence = warp::path!("ws" / "presence")
+        .and(warp::ws())
+        .and(middleware::auth::require_ws_jwt(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::websocket::presence_websocket);
+
+    let ws_geofence = warp::path!("ws" / "geofences" / Uuid)
+        .and(warp::ws())
+        .and(middleware::auth::require_ws_jwt(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::websocket::geofence_websocket);
+
     // Metrics endpoint
     let metrics = warp::path("metrics")
         .and(warp::get())
-        .and_then(handlers::metrics::prometheus_metrics);
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::metrics::prometheus_metrics)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let get_usage = warp::path!("api" / "v1" / "usage")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(warp::query())
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::usage::get_usage)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    let debug_stats = warp::path!("debug" / "stats")
+        .and(warp::get())
+        .and(middleware::auth::require_jwt(app_state.config.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(handlers::debug::stats)
+        .and(deadline.clone())
+        .and_then(middleware::deadline::run);
+
+    // Machine-readable API description
+    let openapi_document = warp::path!("openapi.json")
+        .and(warp::get())
+        .map(openapi::serve);
 
     // Root endpoint
     let root = warp::path::end()
         .and(warp::get())
-        .map(|| {
-            warp::reply::json(&serde_json::json!({
-                "service": "Suuupra Live Tracking Service",
-                "version": "1.0.0",
-                "status": "running",
-                "features": [
-                    "Real-time GPS tracking",
-                    "Route optimization",
-                    "Geofencing",
-                    "Analytics and reporting",
-                    "WebSocket real-time updates"
-                ]
-            }))
-        });
+        .map(handlers::health::banner);
 
-    root
-        .or(health)
+    let routes = root
+        .or(live)
         .or(ready)
         .or(track_location)
+        .or(track_locations_batch)
+        .or(get_nearby_locations)
+        .or(get_clusters)
         .or(get_location)
         .or(get_location_history)
+        .or(get_location_at)
+        .or(get_matched_track)
+        .or(get_polyline)
+        .or(export_location_history)
+        .or(sign_export)
+        .or(export_location_gpx)
+        .or(import_location_gpx)
+        .or(get_user_status)
+        .or(get_user_geofences)
+        .or(get_presence_events)
+        .or(list_tracked_users)
+        .or(erase_user_data)
         .or(optimize_route)
         .or(get_route)
         .or(get_analytics)
+        .or(get_active_users)
+        .or(get_heatmap)
+        .or(get_distance)
+        .or(get_stops)
+        .or(get_trips)
+        .or(get_usage)
         .or(create_geofence)
         .or(get_geofences)
+        .or(import_geofences)
+        .or(evaluate_geofences)
+        .or(get_nearest_geofences)
+        .or(update_geofence)
+        .or(delete_geofence)
+        .or(get_geofence_distance)
+        .or(get_geofence_visits)
         .or(ws_tracking)
+        .or(ws_geofence)
+        .or(ws_presence)
+        .or(ws_replay)
         .or(metrics)
+        .or(debug_stats)
+        .or(openapi_document)
+        .recover(error::handle_rejection);
+
+    let compression_min_bytes = app_state.config.compression_min_bytes;
+    // Tells clients the coordinates they get are coarsened, and how much.
+    let coordinate_precision = app_state.config.coordinate_precision;
+    let metrics = app_state.metrics.clone();
+    middleware::request_metrics::start()
+        .and(middleware::request_id::extract())
+        .and(middleware::compression::negotiate())
+        .and(routes)
+        .then(move |timing, request_id: String, encoding, reply| {
+            let metrics = metrics.clone();
+            async move {
+                let mut reply = warp::reply::with_header(reply, middleware::request_id::HEADER, request_id).into_response();
+                if !coordinate_precision.is_full() {
+                    if let Ok(value) = HeaderValue::from_str(&coordinate_precision.to_string()) {
+                        reply.headers_mut().insert(models::COORDINATE_PRECISION_HEADER, value);
+                    }
+                }
+                let response = middleware::compression::compress(encoding, reply, compression_min_bytes).await;
+                middleware::request_metrics::record(&metrics, timing, &response);
+                response
+            }
+        })
         .with(cors)
-        .with(warp::trace::request())
+        .with(warp::trace(middleware::request_id::span))
 }
 
 fn with_app_state(
@@ -234,24 +720,492 @@ fn with_app_state(
 
 async fn start_background_tasks(app_state: AppState) {
     info!("Starting background tasks");
+    let config = &app_state.config;
+    let mut active = Vec::new();
 
     // Start location data aggregation
-    let tracking_service = app_state.tracking_service.clone();
-    tokio::spawn(async move {
-        tracking_service.start_data_aggregation().await;
-    });
+    if config.data_aggregation_enabled {
+        let tracking_service = app_state.tracking_service.clone();
+        tokio::spawn(async move {
+            tracking_service.start_data_aggregation().await;
+        });
+        active.push("data aggregation");
+    }
+
+    // Start location retention purge
+    if config.retention_purge_enabled {
+        let tracking_service = app_state.tracking_service.clone();
+        tokio::spawn(async move {
+            tracking_service.start_retention_purge().await;
+        });
+        active.push("retention purge");
+    }
 
     // Start analytics processing
-    let analytics_service = app_state.analytics_service.clone();
-    tokio::spawn(async move {
-        analytics_service.start_processing().await;
-    });
+    if config.analytics_processing_enabled {
+        let analytics_service = app_state.analytics_service.clone();
+        tokio::spawn(async move {
+            analytics_service.start_processing().await;
+        });
+        active.push("analytics processing");
+    }
+
+    // Start presence monitoring
+    if config.presence_monitoring_enabled {
+        let presence_service = app_state.presence_service.clone();
+        tokio::spawn(async move {
+            presence_service.start_monitoring().await;
+        });
+        active.push("presence monitoring");
+    }
+
+    // Start usage flushing
+    if config.usage_flush_enabled {
+        let usage_service = app_state.usage_service.clone();
+        tokio::spawn(async move {
+            usage_service.start_flushing().await;
+        });
+        active.push("usage flushing");
+    }
 
     // Start geofence monitoring
-    let geolocation_service = app_state.geolocation_service.clone();
-    tokio::spawn(async move {
-        geolocation_service.start_geofence_monitoring().await;
-    });
+    if config.geofence_monitoring_enabled {
+        let geolocation_service = app_state.geolocation_service.clone();
+        tokio::spawn(async move {
+            geolocation_service.start_geofence_monitoring().await;
+        });
+        active.push("geofence monitoring");
+    }
+
+    info!("Background tasks started: {}", active.join(", "));
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+    use super::*;
+    use crate::test_support;
+
+    #[tokio::test]
+    async fn listing_tracked_users_requires_an_admin_token() {
+        let routes = setup_routes(test_support::state());
+
+        let anonymous = warp::test::request().path("/api/v1/admin/users").reply(&routes).await;
+        assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+
+        let user = warp::test::request()
+            .path("/api/v1/admin/users")
+            .header("authorization", test_support::bearer("alice", Some("acme"), &["user"]))
+            .reply(&routes)
+            .await;
+        assert_eq!(user.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn tenant_data_is_refused_to_anonymous_callers() {
+        let routes = setup_routes(test_support::state());
+        let geofence = r#"{"name": "Depot", "geofence_type": "circle", "center_latitude": 0, "center_longitude": 0, "radius_meters": 100}"#;
+        let geofence_id = Uuid::new_v4();
+        let requests = [
+            ("GET", "/api/v1/location/alice".to_string(), None),
+            ("GET", "/api/v1/location/nearby?lat=0&lon=0&radius=100".to_string(), None),
+            ("GET", "/api/v1/analytics?user_id=alice".to_string(), None),
+            ("GET", "/api/v1/analytics/active-users".to_string(), None),
+            ("GET", "/api/v1/analytics/heatmap?bbox=0,0,1,1".to_string(), None),
+            ("GET", "/api/v1/analytics/distance?user_id=alice".to_string(), None),
+            ("GET", "/api/v1/analytics/stops?user_id=alice".to_string(), None),
+            ("GET", "/api/v1/analytics/trips?user_id=alice".to_string(), None),
+            ("GET", "/api/v1/geofences".to_string(), None),
+            ("POST", "/api/v1/geofences".to_string(), Some(geofence)),
+            ("POST", "/api/v1/geofences/import".to_string(), Some(r#"{"type": "FeatureCollection", "features": []}"#)),
+            ("PUT", format!("/api/v1/geofences/{}", geofence_id), Some(geofence)),
+            ("DELETE", format!("/api/v1/geofences/{}", geofence_id), None),
+            ("GET", "/api/v1/geofences/nearest?lat=0&lon=0".to_string(), None),
+            ("GET", "/api/v1/presence/events".to_string(), None),
+        ];
+        for (method, path, body) in requests {
+            let mut request = warp::test::request().method(method).path(&path);
+            if let Some(body) = body {
+                request = request.header("content-type", "application/json").body(body);
+            }
+            let response = request.reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{} {}", method, path);
+        }
+    }
+
+    /// A WebSocket handshake that is answered like any request rather than upgraded, to see
+    /// refusals.
+    fn websocket_handshake(path: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .path(path)
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+    }
+
+    fn error_code(response: &warp::http::Response<warp::hyper::body::Bytes>) -> String {
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        body["error"]["code"].as_str().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn websockets_without_a_valid_token_are_refused_before_the_upgrade() {
+        let routes = setup_routes(test_support::state());
+        for path in ["/ws/tracking/alice", "/ws/replay/alice", "/ws/presence", "/ws/tracking/alice?access_token=not-a-jwt"] {
+            let response = websocket_handshake(path).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        }
+        let response = websocket_handshake("/ws/tracking/alice")
+            .header("sec-websocket-protocol", "bearer, not-a-jwt")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn another_users_stream_is_forbidden_unless_admin() {
+        let routes = setup_routes(test_support::state());
+        let alice = test_support::bearer("alice", Some("acme"), &["user"]);
+        let token = alice.trim_start_matches("Bearer ");
+
+        let response = websocket_handshake("/ws/tracking/bob")
+            .header("sec-websocket-protocol", format!("bearer, {}", token))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(&response), "forbidden");
+
+        let response = websocket_handshake(&format!("/ws/replay/bob?access_token={}", token)).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let admin = test_support::bearer("carol", Some("acme"), &["admin"]);
+        let handshake = warp::test::ws()
+            .path(&format!("/ws/tracking/bob?access_token={}", admin.trim_start_matches("Bearer ")))
+            .handshake(routes)
+            .await;
+        assert!(handshake.is_ok(), "{:?}", handshake.err());
+    }
+
+    #[tokio::test]
+    async fn users_may_subscribe_to_themselves() {
+        let alice = test_support::bearer("alice", Some("acme"), &["user"]);
+        let handshake = warp::test::ws()
+            .path("/ws/tracking/alice")
+            .header("sec-websocket-protocol", format!("bearer, {}", alice.trim_start_matches("Bearer ")))
+            .handshake(setup_routes(test_support::state()))
+            .await;
+        assert!(handshake.is_ok(), "{:?}", handshake.err());
+    }
+
+    #[tokio::test]
+    async fn only_allowlisted_origins_get_cors_headers() {
+        let mut config = test_support::config();
+        config.cors_allowed_origins = vec!["https://app.example.com".to_string()];
+        let routes = setup_routes(test_support::state_with(config));
+
+        let response = warp::test::request()
+            .method("OPTIONS")
+            .path("/health/live")
+            .header("origin", "https://app.example.com")
+            .header("access-control-request-method", "GET")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://app.example.com");
+
+        for method in ["OPTIONS", "GET"] {
+            let response = warp::test::request()
+                .method(method)
+                .path("/health/live")
+                .header("origin", "https://evil.example.com")
+                .header("access-control-request-method", "GET")
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", method);
+            assert!(!response.headers().contains_key("access-control-allow-origin"), "{}", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_configured_methods_pass_preflight() {
+        let mut config = test_support::config();
+        config.cors_allowed_methods = vec!["GET".to_string()];
+        let routes = setup_routes(test_support::state_with(config));
+
+        let preflight = |method: &'static str| {
+            warp::test::request()
+                .method("OPTIONS")
+                .path("/api/v1/track/location")
+                .header("origin", "https://app.example.com")
+                .header("access-control-request-method", method)
+        };
+        assert_eq!(preflight("GET").reply(&routes).await.status(), StatusCode::OK);
+        assert_eq!(preflight("POST").reply(&routes).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    /// Mints a link to alice's GPX export through `.../export/sign`.
+    async fn signed_export_link(
+        routes: &(impl Filter<Extract = impl Reply, Error = Rejection> + Clone + 'static),
+    ) -> String {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/location/alice/export/sign")
+            .header("authorization", test_support::bearer("alice", Some("acme"), &["user"]))
+            .json(&serde_json::json!({"format": "gpx", "expires_in_secs": 60}))
+            .reply(routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        body["url"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn exports_without_a_token_need_an_untampered_link() {
+        let routes = setup_routes(test_support::state());
+        let link = signed_export_link(&routes).await;
+        assert!(link.starts_with("/api/v1/location/alice/export.gpx?tenant=acme&expires="), "{}", link);
+
+        let other_user = link.replace("/alice/", "/bob/");
+        let other_format = link.replace("export.gpx", "export");
+        let other_tenant = link.replace("tenant=acme", "tenant=globex");
+        for path in ["/api/v1/location/alice/export.gpx", &other_user, &other_format, &other_tenant] {
+            let response = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres"]
+    async fn a_signed_link_downloads_the_export() {
+        let routes = setup_routes(test_support::migrated_state().await);
+        let link = signed_export_link(&routes).await;
+        let response = warp::test::request().path(&link).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(String::from_utf8_lossy(response.body()).contains("<gpx"));
+    }
+
+    #[tokio::test]
+    async fn malformed_json_bodies_get_the_error_envelope_on_every_json_route() {
+        let routes = setup_routes(test_support::state());
+        let token = test_support::bearer("alice", Some("acme"), &["admin"]);
+        for path in ["/api/v1/track/location", "/api/v1/geofences", "/api/v1/geofences/evaluate"] {
+            let response = warp::test::request()
+                .method("POST")
+                .path(path)
+                .header("authorization", &token)
+                .header("content-type", "application/json")
+                .body("{\"latitude\": 51.5, \"longitude\":")
+                .reply(&routes)
+                .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body["error"]["code"], "invalid_json", "{}", path);
+            assert!(body["error"]["message"].as_str().unwrap().contains("line 1 column 31"), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_and_non_json_bodies_are_refused_before_parsing() {
+        let mut config = test_support::config();
+        config.max_body_bytes = 64;
+        let routes = setup_routes(test_support::state_with(config));
+        let post = || {
+            warp::test::request()
+                .method("POST")
+                .path("/api/v1/track/location")
+                .header("authorization", test_support::bearer("alice", Some("acme"), &[]))
+        };
+
+        // Far past the limit, and not JSON at all: only the size is looked at.
+        let oversized = format!("{{\"latitude\": 51.5, \"longitude\": -0.12, \"padding\": \"{}\"", "x".repeat(100));
+        let response = post().body(oversized).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error_code(&response), "payload_too_large");
+
+        let response = post().header("content-type", "text/plain").body("{}").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(error_code(&response), "unsupported_media_type");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres"]
+    async fn a_user_without_fixes_has_an_empty_polyline() {
+        let user_id = format!("polyline-{}", uuid::Uuid::new_v4());
+        let response = warp::test::request()
+            .path(&format!("/api/v1/location/{}/polyline?precision=6", user_id))
+            .header("authorization", test_support::bearer(&user_id, Some("acme"), &[]))
+            .reply(&setup_routes(test_support::migrated_state().await))
+            .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body, serde_json::json!({"polyline": "", "precision": 6, "points": 0}));
+    }
+
+    #[tokio::test]
+    async fn every_documented_operation_is_routed() {
+        let routes = setup_routes(test_support::state());
+        let geofence_id = Uuid::new_v4().to_string();
+        let paths = openapi::document()["paths"].as_object().unwrap();
+        for (template, item) in paths {
+            let path = template
+                .replace("{user_id}", "alice")
+                .replace("{geofence_id}", &geofence_id)
+                .replace("{route_id}", &geofence_id);
+            for method in item.as_object().unwrap().keys() {
+                let request = if path.starts_with("/ws/") {
+                    websocket_handshake(&path)
+                } else {
+                    warp::test::request().method(&method.to_uppercase()).path(&path)
+                };
+                let response = request.reply(&routes).await;
+
+                assert_ne!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{} {}", method, template);
+                let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap_or_default();
+                assert_ne!(body["error"]["message"], "no such route", "{} {}", method, template);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn another_users_history_is_forbidden() {
+        let response = warp::test::request()
+            .path("/api/v1/location/bob/history")
+            .header("authorization", test_support::bearer("alice", Some("acme"), &["user"]))
+            .reply(&setup_routes(test_support::state()))
+            .await;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "forbidden");
+    }
+
+    #[tokio::test]
+    async fn an_overlong_idempotency_key_is_a_bad_request() {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/api/v1/track/location")
+            .header("authorization", test_support::bearer("alice", Some("acme"), &[]))
+            .header("idempotency-key", "k".repeat(256))
+            .json(&serde_json::json!({"user_id": "alice", "latitude": 51.5, "longitude": -0.12}))
+            .reply(&setup_routes(test_support::state()))
+            .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "invalid_idempotency_key");
+    }
+
+    #[tokio::test]
+    async fn analytics_of_another_user_need_an_admin_token() {
+        let routes = setup_routes(test_support::state());
+        let alice = test_support::bearer("alice", Some("acme"), &["user"]);
+        for path in [
+            "/api/v1/analytics?user_id=bob",
+            "/api/v1/analytics/distance?user_id=bob",
+            "/api/v1/analytics/stops?user_id=bob",
+            "/api/v1/analytics/trips?user_id=bob",
+            "/api/v1/analytics/heatmap?bbox=0,0,1,1",
+        ] {
+            let response = warp::test::request().path(path).header("authorization", &alice).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn another_users_location_and_presence_need_an_admin_token() {
+        let routes = setup_routes(test_support::state());
+        let alice = test_support::bearer("alice", Some("acme"), &["user"]);
+        for path in ["/api/v1/location/bob", "/api/v1/users/bob/status", "/api/v1/users/bob/geofences"] {
+            let response = warp::test::request().path(path).header("authorization", &alice).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", path);
+            assert_eq!(error_code(&response), "forbidden", "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn large_json_responses_are_gzipped_for_clients_that_accept_it() {
+        use std::io::Read;
+
+        let routes = setup_routes(test_support::state());
+        let plain = warp::test::request().path("/openapi.json").reply(&routes).await;
+        assert!(plain.body().len() > 1024);
+        assert!(!plain.headers().contains_key("content-encoding"));
+
+        let response = warp::test::request()
+            .path("/openapi.json")
+            .header("accept-encoding", "gzip")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(response.body().as_ref()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, plain.body().as_ref());
+
+        let small = warp::test::request().path("/health").header("accept-encoding", "gzip").reply(&routes).await;
+        assert_eq!(small.status(), StatusCode::OK);
+        assert!(small.body().len() < 1024);
+        assert!(!small.headers().contains_key("content-encoding"));
+        let _: serde_json::Value = serde_json::from_slice(small.body()).unwrap();
+    }
+
+    /// `ROUTE_TEMPLATES` is kept by hand; `openapi::tests::every_route_is_documented` ties it to
+    /// the document, this ties it to the routes actually served.
+    #[tokio::test]
+    async fn every_route_template_is_served_under_its_own_label() {
+        use middleware::request_metrics::{route_template, ROUTE_TEMPLATES};
+
+        let routes = setup_routes(test_support::state());
+        let paths = openapi::document()["paths"].as_object().unwrap();
+        for template in ROUTE_TEMPLATES {
+            let path: String = template
+                .split('/')
+                .map(|segment| if segment.starts_with(':') { "00000000-0000-0000-0000-000000000001" } else { segment })
+                .collect::<Vec<_>>()
+                .join("/");
+            assert_eq!(route_template(&path), *template, "{} is labelled as another route", path);
+
+            let documented: String = template
+                .split('/')
+                .map(|segment| match segment.strip_prefix(':') {
+                    Some(name) => format!("{{{}}}", name),
+                    None => segment.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            let methods: Vec<&str> = match paths.get(&documented) {
+                Some(operations) => operations.as_object().unwrap().keys().map(String::as_str).collect(),
+                None => vec!["get"],
+            };
+            for method in methods {
+                let response = warp::test::request()
+                    .method(&method.to_uppercase())
+                    .path(&path)
+                    .header("authorization", "Bearer not-a-jwt")
+                    .reply(&routes)
+                    .await;
+                assert_ne!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{} {}", method, path);
+                if response.status() == StatusCode::NOT_FOUND {
+                    assert_ne!(error_code(&response), "not_found", "{} {} is not routed", method, path);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_history_window_over_the_maximum_is_a_bad_request() {
+        let state = test_support::state();
+        assert_eq!(state.config.max_query_window_days, 90);
+        let response = warp::test::request()
+            .path("/api/v1/location/alice/history?from=2024-01-01T00:00:00Z&to=2025-02-04T00:00:00Z")
+            .header("authorization", test_support::bearer("alice", Some("acme"), &[]))
+            .reply(&setup_routes(state))
+            .await;
 
-    info!("Background tasks started successfully");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"]["code"], "window_too_large");
+    }
 }
@@ -0,0 +1,125 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub location_updates_total: IntCounter,
+    pub track_location_duration_seconds: Histogram,
+    pub websocket_connections_active: IntGauge,
+    /// Open connections of the user holding the most, so one series however many users connect.
+    pub websocket_connections_max_per_user: IntGauge,
+    /// Labelled by `reason`, `user_limit` or `global_limit`.
+    pub websocket_handshakes_rejected_total: IntCounterVec,
+    pub geofence_transitions_total: IntCounterVec,
+    pub aggregation_queue_depth: IntGauge,
+    pub aggregation_samples_dropped_total: IntCounter,
+    pub active_users: IntGauge,
+    /// Labelled by `method` and the route template, never the concrete path.
+    pub http_request_duration_seconds: HistogramVec,
+    pub http_responses_total: IntCounterVec,
+    /// 0 closed, 1 half-open, 2 open.
+    pub redis_circuit_state: IntGauge,
+    pub redis_circuit_opened_total: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("live_tracking".to_string()), None)?;
+
+        let location_updates_total = IntCounter::new(
+            "location_updates_total",
+            "Total number of location fixes ingested",
+        )?;
+        let track_location_duration_seconds = Histogram::with_opts(HistogramOpts::new(
+            "track_location_duration_seconds",
+            "Latency of the track_location handler",
+        ))?;
+        let websocket_connections_active = IntGauge::new(
+            "websocket_connections_active",
+            "Number of currently open WebSocket connections",
+        )?;
+        let websocket_connections_max_per_user = IntGauge::new(
+            "websocket_connections_max_per_user",
+            "Most WebSocket connections currently open by any one user",
+        )?;
+        let websocket_handshakes_rejected_total = IntCounterVec::new(
+            Opts::new("websocket_handshakes_rejected_total", "Total number of WebSocket handshakes refused at a connection limit"),
+            &["reason"],
+        )?;
+        let geofence_transitions_total = IntCounterVec::new(
+            Opts::new("geofence_transitions_total", "Total number of geofence transitions recorded"),
+            &["event_type"],
+        )?;
+        let aggregation_queue_depth = IntGauge::new(
+            "aggregation_queue_depth",
+            "Number of samples waiting in the daily stats aggregation queue",
+        )?;
+        let aggregation_samples_dropped_total = IntCounter::new(
+            "aggregation_samples_dropped_total",
+            "Total number of samples dropped because the aggregation queue was full",
+        )?;
+        let active_users = IntGauge::new(
+            "active_users",
+            "Distinct users that reported a fix within the default active-users window",
+        )?;
+
+        let http_request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Latency of HTTP requests by route"),
+            &["method", "route"],
+        )?;
+        let http_responses_total = IntCounterVec::new(
+            Opts::new("http_responses_total", "Total number of HTTP responses by route and status code"),
+            &["method", "route", "status"],
+        )?;
+
+        let redis_circuit_state = IntGauge::new(
+            "redis_circuit_state",
+            "State of the Redis circuit breaker: 0 closed, 1 half-open, 2 open",
+        )?;
+        let redis_circuit_opened_total = IntCounter::new(
+            "redis_circuit_opened_total",
+            "Total number of times the Redis circuit breaker opened",
+        )?;
+
+        registry.register(Box::new(location_updates_total.clone()))?;
+        registry.register(Box::new(track_location_duration_seconds.clone()))?;
+        registry.register(Box::new(websocket_connections_active.clone()))?;
+        registry.register(Box::new(websocket_connections_max_per_user.clone()))?;
+        registry.register(Box::new(websocket_handshakes_rejected_total.clone()))?;
+        registry.register(Box::new(geofence_transitions_total.clone()))?;
+        registry.register(Box::new(aggregation_queue_depth.clone()))?;
+        registry.register(Box::new(aggregation_samples_dropped_total.clone()))?;
+        registry.register(Box::new(active_users.clone()))?;
+        registry.register(Box::new(http_request_duration_seconds.clone()))?;
+        registry.register(Box::new(http_responses_total.clone()))?;
+        registry.register(Box::new(redis_circuit_state.clone()))?;
+        registry.register(Box::new(redis_circuit_opened_total.clone()))?;
+
+        Ok(Self {
+            registry,
+            location_updates_total,
+            track_location_duration_seconds,
+            websocket_connections_active,
+            websocket_connections_max_per_user,
+            websocket_handshakes_rejected_total,
+            geofence_transitions_total,
+            aggregation_queue_depth,
+            aggregation_samples_dropped_total,
+            active_users,
+            http_request_duration_seconds,
+            http_responses_total,
+            redis_circuit_state,
+            redis_circuit_opened_total,
+        })
+    }
+
+    /// Renders every registered metric in the Prometheus text exposition format.
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
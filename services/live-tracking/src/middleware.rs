@@ -1 +1,778 @@
-// Placeholder for middleware modules
\ No newline at end of file
+pub mod auth {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+    use serde::{Deserialize, Serialize};
+    use warp::{Filter, Rejection};
+    use crate::config::Config;
+    use crate::error::ApiError;
+    use crate::models::{is_valid_tenant_id, DEFAULT_TENANT_ID};
+
+    #[derive(Debug)]
+    pub enum AuthError {
+        MissingToken,
+        InvalidToken(String),
+    }
+
+    impl From<AuthError> for ApiError {
+        fn from(e: AuthError) -> Self {
+            match e {
+                AuthError::MissingToken => ApiError::Unauthorized("missing bearer token".to_string()),
+                AuthError::InvalidToken(reason) => ApiError::Unauthorized(reason),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Claims {
+        pub sub: String,
+        #[serde(default)]
+        pub roles: Vec<String>,
+        /// Organization the subject belongs to; every read and write is confined to it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub tenant: Option<String>,
+    }
+
+    impl Claims {
+        pub fn has_role(&self, role: &str) -> bool {
+            self.roles.iter().any(|r| r == role)
+        }
+
+        pub fn tenant_id(&self) -> &str {
+            self.tenant.as_deref().unwrap_or(DEFAULT_TENANT_ID)
+        }
+    }
+
+    /// Subprotocol a browser offers ahead of its token, as in `Sec-WebSocket-Protocol: bearer, <jwt>`,
+    /// since it cannot set `Authorization` on a WebSocket handshake.
+    pub const WS_BEARER_PROTOCOL: &str = "bearer";
+
+    /// Claims of an authenticated WebSocket handshake.
+    #[derive(Debug)]
+    pub struct WsAuth {
+        pub claims: Claims,
+        /// The token came in `Sec-WebSocket-Protocol`, so the handshake response must select
+        /// [`WS_BEARER_PROTOCOL`] or the browser aborts the connection.
+        pub via_subprotocol: bool,
+    }
+
+    /// Decodes the claims of an HS256 `Authorization: Bearer` header value.
+    pub fn decode_bearer(header: Option<&str>, config: &Config) -> Result<Claims, AuthError> {
+        let token = header
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(AuthError::MissingToken)?;
+        decode_token(token, config)
+    }
+
+    fn decode_token(token: &str, config: &Config) -> Result<Claims, AuthError> {
+        decode::<Claims>(
+            token.trim(),
+            &DecodingKey::from_secret(config.jwt_secret.as_bytes()),
+            &Validation::new(Algorithm::HS256),
+        )
+        .map_err(|e| AuthError::InvalidToken(e.to_string()))
+        .and_then(|data| match &data.claims.tenant {
+            Some(tenant) if !is_valid_tenant_id(tenant) => Err(AuthError::InvalidToken("invalid tenant claim".to_string())),
+            _ => Ok(data.claims),
+        })
+    }
+
+    /// Anonymous requests yield `None`; a missing token is not an error, an invalid one still is.
+    fn optional<T>(result: Result<T, AuthError>) -> Result<Option<T>, Rejection> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(AuthError::MissingToken) => Ok(None),
+            Err(e) => Err(Rejection::from(ApiError::from(e))),
+        }
+    }
+
+    /// Verifies an HS256 `Authorization: Bearer` token and extracts its claims.
+    pub fn require_jwt(
+        config: Arc<Config>,
+    ) -> impl Filter<Extract = (Claims,), Error = Rejection> + Clone {
+        warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
+            let config = config.clone();
+            async move {
+                decode_bearer(header.as_deref(), &config).map_err(|e| Rejection::from(ApiError::from(e)))
+            }
+        })
+    }
+
+    /// Like [`require_jwt`], but lets requests without a token through as `None`.
+    pub fn optional_jwt(
+        config: Arc<Config>,
+    ) -> impl Filter<Extract = (Option<Claims>,), Error = Rejection> + Clone {
+        warp::header::optional::<String>("authorization").and_then(move |header: Option<String>| {
+            let config = config.clone();
+            async move { optional(decode_bearer(header.as_deref(), &config)) }
+        })
+    }
+
+    fn decode_ws(
+        protocols: Option<String>,
+        query: &HashMap<String, String>,
+        header: Option<String>,
+        config: &Config,
+    ) -> Result<WsAuth, AuthError> {
+        let subprotocol_token = protocols.as_deref().and_then(|protocols| {
+            let mut offered = protocols.split(',').map(str::trim);
+            offered.position(|p| p == WS_BEARER_PROTOCOL)?;
+            offered.next().map(str::to_string)
+        });
+
+        let via_subprotocol = subprotocol_token.is_some();
+        let claims = match subprotocol_token.as_deref().or(query.get("access_token").map(String::as_str)) {
+            Some(token) => decode_token(token, config),
+            None => decode_bearer(header.as_deref(), config),
+        };
+        claims.map(|claims| WsAuth { claims, via_subprotocol })
+    }
+
+    /// Verifies the token of a WebSocket handshake, taken from the `bearer, <jwt>` subprotocol
+    /// pair, the `access_token` query parameter or an `Authorization: Bearer` header, in that order.
+    pub fn require_ws_jwt(
+        config: Arc<Config>,
+    ) -> impl Filter<Extract = (WsAuth,), Error = Rejection> + Clone {
+        warp::header::optional::<String>("sec-websocket-protocol")
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::optional::<String>("authorization"))
+            .and_then(move |protocols: Option<String>, query: HashMap<String, String>, header: Option<String>| {
+                let config = config.clone();
+                async move {
+                    decode_ws(protocols, &query, header, &config).map_err(|e| Rejection::from(ApiError::from(e)))
+                }
+            })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_support;
+
+        fn claims(tenant: Option<&str>) -> Claims {
+            Claims { sub: "alice".to_string(), roles: Vec::new(), tenant: tenant.map(str::to_string) }
+        }
+
+        #[test]
+        fn callers_act_in_the_tenant_of_their_token() {
+            assert_eq!(claims(Some("acme")).tenant_id(), "acme");
+            assert_eq!(claims(None).tenant_id(), DEFAULT_TENANT_ID);
+        }
+
+        fn token(sub: &str) -> String {
+            test_support::bearer(sub, Some("acme"), &[]).trim_start_matches("Bearer ").to_string()
+        }
+
+        fn access_token(sub: &str) -> HashMap<String, String> {
+            HashMap::from([("access_token".to_string(), token(sub))])
+        }
+
+        #[test]
+        fn the_subprotocol_token_is_preferred() {
+            let config = test_support::config();
+            let protocols = Some(format!("{}, {}", WS_BEARER_PROTOCOL, token("alice")));
+            let header = Some(format!("Bearer {}", token("carol")));
+            let auth = decode_ws(protocols, &access_token("bob"), header, &config).unwrap();
+            assert_eq!(auth.claims.sub, "alice");
+            assert!(auth.via_subprotocol);
+        }
+
+        #[test]
+        fn the_query_token_comes_before_the_header() {
+            let config = test_support::config();
+            let header = Some(format!("Bearer {}", token("carol")));
+            let auth = decode_ws(Some("graphql-ws".to_string()), &access_token("bob"), header.clone(), &config).unwrap();
+            assert_eq!(auth.claims.sub, "bob");
+            assert!(!auth.via_subprotocol);
+
+            let auth = decode_ws(None, &HashMap::new(), header, &config).unwrap();
+            assert_eq!(auth.claims.sub, "carol");
+        }
+
+        #[test]
+        fn handshakes_without_a_valid_token_are_refused() {
+            let config = test_support::config();
+            assert!(matches!(decode_ws(None, &HashMap::new(), None, &config), Err(AuthError::MissingToken)));
+            // A bearer protocol with no token after it is not a token.
+            let bare = Some(WS_BEARER_PROTOCOL.to_string());
+            assert!(matches!(decode_ws(bare, &HashMap::new(), None, &config), Err(AuthError::MissingToken)));
+
+            let garbage = HashMap::from([("access_token".to_string(), "not-a-jwt".to_string())]);
+            assert!(matches!(decode_ws(None, &garbage, None, &config), Err(AuthError::InvalidToken(_))));
+        }
+    }
+}
+
+
+pub mod body {
+    use std::sync::Arc;
+    use futures_util::{Stream, StreamExt};
+    use serde::de::DeserializeOwned;
+    use serde_json::error::Category;
+    use warp::{hyper::body::Buf, Filter, Rejection};
+    use crate::config::Config;
+    use crate::error::ApiError;
+
+    fn is_json(content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        essence == "application/json" || (essence.starts_with("application/") && essence.ends_with("+json"))
+    }
+
+    /// Syntax errors and data that does not fit the expected shape both answer 400 and carry
+    /// serde's message, which names the line and column it stopped at.
+    fn parse<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ApiError> {
+        serde_json::from_slice(bytes).map_err(|e| match e.classify() {
+            Category::Data => ApiError::BadRequest { code: "invalid_body", message: e.to_string() },
+            Category::Syntax | Category::Eof | Category::Io => ApiError::BadRequest {
+                code: "invalid_json",
+                message: format!("request body is not valid JSON: {}", e),
+            },
+        })
+    }
+
+    async fn read(
+        body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+        capacity: usize,
+        limit_bytes: usize,
+    ) -> Result<Vec<u8>, ApiError> {
+        let mut body = Box::pin(body);
+        let mut bytes = Vec::with_capacity(capacity);
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| ApiError::BadRequest {
+                code: "invalid_body",
+                message: format!("failed to read request body: {}", e),
+            })?;
+            if bytes.len() + chunk.remaining() > limit_bytes {
+                return Err(ApiError::PayloadTooLarge { limit_bytes });
+            }
+            bytes.extend_from_slice(chunk.chunk());
+        }
+        Ok(bytes)
+    }
+
+    /// Drop-in for `warp::body::json` that renders every failure as an [`ApiError`]. Bodies over
+    /// `max_body_bytes` are refused with 413: by their `Content-Length` before anything is read,
+    /// or, when it is not declared, as soon as the limit is crossed. A `Content-Type` other than
+    /// JSON is refused with 415; a missing one is accepted.
+    pub fn json<T: DeserializeOwned + Send>(
+        config: Arc<Config>,
+    ) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+        warp::header::optional::<String>("content-type")
+            .and(warp::header::optional::<u64>("content-length"))
+            .and(warp::body::stream())
+            .and_then(move |content_type: Option<String>, length: Option<u64>, body| {
+                let limit_bytes = config.max_body_bytes;
+                async move {
+                    if let Some(content_type) = content_type.filter(|content_type| !is_json(content_type)) {
+                        return Err(Rejection::from(ApiError::UnsupportedMediaType(format!(
+                            "expected a JSON body, got {}",
+                            content_type
+                        ))));
+                    }
+                    if length.is_some_and(|length| length > limit_bytes as u64) {
+                        return Err(ApiError::PayloadTooLarge { limit_bytes }.into());
+                    }
+
+                    let bytes = read(body, length.unwrap_or_default() as usize, limit_bytes).await?;
+                    parse(&bytes).map_err(Rejection::from)
+                }
+            })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::collections::HashMap;
+        use futures_util::stream;
+        use warp::hyper::body::Bytes;
+        use super::*;
+
+        fn parse_error(body: &str) -> (&'static str, String) {
+            match parse::<HashMap<String, f64>>(body.as_bytes()) {
+                Err(ApiError::BadRequest { code, message }) => (code, message),
+                other => panic!("expected a bad request, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn json_content_types_are_recognised() {
+            for content_type in ["application/json", "Application/JSON; charset=utf-8", "application/geo+json"] {
+                assert!(is_json(content_type), "{}", content_type);
+            }
+            for content_type in ["text/plain", "application/xml", "application/jsonp", "multipart/form-data"] {
+                assert!(!is_json(content_type), "{}", content_type);
+            }
+        }
+
+        #[test]
+        fn truncated_json_names_where_parsing_stopped() {
+            let (code, message) = parse_error("{\"latitude\": 51.5, \"longitude\":");
+            assert_eq!(code, "invalid_json");
+            assert!(message.contains("line 1 column 31"), "{}", message);
+
+            let (code, message) = parse_error("{\n  \"latitude\": 51.5,\n  oops\n}");
+            assert_eq!(code, "invalid_json");
+            assert!(message.contains("line 3 column 3"), "{}", message);
+        }
+
+        #[test]
+        fn well_formed_json_of_the_wrong_shape_is_an_invalid_body() {
+            let (code, message) = parse_error("{\"latitude\": \"north\"}");
+            assert_eq!(code, "invalid_body");
+            assert!(message.contains("line 1 column 20"), "{}", message);
+        }
+
+        #[tokio::test]
+        async fn reading_stops_as_soon_as_the_limit_is_crossed() {
+            let chunks = |sizes: &[usize]| {
+                let chunks: Vec<_> = sizes.iter().map(|&size| Ok::<_, warp::Error>(Bytes::from(vec![b' '; size]))).collect();
+                stream::iter(chunks)
+            };
+            assert_eq!(read(chunks(&[40, 60]), 0, 100).await.unwrap().len(), 100);
+            assert!(matches!(
+                read(chunks(&[40, 60, 1]), 0, 100).await,
+                Err(ApiError::PayloadTooLarge { limit_bytes: 100 })
+            ));
+        }
+    }
+}
+
+pub mod deadline {
+    use std::convert::Infallible;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing::warn;
+    use warp::{filters::path::FullPath, Filter, Rejection};
+    use crate::config::Config;
+    use crate::error::ApiError;
+    use super::request_metrics::route_template;
+
+    /// The time limit of the request's route, from [`Config::request_timeout`].
+    pub fn limit(config: Arc<Config>) -> impl Filter<Extract = (Duration,), Error = Infallible> + Clone {
+        warp::path::full().map(move |path: FullPath| config.request_timeout(route_template(path.as_str())))
+    }
+
+    /// Awaits a handler's future for at most `limit`, answering 504 once it runs out. The future
+    /// is dropped then, which releases whatever it holds, pooled connections included; a query
+    /// already sent is left to `DB_STATEMENT_TIMEOUT_MS`.
+    pub async fn run<F, R>(handler: F, limit: Duration) -> Result<R, Rejection>
+    where
+        F: Future<Output = Result<R, Rejection>>,
+    {
+        tokio::time::timeout(limit, handler).await.unwrap_or_else(|_| {
+            warn!(limit_ms = limit.as_millis() as u64, "Request timed out");
+            Err(ApiError::Timeout { limit_ms: limit.as_millis() }.into())
+        })
+    }
+}
+
+pub mod rate_limit {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use chrono::Utc;
+    use redis::Script;
+    use tracing::warn;
+    use uuid::Uuid;
+    use warp::{Filter, Rejection};
+    use crate::config::Config;
+    use crate::error::ApiError;
+    use crate::redis_client::RedisClient;
+    use crate::utils::redis_keys;
+    use super::auth::decode_bearer;
+
+    /// Sliding-window log kept in a sorted set scored by arrival time in milliseconds. Admits the
+    /// request and returns 0, or returns how many milliseconds remain until a slot frees up.
+    const SLIDING_WINDOW_SCRIPT: &str = r#"
+        local now = tonumber(ARGV[1])
+        local window = tonumber(ARGV[2])
+        local limit = tonumber(ARGV[3])
+        redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - window)
+        if redis.call('ZCARD', KEYS[1]) < limit then
+            redis.call('ZADD', KEYS[1], now, ARGV[4])
+            redis.call('PEXPIRE', KEYS[1], window)
+            return 0
+        end
+        local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+        return math.max(tonumber(oldest[2]) + window - now, 1)
+    "#;
+
+    /// Limits each client to `rate_limit_requests` per `rate_limit_window_secs`, shared across
+    /// instances through Redis. Clients are identified by their JWT tenant and subject, falling
+    /// back to the peer IP when the request carries no valid token. Requests are let through if
+    /// Redis is down.
+    pub fn per_client(
+        config: Arc<Config>,
+        redis_client: RedisClient,
+    ) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        warp::header::optional::<String>("authorization")
+            .and(warp::addr::remote())
+            .and_then(move |header: Option<String>, remote: Option<SocketAddr>| {
+                let config = config.clone();
+                let redis_client = redis_client.clone();
+                async move {
+                    let key = match decode_bearer(header.as_deref(), &config) {
+                        Ok(claims) => redis_keys::rate_limit_user(claims.tenant_id(), &claims.sub),
+                        Err(_) => redis_keys::rate_limit_ip(
+                            &remote.map(|addr| addr.ip().to_string()).unwrap_or_else(|| "unknown".to_string()),
+                        ),
+                    };
+
+                    match check(&redis_client, &config, &key).await {
+                        Ok(0) => Ok::<_, Rejection>(()),
+                        Ok(retry_after_ms) => Err(ApiError::RateLimited {
+                            retry_after_secs: retry_after_ms.div_ceil(1000),
+                        }
+                        .into()),
+                        Err(e) => {
+                            warn!("Rate limit check failed, allowing request: {}", e);
+                            Ok(())
+                        }
+                    }
+                }
+            })
+            .untuple_one()
+    }
+
+    async fn check(redis_client: &RedisClient, config: &Config, key: &str) -> redis::RedisResult<u64> {
+        let mut conn = redis_client.get_multiplexed_async_connection().await?;
+        Script::new(SLIDING_WINDOW_SCRIPT)
+            .key(key)
+            .arg(Utc::now().timestamp_millis())
+            .arg(config.rate_limit_window_secs.saturating_mul(1000))
+            .arg(config.rate_limit_requests)
+            .arg(Uuid::new_v4().to_string())
+            .invoke_async(&mut conn)
+            .await
+    }
+}
+
+pub mod request_id {
+    use std::convert::Infallible;
+    use tracing::Span;
+    use uuid::Uuid;
+    use warp::{trace::Info, Filter};
+
+    pub const HEADER: &str = "x-request-id";
+    const MAX_LEN: usize = 128;
+
+    /// The span every request runs in. `request_id` is filled in by [`extract`] once the header
+    /// has been read, so logs from handlers and the services they call all carry it.
+    pub fn span(info: Info) -> Span {
+        tracing::info_span!(
+            "request",
+            method = %info.method(),
+            path = %info.path(),
+            request_id = tracing::field::Empty,
+        )
+    }
+
+    /// Takes the caller's `X-Request-Id`, or generates one when it is missing or unusable, and
+    /// records it on the current request span.
+    pub fn extract() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+        warp::header::optional::<String>(HEADER)
+            .or(warp::any().map(|| None))
+            .unify()
+            .map(|header: Option<String>| {
+                let id = header
+                    .filter(|id| is_valid(id))
+                    .unwrap_or_else(|| Uuid::new_v4().to_string());
+                Span::current().record("request_id", id.as_str());
+                id
+            })
+    }
+
+    fn is_valid(id: &str) -> bool {
+        !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+    }
+}
+
+pub mod request_metrics {
+    use std::convert::Infallible;
+    use std::time::Instant;
+    use warp::{http::Method, path::FullPath, Filter};
+    use crate::metrics::Metrics;
+
+    /// Label for paths that match no route, so unknown URLs cannot grow the label set.
+    const UNMATCHED: &str = "unmatched";
+
+    /// Every route, with `:name` for dynamic segments. Literal routes come before dynamic ones
+    /// sharing their prefix, mirroring the order they are matched in.
+    pub(crate) const ROUTE_TEMPLATES: &[&str] = &[
+        "/",
+        "/health",
+        "/health/live",
+        "/health/ready",
+        "/metrics",
+        "/debug/stats",
+        "/openapi.json",
+        "/api/v1/track/location",
+        "/api/v1/track/locations/batch",
+        "/api/v1/location/nearby",
+        "/api/v1/location/clusters",
+        "/api/v1/location/:user_id",
+        "/api/v1/location/:user_id/history",
+        "/api/v1/location/:user_id/at",
+        "/api/v1/location/:user_id/matched",
+        "/api/v1/location/:user_id/polyline",
+        "/api/v1/location/:user_id/export",
+        "/api/v1/location/:user_id/export/sign",
+        "/api/v1/location/:user_id/export.gpx",
+        "/api/v1/location/:user_id/import.gpx",
+        "/api/v1/users/:user_id/status",
+        "/api/v1/users/:user_id/geofences",
+        "/api/v1/users/:user_id/data",
+        "/api/v1/presence/events",
+        "/api/v1/admin/users",
+        "/api/v1/routes/optimize",
+        "/api/v1/routes/:route_id",
+        "/api/v1/analytics",
+        "/api/v1/analytics/active-users",
+        "/api/v1/analytics/heatmap",
+        "/api/v1/analytics/distance",
+        "/api/v1/analytics/stops",
+        "/api/v1/analytics/trips",
+        "/api/v1/usage",
+        "/api/v1/geofences",
+        "/api/v1/geofences/import",
+        "/api/v1/geofences/evaluate",
+        "/api/v1/geofences/nearest",
+        "/api/v1/geofences/:geofence_id",
+        "/api/v1/geofences/:geofence_id/distance",
+        "/api/v1/geofences/:geofence_id/visits",
+        "/ws/tracking/:user_id",
+        "/ws/geofences/:geofence_id",
+        "/ws/presence",
+        "/ws/replay/:user_id",
+    ];
+
+    /// The template a concrete path was served by, e.g. `/api/v1/location/:user_id` for
+    /// `/api/v1/location/alice`.
+    pub fn route_template(path: &str) -> &'static str {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').filter(|s| !s.is_empty()).collect();
+        ROUTE_TEMPLATES
+            .iter()
+            .find(|template| {
+                let parts: Vec<&str> = template.trim_matches('/').split('/').filter(|s| !s.is_empty()).collect();
+                parts.len() == segments.len()
+                    && parts.iter().zip(&segments).all(|(part, segment)| part.starts_with(':') || part == segment)
+            })
+            .copied()
+            .unwrap_or(UNMATCHED)
+    }
+
+    /// What is known about a request before it is routed.
+    #[derive(Debug)]
+    pub struct Timing {
+        started: Instant,
+        method: Method,
+        route: &'static str,
+    }
+
+    pub fn start() -> impl Filter<Extract = (Timing,), Error = Infallible> + Clone {
+        warp::method().and(warp::path::full()).map(|method: Method, path: FullPath| Timing {
+            started: Instant::now(),
+            method,
+            route: route_template(path.as_str()),
+        })
+    }
+
+    /// Records the latency and status of a finished request. For WebSocket routes this covers the
+    /// upgrade handshake only.
+    pub fn record(metrics: &Metrics, timing: Timing, response: &warp::reply::Response) {
+        let method = timing.method.as_str();
+        metrics
+            .http_request_duration_seconds
+            .with_label_values(&[method, timing.route])
+            .observe(timing.started.elapsed().as_secs_f64());
+        metrics
+            .http_responses_total
+            .with_label_values(&[method, timing.route, response.status().as_str()])
+            .inc();
+    }
+}
+
+pub mod compression {
+    use std::convert::Infallible;
+    use std::io::Write;
+    use flate2::{write::{GzEncoder, ZlibEncoder}, Compression};
+    use tracing::warn;
+    use warp::{
+        http::{header, HeaderMap, HeaderValue},
+        hyper::{body::HttpBody, Body},
+        Filter, Reply,
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Encoding {
+        Gzip,
+        Deflate,
+    }
+
+    impl Encoding {
+        fn as_str(self) -> &'static str {
+            match self {
+                Encoding::Gzip => "gzip",
+                Encoding::Deflate => "deflate",
+            }
+        }
+    }
+
+    /// The encoding to answer with according to `Accept-Encoding`: the supported one with the
+    /// highest q-value, gzip on a tie, `None` when neither is acceptable.
+    pub fn negotiate() -> impl Filter<Extract = (Option<Encoding>,), Error = Infallible> + Clone {
+        warp::header::headers_cloned().map(|headers: HeaderMap| {
+            let accepted = headers.get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+            let entries: Vec<(&str, f32)> = accepted
+                .split(',')
+                .filter_map(|entry| {
+                    let mut parts = entry.split(';').map(str::trim);
+                    let coding = parts.next()?;
+                    let q = parts
+                        .find_map(|param| param.strip_prefix("q="))
+                        .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+                    Some((coding, q))
+                })
+                .collect();
+            // An explicit entry overrides `*`.
+            let weight = |name: &str| {
+                let q_of = |wanted: &dyn Fn(&str) -> bool| entries.iter().find(|(coding, _)| wanted(coding)).map(|&(_, q)| q);
+                q_of(&|coding| coding.eq_ignore_ascii_case(name)).or_else(|| q_of(&|coding| coding == "*")).unwrap_or(0.0)
+            };
+            let (gzip, deflate) = (weight("gzip"), weight("deflate"));
+            match (gzip, deflate) {
+                (g, d) if g > 0.0 && g >= d => Some(Encoding::Gzip),
+                (_, d) if d > 0.0 => Some(Encoding::Deflate),
+                _ => None,
+            }
+        })
+    }
+
+    /// Compresses fully buffered JSON bodies of at least `min_bytes`. Everything else passes
+    /// through untouched: streamed bodies such as exports, non-JSON replies such as `/metrics`,
+    /// WebSocket upgrades, and bodies that would not get smaller.
+    pub async fn compress(encoding: Option<Encoding>, reply: impl Reply, min_bytes: usize) -> warp::reply::Response {
+        let mut response = reply.into_response();
+        let is_json = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/json"));
+        if !is_json || response.headers().contains_key(header::CONTENT_ENCODING) {
+            return response;
+        }
+        response.headers_mut().append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+        let Some(encoding) = encoding else { return response };
+        match response.body().size_hint().exact() {
+            Some(len) if len as usize >= min_bytes => {}
+            _ => return response,
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let bytes = match warp::hyper::body::to_bytes(body).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Failed to buffer response for compression: {}", e);
+                return warp::reply::Response::from_parts(parts, Body::empty());
+            }
+        };
+        let plain = bytes.clone();
+        let compressed = tokio::task::spawn_blocking(move || encode(encoding, &bytes)).await;
+
+        match compressed {
+            Ok(Ok(compressed)) if compressed.len() < plain.len() => {
+                parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+                parts.headers.remove(header::CONTENT_LENGTH);
+                warp::reply::Response::from_parts(parts, Body::from(compressed))
+            }
+            _ => warp::reply::Response::from_parts(parts, Body::from(plain)),
+        }
+    }
+
+    /// `data` as a gzip member or, for `deflate`, a zlib stream as RFC 9110 specifies.
+    fn encode(encoding: Encoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match encoding {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::io::Read;
+        use flate2::read::{GzDecoder, ZlibDecoder};
+        use warp::http::{header, StatusCode};
+        use warp::Reply;
+        use super::*;
+
+        fn json_reply(len: usize) -> warp::reply::Response {
+            let body = serde_json::json!({ "data": "x".repeat(len) });
+            warp::reply::json(&body).into_response()
+        }
+
+        async fn body_of(response: warp::reply::Response) -> Vec<u8> {
+            warp::hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()
+        }
+
+        #[tokio::test]
+        async fn gzip_round_trips_through_a_real_decoder() {
+            let plain = body_of(json_reply(4096)).await;
+            let response = compress(Some(Encoding::Gzip), json_reply(4096), 1024).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+            assert_eq!(response.headers()[header::VARY], "accept-encoding");
+
+            let mut decoded = Vec::new();
+            GzDecoder::new(body_of(response).await.as_slice()).read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, plain);
+        }
+
+        #[tokio::test]
+        async fn deflate_is_sent_as_a_zlib_stream() {
+            let plain = body_of(json_reply(4096)).await;
+            let response = compress(Some(Encoding::Deflate), json_reply(4096), 1024).await;
+            assert_eq!(response.headers()[header::CONTENT_ENCODING], "deflate");
+
+            let mut decoded = Vec::new();
+            ZlibDecoder::new(body_of(response).await.as_slice()).read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, plain);
+        }
+
+        #[tokio::test]
+        async fn bodies_under_the_minimum_are_left_alone() {
+            let plain = body_of(json_reply(100)).await;
+            let response = compress(Some(Encoding::Gzip), json_reply(100), 1024).await;
+            assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+            assert_eq!(body_of(response).await, plain);
+        }
+
+        #[tokio::test]
+        async fn non_json_bodies_are_left_alone() {
+            let text = "x".repeat(4096);
+            let response = compress(Some(Encoding::Gzip), text.clone(), 1024).await;
+            assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+            assert_eq!(body_of(response).await, text.into_bytes());
+        }
+
+        async fn negotiated(accept_encoding: &str) -> Option<Encoding> {
+            warp::test::request().header("accept-encoding", accept_encoding).filter(&negotiate()).await.unwrap()
+        }
+
+        #[tokio::test]
+        async fn negotiation_honours_q_values() {
+            assert_eq!(negotiated("gzip, deflate").await, Some(Encoding::Gzip));
+            assert_eq!(negotiated("gzip;q=0.5, deflate").await, Some(Encoding::Deflate));
+            assert_eq!(negotiated("gzip;q=0, *").await, Some(Encoding::Deflate));
+            assert_eq!(negotiated("br").await, None);
+            assert_eq!(warp::test::request().filter(&negotiate()).await.unwrap(), None);
+        }
+    }
+}
@@ -1,14 +1,3351 @@
+use std::collections::HashMap;
+use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use sqlx::types::Json;
+use utoipa::openapi::path::{Parameter, ParameterIn};
+use utoipa::{IntoParams, ToSchema};
+use crate::openapi::params;
+use crate::utils::{
+    distance_to_segment_meters, EARTH_RADIUS_METERS, euclidean_meters, geohash, h3, haversine_meters, manhattan_meters,
+    point_in_polygon, polygon_distance,
+};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Tenant of tokens without a `tenant` claim and of rows stored before tenants existed.
+pub const DEFAULT_TENANT_ID: &str = "default";
+pub const MAX_TENANT_ID_LEN: usize = 64;
+
+/// Tenant ids end up in Redis keys, so they are limited to ASCII letters, digits, `_` and `-`.
+pub fn is_valid_tenant_id(tenant_id: &str) -> bool {
+    !tenant_id.is_empty()
+        && tenant_id.len() <= MAX_TENANT_ID_LEN
+        && tenant_id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// A stored fix.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow, ToSchema)]
 pub struct Location {
     pub id: Uuid,
+    pub tenant_id: String,
+    pub user_id: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+    /// Horizontal accuracy in meters.
+    pub accuracy: Option<f64>,
+    pub speed: Option<f64>,
+    pub heading: Option<f64>,
+    pub battery: Option<f32>,
+    /// Per-device sequence number from the SDK; breaks ties between equal timestamps.
+    pub seq: Option<i64>,
+    pub timestamp: DateTime<Utc>,
+    /// How `timestamp` was arrived at; fixes cached before it was recorded read as `device`.
+    #[serde(default)]
+    #[sqlx(try_from = "String")]
+    pub timestamp_status: TimestampStatus,
+}
+
+/// Response header naming the [`CoordinatePrecision`] fixes are reported at, when coarsened.
+pub const COORDINATE_PRECISION_HEADER: &str = "x-coordinate-precision";
+pub const MAX_COORDINATE_DECIMALS: u8 = 10;
+pub const MAX_COORDINATE_GRID_METERS: f64 = 100_000.0;
+
+/// How finely the coordinates of fixes are reported, for deployments that must not reveal exact
+/// positions. Written as `full`, a number of decimal places, or a grid size such as `100m`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoordinatePrecision {
+    /// As recorded.
+    Full,
+    /// Rounded to this many decimal places of a degree; 3 is about 100 m of latitude.
+    Decimals(u8),
+    /// Snapped to the nearest point of a grid about this many meters apart. Longitude steps widen
+    /// with latitude so the cells stay roughly square.
+    GridMeters(f64),
+}
+
+impl CoordinatePrecision {
+    pub fn is_full(self) -> bool {
+        self == CoordinatePrecision::Full
+    }
+
+    /// The point at this precision, still within the valid coordinate range.
+    pub fn round(self, latitude: f64, longitude: f64) -> (f64, f64) {
+        match self {
+            CoordinatePrecision::Full => (latitude, longitude),
+            CoordinatePrecision::Decimals(places) => {
+                let factor = 10f64.powi(places as i32);
+                ((latitude * factor).round() / factor, (longitude * factor).round() / factor)
+            }
+            CoordinatePrecision::GridMeters(meters) => {
+                let latitude_step = meters / EARTH_RADIUS_METERS.to_radians();
+                let latitude = ((latitude / latitude_step).round() * latitude_step).clamp(-90.0, 90.0);
+                // Measured at the cell's edge nearer the equator, where a degree of longitude is
+                // widest, so no point of the cell moves further east or west than north or south.
+                // Derived from the snapped latitude only, so snapping a snapped point is a no-op.
+                let edge = (latitude.abs() - latitude_step / 2.0).max(0.0);
+                let longitude_step = latitude_step / edge.to_radians().cos().max(1e-9);
+                let longitude = ((longitude / longitude_step).round() * longitude_step).clamp(-180.0, 180.0);
+                (latitude, longitude)
+            }
+        }
+    }
+
+    /// Farthest [`CoordinatePrecision::round`] can move a point, in meters: half a cell's diagonal.
+    pub fn max_shift_meters(self) -> f64 {
+        let cell_meters = match self {
+            CoordinatePrecision::Full => 0.0,
+            CoordinatePrecision::Decimals(places) => 10f64.powi(-(places as i32)) * EARTH_RADIUS_METERS.to_radians(),
+            CoordinatePrecision::GridMeters(meters) => meters,
+        };
+        cell_meters * std::f64::consts::SQRT_2 / 2.0
+    }
+
+    pub fn apply(self, value: &mut impl Coordinates) {
+        if !self.is_full() {
+            value.round_coordinates(self);
+        }
+    }
+}
+
+/// A response value carrying positions of users' fixes, which are reported at the configured
+/// [`CoordinatePrecision`].
+pub trait Coordinates {
+    fn round_coordinates(&mut self, precision: CoordinatePrecision);
+}
+
+impl Coordinates for Location {
+    fn round_coordinates(&mut self, precision: CoordinatePrecision) {
+        (self.latitude, self.longitude) = precision.round(self.latitude, self.longitude);
+    }
+}
+
+impl Coordinates for GeofenceEvent {
+    fn round_coordinates(&mut self, precision: CoordinatePrecision) {
+        (self.latitude, self.longitude) = precision.round(self.latitude, self.longitude);
+    }
+}
+
+impl Coordinates for Stop {
+    fn round_coordinates(&mut self, precision: CoordinatePrecision) {
+        (self.latitude, self.longitude) = precision.round(self.latitude, self.longitude);
+    }
+}
+
+impl Coordinates for Trip {
+    fn round_coordinates(&mut self, precision: CoordinatePrecision) {
+        (self.start_latitude, self.start_longitude) = precision.round(self.start_latitude, self.start_longitude);
+        (self.end_latitude, self.end_longitude) = precision.round(self.end_latitude, self.end_longitude);
+    }
+}
+
+impl Coordinates for ClusterPoint {
+    fn round_coordinates(&mut self, precision: CoordinatePrecision) {
+        (self.latitude, self.longitude) = precision.round(self.latitude, self.longitude);
+    }
+}
+
+impl FromStr for CoordinatePrecision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "full" {
+            return Ok(CoordinatePrecision::Full);
+        }
+        if let Some(meters) = s.strip_suffix('m') {
+            return meters
+                .parse::<f64>()
+                .ok()
+                .filter(|meters| *meters > 0.0 && *meters <= MAX_COORDINATE_GRID_METERS)
+                .map(CoordinatePrecision::GridMeters)
+                .ok_or_else(|| format!("grid size must be greater than 0 and at most {}m", MAX_COORDINATE_GRID_METERS));
+        }
+        s.parse::<u8>()
+            .ok()
+            .filter(|places| *places <= MAX_COORDINATE_DECIMALS)
+            .map(CoordinatePrecision::Decimals)
+            .ok_or_else(|| {
+                format!("expected 'full', 0 to {} decimal places, or a grid size such as '100m'", MAX_COORDINATE_DECIMALS)
+            })
+    }
+}
+
+impl std::fmt::Display for CoordinatePrecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoordinatePrecision::Full => f.write_str("full"),
+            CoordinatePrecision::Decimals(places) => write!(f, "{}", places),
+            CoordinatePrecision::GridMeters(meters) => write!(f, "{}m", meters),
+        }
+    }
+}
+
+/// Written the way it is configured, e.g. in the redacted configuration.
+impl Serialize for CoordinatePrecision {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Where a stored fix's timestamp came from, so analyses can leave out untrustworthy ones.
+/// `device`: reported and within the accepted range. `server`: not reported, the time of receipt
+/// was used. `substituted`: reported out of range and replaced with the time of receipt.
+/// `suspect`: reported and kept, but older than the maximum plausible age. `interpolated`: missing
+/// from an imported track and estimated from the timed points around it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampStatus {
+    #[default]
+    Device,
+    Server,
+    Substituted,
+    Suspect,
+    Interpolated,
+}
+
+impl TimestampStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimestampStatus::Device => "device",
+            TimestampStatus::Server => "server",
+            TimestampStatus::Substituted => "substituted",
+            TimestampStatus::Suspect => "suspect",
+            TimestampStatus::Interpolated => "interpolated",
+        }
+    }
+}
+
+impl TryFrom<String> for TimestampStatus {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "device" => Ok(TimestampStatus::Device),
+            "server" => Ok(TimestampStatus::Server),
+            "substituted" => Ok(TimestampStatus::Substituted),
+            "suspect" => Ok(TimestampStatus::Suspect),
+            "interpolated" => Ok(TimestampStatus::Interpolated),
+            _ => Err(format!("unknown timestamp status '{}'", value)),
+        }
+    }
+}
+
+/// Bounds on reported timestamps, relative to the time a fix is received.
+#[derive(Debug, Clone, Copy)]
+pub struct TimestampPolicy {
+    /// How far ahead of server time a timestamp may be.
+    pub max_skew: Duration,
+    /// How far behind server time a timestamp may be before it is suspect.
+    pub max_age: Duration,
+    /// Replace out-of-range timestamps with the time of receipt instead of rejecting or flagging.
+    pub trust_server_time: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TrackLocationRequest {
+    /// Set from the caller's token before the fix is stored, like `user_id`.
+    #[serde(skip)]
+    pub tenant_id: String,
+    /// Overwritten with the authenticated subject before the fix is stored.
+    #[serde(default)]
     pub user_id: String,
+    #[schema(minimum = -90, maximum = 90)]
     pub latitude: f64,
+    #[schema(minimum = -180, maximum = 180)]
     pub longitude: f64,
     pub altitude: Option<f64>,
     pub accuracy: Option<f64>,
+    pub speed: Option<f64>,
+    #[schema(minimum = 0, exclusive_maximum = 360)]
+    pub heading: Option<f64>,
+    pub battery: Option<f32>,
+    /// Monotonically increasing per device. A fix repeating a stored `seq` is a duplicate.
+    #[serde(default)]
+    #[schema(minimum = 0)]
+    pub seq: Option<i64>,
+    /// Defaults to the time of receipt. Rejected with `timestamp_in_future` when beyond the allowed
+    /// clock skew, unless the server is configured to substitute its own time.
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Set by [`TrackLocationRequest::resolve_timestamp`] before the fix is stored.
+    #[serde(skip)]
+    pub timestamp_status: TimestampStatus,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ValidationError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ValidationError {
+    fn new(code: &'static str, message: String) -> Self {
+        Self { code, message }
+    }
+}
+
+/// Envelope shared by every list endpoint.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Paginated<T> {
+    pub data: Vec<T>,
+    pub page: PageInfo,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PageInfo {
+    pub limit: i64,
+    /// Pass back as `cursor` to fetch the next page; `null` on the last page.
+    pub next_cursor: Option<String>,
+    /// Only computed when the request sets `include_total=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+}
+
+/// Reads the `include_total` flag shared by list endpoints; absent means `false`.
+fn parse_include_total(params: &HashMap<String, String>) -> Result<bool, ValidationError> {
+    match params.get("include_total").map(String::as_str) {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(value) => Err(ValidationError::new(
+            "invalid_parameter",
+            format!("include_total '{}' must be true or false", value),
+        )),
+    }
+}
+
+/// Largest `simplify=` tolerance accepted, in meters.
+pub const MAX_SIMPLIFY_TOLERANCE_METERS: f64 = 10_000.0;
+
+/// Parses the optional `simplify` tolerance, in meters, of the history and export endpoints.
+fn parse_simplify(params: &HashMap<String, String>) -> Result<Option<f64>, ValidationError> {
+    match params.get("simplify") {
+        None => Ok(None),
+        Some(value) => match value.parse::<f64>() {
+            Ok(epsilon) if epsilon > 0.0 && epsilon <= MAX_SIMPLIFY_TOLERANCE_METERS => Ok(Some(epsilon)),
+            _ => Err(ValidationError::new(
+                "invalid_parameter",
+                format!(
+                    "simplify '{}' must be a tolerance in meters above 0 and at most {}",
+                    value, MAX_SIMPLIFY_TOLERANCE_METERS
+                ),
+            )),
+        },
+    }
+}
+
+fn validate_coordinates(latitude: f64, longitude: f64) -> Result<(), ValidationError> {
+    if !(-90.0..=90.0).contains(&latitude) {
+        return Err(ValidationError::new(
+            "invalid_coordinates",
+            format!("latitude {} is outside -90..90", latitude),
+        ));
+    }
+    if !(-180.0..=180.0).contains(&longitude) {
+        return Err(ValidationError::new(
+            "invalid_coordinates",
+            format!("longitude {} is outside -180..180", longitude),
+        ));
+    }
+    Ok(())
+}
+
+impl TrackLocationRequest {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        validate_coordinates(self.latitude, self.longitude)?;
+        if let Some(heading) = self.heading {
+            if !(0.0..360.0).contains(&heading) {
+                return Err(ValidationError::new(
+                    "invalid_heading",
+                    format!("heading {} is outside 0..360", heading),
+                ));
+            }
+        }
+        if let Some(seq) = self.seq {
+            if seq < 0 {
+                return Err(ValidationError::new(
+                    "invalid_seq",
+                    format!("seq {} must not be negative", seq),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the reported timestamp against `received`, filling it in when absent. A timestamp
+    /// beyond the allowed skew is rejected and one older than the maximum age is flagged as
+    /// suspect, unless the policy trusts server time, in which case both are replaced.
+    pub fn resolve_timestamp(&mut self, received: DateTime<Utc>, policy: &TimestampPolicy) -> Result<(), ValidationError> {
+        let Some(timestamp) = self.timestamp else {
+            self.timestamp = Some(received);
+            self.timestamp_status = TimestampStatus::Server;
+            return Ok(());
+        };
+
+        let in_future = timestamp > received + policy.max_skew;
+        let too_old = timestamp < received - policy.max_age;
+        self.timestamp_status = if (in_future || too_old) && policy.trust_server_time {
+            self.timestamp = Some(received);
+            TimestampStatus::Substituted
+        } else if in_future {
+            return Err(ValidationError::new(
+                "timestamp_in_future",
+                format!(
+                    "timestamp {} is more than {} seconds ahead of server time",
+                    timestamp.to_rfc3339(),
+                    policy.max_skew.num_seconds()
+                ),
+            ));
+        } else if too_old {
+            TimestampStatus::Suspect
+        } else {
+            TimestampStatus::Device
+        };
+        Ok(())
+    }
+
+    pub fn into_location(self) -> Location {
+        Location {
+            id: Uuid::new_v4(),
+            tenant_id: self.tenant_id,
+            user_id: self.user_id,
+            latitude: self.latitude,
+            longitude: self.longitude,
+            altitude: self.altitude,
+            accuracy: self.accuracy,
+            speed: self.speed,
+            heading: self.heading,
+            battery: self.battery,
+            seq: self.seq,
+            timestamp: self.timestamp.unwrap_or_else(Utc::now),
+            timestamp_status: self.timestamp_status,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TrackLocationQuery {
+    /// Also evaluate the fix against the user's geofences and report the result.
+    pub geofences: bool,
+}
+
+impl TrackLocationQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let geofences = match params.get("geofences").map(String::as_str) {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(value) => {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    format!("geofences '{}' must be true or false", value),
+                ))
+            }
+        };
+        Ok(Self { geofences })
+    }
+}
+
+impl IntoParams for TrackLocationQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![params::query(
+            "geofences",
+            "Also report the geofences the fix falls in and those it entered or exited. Left out of replays, \
+             and of the response if the evaluation fails.",
+            params::flag(),
+        )]
+    }
+}
+
+/// Geofences of the tenant containing a just-stored fix. `entered` and `exited` compare them with
+/// the membership the monitor has recorded for the user, and are left out when it could not be
+/// read.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FixGeofenceState {
+    pub inside: Vec<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entered: Option<Vec<Uuid>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exited: Option<Vec<Uuid>>,
+}
+
+/// Response of `track_location` with `geofences=true`: the stored fix plus its geofence state.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TrackedLocation {
+    #[serde(flatten)]
+    pub location: Location,
+    pub geofences: FixGeofenceState,
+}
+
+/// Response of `track_locations_batch`. Indices refer to positions in the submitted array.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchResult {
+    /// How many fixes were stored.
+    pub accepted: usize,
+    /// Fixes that failed validation or were rejected as implausible.
+    pub rejected: Vec<usize>,
+    /// Fixes whose `seq` was already stored.
+    pub duplicates: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow, ToSchema)]
+pub struct Geofence {
+    pub id: Uuid,
+    pub tenant_id: String,
+    pub name: String,
+    /// `circle` or `polygon`.
+    pub geofence_type: String,
+    pub center_latitude: Option<f64>,
+    pub center_longitude: Option<f64>,
+    pub radius_meters: Option<f64>,
+    /// GeoJSON rings of `[lon, lat]`, for polygons.
+    #[schema(value_type = Option<Vec<Vec<[f64; 2]>>>)]
+    pub polygon: Option<Json<Vec<Vec<[f64; 2]>>>>,
+    /// Continuous time inside after which a DWELL event fires; `None` disables dwell alerts.
+    pub dwell_threshold_secs: Option<i64>,
+    /// Receives every event of this geofence as a signed POST.
+    pub webhook_url: Option<String>,
+    /// Altitude band in meters a fix must fall in, e.g. one floor of a building; either end may
+    /// be open.
+    pub min_altitude: Option<f64>,
+    pub max_altitude: Option<f64>,
+    /// Speed in m/s above which a fix inside raises a SPEEDING event, e.g. for a school zone.
+    pub speed_limit: Option<f64>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Geofence {
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        match self.geofence_type.as_str() {
+            "polygon" => {
+                let rings = match &self.polygon {
+                    Some(Json(rings)) => rings,
+                    None => return false,
+                };
+                let point = [longitude, latitude];
+                match rings.split_first() {
+                    Some((outer, holes)) => {
+                        point_in_polygon(point, outer) && !holes.iter().any(|hole| point_in_polygon(point, hole))
+                    }
+                    None => false,
+                }
+            }
+            _ => match (self.center_latitude, self.center_longitude, self.radius_meters) {
+                (Some(lat), Some(lon), Some(radius)) => haversine_meters(latitude, longitude, lat, lon) <= radius,
+                _ => false,
+            },
+        }
+    }
+}
+
+impl Geofence {
+    /// [`Geofence::contains`] plus the altitude band. A fix without altitude matches any band
+    /// unless `strict_altitude` is set, in which case it only matches geofences without one.
+    pub fn contains_fix(&self, latitude: f64, longitude: f64, altitude: Option<f64>, strict_altitude: bool) -> bool {
+        let banded = self.min_altitude.is_some() || self.max_altitude.is_some();
+        let in_band = match altitude {
+            Some(altitude) => {
+                self.min_altitude.is_none_or(|min| altitude >= min) && self.max_altitude.is_none_or(|max| altitude <= max)
+            }
+            None => !(banded && strict_altitude),
+        };
+        in_band && self.contains(latitude, longitude)
+    }
+
+    /// A box every point of the geofence lies in, for cheap rejection before [`Geofence::contains`].
+    /// `None` when no simple box exists, e.g. a circle reaching a pole or the antimeridian.
+    pub fn bounding_box(&self) -> Option<BoundingBox> {
+        match self.geofence_type.as_str() {
+            "polygon" => BoundingBox::of_polygon(&self.polygon.as_ref()?.0),
+            _ => BoundingBox::of_circle(self.center_latitude?, self.center_longitude?, self.radius_meters?),
+        }
+    }
+
+    /// Signed distance in meters from the point to the geofence's boundary, negative inside.
+    /// `None` for a geofence missing its geometry.
+    pub fn boundary_distance_meters(&self, latitude: f64, longitude: f64) -> Option<f64> {
+        match self.geofence_type.as_str() {
+            "polygon" => polygon_distance([longitude, latitude], &self.polygon.as_ref()?.0),
+            _ => Some(haversine_meters(latitude, longitude, self.center_latitude?, self.center_longitude?) - self.radius_meters?),
+        }
+    }
+
+    /// Whether any part of the geofence lies within `radius_meters` of the given point.
+    pub fn intersects_circle(&self, latitude: f64, longitude: f64, radius_meters: f64) -> bool {
+        if self.contains(latitude, longitude) {
+            return true;
+        }
+        match self.geofence_type.as_str() {
+            "polygon" => self.polygon.as_ref().is_some_and(|Json(rings)| {
+                rings.iter().any(|ring| {
+                    ring.iter().zip(ring.iter().cycle().skip(1)).any(|(&a, &b)| {
+                        distance_to_segment_meters([longitude, latitude], a, b) <= radius_meters
+                    })
+                })
+            }),
+            _ => match (self.center_latitude, self.center_longitude, self.radius_meters) {
+                (Some(lat), Some(lon), Some(radius)) => {
+                    haversine_meters(latitude, longitude, lat, lon) <= radius + radius_meters
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+pub const DEFAULT_GEOFENCE_LIMIT: i64 = 100;
+pub const MAX_GEOFENCE_LIMIT: i64 = 1000;
+
+/// Circle that listed geofences must intersect.
+#[derive(Debug, Clone, Copy)]
+pub struct SearchCircle {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_meters: f64,
+}
+
+#[derive(Debug)]
+pub struct GeofenceQuery {
+    pub near: Option<SearchCircle>,
+    pub name_contains: Option<String>,
+    pub limit: i64,
+    pub offset: i64,
+    pub include_total: bool,
+}
+
+impl GeofenceQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let number = |name: &str| -> Result<Option<f64>, ValidationError> {
+            match params.get(name) {
+                Some(value) => value.parse::<f64>().ok().filter(|v| v.is_finite()).map(Some).ok_or_else(|| {
+                    ValidationError::new("invalid_parameter", format!("{} '{}' is not a number", name, value))
+                }),
+                None => Ok(None),
+            }
+        };
+
+        let radius_meters = number("radius")?;
+        let near = match (number("near_lat")?, number("near_lon")?) {
+            (Some(latitude), Some(longitude)) => {
+                validate_coordinates(latitude, longitude)?;
+                let radius_meters = radius_meters.unwrap_or(0.0);
+                if radius_meters < 0.0 {
+                    return Err(ValidationError::new(
+                        "invalid_radius",
+                        format!("radius {} must not be negative", radius_meters),
+                    ));
+                }
+                Some(SearchCircle { latitude, longitude, radius_meters })
+            }
+            (None, None) if radius_meters.is_some() => {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    "radius requires near_lat and near_lon".to_string(),
+                ));
+            }
+            (None, None) => None,
+            _ => {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    "near_lat and near_lon must be supplied together".to_string(),
+                ));
+            }
+        };
+
+        let limit = params
+            .get("limit")
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(DEFAULT_GEOFENCE_LIMIT)
+            .clamp(1, MAX_GEOFENCE_LIMIT);
+        // The cursor handed out in `next_cursor` is the offset of the next page.
+        let offset = match params.get("cursor") {
+            Some(value) => value.parse::<i64>().ok().filter(|offset| *offset >= 0).ok_or_else(|| {
+                ValidationError::new("invalid_cursor", format!("cursor '{}' is not valid", value))
+            })?,
+            None => params
+                .get("offset")
+                .and_then(|value| value.parse::<i64>().ok())
+                .unwrap_or(0)
+                .max(0),
+        };
+        let name_contains = params.get("name_contains").filter(|value| !value.is_empty()).cloned();
+        let include_total = parse_include_total(params)?;
+
+        Ok(Self { near, name_contains, limit, offset, include_total })
+    }
+}
+
+impl IntoParams for GeofenceQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![
+            params::query(
+                "near_lat",
+                "With `near_lon`: only geofences reaching within `radius` of this point.",
+                params::between(-90.0, 90.0),
+            ),
+            params::query("near_lon", "See `near_lat`.", params::between(-180.0, 180.0)),
+            params::query(
+                "radius",
+                "Search radius in meters around `near_lat`/`near_lon`.",
+                params::number().minimum(Some(0)).default(Some(0.into())),
+            ),
+            params::query("name_contains", "Case-insensitive name filter.", params::string()),
+            params::limit(DEFAULT_GEOFENCE_LIMIT, MAX_GEOFENCE_LIMIT),
+            params::cursor("`next_cursor` of the previous page; `offset` is accepted instead."),
+            params::include_total(),
+        ]
+    }
+}
+
+/// Geometry of a geofence. Polygons follow GeoJSON: a list of `[lon, lat]` rings where the
+/// first ring is the outer boundary and any further rings are holes.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum GeofenceShape {
+    Circle {
+        #[schema(minimum = -90, maximum = 90)]
+        center_latitude: f64,
+        #[schema(minimum = -180, maximum = 180)]
+        center_longitude: f64,
+        #[schema(exclusive_minimum = 0)]
+        radius_meters: f64,
+    },
+    Polygon {
+        coordinates: Vec<Vec<[f64; 2]>>,
+    },
+}
+
+impl GeofenceShape {
+    /// Same box as [`Geofence::bounding_box`], stored with the geofence to prefilter lookups.
+    pub fn bounding_box(&self) -> Option<BoundingBox> {
+        match self {
+            GeofenceShape::Circle { center_latitude, center_longitude, radius_meters } => {
+                BoundingBox::of_circle(*center_latitude, *center_longitude, *radius_meters)
+            }
+            GeofenceShape::Polygon { coordinates } => BoundingBox::of_polygon(coordinates),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        match self {
+            GeofenceShape::Circle { center_latitude, center_longitude, radius_meters } => {
+                validate_coordinates(*center_latitude, *center_longitude)?;
+                if !radius_meters.is_finite() || *radius_meters <= 0.0 {
+                    return Err(ValidationError::new(
+                        "invalid_radius",
+                        format!("radius_meters {} must be positive", radius_meters),
+                    ));
+                }
+                Ok(())
+            }
+            GeofenceShape::Polygon { coordinates } => {
+                if coordinates.is_empty() {
+                    return Err(ValidationError::new(
+                        "invalid_polygon",
+                        "polygon must have at least one ring".to_string(),
+                    ));
+                }
+                for ring in coordinates {
+                    let closed = ring.len() > 1 && ring.first() == ring.last();
+                    let vertices = if closed { ring.len() - 1 } else { ring.len() };
+                    if vertices < 3 {
+                        return Err(ValidationError::new(
+                            "invalid_polygon",
+                            format!("polygon ring has {} vertices, at least 3 are required", vertices),
+                        ));
+                    }
+                    for &[lon, lat] in ring {
+                        validate_coordinates(lat, lon)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A circle or a polygon, chosen by `type`, plus the common fields.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateGeofenceRequest {
+    pub name: String,
+    #[serde(flatten)]
+    pub shape: GeofenceShape,
+    #[serde(default)]
+    #[schema(minimum = 1)]
+    pub dwell_threshold_secs: Option<i64>,
+    /// Must be an absolute `http://` or `https://` URL; receives signed event POSTs.
+    #[serde(default)]
+    #[schema(format = "uri")]
+    pub webhook_url: Option<String>,
+    #[serde(default)]
+    pub min_altitude: Option<f64>,
+    #[serde(default)]
+    pub max_altitude: Option<f64>,
+    /// In m/s; must be positive. Faster fixes inside raise SPEEDING events.
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0)]
+    pub speed_limit: Option<f64>,
+}
+
+impl CreateGeofenceRequest {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.name.trim().is_empty() {
+            return Err(ValidationError::new(
+                "invalid_name",
+                "geofence name must not be empty".to_string(),
+            ));
+        }
+        if let Some(threshold) = self.dwell_threshold_secs {
+            if threshold <= 0 {
+                return Err(ValidationError::new(
+                    "invalid_dwell_threshold",
+                    format!("dwell_threshold_secs must be positive, got {}", threshold),
+                ));
+            }
+        }
+        if [self.min_altitude, self.max_altitude].into_iter().flatten().any(|altitude| !altitude.is_finite()) {
+            return Err(ValidationError::new(
+                "invalid_altitude_band",
+                "min_altitude and max_altitude must be finite".to_string(),
+            ));
+        }
+        if let (Some(min), Some(max)) = (self.min_altitude, self.max_altitude) {
+            if min > max {
+                return Err(ValidationError::new(
+                    "invalid_altitude_band",
+                    format!("min_altitude {} must not exceed max_altitude {}", min, max),
+                ));
+            }
+        }
+        if let Some(limit) = self.speed_limit {
+            if !limit.is_finite() || limit <= 0.0 {
+                return Err(ValidationError::new(
+                    "invalid_speed_limit",
+                    format!("speed_limit {} must be positive", limit),
+                ));
+            }
+        }
+        if let Some(url) = &self.webhook_url {
+            let valid = url
+                .parse::<warp::http::Uri>()
+                .is_ok_and(|uri| matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some());
+            if !valid {
+                return Err(ValidationError::new(
+                    "invalid_webhook_url",
+                    format!("webhook_url '{}' must be an absolute http:// or https:// URL", url),
+                ));
+            }
+        }
+        self.shape.validate()
+    }
+
+    /// Reads one feature of an import. A `Point` with a `radius` property becomes a circle and a
+    /// `Polygon` a polygon; `name`, `dwell_threshold_secs`, `webhook_url`, `min_altitude`,
+    /// `max_altitude` and `speed_limit` come from the properties. Positions may carry a third, ignored, altitude.
+    pub fn from_feature(feature: serde_json::Value) -> Result<Self, ValidationError> {
+        let feature: GeoJsonFeature = serde_json::from_value(feature)
+            .map_err(|e| ValidationError::new("invalid_feature", e.to_string()))?;
+        if feature.kind != "Feature" {
+            return Err(ValidationError::new(
+                "invalid_feature",
+                format!("type '{}' must be Feature", feature.kind),
+            ));
+        }
+        let properties = feature.properties;
+        let shape = match feature.geometry {
+            GeoJsonGeometry::Point { coordinates } => {
+                let [longitude, latitude] = position(&coordinates)?;
+                let radius_meters = properties.radius.ok_or_else(|| {
+                    ValidationError::new("invalid_radius", "a Point feature needs a radius property in meters".to_string())
+                })?;
+                GeofenceShape::Circle { center_latitude: latitude, center_longitude: longitude, radius_meters }
+            }
+            GeoJsonGeometry::Polygon { coordinates } => GeofenceShape::Polygon {
+                coordinates: coordinates
+                    .iter()
+                    .map(|ring| ring.iter().map(|p| position(p)).collect::<Result<_, _>>())
+                    .collect::<Result<_, _>>()?,
+            },
+        };
+        let request = Self {
+            name: properties.name.unwrap_or_default(),
+            shape,
+            dwell_threshold_secs: properties.dwell_threshold_secs,
+            webhook_url: properties.webhook_url,
+            min_altitude: properties.min_altitude,
+            max_altitude: properties.max_altitude,
+            speed_limit: properties.speed_limit,
+        };
+        request.validate()?;
+        Ok(request)
+    }
+}
+
+/// Most features a single `POST /api/v1/geofences/import` may carry.
+pub const MAX_GEOFENCE_IMPORT_FEATURES: usize = 1000;
+
+/// A GeoJSON FeatureCollection of geofences. Features are kept as raw JSON so that a malformed
+/// one is reported on its own instead of failing the whole body.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct GeofenceImportRequest {
+    /// `FeatureCollection`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[schema(value_type = Vec<GeoJsonFeature>, max_items = 1000)]
+    pub features: Vec<serde_json::Value>,
+}
+
+impl GeofenceImportRequest {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.kind != "FeatureCollection" {
+            return Err(ValidationError::new(
+                "invalid_feature_collection",
+                format!("type '{}' must be FeatureCollection", self.kind),
+            ));
+        }
+        if self.features.len() > MAX_GEOFENCE_IMPORT_FEATURES {
+            return Err(ValidationError::new(
+                "import_too_large",
+                format!(
+                    "import of {} features exceeds the maximum of {}",
+                    self.features.len(),
+                    MAX_GEOFENCE_IMPORT_FEATURES
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct GeoJsonFeature {
+    /// `Feature`.
+    #[serde(rename = "type")]
+    kind: String,
+    geometry: GeoJsonGeometry,
+    #[serde(default)]
+    properties: GeoJsonGeofenceProperties,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(tag = "type")]
+enum GeoJsonGeometry {
+    /// `[lon, lat]`, optionally followed by an altitude.
+    Point { coordinates: Vec<f64> },
+    /// Rings of `[lon, lat]` positions; the first is the outer boundary, later ones are holes.
+    Polygon { coordinates: Vec<Vec<Vec<f64>>> },
+}
+
+/// The fields of [`CreateGeofenceRequest`] other than the shape.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+struct GeoJsonGeofenceProperties {
+    name: Option<String>,
+    /// Circle radius in meters; required for a Point.
+    #[serde(alias = "radius_meters")]
+    radius: Option<f64>,
+    dwell_threshold_secs: Option<i64>,
+    webhook_url: Option<String>,
+    min_altitude: Option<f64>,
+    max_altitude: Option<f64>,
+    speed_limit: Option<f64>,
+}
+
+/// `[lon, lat]` of a GeoJSON position.
+fn position(coordinates: &[f64]) -> Result<[f64; 2], ValidationError> {
+    match *coordinates {
+        [longitude, latitude] | [longitude, latitude, _] => Ok([longitude, latitude]),
+        _ => Err(ValidationError::new(
+            "invalid_coordinates",
+            format!("a position has 2 or 3 numbers, got {}", coordinates.len()),
+        )),
+    }
+}
+
+#[derive(Debug)]
+pub struct GeofenceImportQuery {
+    /// Create nothing unless every feature is valid.
+    pub atomic: bool,
+}
+
+impl GeofenceImportQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let atomic = match params.get("atomic").map(String::as_str) {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(value) => {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    format!("atomic '{}' must be true or false", value),
+                ))
+            }
+        };
+        Ok(Self { atomic })
+    }
+}
+
+impl IntoParams for GeofenceImportQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![params::query(
+            "atomic",
+            "Create nothing unless every feature is valid; otherwise valid features are created and the rest reported.",
+            params::flag(),
+        )]
+    }
+}
+
+/// `skipped`: valid, but not created because an atomic import had invalid features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FeatureImportStatus {
+    Created,
+    Invalid,
+    Skipped,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FeatureImportResult {
+    /// Position of the feature in `features`.
+    pub index: usize,
+    pub status: FeatureImportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geofence: Option<Geofence>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ValidationError>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GeofenceImportReport {
+    pub atomic: bool,
+    pub created: usize,
+    pub invalid: usize,
+    pub features: Vec<FeatureImportResult>,
+}
+
+/// A point to test against every geofence. With a `user_id`, each match also says whether the
+/// monitor already considers that user inside.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EvaluateGeofencesRequest {
+    #[schema(minimum = -90, maximum = 90)]
+    pub latitude: f64,
+    #[schema(minimum = -180, maximum = 180)]
+    pub longitude: f64,
+    #[serde(default)]
+    pub altitude: Option<f64>,
+    #[serde(default)]
+    pub user_id: Option<String>,
+}
+
+impl EvaluateGeofencesRequest {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.altitude.is_some_and(|altitude| !altitude.is_finite()) {
+            return Err(ValidationError::new(
+                "invalid_altitude",
+                "altitude must be a finite number".to_string(),
+            ));
+        }
+        if self.user_id.as_deref().is_some_and(|user_id| user_id.trim().is_empty()) {
+            return Err(ValidationError::new(
+                "invalid_user_id",
+                "user_id must not be empty".to_string(),
+            ));
+        }
+        validate_coordinates(self.latitude, self.longitude)
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GeofenceMatch {
+    pub id: Uuid,
+    pub name: String,
+    /// Whether the monitor records the requested user inside; only present with a `user_id`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currently_inside: Option<bool>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GeofenceEvaluation {
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    pub geofences: Vec<GeofenceMatch>,
+}
+
+/// Signed distance from a point to a geofence's boundary; negative while inside.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GeofenceDistance {
+    pub geofence_id: Uuid,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Meters to the nearest boundary; negative inside.
+    pub distance_meters: f64,
+    pub inside: bool,
+}
+
+pub const DEFAULT_NEAREST_GEOFENCE_LIMIT: usize = 5;
+pub const MAX_NEAREST_GEOFENCE_LIMIT: usize = 100;
+
+/// Point of `GET /api/v1/geofences/nearest`, and how many geofences to suggest.
+#[derive(Debug)]
+pub struct NearestGeofenceQuery {
+    pub point: PointQuery,
+    pub limit: usize,
+}
+
+impl NearestGeofenceQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let point = PointQuery::from_params(params)?;
+        let limit = params
+            .get("limit")
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_NEAREST_GEOFENCE_LIMIT)
+            .clamp(1, MAX_NEAREST_GEOFENCE_LIMIT);
+        Ok(Self { point, limit })
+    }
+}
+
+impl IntoParams for NearestGeofenceQuery {
+    fn into_params(parameter_in: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        let mut parameters = PointQuery::into_params(parameter_in);
+        parameters.push(params::query(
+            "limit",
+            "Most geofences returned; clamped.",
+            params::integer(1, MAX_NEAREST_GEOFENCE_LIMIT).default(Some(DEFAULT_NEAREST_GEOFENCE_LIMIT.into())),
+        ));
+        parameters
+    }
+}
+
+/// A geofence suggested for a point, with its distance to the geofence's boundary; 0 when the
+/// point is inside.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NearestGeofence {
+    pub id: Uuid,
+    pub name: String,
+    /// `circle` or `polygon`.
+    pub geofence_type: String,
+    pub distance_meters: f64,
+    pub inside: bool,
+}
+
+/// The geofences closest to a point, nearest boundary first; ties are ordered by name.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NearestGeofences {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub geofences: Vec<NearestGeofence>,
+}
+
+/// Where a user's current geofences were read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipSource {
+    /// The membership the geofence monitor keeps in Redis.
+    Monitor,
+    /// Containment of the user's latest fix, worked out on the spot.
+    LatestFix,
+}
+
+/// A geofence a user is inside. The entry time is only known from the monitor's membership.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserGeofence {
+    pub id: Uuid,
+    pub name: String,
+    pub entered_at: Option<DateTime<Utc>>,
+    pub inside_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserGeofences {
+    pub user_id: String,
+    /// Entry times are only known when read from the `monitor`.
+    pub source: MembershipSource,
+    pub geofences: Vec<UserGeofence>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum GeofenceTransition {
+    Enter,
+    Exit,
+    /// Still inside after the geofence's dwell threshold; fires once per visit.
+    Dwell,
+    /// Reported a speed above the geofence's limit while inside.
+    Speeding,
+}
+
+impl GeofenceTransition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GeofenceTransition::Enter => "ENTER",
+            GeofenceTransition::Exit => "EXIT",
+            GeofenceTransition::Dwell => "DWELL",
+            GeofenceTransition::Speeding => "SPEEDING",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct GeofenceEvent {
+    pub id: Uuid,
+    pub geofence_id: Uuid,
+    pub user_id: String,
+    pub event_type: GeofenceTransition,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub occurred_at: DateTime<Utc>,
+    /// Speed reported by the fix, in m/s; SPEEDING events only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed_speed: Option<f64>,
+    /// The geofence's limit when the event fired; SPEEDING events only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_limit: Option<f64>,
+}
+
+/// Visits beyond this many are left out of one response.
+pub const MAX_GEOFENCE_VISITS: i64 = 10_000;
+
+/// Window of `GET /api/v1/geofences/{id}/visits`: visits that overlap it, both bounds optional
+/// and inclusive.
+#[derive(Debug, Clone, Copy)]
+pub struct GeofenceVisitQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl GeofenceVisitQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let from = parse_timestamp_param(params, "from")?;
+        let to = parse_timestamp_param(params, "to")?;
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err(ValidationError::new(
+                    "invalid_time_range",
+                    "from must not be later than to".to_string(),
+                ));
+            }
+        }
+
+        Ok(Self { from, to })
+    }
+}
+
+impl IntoParams for GeofenceVisitQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        params::window(false).into()
+    }
+}
+
+/// A user's stay inside a geofence, from the fix that entered it to the first fix outside.
+#[derive(Debug, Serialize, sqlx::FromRow, ToSchema)]
+pub struct GeofenceVisit {
+    pub id: Uuid,
+    pub user_id: String,
+    pub entered_at: DateTime<Utc>,
+    /// `None`, as are `dwell_secs` and `distance_meters`, while the user is still inside.
+    pub exited_at: Option<DateTime<Utc>>,
+    pub dwell_secs: Option<i64>,
+    /// Distance between consecutive fixes from the entering one to the last one inside.
+    pub distance_meters: Option<f64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GeofenceVisits {
+    pub geofence_id: Uuid,
+    /// Oldest entry first.
+    pub visits: Vec<GeofenceVisit>,
+    /// Whether more visits matched than are listed.
+    pub truncated: bool,
+}
+
+/// Messages sent over `/ws/geofences/{geofence_id}`: one snapshot on connect, then live events.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GeofenceStreamMessage {
+    Snapshot { geofence_id: Uuid, users_inside: Vec<String> },
+    Event(GeofenceEvent),
+}
+
+/// How the route optimizer measures the distance between two waypoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DistanceMetric {
+    /// Great-circle distance.
+    #[default]
+    Haversine,
+    /// Straight line on a local flat projection.
+    Euclidean,
+    /// Sum of the east-west and north-south offsets, for city grids.
+    Manhattan,
+}
+
+impl DistanceMetric {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DistanceMetric::Haversine => "haversine",
+            DistanceMetric::Euclidean => "euclidean",
+            DistanceMetric::Manhattan => "manhattan",
+        }
+    }
+
+    pub fn meters(&self, (lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+        match self {
+            DistanceMetric::Haversine => haversine_meters(lat1, lon1, lat2, lon2),
+            DistanceMetric::Euclidean => euclidean_meters(lat1, lon1, lat2, lon2),
+            DistanceMetric::Manhattan => manhattan_meters(lat1, lon1, lat2, lon2),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct OptimizeRouteRequest {
+    /// Waypoints as `[latitude, longitude]` pairs; the first one is treated as the depot.
+    #[schema(value_type = Vec<[f64; 2]>, min_items = 1)]
+    pub waypoints: Vec<(f64, f64)>,
+    #[serde(default)]
+    pub metric: DistanceMetric,
+    /// When the route leaves the depot; defaults to now.
+    #[serde(default)]
+    pub departure_time: Option<DateTime<Utc>>,
+    /// Speed used for every leg without an override; defaults to `DEFAULT_ROUTE_SPEED_KMH`.
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0)]
+    pub average_speed_kmh: Option<f64>,
+    /// Per-leg overrides aligned with `waypoints`: entry `i` is the speed of the leg arriving at
+    /// waypoint `i`. The depot's entry is ignored.
+    #[serde(default)]
+    pub leg_speeds_kmh: Option<Vec<Option<f64>>>,
+}
+
+fn validate_speed(speed_kmh: f64) -> Result<(), ValidationError> {
+    if speed_kmh.is_finite() && speed_kmh > 0.0 {
+        Ok(())
+    } else {
+        Err(ValidationError::new("invalid_speed", format!("speed {} km/h must be positive", speed_kmh)))
+    }
+}
+
+impl OptimizeRouteRequest {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.waypoints.is_empty() {
+            return Err(ValidationError::new(
+                "invalid_waypoints",
+                "at least one waypoint is required".to_string(),
+            ));
+        }
+        for &(lat, lon) in &self.waypoints {
+            validate_coordinates(lat, lon)?;
+        }
+        if let Some(speed_kmh) = self.average_speed_kmh {
+            validate_speed(speed_kmh)?;
+        }
+        if let Some(leg_speeds) = &self.leg_speeds_kmh {
+            if leg_speeds.len() != self.waypoints.len() {
+                return Err(ValidationError::new(
+                    "invalid_speed",
+                    format!("leg_speeds_kmh has {} entries but there are {} waypoints", leg_speeds.len(), self.waypoints.len()),
+                ));
+            }
+            for &speed_kmh in leg_speeds.iter().flatten() {
+                validate_speed(speed_kmh)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OptimizedRoute {
+    /// Indices into the submitted waypoint list, in visiting order.
+    pub order: Vec<usize>,
+    pub metric: DistanceMetric,
+    /// Length of the path under `metric`.
+    pub total_distance_meters: f64,
+    pub two_opt_iterations: u32,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct Route {
+    #[serde(rename = "route_id")]
+    pub id: Uuid,
+    /// Waypoints as `[latitude, longitude]` pairs in visiting order.
+    #[schema(value_type = Vec<[f64; 2]>)]
+    pub waypoints: Json<Vec<(f64, f64)>>,
+    /// Position of each visited waypoint in the originally submitted list.
+    #[schema(value_type = Vec<usize>)]
+    pub waypoint_order: Json<Vec<usize>>,
+    /// The metric `total_distance_meters` was measured with.
+    #[schema(value_type = DistanceMetric)]
+    pub distance_metric: String,
+    pub total_distance_meters: f64,
+    pub two_opt_iterations: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct OptimizedRouteResponse {
+    #[serde(flatten)]
+    pub route: Route,
+    /// Estimated arrival at each waypoint of `route.waypoints`, starting with the departure time.
+    pub etas: Vec<DateTime<Utc>>,
+}
+
+pub const DEFAULT_HISTORY_LIMIT: i64 = 100;
+pub const MAX_HISTORY_LIMIT: i64 = 1000;
+
+/// Rank of a fix without a sequence number among fixes sharing its timestamp.
+pub const UNSEQUENCED_RANK: i64 = -1;
+
+/// Keyset position within a user's history, ordered by `(timestamp, seq rank, id)` descending.
+/// Other keysets ordered by `(timestamp, id)` leave `seq` unset.
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryCursor {
+    pub timestamp: DateTime<Utc>,
+    pub seq: Option<i64>,
+    pub id: Uuid,
+}
+
+impl HistoryCursor {
+    pub fn after(location: &Location) -> Self {
+        Self {
+            timestamp: location.timestamp,
+            seq: Some(location.seq.unwrap_or(UNSEQUENCED_RANK)),
+            id: location.id,
+        }
+    }
+
+    /// The `COALESCE(seq, -1)` value to compare against. A bare timestamp must rank below every
+    /// fix at that instant; a token issued before sequence numbers existed ranks as unsequenced.
+    pub fn seq_rank(&self) -> i64 {
+        match self.seq {
+            Some(seq) => seq,
+            None if self.id.is_nil() => i64::MIN,
+            None => UNSEQUENCED_RANK,
+        }
+    }
+
+    /// Opaque token form: `<unix micros>_<uuid>`, or `<unix micros>_<seq>_<uuid>` with a `seq`.
+    pub fn encode(&self) -> String {
+        match self.seq {
+            Some(seq) => format!("{}_{}_{}", self.timestamp.timestamp_micros(), seq, self.id),
+            None => format!("{}_{}", self.timestamp.timestamp_micros(), self.id),
+        }
+    }
+
+    /// Accepts either an opaque token produced by [`HistoryCursor::encode`] or a bare RFC 3339
+    /// timestamp, which selects every fix strictly older than that instant.
+    pub fn parse(value: &str) -> Option<Self> {
+        if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+            return Some(Self {
+                timestamp: timestamp.with_timezone(&Utc),
+                seq: None,
+                id: Uuid::nil(),
+            });
+        }
+
+        let (micros, rest) = value.split_once('_')?;
+        let (seq, id) = match rest.split_once('_') {
+            Some((seq, id)) => (Some(seq.parse().ok()?), id),
+            None => (None, rest),
+        };
+        Some(Self {
+            timestamp: DateTime::from_timestamp_micros(micros.parse().ok()?)?,
+            seq,
+            id: Uuid::parse_str(id).ok()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct HistoryQuery {
+    pub limit: i64,
+    pub before: Option<HistoryCursor>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub include_total: bool,
+    /// Douglas-Peucker tolerance in meters applied to each page.
+    pub simplify: Option<f64>,
+}
+
+/// Parses an optional RFC 3339 query parameter, honouring any UTC offset it carries. An unescaped
+/// `+` in the offset arrives as a space after URL decoding, so it is restored before parsing.
+pub fn parse_timestamp_param(
+    params: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<DateTime<Utc>>, ValidationError> {
+    match params.get(name) {
+        Some(value) => DateTime::parse_from_rfc3339(&value.replace(' ', "+"))
+            .map(|timestamp| Some(timestamp.with_timezone(&Utc)))
+            .map_err(|_| {
+                ValidationError::new(
+                    "invalid_timestamp",
+                    format!("{} '{}' is not an RFC 3339 timestamp", name, value),
+                )
+            }),
+        None => Ok(None),
+    }
+}
+
+/// Rejects a time window longer than `max`, pointing the client at paging. A window without
+/// `from` is not bounded by the caller and passes; one without `to` runs until now.
+pub fn validate_time_span(
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    max: Duration,
+) -> Result<(), ValidationError> {
+    let Some(from) = from else {
+        return Ok(());
+    };
+    if to.unwrap_or_else(Utc::now) - from <= max {
+        return Ok(());
+    }
+
+    let limit = if max.num_hours() % 24 == 0 {
+        format!("{} days", max.num_days())
+    } else {
+        format!("{} hours", max.num_hours())
+    };
+    Err(ValidationError::new(
+        "window_too_large",
+        format!("time window must not exceed {}; request longer ranges in consecutive pages of at most that span", limit),
+    ))
+}
+
+impl HistoryQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let limit = params
+            .get("limit")
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(DEFAULT_HISTORY_LIMIT)
+            .clamp(1, MAX_HISTORY_LIMIT);
+
+        // `cursor` is what `next_cursor` tells clients to send; `before` is the original name.
+        let before = match params.get("cursor").or_else(|| params.get("before")) {
+            Some(value) => Some(HistoryCursor::parse(value).ok_or_else(|| {
+                ValidationError::new("invalid_cursor", format!("cursor '{}' is not valid", value))
+            })?),
+            None => None,
+        };
+
+        let from = parse_timestamp_param(params, "from")?;
+        let to = parse_timestamp_param(params, "to")?;
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err(ValidationError::new(
+                    "invalid_time_range",
+                    "from must not be later than to".to_string(),
+                ));
+            }
+        }
+
+        let include_total = parse_include_total(params)?;
+        let simplify = parse_simplify(params)?;
+
+        Ok(Self { limit, before, from, to, include_total, simplify })
+    }
+}
+
+impl IntoParams for HistoryQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        let [from, to] = params::window(false);
+        vec![
+            params::limit(DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT),
+            params::cursor("`next_cursor` of the previous page; `before` is accepted as an alias."),
+            from,
+            to,
+            params::include_total(),
+            params::simplify(),
+        ]
+    }
+}
+
+/// Time bounds of a full-history export; both are optional and inclusive.
+#[derive(Debug, Clone, Copy)]
+pub struct ExportQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Douglas-Peucker tolerance in meters applied to the exported track.
+    pub simplify: Option<f64>,
+}
+
+impl ExportQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let from = parse_timestamp_param(params, "from")?;
+        let to = parse_timestamp_param(params, "to")?;
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err(ValidationError::new(
+                    "invalid_time_range",
+                    "from must not be later than to".to_string(),
+                ));
+            }
+        }
+        let simplify = parse_simplify(params)?;
+
+        Ok(Self { from, to, simplify })
+    }
+}
+
+impl IntoParams for ExportQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        let [from, to] = params::window(false);
+        vec![from, to, params::simplify()]
+    }
+}
+
+/// Decimal places kept by encoded polylines unless asked otherwise, as in Google's encoder.
+pub const DEFAULT_POLYLINE_PRECISION: u32 = 5;
+
+/// Window of a user's track to encode as a polyline; bounds and `simplify` as for [`ExportQuery`].
+#[derive(Debug, Clone, Copy)]
+pub struct PolylineQuery {
+    pub export: ExportQuery,
+    /// 5 or 6 decimal places.
+    pub precision: u32,
+}
+
+impl PolylineQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let export = ExportQuery::from_params(params)?;
+        let precision = match params.get("precision").map(String::as_str) {
+            None => DEFAULT_POLYLINE_PRECISION,
+            Some("5") => 5,
+            Some("6") => 6,
+            Some(value) => {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    format!("precision '{}' must be 5 or 6", value),
+                ))
+            }
+        };
+
+        Ok(Self { export, precision })
+    }
+}
+
+impl IntoParams for PolylineQuery {
+    fn into_params(parameter_in: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        let mut parameters = ExportQuery::into_params(parameter_in);
+        parameters.push(params::query(
+            "precision",
+            "Decimal places kept per coordinate.",
+            params::one_of([5, 6]).default(Some(DEFAULT_POLYLINE_PRECISION.into())),
+        ));
+        parameters
+    }
+}
+
+/// A track in Google's encoded polyline format, oldest fix first.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct EncodedPolyline {
+    /// Empty when there are no fixes in the window.
+    pub polyline: String,
+    pub precision: u32,
+    /// Fixes encoded, after any simplification.
+    pub points: usize,
+}
+
+/// File format of an export download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Ndjson,
+    Gpx,
+}
+
+impl ExportFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Gpx => "gpx",
+        }
+    }
+
+    /// Last path segment of the export endpoint serving this format.
+    pub fn path_segment(self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "export",
+            ExportFormat::Gpx => "export.gpx",
+        }
+    }
+}
+
+/// Body of `POST /api/v1/location/{user_id}/export/sign`: the export a link is minted for.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SignExportRequest {
+    pub format: ExportFormat,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Douglas-Peucker tolerance in meters.
+    #[schema(exclusive_minimum = 0, maximum = 10000)]
+    pub simplify: Option<f64>,
+    /// Lifetime of the link; defaults to, and may not exceed, `EXPORT_URL_TTL_SECS`.
+    #[schema(minimum = 1)]
+    pub expires_in_secs: Option<u64>,
+}
+
+impl SignExportRequest {
+    pub fn validate(&self, max_expires_in_secs: u64) -> Result<(), ValidationError> {
+        if let (Some(from), Some(to)) = (self.from, self.to) {
+            if from > to {
+                return Err(ValidationError::new(
+                    "invalid_time_range",
+                    "from must not be later than to".to_string(),
+                ));
+            }
+        }
+        if let Some(epsilon) = self.simplify {
+            if !(epsilon > 0.0 && epsilon <= MAX_SIMPLIFY_TOLERANCE_METERS) {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    format!("simplify must be a tolerance in meters above 0 and at most {}", MAX_SIMPLIFY_TOLERANCE_METERS),
+                ));
+            }
+        }
+        if let Some(expires_in_secs) = self.expires_in_secs {
+            if expires_in_secs == 0 || expires_in_secs > max_expires_in_secs {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    format!("expires_in_secs must be between 1 and {}", max_expires_in_secs),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A link to an export that needs no JWT until it expires.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SignedExportUrl {
+    /// Path and query of the export on this service.
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+
+/// Presence of a user, from their last accepted report.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserStatus {
+    pub user_id: String,
+    pub last_seen: DateTime<Utc>,
+    pub online: bool,
+    pub battery: Option<f32>,
+    /// Absent when the user never sent a sequence number or it could not be checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<SequenceDiagnostics>,
+}
+
+/// How many sequence numbers this many below the latest are checked for gaps.
+pub const SEQUENCE_GAP_WINDOW: i64 = 1000;
+
+/// Gaps among the user's most recent sequence numbers, i.e. fixes sent but never received.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SequenceDiagnostics {
+    pub last_seq: i64,
+    /// Lowest sequence number considered, a fixed window below `last_seq`.
+    pub window_start_seq: i64,
+    /// Sequence numbers never received between `window_start_seq` and `last_seq`.
+    pub missing_seq: i64,
+}
+
+pub const DEFAULT_TRACKED_USER_LIMIT: i64 = 100;
+pub const MAX_TRACKED_USER_LIMIT: i64 = 1000;
+
+/// Order of a tracked-user listing by last report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LastSeenOrder {
+    /// `sort=-last_seen`, the default.
+    NewestFirst,
+    /// `sort=last_seen`.
+    OldestFirst,
+}
+
+/// Every user of the tenant that has reported, for the admin overview.
+#[derive(Debug)]
+pub struct TrackedUsersQuery {
+    pub online_only: bool,
+    pub order: LastSeenOrder,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl TrackedUsersQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let online_only = match params.get("online_only").map(String::as_str) {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(value) => {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    format!("online_only '{}' must be true or false", value),
+                ))
+            }
+        };
+        let order = match params.get("sort").map(String::as_str) {
+            None | Some("-last_seen") => LastSeenOrder::NewestFirst,
+            Some("last_seen") => LastSeenOrder::OldestFirst,
+            Some(value) => {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    format!("sort '{}' must be last_seen or -last_seen", value),
+                ))
+            }
+        };
+        let limit = params
+            .get("limit")
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(DEFAULT_TRACKED_USER_LIMIT)
+            .clamp(1, MAX_TRACKED_USER_LIMIT);
+        // The cursor handed out in `next_cursor` is the offset of the next page.
+        let offset = match params.get("cursor") {
+            Some(value) => value.parse::<i64>().ok().filter(|offset| *offset >= 0).ok_or_else(|| {
+                ValidationError::new("invalid_cursor", format!("cursor '{}' is not valid", value))
+            })?,
+            None => params
+                .get("offset")
+                .and_then(|value| value.parse::<i64>().ok())
+                .unwrap_or(0)
+                .max(0),
+        };
+
+        Ok(Self { online_only, order, limit, offset })
+    }
+}
+
+impl IntoParams for TrackedUsersQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![
+            params::query("online_only", "Only users online now.", params::flag()),
+            params::query(
+                "sort",
+                "`-last_seen` for the most recent reports first, `last_seen` for the oldest first.",
+                params::one_of(["-last_seen", "last_seen"]).default(Some("-last_seen".into())),
+            ),
+            params::limit(DEFAULT_TRACKED_USER_LIMIT, MAX_TRACKED_USER_LIMIT),
+            params::cursor("`next_cursor` of the previous page; `offset` is accepted instead."),
+        ]
+    }
+}
+
+/// A user in the admin overview: presence as in [`UserStatus`] plus their latest fix.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TrackedUser {
+    pub user_id: String,
+    pub last_seen: DateTime<Utc>,
+    pub online: bool,
+    pub battery: Option<f32>,
+    /// `None` when no fix of the user is stored any more.
+    pub last_location: Option<Location>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PresenceTransition {
+    /// Reported again after being offline, or for the first time.
+    Online,
+    /// Silent for longer than the staleness window.
+    Offline,
+}
+
+impl PresenceTransition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PresenceTransition::Online => "ONLINE",
+            PresenceTransition::Offline => "OFFLINE",
+        }
+    }
+}
+
+impl TryFrom<String> for PresenceTransition {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "ONLINE" => Ok(PresenceTransition::Online),
+            "OFFLINE" => Ok(PresenceTransition::Offline),
+            _ => Err(format!("unknown presence transition '{}'", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow, ToSchema)]
+pub struct PresenceEvent {
+    pub id: Uuid,
+    pub tenant_id: String,
+    pub user_id: String,
+    #[sqlx(try_from = "String")]
+    pub event_type: PresenceTransition,
+    /// Time of the user's last report when the transition was detected.
+    pub last_seen: DateTime<Utc>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+pub const DEFAULT_PRESENCE_EVENT_LIMIT: i64 = 100;
+pub const MAX_PRESENCE_EVENT_LIMIT: i64 = 1000;
+
+/// Presence events newest first, optionally for one user.
+#[derive(Debug)]
+pub struct PresenceQuery {
+    pub user_id: Option<String>,
+    pub limit: i64,
+    pub before: Option<HistoryCursor>,
+}
+
+impl PresenceQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let user_id = params.get("user_id").map(|value| value.trim()).filter(|value| !value.is_empty());
+        let limit = params
+            .get("limit")
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(DEFAULT_PRESENCE_EVENT_LIMIT)
+            .clamp(1, MAX_PRESENCE_EVENT_LIMIT);
+        let before = match params.get("cursor") {
+            Some(value) => Some(HistoryCursor::parse(value).ok_or_else(|| {
+                ValidationError::new("invalid_cursor", format!("cursor '{}' is not valid", value))
+            })?),
+            None => None,
+        };
+
+        Ok(Self { user_id: user_id.map(str::to_string), limit, before })
+    }
+}
+
+impl IntoParams for PresenceQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![
+            params::query("user_id", "Only this user's transitions.", params::string()),
+            params::limit(DEFAULT_PRESENCE_EVENT_LIMIT, MAX_PRESENCE_EVENT_LIMIT),
+            params::cursor("`next_cursor` of the previous page."),
+        ]
+    }
+}
+
+/// Rows and cache entries removed by a user data erasure. All zero when there was nothing left
+/// to delete.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErasureResult {
+    pub user_id: String,
+    pub locations: u64,
+    pub rejected_locations: u64,
+    pub daily_stats: u64,
+    pub geofence_events: u64,
+    pub presence_events: u64,
+    pub cache_keys: u64,
+}
+
+/// What a GPX import does with track points that have no `<time>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingTimePolicy {
+    /// Skip them and count them as skipped.
+    Skip,
+    /// Spread them between the timed points before and after them, in proportion to the distance
+    /// travelled. Those before the first or after the last timed point are still skipped.
+    Interpolate,
+}
+
+impl FromStr for MissingTimePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(MissingTimePolicy::Skip),
+            "interpolate" => Ok(MissingTimePolicy::Interpolate),
+            _ => Err("expected 'skip' or 'interpolate'".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GpxImportQuery {
+    pub missing_time: MissingTimePolicy,
+}
+
+impl GpxImportQuery {
+    /// `missing_time` falls back to the configured policy.
+    pub fn from_params(params: &HashMap<String, String>, default: MissingTimePolicy) -> Result<Self, ValidationError> {
+        let missing_time = match params.get("missing_time") {
+            Some(value) => value.parse().map_err(|e| {
+                ValidationError::new("invalid_parameter", format!("missing_time '{}': {}", value, e))
+            })?,
+            None => default,
+        };
+        Ok(Self { missing_time })
+    }
+}
+
+impl IntoParams for GpxImportQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![params::query(
+            "missing_time",
+            "What to do with points without `<time>`: `skip` them, or `interpolate` times from the timed points \
+             around them. Defaults to `GPX_IMPORT_MISSING_TIME`.",
+            params::one_of(["skip", "interpolate"]),
+        )]
+    }
+}
+
+/// Outcome of a GPX import. `interpolated` points are among the `imported`; `skipped` ones had
+/// no usable position, a time that doesn't parse or lies in the future, or no time at all
+/// (`skipped_missing_time`).
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct GpxImportResult {
+    pub user_id: String,
+    pub imported: u64,
+    /// Imported points whose time was interpolated.
+    pub interpolated: u64,
+    /// Points without a usable position or time.
+    pub skipped: u64,
+    /// Skipped points that had no time at all.
+    pub skipped_missing_time: u64,
+}
+
+pub const DEFAULT_ANALYTICS_WINDOW_HOURS: i64 = 24;
+
+/// Window of a user's fixes to summarise. `to` defaults to now and `from` to
+/// [`DEFAULT_ANALYTICS_WINDOW_HOURS`] before `to`; both bounds are inclusive.
+#[derive(Debug)]
+pub struct AnalyticsQuery {
+    pub user_id: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl AnalyticsQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let user_id = match params.get("user_id").map(|value| value.trim()) {
+            Some(user_id) if !user_id.is_empty() => user_id.to_string(),
+            _ => {
+                return Err(ValidationError::new(
+                    "missing_user_id",
+                    "user_id query parameter is required".to_string(),
+                ));
+            }
+        };
+
+        let to = parse_timestamp_param(params, "to")?.unwrap_or_else(Utc::now);
+        let from = parse_timestamp_param(params, "from")?
+            .unwrap_or_else(|| to - Duration::hours(DEFAULT_ANALYTICS_WINDOW_HOURS));
+        if from > to {
+            return Err(ValidationError::new(
+                "invalid_time_range",
+                "from must not be later than to".to_string(),
+            ));
+        }
+
+        Ok(Self { user_id, from, to })
+    }
+}
+
+impl IntoParams for AnalyticsQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        let [from, to] = params::window(true);
+        vec![
+            params::required(
+                "user_id",
+                "User whose fixes are analysed; another user's needs an admin token.",
+                params::string(),
+            ),
+            from,
+            to,
+        ]
+    }
+}
+
+/// Fixes beyond this many are left out of a map-matching request.
+pub const MAX_MATCH_POINTS: i64 = 10_000;
+
+/// Window of a user's track to snap to roads; defaults as for [`AnalyticsQuery`].
+#[derive(Debug, Clone, Copy)]
+pub struct MatchQuery {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl MatchQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let to = parse_timestamp_param(params, "to")?.unwrap_or_else(Utc::now);
+        let from = parse_timestamp_param(params, "from")?
+            .unwrap_or_else(|| to - Duration::hours(DEFAULT_ANALYTICS_WINDOW_HOURS));
+        if from > to {
+            return Err(ValidationError::new(
+                "invalid_time_range",
+                "from must not be later than to".to_string(),
+            ));
+        }
+
+        Ok(Self { from, to })
+    }
+}
+
+impl IntoParams for MatchQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        params::window(true).into()
+    }
+}
+
+/// Which fix answers a point-in-time lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocationAtMode {
+    /// Closest in time on either side; the earlier one on a tie.
+    Nearest,
+    /// Last at or before the instant.
+    Before,
+}
+
+#[derive(Debug)]
+pub struct LocationAtQuery {
+    pub timestamp: DateTime<Utc>,
+    pub mode: LocationAtMode,
+    /// Answer 404 rather than an uncertain fix when the gap exceeds the configured maximum.
+    pub strict: bool,
+    /// Estimate the position between the fixes either side of the instant instead of picking one.
+    pub interpolate: bool,
+}
+
+impl LocationAtQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let timestamp = parse_timestamp_param(params, "timestamp")?.ok_or_else(|| {
+            ValidationError::new("missing_parameter", "timestamp query parameter is required".to_string())
+        })?;
+        let mode = match params.get("mode").map(String::as_str) {
+            None | Some("nearest") => LocationAtMode::Nearest,
+            Some("before") => LocationAtMode::Before,
+            Some(value) => {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    format!("mode '{}' must be nearest or before", value),
+                ))
+            }
+        };
+        let flag = |name: &str| match params.get(name).map(String::as_str) {
+            None | Some("false") => Ok(false),
+            Some("true") => Ok(true),
+            Some(value) => Err(ValidationError::new(
+                "invalid_parameter",
+                format!("{} '{}' must be true or false", name, value),
+            )),
+        };
+        let strict = flag("strict")?;
+        let interpolate = flag("interpolate")?;
+        if interpolate && mode == LocationAtMode::Before {
+            return Err(ValidationError::new(
+                "invalid_parameter",
+                "interpolate needs the fixes on both sides and cannot be combined with mode=before".to_string(),
+            ));
+        }
+
+        Ok(Self { timestamp, mode, strict, interpolate })
+    }
+}
+
+impl IntoParams for LocationAtQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![
+            params::required("timestamp", "The instant, RFC 3339.", params::timestamp()),
+            params::query(
+                "mode",
+                "`nearest` fix on either side, or the last one `before`.",
+                params::one_of(["nearest", "before"]).default(Some("nearest".into())),
+            ),
+            params::query("strict", "Answer 404 `no_location_near` instead of an uncertain fix.", params::flag()),
+            params::query(
+                "interpolate",
+                "Estimate the position between the fixes either side of the instant, along the great circle \
+                 joining them. Not allowed with `mode=before`.",
+                params::flag(),
+            ),
+        ]
+    }
+}
+
+/// The fix answering a point-in-time lookup and how far it is from the requested instant.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LocationAt {
+    pub requested_at: DateTime<Utc>,
+    pub location: Location,
+    /// Absolute distance in seconds between `requested_at` and the fix.
+    pub gap_secs: f64,
+    /// Whether the gap exceeds the configured maximum, so the user may well have been elsewhere.
+    pub uncertain: bool,
+    /// Whether `location` was estimated between two fixes, with a nil id; `gap_secs` is then to
+    /// the closer one.
+    pub interpolated: bool,
+}
+
+/// Playback rate bounds of a track replay; 1 is real time.
+pub const MIN_REPLAY_SPEED: f64 = 0.1;
+pub const MAX_REPLAY_SPEED: f64 = 1000.0;
+
+/// Window of a user's track to replay over `/ws/replay/{user_id}`; `from` and `to` default as for
+/// [`AnalyticsQuery`] and `speed` to real time.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayQuery {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub speed: f64,
+}
+
+impl ReplayQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let MatchQuery { from, to } = MatchQuery::from_params(params)?;
+        let speed = match params.get("speed") {
+            Some(value) => value
+                .parse::<f64>()
+                .ok()
+                .filter(|speed| (MIN_REPLAY_SPEED..=MAX_REPLAY_SPEED).contains(speed))
+                .ok_or_else(|| {
+                    ValidationError::new(
+                        "invalid_speed",
+                        format!("speed '{}' must be between {} and {}", value, MIN_REPLAY_SPEED, MAX_REPLAY_SPEED),
+                    )
+                })?,
+            None => 1.0,
+        };
+
+        Ok(Self { from, to, speed })
+    }
+}
+
+impl IntoParams for ReplayQuery {
+    fn into_params(parameter_in: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        let mut parameters = MatchQuery::into_params(parameter_in);
+        parameters.push(params::query(
+            "speed",
+            "Playback rate; 1 is real time.",
+            params::between(MIN_REPLAY_SPEED, MAX_REPLAY_SPEED).default(Some(1.into())),
+        ));
+        parameters
+    }
+}
+
+pub const MIN_STREAM_RATE_MS: u64 = 100;
+pub const MAX_STREAM_RATE_MS: u64 = 60_000;
+
+/// Options of `/ws/tracking/{user_id}`. With `rate`, e.g. `1s` or `250ms`, fixes are coalesced so
+/// at most one, the latest, is sent per interval; without it every fix is sent.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrackingStreamQuery {
+    pub rate: Option<std::time::Duration>,
+}
+
+impl TrackingStreamQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let Some(value) = params.get("rate") else {
+            return Ok(Self::default());
+        };
+        let (number, unit_ms) = match value.strip_suffix("ms") {
+            Some(number) => (number, 1.0),
+            None => (value.strip_suffix('s').unwrap_or(value), 1000.0),
+        };
+        let rate_ms = number
+            .parse::<f64>()
+            .ok()
+            .map(|number| number * unit_ms)
+            .filter(|ms| (MIN_STREAM_RATE_MS as f64..=MAX_STREAM_RATE_MS as f64).contains(ms))
+            .ok_or_else(|| {
+                ValidationError::new(
+                    "invalid_rate",
+                    format!(
+                        "rate '{}' must be a duration like 1s or 250ms, between {}ms and {}ms",
+                        value, MIN_STREAM_RATE_MS, MAX_STREAM_RATE_MS
+                    ),
+                )
+            })?;
+
+        Ok(Self { rate: Some(std::time::Duration::from_secs_f64(rate_ms / 1000.0)) })
+    }
+}
+
+impl IntoParams for TrackingStreamQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![params::query(
+            "rate",
+            &format!(
+                "Send at most one fix, the latest, per interval, e.g. `1s` or `250ms`, between {}ms and {}ms; a \
+                 held fix goes out as soon as the interval is up. Every fix is sent when absent.",
+                MIN_STREAM_RATE_MS, MAX_STREAM_RATE_MS
+            ),
+            params::string().pattern(Some("^[0-9.]+(ms|s)?$")),
+        )]
+    }
+}
+
+/// A fix as placed by map matching, next to where it was recorded.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MatchedPoint {
+    pub timestamp: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub raw_latitude: f64,
+    pub raw_longitude: f64,
+    /// Confidence of the matching the fix belongs to, from 0 to 1; 0 for fixes the backend
+    /// discarded and `None` when the raw track is returned.
+    #[schema(minimum = 0, maximum = 1)]
+    pub confidence: Option<f64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MatchedTrack {
+    pub user_id: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// `false` when the raw track is returned because no backend is configured or it failed.
+    pub matched: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_reason: Option<String>,
+    /// Set when the window held more fixes than are sent for matching and only the oldest were used.
+    pub truncated: bool,
+    /// Road geometry as GeoJSON `[lon, lat]` positions.
+    pub geometry: Vec<[f64; 2]>,
+    pub points: Vec<MatchedPoint>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AnalyticsSummary {
+    pub user_id: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// Sum of distances between consecutive fixes on the same UTC day.
+    pub total_distance_meters: f64,
+    /// Mean and maximum of the speeds reported by the device, in m/s.
+    pub average_speed: Option<f64>,
+    pub max_speed: Option<f64>,
+    pub point_count: i64,
+    /// Time between the first and the last fix in the window.
+    pub active_duration_secs: i64,
+    /// Figures over the last rolling window up to now, regardless of `from` and `to`; `None`
+    /// when neither Redis nor `rolling_stats` could be read.
+    pub rolling: Option<RollingStats>,
+}
+
+/// A user's fixes stored during the last `window_secs`, kept as running totals per
+/// `bucket_secs` so they can be read without scanning raw fixes.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RollingStats {
+    pub window_secs: u64,
+    pub bucket_secs: u64,
+    /// Sum of distances between consecutive fixes.
+    pub distance_meters: f64,
+    /// Mean and maximum speed in m/s: the reported one, or else the one implied by the distance
+    /// from the previous fix.
+    pub average_speed: Option<f64>,
+    pub max_speed: Option<f64>,
+    pub point_count: i64,
+}
+
+/// A period during which a user stayed within the configured stop radius.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Stop {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub duration_secs: i64,
+    pub point_count: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StopsResult {
+    pub user_id: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub stops: Vec<Stop>,
+}
+
+/// Continuous movement between two stops, or between a stop and a gap in the fixes.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Trip {
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub start_latitude: f64,
+    pub start_longitude: f64,
+    pub end_latitude: f64,
+    pub end_longitude: f64,
+    pub distance_meters: f64,
+    pub duration_secs: i64,
+    /// Distance over duration, in m/s.
+    pub average_speed: Option<f64>,
+    pub point_count: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TripsResult {
+    pub user_id: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub trips: Vec<Trip>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DistanceResult {
+    pub user_id: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// Whether the track was Kalman-smoothed before measuring.
+    pub smoothed: bool,
+    pub distance_meters: f64,
+    pub point_count: usize,
+}
+
+/// A point given as `lat` and `lon` query parameters, both required.
+#[derive(Debug, Clone, Copy)]
+pub struct PointQuery {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl PointQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let number = |name: &str| -> Result<f64, ValidationError> {
+            let value = params.get(name).ok_or_else(|| {
+                ValidationError::new("missing_parameter", format!("{} query parameter is required", name))
+            })?;
+            value.parse::<f64>().ok().filter(|v| v.is_finite()).ok_or_else(|| {
+                ValidationError::new("invalid_parameter", format!("{} '{}' is not a number", name, value))
+            })
+        };
+
+        let latitude = number("lat")?;
+        let longitude = number("lon")?;
+        validate_coordinates(latitude, longitude)?;
+        Ok(Self { latitude, longitude })
+    }
+}
+
+impl IntoParams for PointQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![params::latitude("Latitude of the point."), params::longitude("Longitude of the point.")]
+    }
+}
+
+pub const DEFAULT_NEARBY_LIMIT: usize = 50;
+pub const MAX_NEARBY_LIMIT: usize = 500;
+pub const MAX_NEARBY_RADIUS_METERS: f64 = 50_000.0;
+
+#[derive(Debug)]
+pub struct NearbyQuery {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_meters: f64,
+    pub limit: usize,
+}
+
+impl NearbyQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let number = |name: &str| -> Result<f64, ValidationError> {
+            let value = params.get(name).ok_or_else(|| {
+                ValidationError::new("missing_parameter", format!("{} query parameter is required", name))
+            })?;
+            value.parse::<f64>().ok().filter(|v| v.is_finite()).ok_or_else(|| {
+                ValidationError::new("invalid_parameter", format!("{} '{}' is not a number", name, value))
+            })
+        };
+
+        let latitude = number("lat")?;
+        let longitude = number("lon")?;
+        validate_coordinates(latitude, longitude)?;
+
+        let radius_meters = number("radius")?;
+        if radius_meters <= 0.0 || radius_meters > MAX_NEARBY_RADIUS_METERS {
+            return Err(ValidationError::new(
+                "invalid_radius",
+                format!("radius must be greater than 0 and at most {} meters", MAX_NEARBY_RADIUS_METERS),
+            ));
+        }
+
+        let limit = params
+            .get("limit")
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_NEARBY_LIMIT)
+            .clamp(1, MAX_NEARBY_LIMIT);
+
+        Ok(Self { latitude, longitude, radius_meters, limit })
+    }
+}
+
+impl IntoParams for NearbyQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![
+            params::latitude("Center latitude."),
+            params::longitude("Center longitude."),
+            params::required("radius", "Radius in meters.", params::positive(MAX_NEARBY_RADIUS_METERS)),
+            params::query(
+                "limit",
+                "Most users returned; clamped.",
+                params::integer(1, MAX_NEARBY_LIMIT).default(Some(DEFAULT_NEARBY_LIMIT.into())),
+            ),
+        ]
+    }
+}
+
+pub const DEFAULT_HEATMAP_PRECISION: usize = 6;
+/// Cells about as wide as those of [`DEFAULT_HEATMAP_PRECISION`].
+pub const DEFAULT_HEATMAP_RESOLUTION: u8 = 7;
+pub const MAX_HEATMAP_CELLS: usize = 5000;
+
+/// Cells that heatmaps and clusters aggregate by, chosen with `indexing=geohash|h3`. Serialized
+/// as `"indexing"` plus the cell size, e.g. `{"indexing": "h3", "resolution": 7}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(tag = "indexing", rename_all = "lowercase")]
+pub enum Grid {
+    /// Geohash cells of `precision` characters; the default.
+    Geohash { precision: usize },
+    /// H3 hexagons at `resolution`, from 0 to 15.
+    H3 { resolution: u8 },
+}
+
+/// Id of a cell of a [`Grid`], serialized as `"geohash"` or `"h3"` (the cell's hex index).
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CellId {
+    Geohash(String),
+    H3(String),
+}
+
+/// Reads `indexing`; absent means geohash, so that existing callers are unaffected.
+fn parse_h3_indexing(params: &HashMap<String, String>) -> Result<bool, ValidationError> {
+    match params.get("indexing").map(String::as_str) {
+        None | Some("geohash") => Ok(false),
+        Some("h3") => Ok(true),
+        Some(value) => Err(ValidationError::new(
+            "invalid_parameter",
+            format!("indexing '{}' must be geohash or h3", value),
+        )),
+    }
+}
+
+/// `minLon,minLat,maxLon,maxLat`. Boxes crossing the antimeridian are not supported.
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingBox {
+    pub min_longitude: f64,
+    pub min_latitude: f64,
+    pub max_longitude: f64,
+    pub max_latitude: f64,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        (self.min_latitude..=self.max_latitude).contains(&latitude)
+            && (self.min_longitude..=self.max_longitude).contains(&longitude)
+    }
+
+    /// Extent of a polygon's outer ring; holes lie within it.
+    pub fn of_polygon(rings: &[Vec<[f64; 2]>]) -> Option<Self> {
+        let outer = rings.first()?;
+        let mut bbox = BoundingBox {
+            min_longitude: f64::INFINITY,
+            min_latitude: f64::INFINITY,
+            max_longitude: f64::NEG_INFINITY,
+            max_latitude: f64::NEG_INFINITY,
+        };
+        for &[lon, lat] in outer {
+            bbox.min_longitude = bbox.min_longitude.min(lon);
+            bbox.min_latitude = bbox.min_latitude.min(lat);
+            bbox.max_longitude = bbox.max_longitude.max(lon);
+            bbox.max_latitude = bbox.max_latitude.max(lat);
+        }
+        Some(bbox)
+    }
+
+    /// A box around a circle, `None` when the circle reaches a pole or the antimeridian.
+    pub fn of_circle(latitude: f64, longitude: f64, radius_meters: f64) -> Option<Self> {
+        // Slightly generous so rounding never rejects a point on the boundary.
+        let lat_delta = radius_meters / EARTH_RADIUS_METERS.to_radians() * 1.01;
+        let (min_latitude, max_latitude) = (latitude - lat_delta, latitude + lat_delta);
+        if min_latitude <= -90.0 || max_latitude >= 90.0 {
+            return None;
+        }
+        let widest_cos = min_latitude.to_radians().cos().min(max_latitude.to_radians().cos());
+        let lon_delta = lat_delta / widest_cos;
+        let (min_longitude, max_longitude) = (longitude - lon_delta, longitude + lon_delta);
+        if min_longitude < -180.0 || max_longitude > 180.0 {
+            return None;
+        }
+        Some(BoundingBox { min_longitude, min_latitude, max_longitude, max_latitude })
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || {
+            ValidationError::new(
+                "invalid_bbox",
+                format!("bbox '{}' must be minLon,minLat,maxLon,maxLat", s),
+            )
+        };
+        let values = s
+            .split(',')
+            .map(|part| part.trim().parse::<f64>().ok().filter(|v| v.is_finite()))
+            .collect::<Option<Vec<f64>>>()
+            .ok_or_else(malformed)?;
+        let [min_longitude, min_latitude, max_longitude, max_latitude] = values[..] else {
+            return Err(malformed());
+        };
+
+        validate_coordinates(min_latitude, min_longitude)?;
+        validate_coordinates(max_latitude, max_longitude)?;
+        if min_longitude > max_longitude || min_latitude > max_latitude {
+            return Err(ValidationError::new(
+                "invalid_bbox",
+                "bbox minimums must not exceed its maximums".to_string(),
+            ));
+        }
+
+        Ok(Self { min_longitude, min_latitude, max_longitude, max_latitude })
+    }
+}
+
+#[derive(Debug)]
+pub struct HeatmapQuery {
+    pub bbox: BoundingBox,
+    /// Geohash `precision` (1..=[`geohash::MAX_PRECISION`]) or H3 `resolution` of each cell.
+    pub grid: Grid,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl HeatmapQuery {
+    /// `from` and `to` default the same way as for [`AnalyticsQuery`].
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let bbox = params
+            .get("bbox")
+            .ok_or_else(|| ValidationError::new("missing_parameter", "bbox query parameter is required".to_string()))?
+            .parse()?;
+
+        let grid = if parse_h3_indexing(params)? {
+            if params.contains_key("precision") {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    "precision applies to geohash indexing; use resolution with indexing=h3".to_string(),
+                ));
+            }
+            let resolution = match params.get("resolution") {
+                Some(value) => value
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|resolution| *resolution <= h3::MAX_RESOLUTION)
+                    .ok_or_else(|| {
+                        ValidationError::new(
+                            "invalid_resolution",
+                            format!("resolution '{}' must be between 0 and {}", value, h3::MAX_RESOLUTION),
+                        )
+                    })?,
+                None => DEFAULT_HEATMAP_RESOLUTION,
+            };
+            Grid::H3 { resolution }
+        } else {
+            if params.contains_key("resolution") {
+                return Err(ValidationError::new(
+                    "invalid_parameter",
+                    "resolution applies to indexing=h3".to_string(),
+                ));
+            }
+            let precision = match params.get("precision") {
+                Some(value) => value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|precision| (1..=geohash::MAX_PRECISION).contains(precision))
+                    .ok_or_else(|| {
+                        ValidationError::new(
+                            "invalid_precision",
+                            format!("precision '{}' must be between 1 and {}", value, geohash::MAX_PRECISION),
+                        )
+                    })?,
+                None => DEFAULT_HEATMAP_PRECISION,
+            };
+            Grid::Geohash { precision }
+        };
+
+        let to = parse_timestamp_param(params, "to")?.unwrap_or_else(Utc::now);
+        let from = parse_timestamp_param(params, "from")?
+            .unwrap_or_else(|| to - Duration::hours(DEFAULT_ANALYTICS_WINDOW_HOURS));
+        if from > to {
+            return Err(ValidationError::new(
+                "invalid_time_range",
+                "from must not be later than to".to_string(),
+            ));
+        }
+
+        Ok(Self { bbox, grid, from, to })
+    }
+}
+
+impl IntoParams for HeatmapQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        let [from, to] = params::window(true);
+        vec![
+            params::bbox(),
+            params::indexing(),
+            params::query(
+                "precision",
+                "Geohash length of each cell; geohash indexing only.",
+                params::integer(1, geohash::MAX_PRECISION).default(Some(DEFAULT_HEATMAP_PRECISION.into())),
+            ),
+            params::query(
+                "resolution",
+                "H3 resolution of each cell; h3 indexing only.",
+                params::integer(0, h3::MAX_RESOLUTION).default(Some(DEFAULT_HEATMAP_RESOLUTION.into())),
+            ),
+            from,
+            to,
+        ]
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HeatmapCell {
+    #[serde(flatten)]
+    pub cell: CellId,
+    /// Center of the cell.
+    pub latitude: f64,
+    pub longitude: f64,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HeatmapResult {
+    #[serde(flatten)]
+    pub grid: Grid,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// Densest first.
+    pub cells: Vec<HeatmapCell>,
+    /// Whether the sparsest cells were left out to bound the response.
+    pub truncated: bool,
+}
+
+pub const DEFAULT_ACTIVE_USERS_WINDOW_MINUTES: u32 = 5;
+pub const MAX_ACTIVE_USERS_WINDOW_MINUTES: u32 = 60;
+
+#[derive(Debug)]
+pub struct ActiveUsersQuery {
+    /// Clamped to 1..=[`MAX_ACTIVE_USERS_WINDOW_MINUTES`].
+    pub window_minutes: u32,
+}
+
+impl ActiveUsersQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let window_minutes = match params.get("window_minutes") {
+            Some(value) => value.parse::<u32>().map_err(|_| {
+                ValidationError::new("invalid_parameter", format!("window_minutes '{}' is not a whole number", value))
+            })?,
+            None => DEFAULT_ACTIVE_USERS_WINDOW_MINUTES,
+        };
+        Ok(Self { window_minutes: window_minutes.clamp(1, MAX_ACTIVE_USERS_WINDOW_MINUTES) })
+    }
+}
+
+impl IntoParams for ActiveUsersQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![params::query(
+            "window_minutes",
+            "Look-back window; clamped.",
+            params::integer(1, MAX_ACTIVE_USERS_WINDOW_MINUTES).default(Some(DEFAULT_ACTIVE_USERS_WINDOW_MINUTES.into())),
+        )]
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ActiveUsersResult {
+    pub window_minutes: u32,
+    pub active_users: u64,
+}
+
+/// A tenant's billing month: `month` is `YYYY-MM` in UTC and defaults to the current one.
+#[derive(Debug)]
+pub struct UsageQuery {
+    pub tenant_id: Option<String>,
+    /// First day of the month.
+    pub month: NaiveDate,
+}
+
+impl UsageQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let tenant_id = match params.get("tenant_id").map(|value| value.trim()).filter(|value| !value.is_empty()) {
+            Some(tenant_id) if !is_valid_tenant_id(tenant_id) => {
+                return Err(ValidationError::new("invalid_parameter", format!("tenant_id '{}' is not valid", tenant_id)));
+            }
+            tenant_id => tenant_id.map(str::to_string),
+        };
+        let month = match params.get("month") {
+            Some(value) => NaiveDate::parse_from_str(&format!("{}-01", value), "%Y-%m-%d").map_err(|_| {
+                ValidationError::new("invalid_parameter", format!("month '{}' is not a YYYY-MM month", value))
+            })?,
+            None => Utc::now().date_naive().with_day(1).unwrap_or_default(),
+        };
+        Ok(Self { tenant_id, month })
+    }
+}
+
+impl IntoParams for UsageQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![
+            params::query("tenant_id", "Tenant to report; defaults to the token's.", params::string()),
+            params::query(
+                "month",
+                "Month as `YYYY-MM`; defaults to the current one.",
+                params::string().pattern(Some("^[0-9]{4}-[0-9]{2}$")),
+            ),
+        ]
+    }
+}
+
+/// Fixes a tenant stored during a month; rejected and duplicate fixes are not counted.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Usage {
+    pub tenant_id: String,
+    /// `YYYY-MM`, UTC.
+    pub month: String,
+    pub fixes: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NearbyLocation {
+    #[serde(flatten)]
+    pub location: Location,
+    pub distance_meters: f64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NearbyResult {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub radius_meters: f64,
+    pub users: Vec<NearbyLocation>,
+}
+
+pub const MAX_CLUSTER_ZOOM: u8 = 22;
+/// Most cells, clusters and single points together, returned by one clustering query.
+pub const MAX_CLUSTERS: usize = 2000;
+
+/// Geohash length used to cluster at a web-map zoom level, so that a 256 px tile spans a few
+/// cells at every zoom:
+///
+/// | zoom  | precision | cell size    |
+/// |-------|-----------|--------------|
+/// | 0–1   | 1         | ~5000 km     |
+/// | 2–4   | 2         | ~1250 km     |
+/// | 5–6   | 3         | ~156 km      |
+/// | 7–9   | 4         | ~39 km       |
+/// | 10–11 | 5         | ~4.9 km      |
+/// | 12–14 | 6         | ~1.2 km      |
+/// | 15–16 | 7         | ~153 m       |
+/// | 17+   | 8         | ~38 m        |
+pub fn cluster_precision(zoom: u8) -> usize {
+    match zoom {
+        0..=1 => 1,
+        2..=4 => 2,
+        5..=6 => 3,
+        7..=9 => 4,
+        10..=11 => 5,
+        12..=14 => 6,
+        15..=16 => 7,
+        _ => 8,
+    }
+}
+
+#[derive(Debug)]
+pub struct ClusterQuery {
+    pub bbox: BoundingBox,
+    /// 0..=[`MAX_CLUSTER_ZOOM`].
+    pub zoom: u8,
+    /// Cells of the zoom's [`cluster_precision`], or of its [`h3::resolution_for_zoom`] with
+    /// `indexing=h3`.
+    pub grid: Grid,
+}
+
+impl ClusterQuery {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ValidationError> {
+        let bbox = params
+            .get("bbox")
+            .ok_or_else(|| ValidationError::new("missing_parameter", "bbox query parameter is required".to_string()))?
+            .parse()?;
+        let value = params
+            .get("zoom")
+            .ok_or_else(|| ValidationError::new("missing_parameter", "zoom query parameter is required".to_string()))?;
+        let zoom = value
+            .parse::<u8>()
+            .ok()
+            .filter(|zoom| *zoom <= MAX_CLUSTER_ZOOM)
+            .ok_or_else(|| {
+                ValidationError::new("invalid_zoom", format!("zoom '{}' must be between 0 and {}", value, MAX_CLUSTER_ZOOM))
+            })?;
+
+        let grid = if parse_h3_indexing(params)? {
+            Grid::H3 { resolution: h3::resolution_for_zoom(zoom) }
+        } else {
+            Grid::Geohash { precision: cluster_precision(zoom) }
+        };
+
+        Ok(Self { bbox, zoom, grid })
+    }
+}
+
+impl IntoParams for ClusterQuery {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![
+            params::bbox(),
+            params::required(
+                "zoom",
+                "Web-map zoom level. Cells are geohashes of length 1 at zoom 0–1, 2 at 2–4, 3 at 5–6, 4 at 7–9, \
+                 5 at 10–11, 6 at 12–14, 7 at 15–16 and 8 above; with `indexing=h3`, H3 cells of resolution \
+                 0, 1, 3, 4, 6, 7, 9 and 10 respectively.",
+                params::integer(0, MAX_CLUSTER_ZOOM),
+            ),
+            params::indexing(),
+        ]
+    }
+}
+
+/// Two or more users whose current fixes share a cell.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LocationCluster {
+    #[serde(flatten)]
+    pub cell: CellId,
+    /// Centroid of the users' fixes for geohash cells; the center of the cell for H3 ones.
+    pub latitude: f64,
+    pub longitude: f64,
+    pub count: i64,
+}
+
+/// The only user in its cell, placed at their own fix.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClusterPoint {
+    pub user_id: String,
+    pub latitude: f64,
+    pub longitude: f64,
     pub timestamp: DateTime<Utc>,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClusterResult {
+    pub zoom: u8,
+    #[serde(flatten)]
+    pub grid: Grid,
+    /// Largest first.
+    pub clusters: Vec<LocationCluster>,
+    pub points: Vec<ClusterPoint>,
+    /// Whether cells were left out to bound the response; the smallest go first.
+    pub truncated: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: (f64, f64), expected: (f64, f64)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn coordinate_precision_parses_full_decimals_and_grid_sizes() {
+        assert_eq!("full".parse(), Ok(CoordinatePrecision::Full));
+        assert_eq!("0".parse(), Ok(CoordinatePrecision::Decimals(0)));
+        assert_eq!("10".parse(), Ok(CoordinatePrecision::Decimals(10)));
+        assert_eq!("100m".parse(), Ok(CoordinatePrecision::GridMeters(100.0)));
+        assert_eq!("0.5m".parse(), Ok(CoordinatePrecision::GridMeters(0.5)));
+        for invalid in ["", "11", "-1", "2.5", "abc", "m", "0m", "-5m", "100001m", "FULL"] {
+            assert!(invalid.parse::<CoordinatePrecision>().is_err(), "{:?} should not parse", invalid);
+        }
+    }
+
+    #[test]
+    fn coordinate_precision_is_written_the_way_it_is_parsed() {
+        for precision in [CoordinatePrecision::Full, CoordinatePrecision::Decimals(3), CoordinatePrecision::GridMeters(250.0)] {
+            assert_eq!(precision.to_string().parse(), Ok(precision));
+        }
+        assert_eq!(serde_json::to_value(CoordinatePrecision::GridMeters(100.0)).unwrap(), "100m");
+    }
+
+    #[test]
+    fn full_precision_reports_coordinates_as_recorded() {
+        assert_eq!(CoordinatePrecision::Full.round(12.345678901, -98.765432109), (12.345678901, -98.765432109));
+        assert_eq!(CoordinatePrecision::Full.max_shift_meters(), 0.0);
+    }
+
+    #[test]
+    fn decimal_precision_rounds_each_coordinate() {
+        assert_close(CoordinatePrecision::Decimals(3).round(12.34567, -98.76543), (12.346, -98.765));
+        assert_close(CoordinatePrecision::Decimals(0).round(12.6, -98.4), (13.0, -98.0));
+        assert_close(CoordinatePrecision::Decimals(0).round(89.7, 179.6), (90.0, 180.0));
+    }
+
+    #[test]
+    fn grid_precision_moves_a_point_at_most_half_a_cell_diagonal() {
+        let points = [
+            (0.0, 0.0),
+            (51.50735, -0.12776),
+            (-33.86882, 151.20929),
+            (60.0, 25.0),
+            (89.9993, 64.03),
+            (89.9999, 45.0),
+            (90.0, 123.0),
+            (-89.9999, -170.0),
+            (0.0, 179.9999),
+            (0.0, -179.9999),
+            (45.0, 180.0),
+        ];
+        for meters in [1.0, 100.0, 5_000.0, MAX_COORDINATE_GRID_METERS] {
+            let precision = CoordinatePrecision::GridMeters(meters);
+            for (latitude, longitude) in points {
+                let (snapped_latitude, snapped_longitude) = precision.round(latitude, longitude);
+                assert!(validate_coordinates(snapped_latitude, snapped_longitude).is_ok());
+                let shift = haversine_meters(latitude, longitude, snapped_latitude, snapped_longitude);
+                assert!(
+                    shift <= precision.max_shift_meters() * 1.001,
+                    "{}m grid moved ({}, {}) by {}m",
+                    meters,
+                    latitude,
+                    longitude,
+                    shift
+                );
+                assert_eq!(precision.round(snapped_latitude, snapped_longitude), (snapped_latitude, snapped_longitude));
+            }
+        }
+    }
+
+    #[test]
+    fn grid_precision_shares_points_between_neighbours() {
+        let precision = CoordinatePrecision::GridMeters(100.0);
+        assert_eq!(precision.round(51.500001, -0.120001), precision.round(51.500002, -0.120002));
+    }
+
+    #[test]
+    fn grid_precision_keeps_points_across_the_antimeridian_on_it() {
+        let precision = CoordinatePrecision::GridMeters(100.0);
+        assert_eq!(precision.round(0.0, 179.9999).1, 180.0);
+        assert_eq!(precision.round(0.0, -179.9999).1, -180.0);
+    }
+
+    fn at(timestamp: &str) -> Option<DateTime<Utc>> {
+        Some(DateTime::parse_from_rfc3339(timestamp).unwrap().with_timezone(&Utc))
+    }
+
+    #[test]
+    fn a_400_day_window_is_rejected_when_the_maximum_is_90_days() {
+        let error = validate_time_span(at("2024-01-01T00:00:00Z"), at("2025-02-04T00:00:00Z"), Duration::days(90)).unwrap_err();
+        assert_eq!(error.code, "window_too_large");
+        assert!(error.message.contains("90 days"), "{}", error.message);
+    }
+
+    #[test]
+    fn a_window_of_exactly_the_maximum_is_accepted() {
+        assert!(validate_time_span(at("2024-01-01T00:00:00Z"), at("2024-03-31T00:00:00Z"), Duration::days(90)).is_ok());
+        assert!(validate_time_span(at("2024-01-01T00:00:00Z"), at("2024-03-31T00:00:01Z"), Duration::days(90)).is_err());
+    }
+
+    #[test]
+    fn a_window_without_from_is_not_capped_and_one_without_to_runs_until_now() {
+        assert!(validate_time_span(None, at("2024-01-01T00:00:00Z"), Duration::days(1)).is_ok());
+        assert!(validate_time_span(None, None, Duration::days(1)).is_ok());
+        assert!(validate_time_span(Some(Utc::now() - Duration::hours(2)), None, Duration::hours(3)).is_ok());
+        assert!(validate_time_span(Some(Utc::now() - Duration::days(2)), None, Duration::days(1)).is_err());
+    }
+
+    #[test]
+    fn a_cap_of_part_of_a_day_is_named_in_hours() {
+        let error = validate_time_span(at("2024-01-01T00:00:00Z"), at("2024-01-02T00:00:00Z"), Duration::hours(12)).unwrap_err();
+        assert!(error.message.contains("12 hours"), "{}", error.message);
+    }
+
+    #[test]
+    fn precision_applies_to_every_coordinate_of_a_trip() {
+        let mut trip = Trip {
+            started_at: Utc::now(),
+            ended_at: Utc::now(),
+            start_latitude: 1.23456,
+            start_longitude: 2.34567,
+            end_latitude: 3.45678,
+            end_longitude: 4.56789,
+            distance_meters: 1000.0,
+            duration_secs: 60,
+            average_speed: None,
+            point_count: 2,
+        };
+        CoordinatePrecision::Decimals(2).apply(&mut trip);
+        assert_close((trip.start_latitude, trip.start_longitude), (1.23, 2.35));
+        assert_close((trip.end_latitude, trip.end_longitude), (3.46, 4.57));
+    }
+
+    fn fix_at(timestamp: Option<DateTime<Utc>>) -> TrackLocationRequest {
+        let mut request: TrackLocationRequest =
+            serde_json::from_value(serde_json::json!({"user_id": "alice", "latitude": 1.0, "longitude": 2.0})).unwrap();
+        request.timestamp = timestamp;
+        request
+    }
+
+    fn policy(trust_server_time: bool) -> TimestampPolicy {
+        TimestampPolicy { max_skew: Duration::minutes(5), max_age: Duration::days(7), trust_server_time }
+    }
+
+    #[test]
+    fn an_in_range_timestamp_is_kept_as_reported() {
+        let received = at("2024-06-01T12:00:00Z").unwrap();
+        for timestamp in ["2024-06-01T11:59:00Z", "2024-06-01T12:04:59Z", "2024-05-25T12:00:00Z"] {
+            let mut request = fix_at(at(timestamp));
+            request.resolve_timestamp(received, &policy(false)).unwrap();
+            assert_eq!(request.timestamp, at(timestamp));
+            assert_eq!(request.timestamp_status, TimestampStatus::Device);
+        }
+    }
+
+    #[test]
+    fn a_missing_timestamp_is_the_time_of_receipt() {
+        let received = at("2024-06-01T12:00:00Z").unwrap();
+        let mut request = fix_at(None);
+        request.resolve_timestamp(received, &policy(false)).unwrap();
+        assert_eq!(request.timestamp, Some(received));
+        assert_eq!(request.timestamp_status, TimestampStatus::Server);
+    }
+
+    #[test]
+    fn a_timestamp_beyond_the_skew_is_rejected() {
+        let mut request = fix_at(at("2024-06-01T12:05:01Z"));
+        let error = request.resolve_timestamp(at("2024-06-01T12:00:00Z").unwrap(), &policy(false)).unwrap_err();
+        assert_eq!(error.code, "timestamp_in_future");
+        assert!(error.message.contains("300 seconds"), "{}", error.message);
+    }
+
+    #[test]
+    fn an_ancient_timestamp_is_kept_but_flagged() {
+        for timestamp in ["1970-01-01T00:00:00Z", "2024-05-25T11:59:59Z"] {
+            let mut request = fix_at(at(timestamp));
+            request.resolve_timestamp(at("2024-06-01T12:00:00Z").unwrap(), &policy(false)).unwrap();
+            assert_eq!(request.timestamp, at(timestamp));
+            assert_eq!(request.timestamp_status, TimestampStatus::Suspect);
+        }
+    }
+
+    #[test]
+    fn trusting_server_time_substitutes_future_and_ancient_timestamps() {
+        let received = at("2024-06-01T12:00:00Z").unwrap();
+        for timestamp in ["2030-01-01T00:00:00Z", "1970-01-01T00:00:00Z"] {
+            let mut request = fix_at(at(timestamp));
+            request.resolve_timestamp(received, &policy(true)).unwrap();
+            assert_eq!(request.timestamp, Some(received));
+            assert_eq!(request.timestamp_status, TimestampStatus::Substituted);
+        }
+
+        let mut request = fix_at(at("2024-06-01T11:00:00Z"));
+        request.resolve_timestamp(received, &policy(true)).unwrap();
+        assert_eq!(request.timestamp, at("2024-06-01T11:00:00Z"));
+        assert_eq!(request.timestamp_status, TimestampStatus::Device);
+    }
+
+    /// A 100 m circle at the origin holding the floors between `min` and `max` meters.
+    fn banded(min: Option<f64>, max: Option<f64>) -> Geofence {
+        Geofence {
+            id: Uuid::new_v4(),
+            tenant_id: "acme".to_string(),
+            name: "floor".to_string(),
+            geofence_type: "circle".to_string(),
+            center_latitude: Some(0.0),
+            center_longitude: Some(0.0),
+            radius_meters: Some(100.0),
+            polygon: None,
+            dwell_threshold_secs: None,
+            webhook_url: None,
+            min_altitude: min,
+            max_altitude: max,
+            speed_limit: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn fixes_must_be_within_the_altitude_band() {
+        let floor = banded(Some(10.0), Some(14.0));
+        for (altitude, inside) in [(10.0, true), (12.0, true), (14.0, true), (9.9, false), (14.1, false)] {
+            assert_eq!(floor.contains_fix(0.0, 0.0, Some(altitude), false), inside, "{}", altitude);
+            assert_eq!(floor.contains_fix(0.0, 0.0, Some(altitude), true), inside, "{}", altitude);
+        }
+        // In band but outside the circle.
+        assert!(!floor.contains_fix(0.01, 0.0, Some(12.0), false));
+
+        assert!(banded(Some(10.0), None).contains_fix(0.0, 0.0, Some(1000.0), false));
+        assert!(!banded(None, Some(14.0)).contains_fix(0.0, 0.0, Some(15.0), false));
+    }
+
+    #[test]
+    fn fixes_without_altitude_match_any_band_unless_strict() {
+        let floor = banded(Some(10.0), Some(14.0));
+        assert!(floor.contains_fix(0.0, 0.0, None, false));
+        assert!(!floor.contains_fix(0.0, 0.0, None, true));
+
+        let unbanded = banded(None, None);
+        assert!(unbanded.contains_fix(0.0, 0.0, None, true));
+        assert!(unbanded.contains_fix(0.0, 0.0, Some(-30.0), true));
+    }
+
+    #[test]
+    fn distance_to_a_circle_is_measured_from_its_rim() {
+        let circle = banded(None, None);
+        assert!((circle.boundary_distance_meters(0.0, 0.0).unwrap() + 100.0).abs() < 1e-9);
+        let east = circle.boundary_distance_meters(0.0, 0.002).unwrap();
+        assert!((east - (haversine_meters(0.0, 0.0, 0.0, 0.002) - 100.0)).abs() < 1e-9, "{}", east);
+
+        let broken = Geofence { radius_meters: None, ..banded(None, None) };
+        assert!(broken.boundary_distance_meters(0.0, 0.0).is_none());
+    }
+
+    fn geofence_code(request: serde_json::Value) -> Option<&'static str> {
+        let request: CreateGeofenceRequest = serde_json::from_value(request).unwrap();
+        request.validate().err().map(|e| e.code)
+    }
+
+    #[test]
+    fn webhooks_must_be_absolute_http_or_https_urls() {
+        let with_webhook = |url: &str| {
+            serde_json::json!({
+                "name": "depot",
+                "type": "circle",
+                "center_latitude": 0.0,
+                "center_longitude": 0.0,
+                "radius_meters": 100.0,
+                "webhook_url": url,
+            })
+        };
+        assert_eq!(geofence_code(with_webhook("http://hooks.example.com/geofences")), None);
+        assert_eq!(geofence_code(with_webhook("https://hooks.example.com/geofences")), None);
+        for url in ["ftp://hooks.example.com", "hooks.example.com/geofences", "/geofences", "https://"] {
+            assert_eq!(geofence_code(with_webhook(url)), Some("invalid_webhook_url"), "{}", url);
+        }
+    }
+
+    #[test]
+    fn altitude_bands_must_be_ordered() {
+        let with_band = |min: f64, max: f64| {
+            serde_json::json!({
+                "name": "floor",
+                "type": "circle",
+                "center_latitude": 0.0,
+                "center_longitude": 0.0,
+                "radius_meters": 100.0,
+                "min_altitude": min,
+                "max_altitude": max,
+            })
+        };
+        assert_eq!(geofence_code(with_band(10.0, 14.0)), None);
+        assert_eq!(geofence_code(with_band(12.0, 12.0)), None);
+        assert_eq!(geofence_code(with_band(14.0, 10.0)), Some("invalid_altitude_band"));
+    }
+
+    #[test]
+    fn simplify_tolerances_must_be_positive_and_bounded() {
+        let simplify = |value: &str| parse_simplify(&HashMap::from([("simplify".to_string(), value.to_string())]));
+        assert_eq!(parse_simplify(&HashMap::new()).unwrap(), None);
+        assert_eq!(simplify("2.5").unwrap(), Some(2.5));
+        assert_eq!(simplify(&MAX_SIMPLIFY_TOLERANCE_METERS.to_string()).unwrap(), Some(MAX_SIMPLIFY_TOLERANCE_METERS));
+        for invalid in ["0", "-1", "10000.5", "NaN", "inf", "five"] {
+            assert_eq!(simplify(invalid).unwrap_err().code, "invalid_parameter", "{}", invalid);
+        }
+    }
+
+    fn location_at(params: &[(&str, &str)]) -> Result<LocationAtQuery, &'static str> {
+        let params = params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        LocationAtQuery::from_params(&params).map_err(|e| e.code)
+    }
+
+    #[test]
+    fn point_in_time_lookups_need_a_timestamp_and_default_to_the_nearest_fix() {
+        let query = location_at(&[("timestamp", "2024-06-01T15:42:00Z")]).unwrap();
+        assert_eq!(Some(query.timestamp), at("2024-06-01T15:42:00Z"));
+        assert_eq!(query.mode, LocationAtMode::Nearest);
+        assert!(!query.strict && !query.interpolate);
+
+        let query = location_at(&[("timestamp", "2024-06-01T15:42:00Z"), ("mode", "before"), ("strict", "true")]).unwrap();
+        assert_eq!(query.mode, LocationAtMode::Before);
+        assert!(query.strict);
+
+        assert_eq!(location_at(&[]).unwrap_err(), "missing_parameter");
+        assert_eq!(location_at(&[("timestamp", "3:42pm")]).unwrap_err(), "invalid_timestamp");
+        assert_eq!(location_at(&[("timestamp", "2024-06-01T15:42:00Z"), ("mode", "after")]).unwrap_err(), "invalid_parameter");
+        assert_eq!(location_at(&[("timestamp", "2024-06-01T15:42:00Z"), ("strict", "yes")]).unwrap_err(), "invalid_parameter");
+    }
+
+    #[test]
+    fn interpolation_needs_fixes_on_both_sides() {
+        let query = location_at(&[("timestamp", "2024-06-01T15:42:00Z"), ("interpolate", "true")]).unwrap();
+        assert!(query.interpolate);
+        let with_before = [("timestamp", "2024-06-01T15:42:00Z"), ("interpolate", "true"), ("mode", "before")];
+        assert_eq!(location_at(&with_before).unwrap_err(), "invalid_parameter");
+    }
+
+    #[test]
+    fn a_polygon_box_spans_its_outer_ring() {
+        let rings = vec![
+            vec![[-0.2, 51.4], [0.1, 51.45], [0.05, 51.6], [-0.15, 51.55]],
+            vec![[-0.1, 51.5], [0.0, 51.5], [0.0, 51.52]],
+        ];
+        let bbox = BoundingBox::of_polygon(&rings).unwrap();
+        assert_eq!(
+            (bbox.min_longitude, bbox.min_latitude, bbox.max_longitude, bbox.max_latitude),
+            (-0.2, 51.4, 0.1, 51.6)
+        );
+        assert!(BoundingBox::of_polygon(&[]).is_none());
+    }
+
+    #[test]
+    fn a_circle_box_holds_the_circle_at_any_latitude() {
+        for latitude in [0.0, 51.5, -60.0, 80.0] {
+            let radius_meters = 10_000.0;
+            let bbox = BoundingBox::of_circle(latitude, 10.0, radius_meters).unwrap();
+            // The rim due north, south, east and west, just inside.
+            let lat_delta = radius_meters * 0.999 / EARTH_RADIUS_METERS.to_radians();
+            assert!(bbox.contains(latitude + lat_delta, 10.0), "{}", latitude);
+            assert!(bbox.contains(latitude - lat_delta, 10.0), "{}", latitude);
+            for longitude in [bbox.min_longitude, bbox.max_longitude] {
+                let distance = haversine_meters(latitude, 10.0, latitude, longitude);
+                assert!(distance >= radius_meters, "{} m at {}", distance, latitude);
+            }
+        }
+    }
+
+    #[test]
+    fn circles_reaching_a_pole_or_the_antimeridian_have_no_box() {
+        assert!(BoundingBox::of_circle(89.99, 0.0, 5000.0).is_none());
+        assert!(BoundingBox::of_circle(-89.99, 0.0, 5000.0).is_none());
+        assert!(BoundingBox::of_circle(0.0, 179.99, 5000.0).is_none());
+        assert!(BoundingBox::of_circle(0.0, -179.99, 5000.0).is_none());
+        assert!(BoundingBox::of_circle(0.0, 179.9, 5000.0).is_some());
+    }
+
+    #[test]
+    fn polylines_keep_five_or_six_decimal_places() {
+        let precision = |value: Option<&str>| {
+            let params = value.map(|value| ("precision".to_string(), value.to_string())).into_iter().collect();
+            PolylineQuery::from_params(&params).map(|query| query.precision).map_err(|e| e.code)
+        };
+        assert_eq!(precision(None), Ok(DEFAULT_POLYLINE_PRECISION));
+        assert_eq!(precision(Some("5")), Ok(5));
+        assert_eq!(precision(Some("6")), Ok(6));
+        for invalid in ["4", "7", "five", ""] {
+            assert_eq!(precision(Some(invalid)), Err("invalid_parameter"), "{}", invalid);
+        }
+    }
+
+    fn evaluation_code(request: serde_json::Value) -> Option<&'static str> {
+        let request: EvaluateGeofencesRequest = serde_json::from_value(request).unwrap();
+        request.validate().err().map(|e| e.code)
+    }
+
+    #[test]
+    fn evaluation_requests_need_a_valid_point_and_user() {
+        assert_eq!(evaluation_code(serde_json::json!({"latitude": 51.5, "longitude": -0.12})), None);
+        assert_eq!(
+            evaluation_code(serde_json::json!({"latitude": 51.5, "longitude": -0.12, "altitude": 12.0, "user_id": "alice"})),
+            None
+        );
+        assert_eq!(evaluation_code(serde_json::json!({"latitude": 91.0, "longitude": 0.0})), Some("invalid_coordinates"));
+        assert_eq!(evaluation_code(serde_json::json!({"latitude": 0.0, "longitude": -181.0})), Some("invalid_coordinates"));
+        assert_eq!(
+            evaluation_code(serde_json::json!({"latitude": 0.0, "longitude": 0.0, "user_id": " "})),
+            Some("invalid_user_id")
+        );
+    }
+}
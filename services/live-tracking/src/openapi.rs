@@ -0,0 +1,422 @@
+// OpenAPI 3.1 description of the HTTP API, served at `GET /openapi.json`. Derived from the
+// `#[utoipa::path]` attributes on the handlers and the `ToSchema`/`IntoParams` impls of the
+// models, so it follows their fields; the conventions every route shares are added by
+// `Conventions` rather than repeated on each handler.
+use std::sync::OnceLock;
+use serde_json::Value;
+use utoipa::openapi::path::{Operation, Parameter, ParameterIn};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme};
+use utoipa::openapi::{Content, HeaderBuilder, OpenApi as Document, Ref, RefOr, ResponseBuilder};
+use utoipa::{IntoParams, Modify, OpenApi};
+use crate::error::ErrorBody;
+use crate::handlers;
+use crate::utils::signed_url;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Suuupra Live Tracking Service",
+        description = "Every error is answered with the `Error` envelope. Every response carries an `x-request-id` \
+                       header, echoing the request's own when it sent one. When the deployment reports coordinates at \
+                       reduced precision, every response also carries `x-coordinate-precision`: a number of decimal \
+                       places, or a grid size such as `100m`. Data is partitioned by the `tenant` claim of the \
+                       caller's token, so every route reading or writing it needs a token; tokens without a `tenant` \
+                       claim use the `default` tenant. Ids belonging to another tenant are answered as not found.",
+    ),
+    paths(
+        handlers::health::banner,
+        serve,
+        handlers::health::liveness_check,
+        handlers::health::readiness_check,
+        handlers::metrics::prometheus_metrics,
+        handlers::debug::stats,
+        handlers::tracking::track_location,
+        handlers::tracking::track_locations_batch,
+        handlers::tracking::get_nearby_locations,
+        handlers::tracking::get_clusters,
+        handlers::tracking::get_current_location,
+        handlers::tracking::get_location_history,
+        handlers::tracking::get_location_at,
+        handlers::tracking::get_matched_track,
+        handlers::tracking::get_polyline,
+        handlers::tracking::export_location_history,
+        handlers::tracking::sign_export,
+        handlers::tracking::export_location_gpx,
+        handlers::tracking::import_location_gpx,
+        handlers::users::get_user_status,
+        handlers::users::get_user_geofences,
+        handlers::users::erase_user_data,
+        handlers::users::get_presence_events,
+        handlers::users::list_tracked_users,
+        handlers::routes::optimize_route,
+        handlers::routes::get_route,
+        handlers::analytics::get_analytics,
+        handlers::analytics::get_active_users,
+        handlers::analytics::get_heatmap,
+        handlers::analytics::get_distance,
+        handlers::analytics::get_stops,
+        handlers::analytics::get_trips,
+        handlers::usage::get_usage,
+        handlers::geofencing::create_geofence,
+        handlers::geofencing::get_geofences,
+        handlers::geofencing::import_geofences,
+        handlers::geofencing::evaluate_geofences,
+        handlers::geofencing::get_nearest_geofences,
+        handlers::geofencing::update_geofence,
+        handlers::geofencing::delete_geofence,
+        handlers::geofencing::get_geofence_distance,
+        handlers::geofencing::get_geofence_visits,
+        handlers::websocket::tracking_websocket,
+        handlers::websocket::geofence_websocket,
+        handlers::websocket::presence_websocket,
+        handlers::websocket::replay_websocket,
+    ),
+    components(schemas(ErrorBody)),
+    modifiers(&Conventions),
+)]
+struct ApiDoc;
+
+/// The document is the same for the life of the process, so it is built once.
+pub fn document() -> &'static Value {
+    static DOCUMENT: OnceLock<Value> = OnceLock::new();
+    DOCUMENT.get_or_init(|| serde_json::to_value(ApiDoc::openapi()).expect("the OpenAPI document serializes"))
+}
+
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    summary = "This document",
+    responses((status = 200, description = "OpenAPI 3.1 document.", body = Object)),
+)]
+pub fn serve() -> impl warp::Reply {
+    warp::reply::json(document())
+}
+
+const BEARER_AUTH: &str = "bearerAuth";
+
+/// What every route has in common: errors rendered with the `Error` envelope, a 401 wherever a
+/// token is accepted, the body checks of JSON routes, and the request time limit.
+struct Conventions;
+
+impl Modify for Conventions {
+    fn modify(&self, openapi: &mut Document) {
+        openapi.info.license = None;
+        openapi.components.get_or_insert_with(Default::default).add_security_scheme(
+            BEARER_AUTH,
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()),
+        );
+
+        let bearer = SecurityRequirement::new(BEARER_AUTH, Vec::<String>::new());
+        for item in openapi.paths.paths.values_mut() {
+            let operations = [&mut item.get, &mut item.put, &mut item.post, &mut item.delete];
+            for operation in operations.into_iter().flatten() {
+                let mut implied = vec![500];
+                if operation.security.as_ref().is_some_and(|security| security.contains(&bearer)) {
+                    implied.push(401);
+                }
+                if operation.request_body.as_ref().is_some_and(|body| body.content.contains_key("application/json")) {
+                    // Every JSON body goes through the same size, type and parse checks.
+                    implied.extend([400, 413, 415]);
+                }
+                // Upgrades are not run under the request time limit.
+                if !operation.responses.responses.contains_key("101") {
+                    implied.push(504);
+                }
+                add_errors(operation, &implied);
+                operation.tags = None;
+            }
+        }
+    }
+}
+
+/// Gives each error response without a body the `Error` envelope, and a standard description
+/// when the handler gave none.
+fn add_errors(operation: &mut Operation, implied: &[u16]) {
+    let responses = &mut operation.responses.responses;
+    for status in implied {
+        responses.entry(status.to_string()).or_insert_with(|| RefOr::T(ResponseBuilder::new().build()));
+    }
+    for (status, response) in responses.iter_mut() {
+        let (RefOr::T(response), Ok(status)) = (response, status.parse::<u16>()) else {
+            continue;
+        };
+        if status < 400 || !response.content.is_empty() {
+            continue;
+        }
+        if response.description.is_empty() {
+            response.description = error_description(status).to_string();
+        }
+        response.content.insert("application/json".to_string(), Content::new(Some(Ref::from_schema_name("Error"))));
+        if status == 429 {
+            response.headers.insert(
+                "retry-after".to_string(),
+                HeaderBuilder::new()
+                    .schema(params::seconds())
+                    .description(Some("Seconds until a request may succeed."))
+                    .build(),
+            );
+        }
+    }
+}
+
+fn error_description(status: u16) -> &'static str {
+    match status {
+        400 => "Invalid parameters or body.",
+        401 => "Missing or invalid bearer token.",
+        403 => "The token does not grant access to this resource.",
+        404 => "No such resource.",
+        409 => "Conflicts with a request still in progress.",
+        413 => "Body larger than the configured limit.",
+        415 => "Body is not JSON.",
+        422 => "Well-formed but rejected.",
+        429 => "Rate limited.",
+        503 => "A dependency is unavailable; retry later.",
+        504 => "Did not complete within the route's time limit.",
+        _ => "Unexpected failure.",
+    }
+}
+
+/// The query parameters of a link from `.../export/sign`, which lets an export be downloaded
+/// without a token.
+pub(crate) struct SignedLink;
+
+impl IntoParams for SignedLink {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![
+            params::query(signed_url::TENANT, "Tenant of a signed link.", params::string()),
+            params::query(signed_url::EXPIRES, "Expiry of a signed link, in Unix seconds.", params::seconds()),
+            params::query(
+                signed_url::SIGNATURE,
+                "Signature of a signed link, used when no token is sent. Invalid or expired signatures answer 401.",
+                params::string(),
+            ),
+        ]
+    }
+}
+
+/// How WebSocket clients that cannot set headers authenticate.
+pub(crate) struct AccessToken;
+
+impl IntoParams for AccessToken {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![params::query(
+            "access_token",
+            "JWT for clients that cannot set headers; the `bearer, <jwt>` Sec-WebSocket-Protocol pair is preferred \
+             and is tried first.",
+            params::string(),
+        )]
+    }
+}
+
+/// Building blocks for the hand-written `IntoParams` impls of the query types, which parse
+/// their parameters from a map rather than deriving `Deserialize`.
+pub(crate) mod params {
+    use serde_json::Value;
+    use utoipa::openapi::path::{Parameter, ParameterBuilder, ParameterIn};
+    use utoipa::openapi::schema::{KnownFormat, ObjectBuilder, SchemaFormat, Type};
+    use utoipa::openapi::{Required, RefOr, Schema};
+    use utoipa::Number;
+    use crate::models::{DEFAULT_ANALYTICS_WINDOW_HOURS, MAX_SIMPLIFY_TOLERANCE_METERS};
+
+    /// An optional query parameter.
+    pub fn query(name: &str, description: &str, schema: impl Into<RefOr<Schema>>) -> Parameter {
+        parameter(name, description, schema, Required::False)
+    }
+
+    pub fn required(name: &str, description: &str, schema: impl Into<RefOr<Schema>>) -> Parameter {
+        parameter(name, description, schema, Required::True)
+    }
+
+    fn parameter(name: &str, description: &str, schema: impl Into<RefOr<Schema>>, required: Required) -> Parameter {
+        ParameterBuilder::new()
+            .name(name)
+            .parameter_in(ParameterIn::Query)
+            .required(required)
+            .description(Some(description))
+            .schema(Some(schema))
+            .build()
+    }
+
+    pub fn string() -> ObjectBuilder {
+        ObjectBuilder::new().schema_type(Type::String)
+    }
+
+    pub fn timestamp() -> ObjectBuilder {
+        string().format(Some(SchemaFormat::KnownFormat(KnownFormat::DateTime)))
+    }
+
+    /// A boolean that is false when left out.
+    pub fn flag() -> ObjectBuilder {
+        ObjectBuilder::new().schema_type(Type::Boolean).default(Some(false.into()))
+    }
+
+    pub fn integer(min: impl Into<Number>, max: impl Into<Number>) -> ObjectBuilder {
+        ObjectBuilder::new().schema_type(Type::Integer).minimum(Some(min)).maximum(Some(max))
+    }
+
+    pub fn seconds() -> ObjectBuilder {
+        ObjectBuilder::new().schema_type(Type::Integer).minimum(Some(0))
+    }
+
+    pub fn number() -> ObjectBuilder {
+        ObjectBuilder::new().schema_type(Type::Number)
+    }
+
+    pub fn between(min: f64, max: f64) -> ObjectBuilder {
+        number().minimum(Some(min)).maximum(Some(max))
+    }
+
+    /// A number above 0, up to `max`.
+    pub fn positive(max: f64) -> ObjectBuilder {
+        number().exclusive_minimum(Some(0)).maximum(Some(max))
+    }
+
+    pub fn one_of<V: Into<Value>>(values: impl IntoIterator<Item = V>) -> ObjectBuilder {
+        let values: Vec<Value> = values.into_iter().map(Into::into).collect();
+        let schema_type = match values.first() {
+            Some(Value::Number(_)) => Type::Integer,
+            _ => Type::String,
+        };
+        ObjectBuilder::new().schema_type(schema_type).enum_values(Some(values))
+    }
+
+    pub fn latitude(description: &str) -> Parameter {
+        required("lat", description, between(-90.0, 90.0))
+    }
+
+    pub fn longitude(description: &str) -> Parameter {
+        required("lon", description, between(-180.0, 180.0))
+    }
+
+    pub fn limit(default: impl Into<Value>, max: impl Into<Number>) -> Parameter {
+        query("limit", "Page size; out-of-range values are clamped.", integer(1, max).default(Some(default.into())))
+    }
+
+    pub fn cursor(description: &str) -> Parameter {
+        query("cursor", description, string())
+    }
+
+    pub fn include_total() -> Parameter {
+        query("include_total", "Also count every matching item into `page.total`.", flag())
+    }
+
+    pub fn simplify() -> Parameter {
+        query(
+            "simplify",
+            "Douglas-Peucker tolerance in meters; the first and last fix are always kept.",
+            positive(MAX_SIMPLIFY_TOLERANCE_METERS),
+        )
+    }
+
+    pub fn indexing() -> Parameter {
+        query(
+            "indexing",
+            "Grid the cells belong to: geohash, or Uber's H3 hexagons.",
+            one_of(["geohash", "h3"]).default(Some("geohash".into())),
+        )
+    }
+
+    pub fn bbox() -> Parameter {
+        required("bbox", "`minLon,minLat,maxLon,maxLat`; may not cross the antimeridian.", string())
+    }
+
+    /// `from` and `to`; with `defaulted`, the window is the last `DEFAULT_ANALYTICS_WINDOW_HOURS`
+    /// when they are left out, otherwise it is unbounded.
+    pub fn window(defaulted: bool) -> [Parameter; 2] {
+        let (from, to) = if defaulted {
+            (
+                format!(
+                    "Inclusive start; defaults to {} hours before `to`. At most `MAX_QUERY_WINDOW_DAYS` before `to`.",
+                    DEFAULT_ANALYTICS_WINDOW_HOURS
+                ),
+                "Inclusive end; defaults to now.",
+            )
+        } else {
+            (
+                "Inclusive start; unbounded when omitted. At most `MAX_QUERY_WINDOW_DAYS` before `to`, or now.".to_string(),
+                "Inclusive end; unbounded when omitted.",
+            )
+        };
+        [query("from", &from, timestamp()), query("to", to, timestamp())]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::request_metrics::ROUTE_TEMPLATES;
+    use crate::models::{MAX_GEOFENCE_IMPORT_FEATURES, MAX_SIMPLIFY_TOLERANCE_METERS};
+
+    /// `/api/v1/location/:user_id` as the document spells it, `/api/v1/location/{user_id}`.
+    fn documented_path(template: &str) -> String {
+        template
+            .split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => format!("{{{}}}", name),
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        let paths = document()["paths"].as_object().unwrap();
+        // `/health` is an alias, mentioned in the description of `/health/live`.
+        let missing: Vec<String> = ROUTE_TEMPLATES
+            .iter()
+            .filter(|template| **template != "/health")
+            .map(|template| documented_path(template))
+            .filter(|path| !paths.contains_key(path))
+            .collect();
+        assert!(missing.is_empty(), "undocumented routes: {:?}", missing);
+        assert_eq!(paths.len(), ROUTE_TEMPLATES.len() - 1, "documented paths that are not routes");
+    }
+
+    #[test]
+    fn every_referenced_schema_is_defined() {
+        let document = document();
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        let text = document.to_string();
+        let undefined: Vec<&str> = text
+            .split("\"#/components/schemas/")
+            .skip(1)
+            .filter_map(|rest| rest.split('"').next())
+            .filter(|name| !schemas.contains_key(*name))
+            .collect();
+        assert!(undefined.is_empty(), "undefined schemas: {:?}", undefined);
+    }
+
+    #[test]
+    fn conventions_apply_to_every_operation() {
+        let track = &document()["paths"]["/api/v1/track/location"]["post"];
+        for status in ["400", "401", "413", "415", "429", "500", "504"] {
+            assert_eq!(
+                track["responses"][status]["content"]["application/json"]["schema"]["$ref"],
+                "#/components/schemas/Error",
+                "{}",
+                status
+            );
+        }
+        assert!(track["responses"]["429"]["headers"]["retry-after"].is_object());
+        assert_eq!(track["responses"]["401"]["description"], "Missing or invalid bearer token.");
+
+        let metrics = &document()["paths"]["/metrics"]["get"];
+        assert!(metrics["responses"]["401"].is_null());
+        assert!(metrics["security"].is_null());
+
+        let stream = &document()["paths"]["/ws/presence"]["get"];
+        assert!(stream["responses"]["101"].is_object());
+        assert!(stream["responses"]["504"].is_null());
+    }
+
+    #[test]
+    fn literal_bounds_match_the_constants() {
+        let schemas = &document()["components"]["schemas"];
+        assert_eq!(schemas["GeofenceImportRequest"]["properties"]["features"]["maxItems"], MAX_GEOFENCE_IMPORT_FEATURES);
+        assert_eq!(
+            schemas["SignExportRequest"]["properties"]["simplify"]["maximum"].as_f64(),
+            Some(MAX_SIMPLIFY_TOLERANCE_METERS)
+        );
+    }
+}
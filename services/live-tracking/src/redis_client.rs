@@ -0,0 +1,164 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use redis::aio::{ConnectionLike, MultiplexedConnection};
+use redis::{Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, Value};
+use tokio::time::Instant;
+use tracing::{info, warn};
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+/// `redis::Client` behind a circuit breaker. After enough consecutive connection-level failures
+/// Redis is skipped outright for a cooldown, so callers fall back to Postgres at once instead of
+/// waiting on connection attempts; one probe is then let through and closes the circuit again
+/// if it succeeds.
+#[derive(Debug, Clone)]
+pub struct RedisClient {
+    client: redis::Client,
+    breaker: Arc<CircuitBreaker>,
+    connect_timeout: Duration,
+}
+
+impl RedisClient {
+    pub fn new(client: redis::Client, config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self {
+            client,
+            breaker: Arc::new(CircuitBreaker {
+                state: Mutex::new(BreakerState::Closed { failures: 0 }),
+                failure_threshold: config.redis_breaker_failure_threshold,
+                cooldown: Duration::from_secs(config.redis_breaker_cooldown_secs),
+                metrics,
+            }),
+            connect_timeout: Duration::from_millis(config.redis_connect_timeout_ms),
+        }
+    }
+
+    /// Fails immediately while the circuit is open.
+    pub async fn get_multiplexed_async_connection(&self) -> RedisResult<BreakerConnection> {
+        if !self.breaker.allow() {
+            return Err(RedisError::from((ErrorKind::IoError, "Redis circuit breaker is open")));
+        }
+
+        let connected = tokio::time::timeout(self.connect_timeout, self.client.get_multiplexed_async_connection())
+            .await
+            .unwrap_or_else(|_| Err(RedisError::from((ErrorKind::IoError, "timed out connecting to Redis"))));
+        match connected {
+            Ok(inner) => Ok(BreakerConnection { inner, breaker: self.breaker.clone() }),
+            Err(e) => {
+                self.breaker.record_failure();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A connection that reports the outcome of every command to the breaker it came from.
+pub struct BreakerConnection {
+    inner: MultiplexedConnection,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl ConnectionLike for BreakerConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            let result = self.inner.req_packed_command(cmd).await;
+            self.breaker.record(&result);
+            result
+        })
+    }
+
+    fn req_packed_commands<'a>(&'a mut self, cmd: &'a Pipeline, offset: usize, count: usize) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let result = self.inner.req_packed_commands(cmd, offset, count).await;
+            self.breaker.record(&result);
+            result
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.inner.get_db()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// A probe is in flight; another is allowed if it has not reported back within the cooldown.
+    HalfOpen { since: Instant },
+}
+
+impl BreakerState {
+    fn gauge_value(&self) -> i64 {
+        match self {
+            BreakerState::Closed { .. } => 0,
+            BreakerState::HalfOpen { .. } => 1,
+            BreakerState::Open { .. } => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+    failure_threshold: u32,
+    cooldown: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl CircuitBreaker {
+    /// Whether a call may go to Redis. Once the cooldown has passed the caller becomes the probe.
+    fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if now < until => false,
+            BreakerState::HalfOpen { since } if now < since + self.cooldown => false,
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => {
+                self.transition(&mut state, BreakerState::HalfOpen { since: now });
+                true
+            }
+        }
+    }
+
+    /// Only errors that say Redis itself is unreachable count; a bad command or reply does not.
+    fn record<T>(&self, result: &RedisResult<T>) {
+        match result {
+            Err(e) if e.is_io_error() || e.is_connection_refusal() || e.is_connection_dropped() || e.is_timeout() => {
+                self.record_failure()
+            }
+            _ => self.record_success(),
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, BreakerState::Closed { failures: 0 }) {
+            if !matches!(*state, BreakerState::Closed { .. }) {
+                info!("Redis circuit breaker closed");
+            }
+            self.transition(&mut state, BreakerState::Closed { failures: 0 });
+        }
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            BreakerState::Closed { failures } => failures + 1,
+            BreakerState::HalfOpen { .. } => self.failure_threshold,
+            BreakerState::Open { .. } => return,
+        };
+        if failures >= self.failure_threshold {
+            warn!("Redis circuit breaker opened for {:?} after {} consecutive failures", self.cooldown, failures);
+            self.metrics.redis_circuit_opened_total.inc();
+            self.transition(&mut state, BreakerState::Open { until: Instant::now() + self.cooldown });
+        } else {
+            self.transition(&mut state, BreakerState::Closed { failures });
+        }
+    }
+
+    fn transition(&self, state: &mut BreakerState, next: BreakerState) {
+        *state = next;
+        self.metrics.redis_circuit_state.set(next.gauge_value());
+    }
+}
@@ -1,97 +1,5169 @@
 pub mod tracking_service {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use chrono::{DateTime, NaiveDate, Utc};
+    use futures_util::StreamExt;
+    use sqlx::{FromRow, PgConnection, Pool, Postgres, QueryBuilder, Row, Transaction};
+    use redis::{AsyncCommands, Script};
+    use tokio::sync::mpsc::{self, error::TrySendError};
+    use tracing::{debug, error, info, warn};
+    use uuid::Uuid;
+    use crate::config::{Config, OverflowPolicy};
+    use crate::metrics::Metrics;
+    use crate::models::{
+        CellId, ClusterPoint, ClusterQuery, ClusterResult, ErasureResult, ExportQuery, GpxImportResult, Grid, HistoryCursor, HistoryQuery,
+        Location, LocationAt, LocationAtMode, LocationAtQuery, LocationCluster, MissingTimePolicy, NearbyLocation, NearbyQuery, NearbyResult,
+        LastSeenOrder, PageInfo, Paginated, UserStatus, SequenceDiagnostics, TimestampStatus, TrackLocationRequest, TrackedUser,
+        TrackedUsersQuery, MAX_CLUSTERS, SEQUENCE_GAP_WINDOW,
+    };
+    use crate::redis_client::RedisClient;
+    use crate::utils::{
+        geohash, gpx::TrackPoint, h3, haversine_distance, haversine_meters, interpolate, redis_keys, simplify::douglas_peucker,
+        smoothing::kalman_smooth_with_accuracy, track_distance_meters,
+    };
+    use super::usage_service::UsageService;
+
+    const LOCATION_GEOHASH_PRECISION: usize = geohash::MAX_PRECISION;
+    const IMPLAUSIBLE_SPEED_REASON: &str = "implausible_speed";
+    const IDEMPOTENCY_PENDING: &str = "pending";
+    const EXPORT_BUFFER_ROWS: usize = 256;
+    /// Rows written per statement by a GPX import.
+    const IMPORT_BATCH_ROWS: usize = 1000;
+
+    /// Caches a fix as the user's current location unless the cache already holds one at least as
+    /// new, so fixes arriving out of order never replace a newer position. Returns 1 when written.
+    const CACHE_IF_NEWER_SCRIPT: &str = r#"
+        local cached = tonumber(redis.call('GET', KEYS[2]))
+        if cached and redis.call('EXISTS', KEYS[1]) == 1 and cached >= tonumber(ARGV[2]) then
+            return 0
+        end
+        redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[3])
+        redis.call('SET', KEYS[2], ARGV[2], 'EX', ARGV[3])
+        return 1
+    "#;
+    /// Fixes simplified together during a simplified export; consecutive chunks share an endpoint.
+    const EXPORT_SIMPLIFY_CHUNK_ROWS: usize = 10_000;
+
+    /// Result of ingesting a single fix.
+    #[derive(Debug)]
+    pub enum Recorded {
+        Stored(Location),
+        /// The fix was kept out of `locations` and logged to `rejected_locations` instead.
+        Rejected { reason: &'static str, message: String },
+        /// The idempotency key or `seq` was already used; this is the fix stored the first time.
+        Replayed(Location),
+        /// Another request with the same idempotency key has not finished yet.
+        InProgress,
+    }
+
+    /// State of an idempotency key after trying to claim it.
+    enum Claim {
+        Claimed,
+        Replay(Location),
+        InProgress,
+        /// Redis is unreachable; the request goes ahead without deduplication.
+        Unavailable,
+    }
+
+    #[derive(Debug)]
+    pub struct RecordedBatch {
+        pub stored: Vec<Location>,
+        /// Positions, within the submitted batch, of fixes logged to `rejected_locations`.
+        pub rejected: Vec<usize>,
+        /// Positions of fixes whose `seq` was already stored, earlier in the batch or before.
+        pub duplicates: Vec<usize>,
+    }
+
+    /// A fix that failed plausibility checks against the one before it.
+    struct Implausible {
+        location: Location,
+        implied_speed_kmh: f64,
+    }
+
+    /// Why a user data erasure did not complete. It is safe to retry either way.
+    #[derive(Debug)]
+    pub enum ErasureError {
+        Storage(sqlx::Error),
+        /// The database rows are gone but cached keys may remain.
+        Cache(redis::RedisError),
+    }
+
+    /// Why a tracked-user listing failed.
+    #[derive(Debug)]
+    pub enum TrackedUsersError {
+        Storage(sqlx::Error),
+        /// Last-seen times could not be read.
+        Cache(redis::RedisError),
+    }
+
+    /// Members of the shared last-seen set read per round trip while looking for a tenant's users.
+    const LAST_SEEN_SCAN_CHUNK: isize = 1000;
+
+    /// A (tenant, user, UTC day) whose `daily_stats` row needs rebuilding.
+    type DirtyDay = (String, String, NaiveDate);
+
+    #[derive(Debug)]
+    pub struct TrackingService {
+        db_pool: Pool<Postgres>,
+        redis_client: RedisClient,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+        aggregation_tx: mpsc::Sender<DirtyDay>,
+        /// Taken by [`TrackingService::start_data_aggregation`].
+        aggregation_rx: Mutex<Option<mpsc::Receiver<DirtyDay>>>,
+        /// Set when a sample was dropped, so the next pass scans the database instead.
+        aggregation_resync: AtomicBool,
+        usage: Arc<UsageService>,
+    }
+
+    /// The users of `tenant_id` among members of the shared last-seen set, keeping their scores.
+    fn tenant_members<'a>(
+        members: Vec<(String, i64)>,
+        tenant_id: &'a str,
+    ) -> impl Iterator<Item = (String, i64)> + 'a {
+        members.into_iter().filter_map(move |(member, last_seen_ms)| match redis_keys::parse_presence_member(&member) {
+            Some((member_tenant, user_id)) if member_tenant == tenant_id => Some((user_id.to_string(), last_seen_ms)),
+            _ => None,
+        })
+    }
+
+    /// The candidates within the query radius, nearest first and at most `limit` of them. The
+    /// result does not depend on how coarsely the candidates were prefiltered.
+    fn rank_nearby(candidates: Vec<Location>, query: &NearbyQuery) -> Vec<NearbyLocation> {
+        let mut users: Vec<NearbyLocation> = candidates
+            .into_iter()
+            .map(|location| NearbyLocation {
+                distance_meters: haversine_meters(query.latitude, query.longitude, location.latitude, location.longitude),
+                location,
+            })
+            .filter(|nearby| nearby.distance_meters <= query.radius_meters)
+            .collect();
+        users.sort_by(|a, b| a.distance_meters.total_cmp(&b.distance_meters));
+        users.truncate(query.limit);
+        users
+    }
+
+    fn location_geohash(location: &Location) -> Option<String> {
+        geohash::encode(location.latitude, location.longitude, LOCATION_GEOHASH_PRECISION).ok()
+    }
+
+    fn push_time_range(builder: &mut QueryBuilder<'_, Postgres>, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) {
+        match (from, to) {
+            (Some(from), Some(to)) => {
+                builder.push(" AND timestamp BETWEEN ").push_bind(from).push(" AND ").push_bind(to);
+            }
+            (Some(from), None) => {
+                builder.push(" AND timestamp >= ").push_bind(from);
+            }
+            (None, Some(to)) => {
+                builder.push(" AND timestamp <= ").push_bind(to);
+            }
+            (None, None) => {}
+        }
+    }
+
+    async fn insert_rejected(conn: &mut PgConnection, rejected: &[Implausible]) -> Result<(), sqlx::Error> {
+        if rejected.is_empty() {
+            return Ok(());
+        }
+
+        let mut builder = QueryBuilder::<Postgres>::new(
+            "INSERT INTO rejected_locations (id, tenant_id, user_id, latitude, longitude, accuracy, timestamp, reason, implied_speed_kmh) ",
+        );
+        builder.push_values(rejected, |mut row, rejected| {
+            row.push_bind(rejected.location.id)
+                .push_bind(&rejected.location.tenant_id)
+                .push_bind(&rejected.location.user_id)
+                .push_bind(rejected.location.latitude)
+                .push_bind(rejected.location.longitude)
+                .push_bind(rejected.location.accuracy)
+                .push_bind(rejected.location.timestamp)
+                .push_bind(IMPLAUSIBLE_SPEED_REASON)
+                .push_bind(rejected.implied_speed_kmh);
+        });
+        builder.build().execute(conn).await?;
+        Ok(())
+    }
+
+    impl TrackingService {
+        pub fn new(
+            db_pool: Pool<Postgres>,
+            redis_client: RedisClient,
+            config: Arc<Config>,
+            metrics: Arc<Metrics>,
+            usage: Arc<UsageService>,
+        ) -> Self {
+            let (aggregation_tx, aggregation_rx) = mpsc::channel(config.aggregation_queue_capacity);
+            Self {
+                db_pool,
+                redis_client,
+                config,
+                metrics,
+                aggregation_tx,
+                aggregation_rx: Mutex::new(Some(aggregation_rx)),
+                aggregation_resync: AtomicBool::new(false),
+                usage,
+            }
+        }
+
+        /// A fix repeating a stored `seq` is not stored again; the original is replayed instead.
+        pub async fn record_location(&self, request: TrackLocationRequest) -> Result<Recorded, sqlx::Error> {
+            let location = self.stored_location(request);
+            if let Some(seq) = location.seq {
+                if let Some(original) = self.location_by_seq(&location.tenant_id, &location.user_id, seq).await? {
+                    return Ok(Recorded::Replayed(original));
+                }
+            }
+
+            let previous = self.cached_current_location(&location.tenant_id, &location.user_id).await;
+            if let Some(implied_speed_kmh) = previous.and_then(|previous| self.implausible_speed(&previous, &location)) {
+                let message = format!(
+                    "implied speed of {:.0} km/h exceeds the limit of {:.0} km/h",
+                    implied_speed_kmh, self.config.max_implied_speed_kmh
+                );
+                let mut conn = self.db_pool.acquire().await?;
+                insert_rejected(&mut conn, &[Implausible { location, implied_speed_kmh }]).await?;
+                return Ok(Recorded::Rejected { reason: IMPLAUSIBLE_SPEED_REASON, message });
+            }
+
+            let inserted = sqlx::query(
+                "INSERT INTO locations (id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status, geohash)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+                 ON CONFLICT (tenant_id, user_id, seq) WHERE seq IS NOT NULL DO NOTHING",
+            )
+            .bind(location.id)
+            .bind(&location.tenant_id)
+            .bind(&location.user_id)
+            .bind(location.latitude)
+            .bind(location.longitude)
+            .bind(location.altitude)
+            .bind(location.accuracy)
+            .bind(location.speed)
+            .bind(location.heading)
+            .bind(location.battery)
+            .bind(location.seq)
+            .bind(location.timestamp)
+            .bind(location.timestamp_status.as_str())
+            .bind(location_geohash(&location))
+            .execute(&self.db_pool)
+            .await?
+            .rows_affected();
+            // A retransmission of the same `seq` won the race since the check above.
+            if inserted == 0 {
+                let seq = location.seq.unwrap_or_default();
+                if let Some(original) = self.location_by_seq(&location.tenant_id, &location.user_id, seq).await? {
+                    return Ok(Recorded::Replayed(original));
+                }
+            }
+
+            self.cache_current_location(&location).await;
+            self.mark_active(&location.tenant_id, &location.user_id).await;
+            self.record_last_seen(&location.tenant_id, &location.user_id, location.battery).await;
+            self.enqueue_for_aggregation(std::slice::from_ref(&location)).await;
+            self.usage.record(&location.tenant_id, 1).await;
+
+            Ok(Recorded::Stored(location))
+        }
+
+        /// Like [`record_location`](Self::record_location), but stores at most one fix per
+        /// idempotency key. The key is claimed with `SET NX` before inserting, so concurrent
+        /// retries cannot both insert, and afterwards holds the stored fix for
+        /// `idempotency_ttl_secs`. A rejected or failed request releases the key so it can be retried.
+        pub async fn record_location_once(&self, request: TrackLocationRequest, key: &str) -> Result<Recorded, sqlx::Error> {
+            let redis_key = redis_keys::track_idempotency(&request.tenant_id, &request.user_id, key);
+            match self.claim_idempotency_key(&redis_key).await {
+                Claim::Claimed => {}
+                Claim::Replay(location) => return Ok(Recorded::Replayed(location)),
+                Claim::InProgress => return Ok(Recorded::InProgress),
+                Claim::Unavailable => return self.record_location(request).await,
+            }
+
+            let recorded = self.record_location(request).await;
+            let result: redis::RedisResult<()> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                match &recorded {
+                    Ok(Recorded::Stored(location)) => {
+                        let payload = serde_json::to_string(location).unwrap_or_default();
+                        conn.set_ex(&redis_key, payload, self.config.idempotency_ttl_secs as usize).await
+                    }
+                    _ => conn.del(&redis_key).await,
+                }
+            }
+            .await;
+            if let Err(e) = result {
+                warn!("Failed to settle idempotency key {}: {}", redis_key, e);
+            }
+            recorded
+        }
+
+        async fn claim_idempotency_key(&self, redis_key: &str) -> Claim {
+            let result: redis::RedisResult<(bool, Option<String>)> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                let claimed: Option<String> = redis::cmd("SET")
+                    .arg(redis_key)
+                    .arg(IDEMPOTENCY_PENDING)
+                    .arg("NX")
+                    .arg("EX")
+                    .arg(self.config.idempotency_ttl_secs)
+                    .query_async(&mut conn)
+                    .await?;
+                if claimed.is_some() {
+                    return Ok((true, None));
+                }
+                Ok((false, conn.get(redis_key).await?))
+            }
+            .await;
+
+            match result {
+                Ok((true, _)) => Claim::Claimed,
+                Ok((false, Some(payload))) if payload != IDEMPOTENCY_PENDING => {
+                    match serde_json::from_str(&payload) {
+                        Ok(location) => Claim::Replay(location),
+                        Err(e) => {
+                            warn!("Unreadable idempotency record {}: {}", redis_key, e);
+                            Claim::InProgress
+                        }
+                    }
+                }
+                // Still pending, or expired between SET and GET.
+                Ok((false, _)) => Claim::InProgress,
+                Err(e) => {
+                    warn!("Idempotency check failed, recording without it: {}", e);
+                    Claim::Unavailable
+                }
+            }
+        }
+
+        /// Inserts a batch of fixes in a single transaction. Fixes are stored in the order given,
+        /// regardless of their timestamps, and each is checked for plausibility against the last
+        /// accepted fix before it (or the cached current location for the first one). Fixes
+        /// repeating a stored `seq` are skipped as duplicates before any check.
+        pub async fn record_locations(&self, requests: Vec<TrackLocationRequest>) -> Result<RecordedBatch, sqlx::Error> {
+            let mut batch = RecordedBatch {
+                stored: Vec::with_capacity(requests.len()),
+                rejected: Vec::new(),
+                duplicates: Vec::new(),
+            };
+            let Some((tenant_id, user_id)) = requests.first().map(|request| (request.tenant_id.clone(), request.user_id.clone())) else {
+                return Ok(batch);
+            };
+
+            let seqs: Vec<i64> = requests.iter().filter_map(|request| request.seq).collect();
+            let mut seen_seqs: HashSet<i64> = if seqs.is_empty() {
+                HashSet::new()
+            } else {
+                sqlx::query_scalar("SELECT seq FROM locations WHERE tenant_id = $1 AND user_id = $2 AND seq = ANY($3)")
+                    .bind(&tenant_id)
+                    .bind(&user_id)
+                    .bind(&seqs)
+                    .fetch_all(&self.db_pool)
+                    .await?
+                    .into_iter()
+                    .collect()
+            };
+
+            let mut previous = self.cached_current_location(&tenant_id, &user_id).await;
+            let mut implausible = Vec::new();
+            let mut stored_positions = Vec::with_capacity(requests.len());
+            for (index, request) in requests.into_iter().enumerate() {
+                let location = self.stored_location(request);
+                if location.seq.is_some_and(|seq| !seen_seqs.insert(seq)) {
+                    batch.duplicates.push(index);
+                    continue;
+                }
+                match previous.as_ref().and_then(|previous| self.implausible_speed(previous, &location)) {
+                    Some(implied_speed_kmh) => {
+                        batch.rejected.push(index);
+                        implausible.push(Implausible { location, implied_speed_kmh });
+                    }
+                    None => {
+                        previous = Some(location.clone());
+                        batch.stored.push(location);
+                        stored_positions.push(index);
+                    }
+                }
+            }
+
+            let mut tx = self.db_pool.begin().await?;
+            if !batch.stored.is_empty() {
+                let mut builder = QueryBuilder::<Postgres>::new(
+                    "INSERT INTO locations (id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status, geohash) ",
+                );
+                builder.push_values(&batch.stored, |mut row, location| {
+                    row.push_bind(location.id)
+                        .push_bind(&location.tenant_id)
+                        .push_bind(&location.user_id)
+                        .push_bind(location.latitude)
+                        .push_bind(location.longitude)
+                        .push_bind(location.altitude)
+                        .push_bind(location.accuracy)
+                        .push_bind(location.speed)
+                        .push_bind(location.heading)
+                        .push_bind(location.battery)
+                        .push_bind(location.seq)
+                        .push_bind(location.timestamp)
+                        .push_bind(location.timestamp_status.as_str())
+                        .push_bind(location_geohash(location));
+                });
+                builder.push(" ON CONFLICT (tenant_id, user_id, seq) WHERE seq IS NOT NULL DO NOTHING RETURNING id");
+                let inserted: HashSet<Uuid> = builder.build_query_scalar().fetch_all(&mut *tx).await?.into_iter().collect();
+
+                // Retransmissions stored concurrently since the lookup above.
+                if inserted.len() < batch.stored.len() {
+                    let (stored, raced): (Vec<_>, Vec<_>) = std::mem::take(&mut batch.stored)
+                        .into_iter()
+                        .zip(stored_positions)
+                        .partition(|(location, _)| inserted.contains(&location.id));
+                    batch.stored = stored.into_iter().map(|(location, _)| location).collect();
+                    batch.duplicates.extend(raced.into_iter().map(|(_, index)| index));
+                    batch.duplicates.sort_unstable();
+                }
+            }
+            insert_rejected(&mut tx, &implausible).await?;
+            tx.commit().await?;
+
+            if let Some(latest) = batch.stored.iter().max_by_key(|location| location.timestamp) {
+                self.cache_current_location(latest).await;
+                self.mark_active(&tenant_id, &user_id).await;
+                let battery = batch
+                    .stored
+                    .iter()
+                    .filter(|location| location.battery.is_some())
+                    .max_by_key(|location| location.timestamp)
+                    .and_then(|location| location.battery);
+                self.record_last_seen(&tenant_id, &user_id, battery).await;
+            }
+            self.enqueue_for_aggregation(&batch.stored).await;
+            self.usage.record(&tenant_id, batch.stored.len() as u64).await;
+
+            Ok(batch)
+        }
+
+        /// The fix a request is stored as: with `coordinate_precision_strict`, already rounded.
+        fn stored_location(&self, request: TrackLocationRequest) -> Location {
+            let mut location = request.into_location();
+            if self.config.coordinate_precision_strict {
+                self.config.coordinate_precision.apply(&mut location);
+            }
+            location
+        }
+
+        /// A fix as it is handed out, at the configured coordinate precision.
+        pub fn reported(&self, mut location: Location) -> Location {
+            self.config.coordinate_precision.apply(&mut location);
+            location
+        }
+
+        /// Speed in km/h implied by moving from `previous` to `next`, if it exceeds the configured
+        /// limit. Fixes that are not newer than `previous` cannot be judged and always pass; time
+        /// deltas under a second are rounded up so GPS jitter between rapid fixes is not flagged.
+        /// Fixes rounded before storage may each have moved by the rounding, which is forgiven.
+        fn implausible_speed(&self, previous: &Location, next: &Location) -> Option<f64> {
+            let elapsed_ms = (next.timestamp - previous.timestamp).num_milliseconds();
+            if elapsed_ms <= 0 {
+                return None;
+            }
+            let elapsed_secs = (elapsed_ms as f64 / 1000.0).max(1.0);
+            let rounding_meters = if self.config.coordinate_precision_strict {
+                2.0 * self.config.coordinate_precision.max_shift_meters()
+            } else {
+                0.0
+            };
+            let distance_meters = (haversine_distance(previous, next) - rounding_meters).max(0.0);
+            let implied_speed_kmh = distance_meters / elapsed_secs * 3.6;
+            (implied_speed_kmh > self.config.max_implied_speed_kmh).then_some(implied_speed_kmh)
+        }
+
+        /// Latest fix for a user, served from Redis when cached and from Postgres otherwise.
+        pub async fn current_location(&self, tenant_id: &str, user_id: &str) -> Result<Option<Location>, sqlx::Error> {
+            if let Some(location) = self.cached_current_location(tenant_id, user_id).await {
+                return Ok(Some(self.reported(location)));
+            }
+
+            let location = sqlx::query_as::<_, Location>(
+                "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                 FROM locations WHERE tenant_id = $1 AND user_id = $2 ORDER BY timestamp DESC, seq DESC NULLS LAST LIMIT 1",
+            )
+            .bind(tenant_id)
+            .bind(user_id)
+            .fetch_optional(&self.db_pool)
+            .await?;
+
+            if let Some(location) = &location {
+                self.cache_current_location(location).await;
+            }
+
+            Ok(location.map(|location| self.reported(location)))
+        }
+
+        /// The fix closest to the requested instant (or the last one before it), `None` when the user
+        /// has no fix that qualifies. With `interpolate`, an instant between two fixes gets a position
+        /// estimated between them instead. Gaps above `location_at_max_gap_secs` are marked uncertain.
+        pub async fn location_at(&self, tenant_id: &str, user_id: &str, query: &LocationAtQuery) -> Result<Option<LocationAt>, sqlx::Error> {
+            let before = sqlx::query_as::<_, Location>(
+                "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                 FROM locations WHERE tenant_id = $1 AND user_id = $2 AND timestamp <= $3
+                 ORDER BY timestamp DESC, seq DESC NULLS LAST, id DESC LIMIT 1",
+            )
+            .bind(tenant_id)
+            .bind(user_id)
+            .bind(query.timestamp)
+            .fetch_optional(&self.db_pool)
+            .await?;
+
+            let after = match query.mode {
+                LocationAtMode::Before => None,
+                // An exact hit cannot be beaten.
+                LocationAtMode::Nearest if before.as_ref().is_some_and(|fix| fix.timestamp == query.timestamp) => None,
+                LocationAtMode::Nearest => {
+                    sqlx::query_as::<_, Location>(
+                        "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                         FROM locations WHERE tenant_id = $1 AND user_id = $2 AND timestamp > $3
+                         ORDER BY timestamp, seq, id LIMIT 1",
+                    )
+                    .bind(tenant_id)
+                    .bind(user_id)
+                    .bind(query.timestamp)
+                    .fetch_optional(&self.db_pool)
+                    .await?
+                }
+            };
+
+            let gap = |fix: &Location| (fix.timestamp - query.timestamp).num_milliseconds().abs();
+            let (location, gap_ms, interpolated) = match (before, after) {
+                (Some(before), Some(after)) if query.interpolate => {
+                    let gap_ms = gap(&before).min(gap(&after));
+                    (interpolate::at_time(&before, &after, query.timestamp), gap_ms, true)
+                }
+                (Some(before), Some(after)) if gap(&after) < gap(&before) => {
+                    let gap_ms = gap(&after);
+                    (after, gap_ms, false)
+                }
+                (Some(fix), _) | (None, Some(fix)) => {
+                    let gap_ms = gap(&fix);
+                    (fix, gap_ms, false)
+                }
+                (None, None) => return Ok(None),
+            };
+
+            let gap_secs = gap_ms as f64 / 1000.0;
+            Ok(Some(LocationAt {
+                requested_at: query.timestamp,
+                location: self.reported(location),
+                gap_secs,
+                uncertain: gap_secs > self.config.location_at_max_gap_secs as f64,
+                interpolated,
+            }))
+        }
+
+        /// Streams every fix of a user within the export window, oldest first. Rows are read from a
+        /// database cursor on a background task and handed over through a small bounded channel,
+        /// so memory stays flat however long the history is. The stream ends after the first error.
+        /// A simplified export is simplified in chunks of [`EXPORT_SIMPLIFY_CHUNK_ROWS`] fixes, each
+        /// within the tolerance, to keep that bound.
+        pub fn export_locations(
+            &self,
+            tenant_id: String,
+            user_id: String,
+            query: ExportQuery,
+        ) -> mpsc::Receiver<Result<Location, sqlx::Error>> {
+            let (tx, rx) = mpsc::channel(EXPORT_BUFFER_ROWS);
+            let db_pool = self.db_pool.clone();
+            let precision = self.config.coordinate_precision;
+            let reported = move |mut location: Location| {
+                precision.apply(&mut location);
+                location
+            };
+
+            tokio::spawn(async move {
+                let mut builder = QueryBuilder::<Postgres>::new(
+                    "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                     FROM locations WHERE tenant_id = ",
+                );
+                builder.push_bind(tenant_id).push(" AND user_id = ").push_bind(user_id);
+                push_time_range(&mut builder, query.from, query.to);
+                builder.push(" ORDER BY timestamp, seq, id");
+
+                let mut rows = builder.build_query_as::<Location>().fetch(&db_pool);
+                let Some(epsilon) = query.simplify else {
+                    while let Some(row) = rows.next().await {
+                        let row = row.map(reported);
+                        let failed = row.is_err();
+                        // A closed channel means the client went away.
+                        if tx.send(row).await.is_err() || failed {
+                            break;
+                        }
+                    }
+                    return;
+                };
+
+                let mut chunk = Vec::new();
+                while let Some(row) = rows.next().await {
+                    match row {
+                        Ok(location) => chunk.push(location),
+                        Err(e) => {
+                            let _ = tx.send(Err(e)).await;
+                            return;
+                        }
+                    }
+                    if chunk.len() >= EXPORT_SIMPLIFY_CHUNK_ROWS {
+                        let mut simplified = douglas_peucker(&chunk, epsilon);
+                        // The last kept fix opens the next chunk instead of being sent twice.
+                        chunk = simplified.pop().into_iter().collect();
+                        for location in simplified {
+                            if tx.send(Ok(reported(location))).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+                for location in douglas_peucker(&chunk, epsilon) {
+                    if tx.send(Ok(reported(location))).await.is_err() {
+                        return;
+                    }
+                }
+            });
+
+            rx
+        }
+
+        /// One page of a user's history, newest first. Fetches one extra row to decide whether a
+        /// further page exists. A requested total is counted with a window function over the
+        /// filtered rows before the cursor narrows them down, so it covers every page.
+        pub async fn location_history(
+            &self,
+            tenant_id: &str,
+            user_id: &str,
+            query: &HistoryQuery,
+        ) -> Result<Paginated<Location>, sqlx::Error> {
+            let mut builder = QueryBuilder::<Postgres>::new(format!(
+                "SELECT * FROM (
+                     SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status,
+                            {} AS total
+                     FROM locations WHERE tenant_id = ",
+                if query.include_total { "COUNT(*) OVER ()" } else { "NULL::BIGINT" }
+            ));
+            builder.push_bind(tenant_id).push(" AND user_id = ").push_bind(user_id);
+            push_time_range(&mut builder, query.from, query.to);
+            builder.push(") AS filtered WHERE TRUE");
+            if let Some(cursor) = &query.before {
+                builder
+                    .push(" AND (timestamp, COALESCE(seq, -1), id) < (")
+                    .push_bind(cursor.timestamp)
+                    .push(", ")
+                    .push_bind(cursor.seq_rank())
+                    .push(", ")
+                    .push_bind(cursor.id)
+                    .push(")");
+            }
+            builder
+                .push(" ORDER BY timestamp DESC, COALESCE(seq, -1) DESC, id DESC LIMIT ")
+                .push_bind(query.limit + 1);
+
+            let rows = builder.build().fetch_all(&self.db_pool).await?;
+            let mut total = match rows.first() {
+                Some(row) => row.try_get::<Option<i64>, _>("total")?,
+                None => None,
+            };
+            let mut locations = rows.iter().map(Location::from_row).collect::<Result<Vec<_>, _>>()?;
+
+            // A page past the end has no rows to carry the windowed count.
+            if query.include_total && total.is_none() {
+                let mut count = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM locations WHERE tenant_id = ");
+                count.push_bind(tenant_id).push(" AND user_id = ").push_bind(user_id);
+                push_time_range(&mut count, query.from, query.to);
+                total = Some(count.build_query_scalar::<i64>().fetch_one(&self.db_pool).await?);
+            }
+
+            let next_cursor = if locations.len() as i64 > query.limit {
+                locations.truncate(query.limit as usize);
+                locations.last().map(|last| HistoryCursor::after(last).encode())
+            } else {
+                None
+            };
+            // After the cursor is taken, so simplification never skips rows between pages.
+            if let Some(epsilon) = query.simplify {
+                locations = douglas_peucker(&locations, epsilon);
+            }
+
+            Ok(Paginated {
+                data: locations.into_iter().map(|location| self.reported(location)).collect(),
+                page: PageInfo {
+                    limit: query.limit,
+                    next_cursor,
+                    total,
+                },
+            })
+        }
+
+        /// Groups the current fixes inside the box by the query's grid cell. Cells holding one user
+        /// come back as that user's point; the rest with counts, at the users' centroid for
+        /// geohash cells and at the cell center for H3 ones. Fixes older than
+        /// `nearby_max_age_secs` are not considered current.
+        pub async fn clusters(&self, tenant_id: &str, query: &ClusterQuery) -> Result<ClusterResult, sqlx::Error> {
+            let since = Utc::now() - chrono::Duration::seconds(self.config.nearby_max_age_secs as i64);
+            let (clusters, mut points, truncated) = match query.grid {
+                Grid::Geohash { precision } => self.geohash_clusters(tenant_id, query, precision, since).await?,
+                Grid::H3 { resolution } => self.h3_clusters(tenant_id, query, resolution, since).await?,
+            };
+            for point in &mut points {
+                self.config.coordinate_precision.apply(point);
+            }
+
+            Ok(ClusterResult { zoom: query.zoom, grid: query.grid, clusters, points, truncated })
+        }
+
+        /// Clusters grouped in Postgres by geohash prefix.
+        async fn geohash_clusters(
+            &self,
+            tenant_id: &str,
+            query: &ClusterQuery,
+            precision: usize,
+            since: DateTime<Utc>,
+        ) -> Result<(Vec<LocationCluster>, Vec<ClusterPoint>, bool), sqlx::Error> {
+            let mut cells = sqlx::query_as::<_, (String, i64, f64, f64, String, DateTime<Utc>)>(
+                "SELECT LEFT(geohash, $1) AS cell, COUNT(*) AS count, AVG(latitude), AVG(longitude),
+                        MIN(user_id), MAX(timestamp)
+                 FROM (
+                     SELECT DISTINCT ON (user_id) user_id, latitude, longitude, geohash, timestamp
+                     FROM locations
+                     WHERE tenant_id = $2 AND timestamp > $3
+                     ORDER BY user_id, timestamp DESC, seq DESC NULLS LAST, id DESC
+                 ) latest
+                 WHERE geohash IS NOT NULL
+                   AND longitude BETWEEN $4 AND $5
+                   AND latitude BETWEEN $6 AND $7
+                 GROUP BY cell
+                 ORDER BY count DESC, cell
+                 LIMIT $8",
+            )
+            .bind(precision as i32)
+            .bind(tenant_id)
+            .bind(since)
+            .bind(query.bbox.min_longitude)
+            .bind(query.bbox.max_longitude)
+            .bind(query.bbox.min_latitude)
+            .bind(query.bbox.max_latitude)
+            .bind(MAX_CLUSTERS as i64 + 1)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+            let truncated = cells.len() > MAX_CLUSTERS;
+            cells.truncate(MAX_CLUSTERS);
+
+            let mut clusters = Vec::new();
+            let mut points = Vec::new();
+            for (cell, count, latitude, longitude, user_id, timestamp) in cells {
+                if count == 1 {
+                    points.push(ClusterPoint { user_id, latitude, longitude, timestamp });
+                } else {
+                    clusters.push(LocationCluster { cell: CellId::Geohash(cell), latitude, longitude, count });
+                }
+            }
+
+            Ok((clusters, points, truncated))
+        }
+
+        /// Clusters grouped here, as Postgres knows nothing of H3: every current fix inside the box
+        /// is loaded, one per user.
+        async fn h3_clusters(
+            &self,
+            tenant_id: &str,
+            query: &ClusterQuery,
+            resolution: u8,
+            since: DateTime<Utc>,
+        ) -> Result<(Vec<LocationCluster>, Vec<ClusterPoint>, bool), sqlx::Error> {
+            let fixes = sqlx::query_as::<_, (String, f64, f64, DateTime<Utc>)>(
+                "SELECT user_id, latitude, longitude, timestamp
+                 FROM (
+                     SELECT DISTINCT ON (user_id) user_id, latitude, longitude, timestamp
+                     FROM locations
+                     WHERE tenant_id = $1 AND timestamp > $2
+                     ORDER BY user_id, timestamp DESC, seq DESC NULLS LAST, id DESC
+                 ) latest
+                 WHERE longitude BETWEEN $3 AND $4
+                   AND latitude BETWEEN $5 AND $6",
+            )
+            .bind(tenant_id)
+            .bind(since)
+            .bind(query.bbox.min_longitude)
+            .bind(query.bbox.max_longitude)
+            .bind(query.bbox.min_latitude)
+            .bind(query.bbox.max_latitude)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+            let mut cells: HashMap<h3::CellIndex, Vec<ClusterPoint>> = HashMap::new();
+            for (user_id, latitude, longitude, timestamp) in fixes {
+                if let Some(cell) = h3::cell(latitude, longitude, resolution) {
+                    cells.entry(cell).or_default().push(ClusterPoint { user_id, latitude, longitude, timestamp });
+                }
+            }
+            let mut cells: Vec<_> = cells.into_iter().collect();
+            cells.sort_unstable_by(|(a, a_points), (b, b_points)| b_points.len().cmp(&a_points.len()).then(a.cmp(b)));
+            let truncated = cells.len() > MAX_CLUSTERS;
+            cells.truncate(MAX_CLUSTERS);
+
+            let mut clusters = Vec::new();
+            let mut points = Vec::new();
+            for (cell, mut members) in cells {
+                if members.len() == 1 {
+                    points.append(&mut members);
+                } else {
+                    let (latitude, longitude) = h3::center(cell);
+                    let count = members.len() as i64;
+                    clusters.push(LocationCluster { cell: CellId::H3(cell.to_string()), latitude, longitude, count });
+                }
+            }
+
+            Ok((clusters, points, truncated))
+        }
+
+        /// Latest fix of every user whose current position is within the query radius, nearest
+        /// first. Geohash prefixes narrow the candidates before exact Haversine filtering; fixes
+        /// older than `nearby_max_age_secs` are not considered current.
+        pub async fn nearby_locations(&self, tenant_id: &str, query: &NearbyQuery) -> Result<NearbyResult, sqlx::Error> {
+            let patterns: Vec<String> = geohash::covering(query.latitude, query.longitude, query.radius_meters)
+                .into_iter()
+                .map(|prefix| format!("{}%", prefix))
+                .collect();
+
+            let candidates = sqlx::query_as::<_, Location>(
+                "SELECT DISTINCT ON (l.user_id) l.id, l.tenant_id, l.user_id, l.latitude, l.longitude, l.altitude, l.accuracy,
+                        l.speed, l.heading, l.battery, l.seq, l.timestamp, l.timestamp_status
+                 FROM locations l
+                 WHERE l.tenant_id = $1 AND l.geohash LIKE ANY($2) AND l.timestamp > $3
+                   AND NOT EXISTS (
+                       SELECT 1 FROM locations newer
+                       WHERE newer.tenant_id = l.tenant_id AND newer.user_id = l.user_id AND newer.timestamp > l.timestamp
+                   )
+                 ORDER BY l.user_id, l.timestamp DESC",
+            )
+            .bind(tenant_id)
+            .bind(&patterns)
+            .bind(Utc::now() - chrono::Duration::seconds(self.config.nearby_max_age_secs as i64))
+            .fetch_all(&self.db_pool)
+            .await?;
+
+            let mut users = rank_nearby(candidates, query);
+            for nearby in &mut users {
+                self.config.coordinate_precision.apply(&mut nearby.location);
+            }
+
+            Ok(NearbyResult {
+                latitude: query.latitude,
+                longitude: query.longitude,
+                radius_meters: query.radius_meters,
+                users,
+            })
+        }
+
+        async fn cached_current_location(&self, tenant_id: &str, user_id: &str) -> Option<Location> {
+            let result: redis::RedisResult<Option<String>> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                conn.get(redis_keys::current_location(tenant_id, user_id)).await
+            }
+            .await;
+
+            match result {
+                Ok(Some(payload)) => serde_json::from_str(&payload).ok(),
+                Ok(None) => None,
+                Err(e) => {
+                    warn!("Redis lookup for current location failed: {}", e);
+                    None
+                }
+            }
+        }
+
+        /// Compare-and-set on the fix timestamp: an older fix than the cached one is left out.
+        async fn cache_current_location(&self, location: &Location) {
+            let payload = match serde_json::to_string(location) {
+                Ok(payload) => payload,
+                Err(_) => return,
+            };
+
+            let result: redis::RedisResult<i64> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                Script::new(CACHE_IF_NEWER_SCRIPT)
+                    .key(redis_keys::current_location(&location.tenant_id, &location.user_id))
+                    .key(redis_keys::current_location_timestamp(&location.tenant_id, &location.user_id))
+                    .arg(payload)
+                    .arg(location.timestamp.timestamp_millis())
+                    .arg(self.config.current_location_ttl_secs)
+                    .invoke_async(&mut conn)
+                    .await
+            }
+            .await;
+
+            match result {
+                Ok(0) => debug!("Kept newer cached location for {} over fix at {}", location.user_id, location.timestamp),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to cache current location: {}", e),
+            }
+        }
+
+        async fn mark_active(&self, tenant_id: &str, user_id: &str) {
+            let ttl_secs = self.config.active_users_bucket_ttl_secs;
+            if let Err(e) = super::analytics_service::mark_active(&self.redis_client, tenant_id, user_id, ttl_secs).await {
+                warn!("Failed to mark user active: {}", e);
+            }
+        }
+
+        /// Stamps the user as seen now, keeping the previous battery level when the report has none.
+        async fn record_last_seen(&self, tenant_id: &str, user_id: &str, battery: Option<f32>) {
+            let key = redis_keys::last_seen(tenant_id, user_id);
+            let now_ms = Utc::now().timestamp_millis();
+            let mut pipe = redis::pipe();
+            pipe.hset(&key, "reported_at", now_ms).ignore();
+            pipe.zadd(redis_keys::PRESENCE_LAST_SEEN, redis_keys::presence_member(tenant_id, user_id), now_ms).ignore();
+            if let Some(battery) = battery {
+                pipe.hset(&key, "battery", battery).ignore();
+            }
+
+            let result: redis::RedisResult<()> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                pipe.query_async(&mut conn).await
+            }
+            .await;
+            if let Err(e) = result {
+                warn!("Failed to record last seen for {}: {}", user_id, e);
+            }
+        }
+
+        /// When the user last reported and whether that is recent enough to count as online, or
+        /// `None` for a user never seen.
+        pub async fn user_status(&self, tenant_id: &str, user_id: &str) -> redis::RedisResult<Option<UserStatus>> {
+            let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+            let (reported_at, battery): (Option<i64>, Option<f32>) =
+                conn.hget(redis_keys::last_seen(tenant_id, user_id), &["reported_at", "battery"]).await?;
+
+            let Some(last_seen) = reported_at.and_then(DateTime::from_timestamp_millis) else {
+                return Ok(None);
+            };
+            let staleness = chrono::Duration::seconds(self.config.presence_staleness_secs as i64);
+            Ok(Some(UserStatus {
+                user_id: user_id.to_string(),
+                last_seen,
+                online: Utc::now() - last_seen <= staleness,
+                battery,
+                diagnostics: None,
+            }))
+        }
+
+        /// A page of the tenant's users ordered by last report, with their latest fix. The shared
+        /// last-seen set is walked in score order, keeping the tenant's members, until the page
+        /// is filled. Fixes not in the current-location cache are read in one query.
+        pub async fn tracked_users(&self, tenant_id: &str, query: &TrackedUsersQuery) -> Result<Paginated<TrackedUser>, TrackedUsersError> {
+            let staleness = chrono::Duration::seconds(self.config.presence_staleness_secs as i64);
+            let now = Utc::now();
+            let min_score = if query.online_only {
+                format!("({}", (now - staleness).timestamp_millis())
+            } else {
+                "-inf".to_string()
+            };
+            let wanted = (query.offset + query.limit + 1) as usize;
+
+            let mut conn = self.redis_client.get_multiplexed_async_connection().await.map_err(TrackedUsersError::Cache)?;
+            let mut matched: Vec<(String, i64)> = Vec::new();
+            let mut start = 0;
+            loop {
+                let mut command = match query.order {
+                    LastSeenOrder::NewestFirst => {
+                        let mut command = redis::cmd("ZREVRANGEBYSCORE");
+                        command.arg(redis_keys::PRESENCE_LAST_SEEN).arg("+inf").arg(&min_score);
+                        command
+                    }
+                    LastSeenOrder::OldestFirst => {
+                        let mut command = redis::cmd("ZRANGEBYSCORE");
+                        command.arg(redis_keys::PRESENCE_LAST_SEEN).arg(&min_score).arg("+inf");
+                        command
+                    }
+                };
+                let chunk: Vec<(String, i64)> = command
+                    .arg("WITHSCORES")
+                    .arg("LIMIT")
+                    .arg(start)
+                    .arg(LAST_SEEN_SCAN_CHUNK)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(TrackedUsersError::Cache)?;
+                let exhausted = (chunk.len() as isize) < LAST_SEEN_SCAN_CHUNK;
+                matched.extend(tenant_members(chunk, tenant_id));
+                if exhausted || matched.len() >= wanted {
+                    break;
+                }
+                start += LAST_SEEN_SCAN_CHUNK;
+            }
+
+            let mut page: Vec<(String, i64)> = matched.into_iter().skip(query.offset as usize).collect();
+            let next_cursor = if page.len() as i64 > query.limit {
+                page.truncate(query.limit as usize);
+                Some((query.offset + query.limit).to_string())
+            } else {
+                None
+            };
+
+            let mut pipe = redis::pipe();
+            for (user_id, _) in &page {
+                pipe.hget(redis_keys::last_seen(tenant_id, user_id), "battery");
+                pipe.get(redis_keys::current_location(tenant_id, user_id));
+            }
+            let cached: Vec<(Option<f32>, Option<String>)> = if page.is_empty() {
+                Vec::new()
+            } else {
+                pipe.query_async(&mut conn).await.map_err(TrackedUsersError::Cache)?
+            };
+            let mut locations: HashMap<String, Location> = cached
+                .iter()
+                .filter_map(|(_, payload)| serde_json::from_str::<Location>(payload.as_deref()?).ok())
+                .map(|location| (location.user_id.clone(), location))
+                .collect();
+
+            let uncached: Vec<&str> = page
+                .iter()
+                .map(|(user_id, _)| user_id.as_str())
+                .filter(|user_id| !locations.contains_key(*user_id))
+                .collect();
+            if !uncached.is_empty() {
+                let stored = sqlx::query_as::<_, Location>(
+                    "SELECT DISTINCT ON (user_id)
+                            id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                     FROM locations WHERE tenant_id = $1 AND user_id = ANY($2)
+                     ORDER BY user_id, timestamp DESC, seq DESC NULLS LAST",
+                )
+                .bind(tenant_id)
+                .bind(&uncached)
+                .fetch_all(&self.db_pool)
+                .await
+                .map_err(TrackedUsersError::Storage)?;
+                locations.extend(stored.into_iter().map(|location| (location.user_id.clone(), location)));
+            }
+
+            let data = page
+                .into_iter()
+                .zip(cached)
+                .filter_map(|((user_id, last_seen_ms), (battery, _))| {
+                    let last_seen = DateTime::from_timestamp_millis(last_seen_ms)?;
+                    Some(TrackedUser {
+                        last_location: locations.remove(&user_id).map(|location| self.reported(location)),
+                        user_id,
+                        last_seen,
+                        online: now - last_seen <= staleness,
+                        battery,
+                    })
+                })
+                .collect();
+
+            Ok(Paginated {
+                data,
+                page: PageInfo { limit: query.limit, next_cursor, total: None },
+            })
+        }
+
+        async fn location_by_seq(&self, tenant_id: &str, user_id: &str, seq: i64) -> Result<Option<Location>, sqlx::Error> {
+            sqlx::query_as::<_, Location>(
+                "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                 FROM locations WHERE tenant_id = $1 AND user_id = $2 AND seq = $3",
+            )
+            .bind(tenant_id)
+            .bind(user_id)
+            .bind(seq)
+            .fetch_optional(&self.db_pool)
+            .await
+        }
+
+        /// Counts sequence numbers missing among the user's latest [`SEQUENCE_GAP_WINDOW`]; `None`
+        /// when the user never sent one.
+        pub async fn sequence_diagnostics(&self, tenant_id: &str, user_id: &str) -> Result<Option<SequenceDiagnostics>, sqlx::Error> {
+            let (last_seq, window_start_seq, received): (Option<i64>, Option<i64>, i64) = sqlx::query_as(
+                "WITH latest AS (
+                     SELECT MAX(seq) AS last_seq FROM locations WHERE tenant_id = $1 AND user_id = $2 AND seq IS NOT NULL
+                 )
+                 SELECT latest.last_seq, MIN(l.seq), COUNT(l.seq)
+                 FROM latest
+                 LEFT JOIN locations l
+                     ON l.tenant_id = $1 AND l.user_id = $2 AND l.seq > latest.last_seq - $3 AND l.seq <= latest.last_seq
+                 GROUP BY latest.last_seq",
+            )
+            .bind(tenant_id)
+            .bind(user_id)
+            .bind(SEQUENCE_GAP_WINDOW)
+            .fetch_one(&self.db_pool)
+            .await?;
+
+            Ok(last_seq.zip(window_start_seq).map(|(last_seq, window_start_seq)| SequenceDiagnostics {
+                last_seq,
+                window_start_seq,
+                missing_seq: last_seq - window_start_seq + 1 - received,
+            }))
+        }
+
+        /// Queues the days touched by `locations` for the aggregation loop. When the queue is full
+        /// this either waits or drops the sample, depending on `aggregation_overflow`. Without a
+        /// local loop, the aggregating instance is asked to scan for them instead.
+        async fn enqueue_for_aggregation(&self, locations: &[Location]) {
+            let days: HashSet<DirtyDay> = locations
+                .iter()
+                .map(|location| (location.tenant_id.clone(), location.user_id.clone(), location.timestamp.date_naive()))
+                .collect();
+            self.enqueue_days(days).await;
+        }
+
+        async fn enqueue_days(&self, days: HashSet<DirtyDay>) {
+            if !self.config.data_aggregation_enabled {
+                let flagged: redis::RedisResult<()> = async {
+                    let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                    conn.set(redis_keys::AGGREGATION_RESYNC, 1).await
+                }
+                .await;
+                if let Err(e) = flagged {
+                    warn!("Failed to flag fixes for aggregation: {}", e);
+                }
+                return;
+            }
+
+            for day in days {
+                match self.config.aggregation_overflow {
+                    OverflowPolicy::Block => {
+                        if self.aggregation_tx.send(day).await.is_err() {
+                            break;
+                        }
+                    }
+                    OverflowPolicy::Drop => {
+                        if let Err(TrySendError::Full(_)) = self.aggregation_tx.try_send(day) {
+                            self.metrics.aggregation_samples_dropped_total.inc();
+                            self.aggregation_resync.store(true, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+            self.update_queue_depth();
+        }
+
+        fn update_queue_depth(&self) {
+            let depth = self.aggregation_tx.max_capacity() - self.aggregation_tx.capacity();
+            self.metrics.aggregation_queue_depth.set(depth as i64);
+        }
+
+        /// Drains the aggregation queue into a set of dirty days and rebuilds them every
+        /// `data_aggregation_interval_secs`. If samples were dropped since the last pass, the days
+        /// with fixes since then are also found by scanning `locations`.
+        pub async fn start_data_aggregation(&self) {
+            let Some(mut samples) = self.aggregation_rx.lock().ok().and_then(|mut rx| rx.take()) else {
+                warn!("Data aggregation is already running");
+                return;
+            };
+            let period = Duration::from_secs(self.config.data_aggregation_interval_secs.max(1));
+            let mut interval = tokio::time::interval(period);
+            let mut dirty = HashSet::new();
+            // Recompute today in full on startup so fixes stored while we were down are covered.
+            let mut scan_since = Some(Utc::now().date_naive().and_hms_opt(0, 0, 0).unwrap_or_default().and_utc());
+            let mut last_pass = Utc::now();
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        let pass_started = Utc::now();
+                        if self.aggregation_resync.swap(false, Ordering::Relaxed) || self.take_remote_resync().await {
+                            scan_since.get_or_insert(last_pass);
+                        }
+
+                        match self.aggregate_daily_stats(&dirty, scan_since).await {
+                            Ok(rollups) => {
+                                if rollups > 0 {
+                                    info!("Updated {} daily stats rollups", rollups);
+                                }
+                                dirty.clear();
+                                scan_since = None;
+                                last_pass = pass_started;
+                            }
+                            Err(e) => error!("Daily stats aggregation failed: {}", e),
+                        }
+                    }
+                    sample = samples.recv() => match sample {
+                        Some(day) => {
+                            dirty.insert(day);
+                            self.update_queue_depth();
+                        }
+                        None => return,
+                    },
+                }
+            }
+        }
+
+        /// Whether an instance without aggregation stored fixes since this was last asked.
+        async fn take_remote_resync(&self) -> bool {
+            let taken: redis::RedisResult<u64> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                conn.del(redis_keys::AGGREGATION_RESYNC).await
+            }
+            .await;
+            match taken {
+                Ok(deleted) => deleted > 0,
+                Err(e) => {
+                    warn!("Failed to check for fixes flagged for aggregation: {}", e);
+                    false
+                }
+            }
+        }
+
+        /// Deletes every stored trace of a user: fixes, rejected fixes, rollups, geofence and
+        /// presence events and presence state in one transaction, then the user's cached Redis
+        /// keys and active-user and presence entries.
+        pub async fn erase_user_data(&self, tenant_id: &str, user_id: &str) -> Result<ErasureResult, ErasureError> {
+            let mut tx = self.db_pool.begin().await.map_err(ErasureError::Storage)?;
+            let mut deleted = [0; 8];
+            for (count, table) in deleted.iter_mut().zip([
+                "locations",
+                "rejected_locations",
+                "daily_stats",
+                "geofence_events",
+                "presence_events",
+                "user_presence",
+                "rolling_stats",
+                "geofence_visits",
+            ]) {
+                *count = sqlx::query(&format!("DELETE FROM {} WHERE tenant_id = $1 AND user_id = $2", table))
+                    .bind(tenant_id)
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(ErasureError::Storage)?
+                    .rows_affected();
+            }
+            tx.commit().await.map_err(ErasureError::Storage)?;
+            let [locations, rejected_locations, daily_stats, geofence_events, presence_events, _, _, _] = deleted;
+
+            let cache_keys = self.erase_cached_user_data(tenant_id, user_id).await.map_err(ErasureError::Cache)?;
+
+            Ok(ErasureResult {
+                user_id: user_id.to_string(),
+                locations,
+                rejected_locations,
+                daily_stats,
+                geofence_events,
+                presence_events,
+                cache_keys,
+            })
+        }
+
+        /// Returns the number of keys deleted; removals from shared active-user buckets and the
+        /// presence set are not counted.
+        async fn erase_cached_user_data(&self, tenant_id: &str, user_id: &str) -> redis::RedisResult<u64> {
+            let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+
+            let mut keys = vec![
+                redis_keys::current_location(tenant_id, user_id),
+                redis_keys::current_location_timestamp(tenant_id, user_id),
+                redis_keys::geofence_membership(tenant_id, user_id),
+                redis_keys::geofence_entered(tenant_id, user_id),
+                redis_keys::geofence_dwelled(tenant_id, user_id),
+                redis_keys::rate_limit_user(tenant_id, user_id),
+                redis_keys::last_seen(tenant_id, user_id),
+                redis_keys::rolling_last_fix(tenant_id, user_id),
+            ];
+            let bucket_secs = self.config.rolling_bucket_secs as i64;
+            let last_bucket = Utc::now().timestamp().div_euclid(bucket_secs);
+            let first_bucket = last_bucket - (self.config.rolling_window_secs as i64) / bucket_secs;
+            keys.extend((first_bucket..=last_bucket).map(|bucket| redis_keys::rolling_bucket(tenant_id, user_id, bucket)));
+            let mut idempotency_keys: redis::AsyncIter<String> =
+                conn.scan_match(redis_keys::track_idempotency_pattern(tenant_id, user_id)).await?;
+            while let Some(key) = idempotency_keys.next_item().await {
+                keys.push(key);
+            }
+            drop(idempotency_keys);
+
+            let mut pipe = redis::pipe();
+            pipe.del(&keys);
+            let member = redis_keys::presence_member(tenant_id, user_id);
+            pipe.zrem(redis_keys::PRESENCE_LAST_SEEN, &member).ignore();
+            let now_minute = Utc::now().timestamp() / 60;
+            let bucket_minutes = (self.config.active_users_bucket_ttl_secs / 60 + 1) as i64;
+            for minute in (now_minute - bucket_minutes)..=now_minute {
+                pipe.srem(redis_keys::active_users_bucket(tenant_id, minute), user_id).ignore();
+                pipe.srem(redis_keys::all_active_users_bucket(minute), &member).ignore();
+            }
+            let (deleted,): (u64,) = pipe.query_async(&mut conn).await?;
+            Ok(deleted)
+        }
+
+        /// Fixes older than this are purged, or `None` when retention is unlimited.
+        fn retention_cutoff(&self) -> Option<DateTime<Utc>> {
+            match self.config.location_retention_days {
+                0 => None,
+                days => Some(Utc::now() - chrono::Duration::days(i64::from(days))),
+            }
+        }
+
+        /// Deletes raw and rejected fixes older than `location_retention_days` every
+        /// `retention_purge_interval_secs`, `retention_purge_batch_size` rows at a time so no
+        /// single delete holds its locks for long. `daily_stats` is left untouched. Returns
+        /// immediately when retention is unlimited.
+        pub async fn start_retention_purge(&self) {
+            if self.config.location_retention_days == 0 {
+                info!("Location retention is unlimited, purge job disabled");
+                return;
+            }
+            let period = Duration::from_secs(self.config.retention_purge_interval_secs.max(1));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let Some(cutoff) = self.retention_cutoff() else { return };
+                for table in ["locations", "rejected_locations"] {
+                    match self.purge_before(table, cutoff).await {
+                        Ok(purged) => info!(
+                            "Retention purge removed {} rows from {} older than {} days (before {})",
+                            purged, table, self.config.location_retention_days, cutoff
+                        ),
+                        Err(e) => error!("Retention purge of {} failed: {}", table, e),
+                    }
+                }
+            }
+        }
+
+        async fn purge_before(&self, table: &str, cutoff: DateTime<Utc>) -> Result<u64, sqlx::Error> {
+            let statement = format!(
+                "DELETE FROM {table} WHERE id IN (SELECT id FROM {table} WHERE timestamp < $1 LIMIT $2)",
+                table = table
+            );
+            let mut purged = 0;
+            loop {
+                let deleted = sqlx::query(&statement)
+                    .bind(cutoff)
+                    .bind(self.config.retention_purge_batch_size)
+                    .execute(&self.db_pool)
+                    .await?
+                    .rows_affected();
+                purged += deleted;
+                if deleted < self.config.retention_purge_batch_size as u64 {
+                    return Ok(purged);
+                }
+            }
+        }
+
+        /// Recomputes the `daily_stats` row of every day in `dirty`, plus every (tenant, user, UTC day)
+        /// that received a fix since `scan_since` when given. Each row is rebuilt from all of that
+        /// day's fixes, so re-running overwrites rather than accumulates; days the retention purge
+        /// has reached are skipped so their rollups survive. Returns the number of rows written.
+        async fn aggregate_daily_stats(
+            &self,
+            dirty: &HashSet<DirtyDay>,
+            scan_since: Option<DateTime<Utc>>,
+        ) -> Result<usize, sqlx::Error> {
+            let mut days = dirty.clone();
+            if let Some(since) = scan_since {
+                days.extend(
+                    sqlx::query_as::<_, DirtyDay>(
+                        "SELECT DISTINCT tenant_id, user_id, (timestamp AT TIME ZONE 'UTC')::date
+                         FROM locations WHERE timestamp > $1",
+                    )
+                    .bind(since)
+                    .fetch_all(&self.db_pool)
+                    .await?,
+                );
+            }
+            if let Some(cutoff) = self.retention_cutoff() {
+                days.retain(|(_, _, date)| *date > cutoff.date_naive());
+            }
+
+            for (tenant_id, user_id, date) in &days {
+                let day_start = date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+                let mut points = sqlx::query_as::<_, Location>(
+                    "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                     FROM locations WHERE tenant_id = $1 AND user_id = $2 AND timestamp >= $3 AND timestamp < $4
+                     ORDER BY timestamp, seq, id",
+                )
+                .bind(tenant_id)
+                .bind(user_id)
+                .bind(day_start)
+                .bind(day_start + chrono::Duration::days(1))
+                .fetch_all(&self.db_pool)
+                .await?;
+
+                if self.config.smooth_tracks {
+                    points = kalman_smooth_with_accuracy(&points, self.config.smoothing_default_accuracy_meters);
+                }
+                // Every fix of the day is gone, e.g. erased on the user's request.
+                if points.is_empty() {
+                    sqlx::query("DELETE FROM daily_stats WHERE tenant_id = $1 AND user_id = $2 AND date = $3")
+                        .bind(tenant_id)
+                        .bind(user_id)
+                        .bind(date)
+                        .execute(&self.db_pool)
+                        .await?;
+                    continue;
+                }
+                let distance_meters = track_distance_meters(&points);
+
+                sqlx::query(
+                    "INSERT INTO daily_stats (tenant_id, user_id, date, distance_meters, point_count, updated_at)
+                     VALUES ($1, $2, $3, $4, $5, NOW())
+                     ON CONFLICT (tenant_id, user_id, date) DO UPDATE
+                     SET distance_meters = EXCLUDED.distance_meters,
+                         point_count = EXCLUDED.point_count,
+                         updated_at = EXCLUDED.updated_at",
+                )
+                .bind(tenant_id)
+                .bind(user_id)
+                .bind(date)
+                .bind(distance_meters)
+                .bind(points.len() as i64)
+                .execute(&self.db_pool)
+                .await?;
+            }
+
+            Ok(days.len())
+        }
+
+        /// Starts importing a historical track for the user. Nothing is visible to other requests
+        /// until [`GpxImport::commit`].
+        pub async fn begin_gpx_import(
+            &self,
+            tenant_id: &str,
+            user_id: &str,
+            missing_time: MissingTimePolicy,
+        ) -> Result<GpxImport<'_>, sqlx::Error> {
+            let latest_allowed = Utc::now() + chrono::Duration::seconds(self.config.max_clock_skew_secs as i64);
+            Ok(GpxImport {
+                tx: self.db_pool.begin().await?,
+                track: GpxTrack::new(tenant_id, user_id, missing_time, latest_allowed),
+                batch: Vec::with_capacity(IMPORT_BATCH_ROWS),
+                days: HashSet::new(),
+                service: self,
+            })
+        }
+    }
+
+    /// A GPX track being stored for one user inside a single transaction, as its points are
+    /// parsed. Imported fixes skip the plausibility checks of live ingestion and leave the user's
+    /// current location and presence alone, as they describe the past.
+    pub struct GpxImport<'a> {
+        service: &'a TrackingService,
+        tx: Transaction<'static, Postgres>,
+        track: GpxTrack,
+        batch: Vec<Location>,
+        days: HashSet<DirtyDay>,
+    }
+
+    impl GpxImport<'_> {
+        pub async fn push(&mut self, points: Vec<TrackPoint>) -> Result<(), sqlx::Error> {
+            let mut fixes = Vec::new();
+            for point in points {
+                self.track.push(point, &mut fixes);
+            }
+            for location in fixes {
+                self.add(location).await?;
+            }
+            Ok(())
+        }
+
+        async fn add(&mut self, mut location: Location) -> Result<(), sqlx::Error> {
+            let config = &self.service.config;
+            if config.coordinate_precision_strict {
+                config.coordinate_precision.apply(&mut location);
+            }
+            self.days.insert((location.tenant_id.clone(), location.user_id.clone(), location.timestamp.date_naive()));
+            self.batch.push(location);
+            if self.batch.len() >= IMPORT_BATCH_ROWS {
+                self.flush().await?;
+            }
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> Result<(), sqlx::Error> {
+            if self.batch.is_empty() {
+                return Ok(());
+            }
+            let mut builder = QueryBuilder::<Postgres>::new(
+                "INSERT INTO locations (id, tenant_id, user_id, latitude, longitude, altitude, timestamp, timestamp_status, geohash) ",
+            );
+            builder.push_values(&self.batch, |mut row, location| {
+                row.push_bind(location.id)
+                    .push_bind(&location.tenant_id)
+                    .push_bind(&location.user_id)
+                    .push_bind(location.latitude)
+                    .push_bind(location.longitude)
+                    .push_bind(location.altitude)
+                    .push_bind(location.timestamp)
+                    .push_bind(location.timestamp_status.as_str())
+                    .push_bind(location_geohash(location));
+            });
+            builder.build().execute(&mut *self.tx).await?;
+            self.track.result.imported += self.batch.len() as u64;
+            self.batch.clear();
+            Ok(())
+        }
+
+        /// Stores what is left and commits. Untimed points after the last timed one are skipped.
+        pub async fn commit(mut self) -> Result<GpxImportResult, sqlx::Error> {
+            self.track.finish();
+            self.flush().await?;
+            self.tx.commit().await?;
+
+            self.service.enqueue_days(self.days).await;
+            self.service.usage.record(&self.track.tenant_id, self.track.result.imported).await;
+            Ok(self.track.result)
+        }
+    }
+
+    /// Turns the points of a GPX track into fixes and counts what it skips, deciding what
+    /// becomes of points without a usable time.
+    #[derive(Debug)]
+    struct GpxTrack {
+        tenant_id: String,
+        missing_time: MissingTimePolicy,
+        /// Points timed later than this are skipped, as for live fixes.
+        latest_allowed: DateTime<Utc>,
+        /// Positions and elevations of untimed points waiting for the next timed one.
+        untimed: Vec<(f64, f64, Option<f64>)>,
+        last_timed: Option<Location>,
+        result: GpxImportResult,
+    }
+
+    impl GpxTrack {
+        fn new(tenant_id: &str, user_id: &str, missing_time: MissingTimePolicy, latest_allowed: DateTime<Utc>) -> Self {
+            Self {
+                tenant_id: tenant_id.to_string(),
+                missing_time,
+                latest_allowed,
+                untimed: Vec::new(),
+                last_timed: None,
+                result: GpxImportResult { user_id: user_id.to_string(), ..GpxImportResult::default() },
+            }
+        }
+
+        /// Appends the fixes `point` completes to `fixes`.
+        fn push(&mut self, point: TrackPoint, fixes: &mut Vec<Location>) {
+            let position = point
+                .latitude
+                .zip(point.longitude)
+                .filter(|(latitude, longitude)| (-90.0..=90.0).contains(latitude) && (-180.0..=180.0).contains(longitude));
+            let Some((latitude, longitude)) = position else {
+                self.result.skipped += 1;
+                return;
+            };
+            match point.time {
+                Some(time) if time <= self.latest_allowed => self.push_timed(latitude, longitude, point.elevation, time, fixes),
+                Some(_) => self.result.skipped += 1,
+                None if point.has_time => self.result.skipped += 1,
+                None if self.missing_time == MissingTimePolicy::Interpolate && self.last_timed.is_some() => {
+                    self.untimed.push((latitude, longitude, point.elevation));
+                }
+                None => self.skip_untimed(1),
+            }
+        }
+
+        /// Skips untimed points after the last timed one.
+        fn finish(&mut self) {
+            let trailing = std::mem::take(&mut self.untimed).len();
+            self.skip_untimed(trailing);
+        }
+
+        fn skip_untimed(&mut self, count: usize) {
+            self.result.skipped += count as u64;
+            self.result.skipped_missing_time += count as u64;
+        }
+
+        fn location(&self, latitude: f64, longitude: f64, altitude: Option<f64>, timestamp: DateTime<Utc>, status: TimestampStatus) -> Location {
+            Location {
+                id: Uuid::new_v4(),
+                tenant_id: self.tenant_id.clone(),
+                user_id: self.result.user_id.clone(),
+                latitude,
+                longitude,
+                altitude,
+                accuracy: None,
+                speed: None,
+                heading: None,
+                battery: None,
+                seq: None,
+                timestamp,
+                timestamp_status: status,
+            }
+        }
+
+        /// A timed point, after the untimed ones since the previous timed point. Those are
+        /// spread over the time between the two by distance along the track, or evenly when the
+        /// track doesn't move; if time went backwards they are skipped.
+        fn push_timed(&mut self, latitude: f64, longitude: f64, altitude: Option<f64>, time: DateTime<Utc>, fixes: &mut Vec<Location>) {
+            let untimed = std::mem::take(&mut self.untimed);
+            match self.last_timed.take() {
+                Some(before) if !untimed.is_empty() && before.timestamp < time => {
+                    let mut previous = (before.latitude, before.longitude);
+                    let mut travelled = Vec::with_capacity(untimed.len());
+                    let mut total = 0.0;
+                    for &(lat, lon, _) in untimed.iter().chain([(latitude, longitude, None)].iter()) {
+                        total += haversine_meters(previous.0, previous.1, lat, lon);
+                        travelled.push(total);
+                        previous = (lat, lon);
+                    }
+                    let span_ms = (time - before.timestamp).num_milliseconds() as f64;
+                    for (index, &(lat, lon, ele)) in untimed.iter().enumerate() {
+                        let fraction = if total > 0.0 {
+                            travelled[index] / total
+                        } else {
+                            (index + 1) as f64 / (untimed.len() + 1) as f64
+                        };
+                        let timestamp = before.timestamp + chrono::Duration::milliseconds((span_ms * fraction) as i64);
+                        fixes.push(self.location(lat, lon, ele, timestamp, TimestampStatus::Interpolated));
+                        self.result.interpolated += 1;
+                    }
+                }
+                _ => self.skip_untimed(untimed.len()),
+            }
+
+            let location = self.location(latitude, longitude, altitude, time, TimestampStatus::Device);
+            self.last_timed = Some(location.clone());
+            fixes.push(location);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_support;
+
+        #[test]
+        fn only_the_tenants_users_are_taken_from_the_last_seen_set() {
+            let members = vec![
+                (redis_keys::presence_member("acme", "alice"), 3),
+                (redis_keys::presence_member("globex", "alice"), 2),
+                (redis_keys::presence_member("acme", "bob"), 1),
+                (redis_keys::presence_member("acme-eu", "carol"), 1),
+                ("malformed".to_string(), 0),
+            ];
+            let acme: Vec<_> = tenant_members(members, "acme").collect();
+            assert_eq!(acme, [("alice".to_string(), 3), ("bob".to_string(), 1)]);
+        }
+
+        fn request(user_id: &str, latitude: f64, longitude: f64) -> TrackLocationRequest {
+            let mut request: TrackLocationRequest = serde_json::from_value(serde_json::json!({
+                "user_id": user_id, "latitude": latitude, "longitude": longitude,
+            }))
+            .unwrap();
+            request.tenant_id = "acme".to_string();
+            request
+        }
+
+        fn fix(user_id: &str, latitude: f64, longitude: f64) -> Location {
+            request(user_id, latitude, longitude).into_location()
+        }
+
+        /// Fixes on rings around the centre: inside the radius, just outside it and far beyond any
+        /// cell the prefilter could keep.
+        fn fixes_around(latitude: f64, longitude: f64, radius_meters: f64) -> Vec<Location> {
+            let meters_per_degree = crate::utils::EARTH_RADIUS_METERS.to_radians();
+            let mut fixes = Vec::new();
+            for (ring, fraction) in [0.0, 0.3, 0.7, 0.99, 1.01, 1.5, 3.0, 100.0].into_iter().enumerate() {
+                for step in 0..16 {
+                    let bearing = (step as f64 * 22.5).to_radians();
+                    let d_lat = fraction * radius_meters * bearing.cos() / meters_per_degree;
+                    let d_lon = fraction * radius_meters * bearing.sin() / (meters_per_degree * latitude.to_radians().cos());
+                    let lon = (longitude + d_lon + 180.0).rem_euclid(360.0) - 180.0;
+                    fixes.push(fix(&format!("user-{}-{}", ring, step), latitude + d_lat, lon));
+                }
+            }
+            fixes
+        }
+
+        fn query(latitude: f64, longitude: f64, radius_meters: f64) -> NearbyQuery {
+            NearbyQuery { latitude, longitude, radius_meters, limit: usize::MAX }
+        }
+
+        fn user_ids(users: &[NearbyLocation]) -> Vec<&str> {
+            users.iter().map(|nearby| nearby.location.user_id.as_str()).collect()
+        }
+
+        fn track_point(latitude: f64, time: Option<&str>) -> TrackPoint {
+            TrackPoint {
+                latitude: Some(latitude),
+                longitude: Some(0.0),
+                time: time.map(|time| DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)),
+                has_time: time.is_some(),
+                ..TrackPoint::default()
+            }
+        }
+
+        /// A timed point, two untimed ones, a timed one a minute later and a trailing untimed one.
+        fn import(missing_time: MissingTimePolicy) -> (Vec<Location>, GpxImportResult) {
+            let mut track = GpxTrack::new("acme", "alice", missing_time, Utc::now());
+            let mut fixes = Vec::new();
+            for point in [
+                track_point(0.0, None),
+                track_point(0.001, Some("2024-01-01T10:00:00Z")),
+                track_point(0.002, None),
+                track_point(0.004, None),
+                track_point(0.005, Some("2024-01-01T10:01:00Z")),
+                track_point(0.006, None),
+            ] {
+                track.push(point, &mut fixes);
+            }
+            track.finish();
+            (fixes, track.result)
+        }
+
+        #[test]
+        fn untimed_gpx_points_are_skipped_under_the_skip_policy() {
+            let (fixes, result) = import(MissingTimePolicy::Skip);
+            assert_eq!(fixes.iter().map(|fix| fix.latitude).collect::<Vec<_>>(), [0.001, 0.005]);
+            assert!(fixes.iter().all(|fix| fix.timestamp_status == TimestampStatus::Device));
+            assert_eq!((result.interpolated, result.skipped, result.skipped_missing_time), (0, 4, 4));
+        }
+
+        #[test]
+        fn untimed_gpx_points_between_timed_ones_are_interpolated_by_distance() {
+            let (fixes, result) = import(MissingTimePolicy::Interpolate);
+            assert_eq!(fixes.iter().map(|fix| fix.latitude).collect::<Vec<_>>(), [0.001, 0.002, 0.004, 0.005]);
+            assert_eq!(fixes[1].timestamp_status, TimestampStatus::Interpolated);
+            assert_eq!(fixes[1].timestamp.to_rfc3339(), "2024-01-01T10:00:15+00:00");
+            assert_eq!(fixes[2].timestamp.to_rfc3339(), "2024-01-01T10:00:45+00:00");
+            assert_eq!(fixes[3].timestamp_status, TimestampStatus::Device);
+            // The untimed points before the first and after the last timed one have nothing to go by.
+            assert_eq!((result.interpolated, result.skipped, result.skipped_missing_time), (2, 2, 2));
+        }
+
+        #[test]
+        fn gpx_points_timed_in_the_future_or_without_a_position_are_skipped() {
+            let mut track = GpxTrack::new("acme", "alice", MissingTimePolicy::Interpolate, Utc::now());
+            let mut fixes = Vec::new();
+            track.push(track_point(0.0, Some("2999-01-01T00:00:00Z")), &mut fixes);
+            track.push(TrackPoint { latitude: Some(91.0), ..track_point(0.0, Some("2024-01-01T10:00:00Z")) }, &mut fixes);
+            track.push(TrackPoint { has_time: true, ..track_point(0.0, None) }, &mut fixes);
+            assert!(fixes.is_empty());
+            assert_eq!((track.result.skipped, track.result.skipped_missing_time), (3, 0));
+        }
+
+        #[test]
+        fn nearby_users_are_the_fixes_within_the_radius_nearest_first() {
+            let query = query(51.5074, -0.1278, 1000.0);
+            let users = rank_nearby(fixes_around(query.latitude, query.longitude, query.radius_meters), &query);
+
+            assert_eq!(users.len(), 4 * 16);
+            assert_eq!(users[0].location.user_id, "user-0-0");
+            assert!(users.windows(2).all(|pair| pair[0].distance_meters <= pair[1].distance_meters));
+            assert!(users.iter().all(|nearby| nearby.distance_meters <= query.radius_meters));
+        }
+
+        #[test]
+        fn nearby_users_are_cut_at_the_limit() {
+            let mut query = query(51.5074, -0.1278, 1000.0);
+            query.limit = 3;
+            let users = rank_nearby(fixes_around(query.latitude, query.longitude, query.radius_meters), &query);
+            assert_eq!(users.len(), 3);
+            assert_eq!(users[0].location.user_id, "user-0-0");
+        }
+
+        #[test]
+        fn the_geohash_prefilter_keeps_every_nearby_user() {
+            // On a cell corner, next to the antimeridian, far north and in an ordinary city.
+            for (latitude, longitude, radius_meters) in [
+                (0.0, 0.0, 500.0),
+                (0.0, 179.999, 2000.0),
+                (-33.8688, -179.9995, 300.0),
+                (78.2232, 15.6267, 5000.0),
+                (51.5074, -0.1278, 1000.0),
+                (40.7128, -74.006, 50.0),
+            ] {
+                let query = query(latitude, longitude, radius_meters);
+                let fixes = fixes_around(latitude, longitude, radius_meters);
+                let prefixes = geohash::covering(latitude, longitude, radius_meters);
+                let prefiltered: Vec<Location> = fixes
+                    .iter()
+                    .filter(|location| {
+                        let hash = location_geohash(location).unwrap();
+                        prefixes.iter().any(|prefix| hash.starts_with(prefix.as_str()))
+                    })
+                    .cloned()
+                    .collect();
+                assert!(prefiltered.len() < fixes.len(), "the prefilter dropped nothing around {},{}", latitude, longitude);
+
+                let without = rank_nearby(fixes, &query);
+                let with = rank_nearby(prefiltered, &query);
+                assert_eq!(user_ids(&with), user_ids(&without), "around {},{}", latitude, longitude);
+            }
+        }
+
+        async fn stored_fixes(service: &TrackingService, user_id: &str) -> i64 {
+            sqlx::query_scalar("SELECT COUNT(*) FROM locations WHERE tenant_id = 'acme' AND user_id = $1")
+                .bind(user_id)
+                .fetch_one(&service.db_pool)
+                .await
+                .unwrap()
+        }
+
+        #[tokio::test]
+        #[ignore = "needs Postgres and Redis"]
+        async fn concurrent_retries_with_one_idempotency_key_store_one_fix() {
+            let state = test_support::migrated_state().await;
+            let service = &state.tracking_service;
+            let user_id = format!("idempotent-{}", Uuid::new_v4());
+            let key = Uuid::new_v4().to_string();
+
+            let attempts = futures_util::future::join_all(
+                (0..8).map(|_| service.record_location_once(request(&user_id, 51.5, -0.12), &key)),
+            )
+            .await;
+            let stored: Vec<Uuid> = attempts
+                .into_iter()
+                .filter_map(|attempt| match attempt.unwrap() {
+                    Recorded::Stored(location) => Some(location.id),
+                    Recorded::InProgress | Recorded::Replayed(_) => None,
+                    Recorded::Rejected { reason, .. } => panic!("rejected: {}", reason),
+                })
+                .collect();
+            assert_eq!(stored.len(), 1);
+
+            // Once settled, a late retry gets the stored fix back.
+            match service.record_location_once(request(&user_id, 51.5, -0.12), &key).await.unwrap() {
+                Recorded::Replayed(location) => assert_eq!(location.id, stored[0]),
+                other => panic!("expected a replay, got {:?}", other),
+            }
+            assert_eq!(stored_fixes(service, &user_id).await, 1);
+        }
+
+        #[tokio::test]
+        #[ignore = "needs Postgres and Redis"]
+        async fn an_older_fix_arriving_late_is_stored_but_not_cached_as_current() {
+            let state = test_support::migrated_state().await;
+            let service = &state.tracking_service;
+            let user_id = format!("out-of-order-{}", Uuid::new_v4());
+            let now = Utc::now();
+
+            let mut newer = request(&user_id, 51.5, -0.12);
+            newer.timestamp = Some(now - chrono::Duration::seconds(10));
+            let mut older = request(&user_id, 48.85, 2.35);
+            older.timestamp = Some(now - chrono::Duration::seconds(60));
+            for request in [newer, older] {
+                assert!(matches!(service.record_location(request).await.unwrap(), Recorded::Stored(_)));
+            }
+
+            assert_eq!(stored_fixes(service, &user_id).await, 2);
+            let cached = service.cached_current_location("acme", &user_id).await.expect("a cached location");
+            assert_eq!((cached.latitude, cached.longitude), (51.5, -0.12));
+        }
+
+        fn at(timestamp: DateTime<Utc>, mode: LocationAtMode) -> LocationAtQuery {
+            LocationAtQuery { timestamp, mode, strict: false, interpolate: false }
+        }
+
+        #[tokio::test]
+        #[ignore = "needs Postgres"]
+        async fn point_in_time_lookups_pick_the_exact_nearest_or_last_earlier_fix() {
+            use chrono::SubsecRound;
+            let state = test_support::migrated_state().await;
+            let service = &state.tracking_service;
+            let user_id = format!("at-{}", Uuid::new_v4());
+            let seconds = chrono::Duration::seconds;
+            // Whole seconds, as Postgres keeps only microseconds.
+            let start = Utc::now().trunc_subsecs(0) - chrono::Duration::hours(2);
+            for (offset, latitude) in [(0, 51.5), (60, 51.6)] {
+                let mut fix = request(&user_id, latitude, -0.12);
+                fix.timestamp = Some(start + seconds(offset));
+                assert!(matches!(service.record_location(fix).await.unwrap(), Recorded::Stored(_)));
+            }
+            let user = user_id.as_str();
+            let lookup =
+                |timestamp, mode| async move { service.location_at("acme", user, &at(timestamp, mode)).await.unwrap() };
+
+            let exact = lookup(start + seconds(60), LocationAtMode::Nearest).await.unwrap();
+            assert_eq!((exact.location.latitude, exact.gap_secs, exact.uncertain), (51.6, 0.0, false));
+
+            let nearest = lookup(start + seconds(20), LocationAtMode::Nearest).await.unwrap();
+            assert_eq!((nearest.location.latitude, nearest.gap_secs), (51.5, 20.0));
+            let nearest = lookup(start + seconds(50), LocationAtMode::Nearest).await.unwrap();
+            assert_eq!((nearest.location.latitude, nearest.gap_secs), (51.6, 10.0));
+
+            let before = lookup(start + seconds(50), LocationAtMode::Before).await.unwrap();
+            assert_eq!((before.location.latitude, before.gap_secs), (51.5, 50.0));
+            assert!(lookup(start - seconds(10), LocationAtMode::Before).await.is_none());
+
+            let stale = lookup(start + seconds(60 + 600), LocationAtMode::Nearest).await.unwrap();
+            assert_eq!((stale.location.latitude, stale.uncertain), (51.6, true));
+
+            let nobody = format!("{}-nobody", user_id);
+            assert!(service.location_at("acme", &nobody, &at(start, LocationAtMode::Nearest)).await.unwrap().is_none());
+        }
+    }
+}
+
+pub mod geolocation_service {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use chrono::{DateTime, Utc};
+    use sqlx::{types::Json, FromRow, PgConnection, Pool, Postgres, QueryBuilder, Row};
+    use redis::AsyncCommands;
+    use tracing::{error, info, warn};
+    use uuid::Uuid;
+    use crate::config::Config;
+    use crate::metrics::Metrics;
+    use crate::models::{
+        BoundingBox, CreateGeofenceRequest, EvaluateGeofencesRequest, FixGeofenceState, Geofence, GeofenceDistance, GeofenceEvaluation, GeofenceEvent,
+        GeofenceMatch, GeofenceQuery, GeofenceShape, GeofenceTransition, GeofenceVisit, GeofenceVisitQuery, GeofenceVisits, Location,
+        NearestGeofence, NearestGeofenceQuery, NearestGeofences, PageInfo, PointQuery, Paginated, UserGeofence, MAX_GEOFENCE_VISITS,
+    };
+    use crate::redis_client::RedisClient;
+    use crate::utils::{redis_keys, track_distance_meters};
+    use super::live_updates::LiveUpdates;
+    use super::webhooks::WebhookDispatcher;
+
+    const GEOFENCE_COLUMNS: &str =
+        "id, tenant_id, name, geofence_type, center_latitude, center_longitude, radius_meters, polygon, dwell_threshold_secs, webhook_url, min_altitude, max_altitude, speed_limit, created_at";
+
+    type MonitorError = Box<dyn std::error::Error + Send + Sync>;
+    /// `geofence_type`, `center_latitude`, `center_longitude`, `radius_meters` and `polygon`.
+    type GeofenceColumns = (&'static str, Option<f64>, Option<f64>, Option<f64>, Option<Json<Vec<Vec<[f64; 2]>>>>);
+
+    #[derive(Debug)]
+    pub struct GeolocationService {
+        db_pool: Pool<Postgres>,
+        redis_client: RedisClient,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+        live_updates: Arc<LiveUpdates>,
+        webhooks: Arc<WebhookDispatcher>,
+    }
+
+    /// The `limit` geofences of `tenant_id` nearest the point by distance to their boundary, 0 for
+    /// those containing it. Equal distances, such as every containing geofence, are ordered by
+    /// name and then id. Geofences of other tenants and those missing their geometry are skipped.
+    fn rank_nearest(geofences: Vec<Geofence>, tenant_id: &str, latitude: f64, longitude: f64, limit: usize) -> Vec<NearestGeofence> {
+        let mut nearest: Vec<NearestGeofence> = geofences
+            .into_iter()
+            .filter(|geofence| geofence.tenant_id == tenant_id)
+            .filter_map(|geofence| {
+                let distance_meters = geofence.boundary_distance_meters(latitude, longitude)?.max(0.0);
+                Some(NearestGeofence {
+                    inside: geofence.contains(latitude, longitude),
+                    id: geofence.id,
+                    name: geofence.name,
+                    geofence_type: geofence.geofence_type,
+                    distance_meters,
+                })
+            })
+            .collect();
+        nearest.sort_by(|a, b| {
+            a.distance_meters
+                .total_cmp(&b.distance_meters)
+                .then_with(|| a.name.cmp(&b.name))
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        nearest.truncate(limit);
+        nearest
+    }
+
+    /// `min_latitude`, `max_latitude`, `min_longitude` and `max_longitude`, all `None` when the
+    /// shape has no simple box.
+    fn bbox_columns(shape: &GeofenceShape) -> [Option<f64>; 4] {
+        match shape.bounding_box() {
+            Some(bbox) => [Some(bbox.min_latitude), Some(bbox.max_latitude), Some(bbox.min_longitude), Some(bbox.max_longitude)],
+            None => [None; 4],
+        }
+    }
+
+    fn shape_columns(shape: GeofenceShape) -> GeofenceColumns {
+        match shape {
+            GeofenceShape::Circle { center_latitude, center_longitude, radius_meters } => {
+                ("circle", Some(center_latitude), Some(center_longitude), Some(radius_meters), None)
+            }
+            GeofenceShape::Polygon { coordinates } => ("polygon", None, None, None, Some(Json(coordinates))),
+        }
+    }
+
+    async fn insert_geofence(
+        conn: &mut PgConnection,
+        tenant_id: &str,
+        request: CreateGeofenceRequest,
+    ) -> Result<Geofence, sqlx::Error> {
+        let [min_latitude, max_latitude, min_longitude, max_longitude] = bbox_columns(&request.shape);
+        let (geofence_type, center_latitude, center_longitude, radius_meters, polygon) = shape_columns(request.shape);
+
+        sqlx::query_as::<_, Geofence>(&format!(
+            "INSERT INTO geofences (id, tenant_id, name, geofence_type, center_latitude, center_longitude, radius_meters, polygon,
+                                    dwell_threshold_secs, webhook_url, min_altitude, max_altitude, speed_limit,
+                                    min_latitude, max_latitude, min_longitude, max_longitude)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)
+             RETURNING {}",
+            GEOFENCE_COLUMNS
+        ))
+        .bind(Uuid::new_v4())
+        .bind(tenant_id)
+        .bind(request.name.trim())
+        .bind(geofence_type)
+        .bind(center_latitude)
+        .bind(center_longitude)
+        .bind(radius_meters)
+        .bind(polygon)
+        .bind(request.dwell_threshold_secs)
+        .bind(request.webhook_url)
+        .bind(request.min_altitude)
+        .bind(request.max_altitude)
+        .bind(request.speed_limit)
+        .bind(min_latitude)
+        .bind(max_latitude)
+        .bind(min_longitude)
+        .bind(max_longitude)
+        .fetch_one(conn)
+        .await
+    }
+
+    /// One tenant's share of a [`GeofenceIndex`].
+    #[derive(Default)]
+    struct TenantGeofences<'a> {
+        /// Sorted by the southern edge of the box.
+        boxed: Vec<(BoundingBox, &'a Geofence)>,
+        /// Tallest box, bounding how far south of a point a box holding it can start.
+        max_span: f64,
+        unboxed: Vec<&'a Geofence>,
+    }
+
+    /// The monitor's in-memory counterpart of `idx_geofences_tenant_bbox`: each fix is only tested
+    /// against the geofences of its tenant whose bounding box holds it, found by a range search on
+    /// the boxes' southern edges, and those without a box.
+    struct GeofenceIndex<'a> {
+        tenants: HashMap<&'a str, TenantGeofences<'a>>,
+    }
+
+    impl<'a> GeofenceIndex<'a> {
+        fn new(geofences: &'a [Geofence]) -> Self {
+            let mut tenants: HashMap<&str, TenantGeofences> = HashMap::new();
+            for geofence in geofences {
+                let tenant = tenants.entry(geofence.tenant_id.as_str()).or_default();
+                match geofence.bounding_box() {
+                    Some(bbox) => {
+                        tenant.max_span = tenant.max_span.max(bbox.max_latitude - bbox.min_latitude);
+                        tenant.boxed.push((bbox, geofence));
+                    }
+                    None => tenant.unboxed.push(geofence),
+                }
+            }
+            for tenant in tenants.values_mut() {
+                tenant.boxed.sort_by(|(a, _), (b, _)| a.min_latitude.total_cmp(&b.min_latitude));
+            }
+            Self { tenants }
+        }
+
+        /// Geofences of the tenant that may contain the point, in no particular order.
+        fn candidates(&self, tenant_id: &str, latitude: f64, longitude: f64) -> impl Iterator<Item = &'a Geofence> + '_ {
+            let (boxed, unboxed) = match self.tenants.get(tenant_id) {
+                Some(tenant) => {
+                    let start = tenant.boxed.partition_point(|(bbox, _)| bbox.min_latitude < latitude - tenant.max_span);
+                    let end = tenant.boxed.partition_point(|(bbox, _)| bbox.min_latitude <= latitude);
+                    (&tenant.boxed[start..end.max(start)], tenant.unboxed.as_slice())
+                }
+                None => (&[][..], &[][..]),
+            };
+            boxed
+                .iter()
+                .filter(move |(bbox, _)| bbox.contains(latitude, longitude))
+                .map(|&(_, geofence)| geofence)
+                .chain(unboxed.iter().copied())
+        }
+    }
+
+    impl GeolocationService {
+        pub fn new(
+            db_pool: Pool<Postgres>,
+            redis_client: RedisClient,
+            config: Arc<Config>,
+            metrics: Arc<Metrics>,
+            live_updates: Arc<LiveUpdates>,
+            webhooks: Arc<WebhookDispatcher>,
+        ) -> Self {
+            Self {
+                db_pool,
+                redis_client,
+                config,
+                metrics,
+                live_updates,
+                webhooks,
+            }
+        }
+
+        /// Geofences matching the query, oldest first. The spatial filter is applied in memory, so
+        /// pagination and the optional total happen after it rather than in SQL when a search
+        /// circle is given.
+        pub async fn list_geofences(&self, tenant_id: &str, query: &GeofenceQuery) -> Result<Paginated<Geofence>, sqlx::Error> {
+            let with_total = query.include_total && query.near.is_none();
+            let mut builder = QueryBuilder::<Postgres>::new(format!(
+                "SELECT {}, {} AS total FROM geofences WHERE deleted_at IS NULL AND tenant_id = ",
+                GEOFENCE_COLUMNS,
+                if with_total { "COUNT(*) OVER ()" } else { "NULL::BIGINT" }
+            ));
+            builder.push_bind(tenant_id);
+            if let Some(name) = &query.name_contains {
+                builder.push(" AND position(lower(").push_bind(name).push(") in lower(name)) > 0");
+            }
+            builder.push(" ORDER BY created_at, id");
+            if query.near.is_none() {
+                builder.push(" LIMIT ").push_bind(query.limit + 1).push(" OFFSET ").push_bind(query.offset);
+            }
+
+            let rows = builder.build().fetch_all(&self.db_pool).await?;
+            let mut total = match rows.first() {
+                Some(row) => row.try_get::<Option<i64>, _>("total")?,
+                None => None,
+            };
+            let mut geofences = rows.iter().map(Geofence::from_row).collect::<Result<Vec<_>, _>>()?;
+
+            if let Some(near) = query.near {
+                geofences.retain(|g| g.intersects_circle(near.latitude, near.longitude, near.radius_meters));
+                if query.include_total {
+                    total = Some(geofences.len() as i64);
+                }
+                geofences = geofences
+                    .into_iter()
+                    .skip(query.offset as usize)
+                    .take(query.limit as usize + 1)
+                    .collect();
+            } else if with_total && total.is_none() {
+                // A page past the end has no rows to carry the windowed count.
+                let mut count = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM geofences WHERE deleted_at IS NULL AND tenant_id = ");
+                count.push_bind(tenant_id);
+                if let Some(name) = &query.name_contains {
+                    count.push(" AND position(lower(").push_bind(name).push(") in lower(name)) > 0");
+                }
+                total = Some(count.build_query_scalar::<i64>().fetch_one(&self.db_pool).await?);
+            }
+
+            let next_cursor = if geofences.len() as i64 > query.limit {
+                geofences.truncate(query.limit as usize);
+                Some((query.offset + query.limit).to_string())
+            } else {
+                None
+            };
+
+            Ok(Paginated {
+                data: geofences,
+                page: PageInfo {
+                    limit: query.limit,
+                    next_cursor,
+                    total,
+                },
+            })
+        }
+
+        /// Every active geofence containing the point, oldest first, using the same containment
+        /// test as the monitor behind a bounding-box check. With a user, each match also reports
+        /// whether the monitor already has that user inside; that part is left out if Redis is
+        /// unreachable.
+        pub async fn evaluate_point(&self, tenant_id: &str, request: EvaluateGeofencesRequest) -> Result<GeofenceEvaluation, sqlx::Error> {
+            let (latitude, longitude) = (request.latitude, request.longitude);
+            let mut matches: Vec<GeofenceMatch> = self
+                .candidate_geofences(tenant_id, latitude, longitude, &[])
+                .await?
+                .into_iter()
+                .filter(|g| self.contains(g, latitude, longitude, request.altitude))
+                .map(|g| GeofenceMatch { id: g.id, name: g.name, currently_inside: None })
+                .collect();
+
+            if let Some(user_id) = &request.user_id {
+                let membership: redis::RedisResult<HashSet<String>> = async {
+                    let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                    conn.smembers(redis_keys::geofence_membership(tenant_id, user_id)).await
+                }
+                .await;
+                match membership {
+                    Ok(inside) => {
+                        for m in &mut matches {
+                            m.currently_inside = Some(inside.contains(&m.id.to_string()));
+                        }
+                    }
+                    Err(e) => warn!("Failed to read geofence membership of {}: {}", user_id, e),
+                }
+            }
+
+            Ok(GeofenceEvaluation {
+                latitude,
+                longitude,
+                user_id: request.user_id,
+                geofences: matches,
+            })
+        }
+
+        /// How far the point is from the geofence's boundary, or `None` when the geofence does not
+        /// exist in the tenant. Altitude bands are ignored.
+        pub async fn distance_to_geofence(
+            &self,
+            tenant_id: &str,
+            id: Uuid,
+            latitude: f64,
+            longitude: f64,
+        ) -> Result<Option<GeofenceDistance>, sqlx::Error> {
+            let geofence = sqlx::query_as::<_, Geofence>(&format!(
+                "SELECT {} FROM geofences WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL",
+                GEOFENCE_COLUMNS
+            ))
+            .bind(id)
+            .bind(tenant_id)
+            .fetch_optional(&self.db_pool)
+            .await?;
+
+            Ok(geofence.and_then(|geofence| {
+                let distance_meters = geofence.boundary_distance_meters(latitude, longitude)?;
+                Some(GeofenceDistance {
+                    geofence_id: id,
+                    latitude,
+                    longitude,
+                    distance_meters,
+                    inside: geofence.contains(latitude, longitude),
+                })
+            }))
+        }
+
+        /// The tenant's active geofences closest to the point, as ranked by [`rank_nearest`]. Every
+        /// geofence of the tenant is measured, as no bounding box limits how far the nearest may
+        /// be; altitude bands are ignored.
+        pub async fn nearest_geofences(&self, tenant_id: &str, query: &NearestGeofenceQuery) -> Result<NearestGeofences, sqlx::Error> {
+            let PointQuery { latitude, longitude } = query.point;
+            let geofences = sqlx::query_as::<_, Geofence>(&format!(
+                "SELECT {} FROM geofences WHERE deleted_at IS NULL AND tenant_id = $1",
+                GEOFENCE_COLUMNS
+            ))
+            .bind(tenant_id)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+            Ok(NearestGeofences {
+                latitude,
+                longitude,
+                geofences: rank_nearest(geofences, tenant_id, latitude, longitude, query.limit),
+            })
+        }
+
+        /// The geofences containing a just-stored fix, and those it entered or exited compared with
+        /// the membership the monitor has recorded. Nothing is recorded here: the monitor still
+        /// emits the ENTER and EXIT events on its next pass. Memberships of deleted geofences are
+        /// not reported as exits, as the monitor drops them silently.
+        pub async fn fix_geofence_state(&self, fix: &Location) -> Result<FixGeofenceState, sqlx::Error> {
+            let membership: redis::RedisResult<HashSet<String>> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                conn.smembers(redis_keys::geofence_membership(&fix.tenant_id, &fix.user_id)).await
+            }
+            .await;
+            // Geofences the user was inside are fetched whatever their box, to tell exits apart
+            // from deleted geofences.
+            let members: Vec<Uuid> = membership
+                .as_ref()
+                .map(|previous| previous.iter().filter_map(|id| Uuid::parse_str(id).ok()).collect())
+                .unwrap_or_default();
+            let geofences = self.candidate_geofences(&fix.tenant_id, fix.latitude, fix.longitude, &members).await?;
+            let inside: Vec<Uuid> = geofences
+                .iter()
+                .filter(|g| self.contains(g, fix.latitude, fix.longitude, fix.altitude))
+                .map(|g| g.id)
+                .collect();
+
+            let (entered, exited) = match membership {
+                Ok(previous) => {
+                    let was_inside = |id: &Uuid| previous.contains(&id.to_string());
+                    let entered = inside.iter().copied().filter(|id| !was_inside(id)).collect();
+                    let exited = geofences
+                        .iter()
+                        .map(|g| g.id)
+                        .filter(|id| was_inside(id) && !inside.contains(id))
+                        .collect();
+                    (Some(entered), Some(exited))
+                }
+                Err(e) => {
+                    warn!("Failed to read geofence membership of {}: {}", fix.user_id, e);
+                    (None, None)
+                }
+            };
+
+            Ok(FixGeofenceState { inside, entered, exited })
+        }
+
+        /// The active geofences the monitor has the user inside, oldest first, with when they
+        /// entered each. `None` when it has none for the user or Redis is unreachable, so callers
+        /// can fall back to [`Self::geofences_containing`].
+        pub async fn recorded_geofences(&self, tenant_id: &str, user_id: &str) -> Result<Option<Vec<UserGeofence>>, sqlx::Error> {
+            let recorded: redis::RedisResult<(HashSet<String>, HashMap<String, i64>)> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                redis::pipe()
+                    .smembers(redis_keys::geofence_membership(tenant_id, user_id))
+                    .hgetall(redis_keys::geofence_entered(tenant_id, user_id))
+                    .query_async(&mut conn)
+                    .await
+            }
+            .await;
+            let (members, entered) = match recorded {
+                Ok(recorded) => recorded,
+                Err(e) => {
+                    warn!("Failed to read geofence membership of {}: {}", user_id, e);
+                    return Ok(None);
+                }
+            };
+            let members: Vec<Uuid> = members.iter().filter_map(|id| Uuid::parse_str(id).ok()).collect();
+            if members.is_empty() {
+                return Ok(None);
+            }
+
+            // Memberships of geofences deleted since the monitor's last pass are left out.
+            let geofences = sqlx::query_as::<_, (Uuid, String)>(
+                "SELECT id, name FROM geofences
+                 WHERE deleted_at IS NULL AND tenant_id = $1 AND id = ANY($2)
+                 ORDER BY created_at, id",
+            )
+            .bind(tenant_id)
+            .bind(&members)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+            let now = Utc::now();
+            let geofences: Vec<UserGeofence> = geofences
+                .into_iter()
+                .map(|(id, name)| {
+                    let entered_at = entered
+                        .get(&id.to_string())
+                        .and_then(|&ms| DateTime::<Utc>::from_timestamp_millis(ms));
+                    UserGeofence {
+                        id,
+                        name,
+                        entered_at,
+                        inside_secs: entered_at.map(|at| (now - at).num_seconds().max(0)),
+                    }
+                })
+                .collect();
+            Ok(Some(geofences).filter(|geofences| !geofences.is_empty()))
+        }
+
+        /// The active geofences containing a fix, oldest first, with no entry times.
+        pub async fn geofences_containing(&self, fix: &Location) -> Result<Vec<UserGeofence>, sqlx::Error> {
+            Ok(self
+                .candidate_geofences(&fix.tenant_id, fix.latitude, fix.longitude, &[])
+                .await?
+                .into_iter()
+                .filter(|g| self.contains(g, fix.latitude, fix.longitude, fix.altitude))
+                .map(|g| UserGeofence { id: g.id, name: g.name, entered_at: None, inside_secs: None })
+                .collect())
+        }
+
+        /// The tenant's active geofences that may contain the point, oldest first: those whose
+        /// stored bounding box holds it, those without a box, and any listed in `also`. A range
+        /// query on `idx_geofences_tenant_bbox`, so the cost follows the geofences near the point
+        /// rather than all of the tenant's.
+        async fn candidate_geofences(
+            &self,
+            tenant_id: &str,
+            latitude: f64,
+            longitude: f64,
+            also: &[Uuid],
+        ) -> Result<Vec<Geofence>, sqlx::Error> {
+            sqlx::query_as::<_, Geofence>(&format!(
+                "SELECT {} FROM geofences
+                 WHERE deleted_at IS NULL AND tenant_id = $1
+                   AND (min_latitude IS NULL
+                        OR (min_latitude <= $2 AND max_latitude >= $2 AND min_longitude <= $3 AND max_longitude >= $3)
+                        OR id = ANY($4))
+                 ORDER BY created_at, id",
+                GEOFENCE_COLUMNS
+            ))
+            .bind(tenant_id)
+            .bind(latitude)
+            .bind(longitude)
+            .bind(also)
+            .fetch_all(&self.db_pool)
+            .await
+        }
+
+        /// The monitor's containment test behind a cheap bounding-box check.
+        fn contains(&self, geofence: &Geofence, latitude: f64, longitude: f64, altitude: Option<f64>) -> bool {
+            geofence.bounding_box().is_none_or(|bbox| bbox.contains(latitude, longitude))
+                && geofence.contains_fix(latitude, longitude, altitude, self.config.geofence_strict_altitude)
+        }
+
+        /// Users whose most recent ENTER or EXIT event for the geofence is an ENTER, or `None` when
+        /// the geofence does not exist in the tenant.
+        pub async fn users_inside(&self, tenant_id: &str, geofence_id: Uuid) -> Result<Option<Vec<String>>, sqlx::Error> {
+            let exists = sqlx::query_scalar::<_, bool>(
+                "SELECT EXISTS (SELECT 1 FROM geofences WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL)",
+            )
+                .bind(geofence_id)
+                .bind(tenant_id)
+                .fetch_one(&self.db_pool)
+                .await?;
+            if !exists {
+                return Ok(None);
+            }
+
+            let users = sqlx::query_scalar::<_, String>(
+                "SELECT user_id FROM (
+                     SELECT DISTINCT ON (user_id) user_id, event_type
+                     FROM geofence_events WHERE geofence_id = $1 AND event_type IN ($2, $3)
+                     ORDER BY user_id, occurred_at DESC
+                 ) latest
+                 WHERE event_type = $2
+                 ORDER BY user_id",
+            )
+            .bind(geofence_id)
+            .bind(GeofenceTransition::Enter.as_str())
+            .bind(GeofenceTransition::Exit.as_str())
+            .fetch_all(&self.db_pool)
+            .await?;
+
+            Ok(Some(users))
+        }
+
+        /// Stores a validated geofence request, with its name trimmed.
+        pub async fn create_geofence(&self, tenant_id: &str, request: CreateGeofenceRequest) -> Result<Geofence, sqlx::Error> {
+            let mut conn = self.db_pool.acquire().await?;
+            insert_geofence(&mut conn, tenant_id, request).await
+        }
+
+        /// Creates the geofences in one transaction, so either all of them exist afterwards or
+        /// none do. Returned in the order given.
+        pub async fn import_geofences(
+            &self,
+            tenant_id: &str,
+            requests: Vec<CreateGeofenceRequest>,
+        ) -> Result<Vec<Geofence>, sqlx::Error> {
+            let mut tx = self.db_pool.begin().await?;
+            let mut created = Vec::with_capacity(requests.len());
+            for request in requests {
+                created.push(insert_geofence(&mut tx, tenant_id, request).await?);
+            }
+            tx.commit().await?;
+            Ok(created)
+        }
+
+        /// Replaces a geofence's name, geometry, altitude band, dwell threshold and webhook, keeping
+        /// its id. When the geometry or band changes the geofence is queued for a full rescan so
+        /// memberships are recomputed against the new boundary, including for users who have not
+        /// moved since. Returns `None` for unknown or
+        /// deleted geofences and those of other tenants.
+        pub async fn update_geofence(
+            &self,
+            tenant_id: &str,
+            id: Uuid,
+            request: CreateGeofenceRequest,
+        ) -> Result<Option<Geofence>, sqlx::Error> {
+            let [min_latitude, max_latitude, min_longitude, max_longitude] = bbox_columns(&request.shape);
+            let columns = shape_columns(request.shape);
+            let band = (request.min_altitude, request.max_altitude);
+            let mut tx = self.db_pool.begin().await?;
+
+            let previous = sqlx::query_as::<_, Geofence>(&format!(
+                "SELECT {} FROM geofences WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL FOR UPDATE",
+                GEOFENCE_COLUMNS
+            ))
+            .bind(id)
+            .bind(tenant_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some(previous) = previous else {
+                return Ok(None);
+            };
+
+            let (geofence_type, center_latitude, center_longitude, radius_meters, polygon) = columns;
+            let geofence = sqlx::query_as::<_, Geofence>(&format!(
+                "UPDATE geofences
+                 SET name = $2, geofence_type = $3, center_latitude = $4, center_longitude = $5,
+                     radius_meters = $6, polygon = $7, dwell_threshold_secs = $8, webhook_url = $9,
+                     min_altitude = $10, max_altitude = $11, speed_limit = $12,
+                     min_latitude = $13, max_latitude = $14, min_longitude = $15, max_longitude = $16
+                 WHERE id = $1
+                 RETURNING {}",
+                GEOFENCE_COLUMNS
+            ))
+            .bind(id)
+            .bind(request.name.trim())
+            .bind(geofence_type)
+            .bind(center_latitude)
+            .bind(center_longitude)
+            .bind(radius_meters)
+            .bind(&polygon)
+            .bind(request.dwell_threshold_secs)
+            .bind(request.webhook_url)
+            .bind(band.0)
+            .bind(band.1)
+            .bind(request.speed_limit)
+            .bind(min_latitude)
+            .bind(max_latitude)
+            .bind(min_longitude)
+            .bind(max_longitude)
+            .fetch_one(&mut *tx)
+            .await?;
+            tx.commit().await?;
+
+            let geometry_changed = (
+                previous.geofence_type.as_str(),
+                previous.center_latitude,
+                previous.center_longitude,
+                previous.radius_meters,
+                previous.polygon.as_ref().map(|Json(rings)| rings),
+            ) != (geofence_type, center_latitude, center_longitude, radius_meters, polygon.as_ref().map(|Json(rings)| rings))
+                || (previous.min_altitude, previous.max_altitude) != band;
+            if geometry_changed {
+                let result: redis::RedisResult<()> = async {
+                    let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                    conn.sadd(redis_keys::GEOFENCE_RESCAN, id.to_string()).await
+                }
+                .await;
+                if let Err(e) = result {
+                    warn!("Failed to queue geofence {} for rescan: {}", id, e);
+                }
+            }
+
+            Ok(Some(geofence))
+        }
+
+        /// Marks a geofence as deleted, keeping its row so past events still resolve. Returns
+        /// `false` when the geofence is unknown, already deleted or another tenant's.
+        pub async fn delete_geofence(&self, tenant_id: &str, id: Uuid) -> Result<bool, sqlx::Error> {
+            let result = sqlx::query(
+                "UPDATE geofences SET deleted_at = NOW() WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL",
+            )
+                .bind(id)
+                .bind(tenant_id)
+                .execute(&self.db_pool)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        pub async fn start_geofence_monitoring(&self) {
+            let period = Duration::from_secs(self.config.geofence_check_interval_secs.max(1));
+            let mut interval = tokio::time::interval(period);
+            let mut since = Utc::now() - chrono::Duration::from_std(period).unwrap_or_default();
+
+            loop {
+                interval.tick().await;
+                let scan_started = Utc::now();
+
+                match self.check_geofences(since).await {
+                    Ok(transitions) => {
+                        if transitions > 0 {
+                            info!("Recorded {} geofence transitions", transitions);
+                        }
+                        since = scan_started;
+                    }
+                    Err(e) => error!("Geofence check failed: {}", e),
+                }
+            }
+        }
+
+        /// Compares the latest fix of every user seen since `since` against their tenant's geofences and
+        /// records ENTER/EXIT events for memberships that changed, opening and closing the matching
+        /// visits, and a DWELL event once per visit
+        /// when a user has stayed inside past the geofence's dwell threshold. Time inside is
+        /// measured between fix timestamps, so a user who stops reporting never dwells. When a
+        /// geofence was queued for a rescan, every user's latest fix is checked instead. SPEEDING
+        /// events are left to [`Self::check_speeding`]. Returns the number of events.
+        async fn check_geofences(&self, since: DateTime<Utc>) -> Result<usize, MonitorError> {
+            let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+            let rescan: Vec<String> = conn.smembers(redis_keys::GEOFENCE_RESCAN).await?;
+            let speeding_since = since;
+            let since = if rescan.is_empty() { since } else { DateTime::<Utc>::MIN_UTC };
+
+            let geofences = sqlx::query_as::<_, Geofence>(&format!(
+                "SELECT {} FROM geofences WHERE deleted_at IS NULL",
+                GEOFENCE_COLUMNS
+            ))
+            .fetch_all(&self.db_pool)
+            .await?;
+            let active: HashSet<String> = geofences.iter().map(|g| g.id.to_string()).collect();
+            let dwell_thresholds: HashMap<String, i64> = geofences
+                .iter()
+                .filter_map(|g| g.dwell_threshold_secs.map(|threshold| (g.id.to_string(), threshold)))
+                .collect();
+            let webhook_urls: HashMap<String, &str> = geofences
+                .iter()
+                .filter_map(|g| g.webhook_url.as_deref().map(|url| (g.id.to_string(), url)))
+                .collect();
+
+            let fixes = sqlx::query_as::<_, Location>(
+                "SELECT DISTINCT ON (tenant_id, user_id) id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                 FROM locations WHERE timestamp > $1 ORDER BY tenant_id, user_id, timestamp DESC",
+            )
+            .bind(since)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+            let mut recorded = 0;
+            let index = GeofenceIndex::new(&geofences);
+
+            for fix in fixes {
+                let inside: HashSet<String> = index
+                    .candidates(&fix.tenant_id, fix.latitude, fix.longitude)
+                    .filter(|g| {
+                        g.contains_fix(fix.latitude, fix.longitude, fix.altitude, self.config.geofence_strict_altitude)
+                    })
+                    .map(|g| g.id.to_string())
+                    .collect();
+
+                let key = redis_keys::geofence_membership(&fix.tenant_id, &fix.user_id);
+                let entered_key = redis_keys::geofence_entered(&fix.tenant_id, &fix.user_id);
+                let dwelled_key = redis_keys::geofence_dwelled(&fix.tenant_id, &fix.user_id);
+                let mut previous: HashSet<String> = conn.smembers(&key).await?;
+
+                // Memberships of deleted geofences are dropped without emitting an EXIT.
+                for geofence_id in previous.difference(&active) {
+                    let _: () = conn.srem(&key, geofence_id).await?;
+                    let _: () = conn.hdel(&entered_key, geofence_id).await?;
+                    let _: () = conn.srem(&dwelled_key, geofence_id).await?;
+                }
+                previous.retain(|geofence_id| active.contains(geofence_id));
+
+                for geofence_id in inside.difference(&previous) {
+                    self.record_transition(&fix, geofence_id, GeofenceTransition::Enter, None, webhook_urls.get(geofence_id).copied()).await?;
+                    self.open_visit(&fix, geofence_id).await?;
+                    let _: () = conn.sadd(&key, geofence_id).await?;
+                    let _: () = conn.hset(&entered_key, geofence_id, fix.timestamp.timestamp_millis()).await?;
+                    let _: () = conn.srem(&dwelled_key, geofence_id).await?;
+                    recorded += 1;
+                }
+
+                for geofence_id in previous.difference(&inside) {
+                    self.record_transition(&fix, geofence_id, GeofenceTransition::Exit, None, webhook_urls.get(geofence_id).copied()).await?;
+                    let entered_ms: Option<i64> = conn.hget(&entered_key, geofence_id).await?;
+                    self.close_visit(&fix, geofence_id, entered_ms).await?;
+                    let _: () = conn.srem(&key, geofence_id).await?;
+                    let _: () = conn.hdel(&entered_key, geofence_id).await?;
+                    let _: () = conn.srem(&dwelled_key, geofence_id).await?;
+                    recorded += 1;
+                }
+
+                for geofence_id in inside.intersection(&previous) {
+                    let Some(&threshold_secs) = dwell_thresholds.get(geofence_id) else {
+                        continue;
+                    };
+                    // Memberships recorded before entry times were kept start counting now.
+                    let _: () = conn.hset_nx(&entered_key, geofence_id, fix.timestamp.timestamp_millis()).await?;
+                    let entered_ms: i64 = conn.hget(&entered_key, geofence_id).await?;
+                    let inside_ms = fix.timestamp.timestamp_millis() - entered_ms;
+                    if inside_ms < threshold_secs.saturating_mul(1000) {
+                        continue;
+                    }
+                    let dwelled: bool = conn.sismember(&dwelled_key, geofence_id).await?;
+                    if !dwelled {
+                        self.record_transition(&fix, geofence_id, GeofenceTransition::Dwell, None, webhook_urls.get(geofence_id).copied()).await?;
+                        let _: () = conn.sadd(&dwelled_key, geofence_id).await?;
+                        recorded += 1;
+                    }
+                }
+
+                // A user who stops reporting is forgotten; their next fix inside counts as an ENTER.
+                if !inside.is_empty() {
+                    let ttl_secs = self.config.geofence_membership_ttl_secs as usize;
+                    for key in [&key, &entered_key, &dwelled_key] {
+                        let _: () = conn.expire(key, ttl_secs).await?;
+                    }
+                }
+            }
+
+            // Only clear what this scan covered; a geofence edited meanwhile stays queued.
+            if !rescan.is_empty() {
+                let _: () = conn.srem(redis_keys::GEOFENCE_RESCAN, &rescan).await?;
+            }
+
+            recorded += self.check_speeding(&geofences, speeding_since).await?;
+            Ok(recorded)
+        }
+
+        /// Records a SPEEDING event for every fix since `since` that reported a speed above the
+        /// limit of a geofence it falls in. Every fix is checked rather than each user's latest, so
+        /// a burst between two scans is not missed, but only one event per user and geofence fires
+        /// within `geofence_speeding_debounce_secs`. Returns the number of events.
+        async fn check_speeding(&self, geofences: &[Geofence], since: DateTime<Utc>) -> Result<usize, MonitorError> {
+            let Some(lowest_limit) = geofences.iter().filter_map(|g| g.speed_limit).min_by(f64::total_cmp) else {
+                return Ok(0);
+            };
+
+            let fixes = sqlx::query_as::<_, Location>(
+                "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                 FROM locations WHERE timestamp > $1 AND speed > $2 ORDER BY timestamp, id",
+            )
+            .bind(since)
+            .bind(lowest_limit)
+            .fetch_all(&self.db_pool)
+            .await?;
+            if fixes.is_empty() {
+                return Ok(0);
+            }
+
+            let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+            let mut recorded = 0;
+            let index = GeofenceIndex::new(geofences);
+            for fix in &fixes {
+                let Some(speed) = fix.speed else { continue };
+                let speeding = index
+                    .candidates(&fix.tenant_id, fix.latitude, fix.longitude)
+                    .filter(|g| g.speed_limit.is_some_and(|limit| speed > limit))
+                    .filter(|g| g.contains_fix(fix.latitude, fix.longitude, fix.altitude, self.config.geofence_strict_altitude));
+                for geofence in speeding {
+                    let geofence_id = geofence.id.to_string();
+                    let debounced: Option<String> = redis::cmd("SET")
+                        .arg(redis_keys::geofence_speeding(&fix.tenant_id, &fix.user_id, &geofence_id))
+                        .arg(fix.timestamp.timestamp_millis())
+                        .arg("NX")
+                        .arg("EX")
+                        .arg(self.config.geofence_speeding_debounce_secs)
+                        .query_async(&mut conn)
+                        .await?;
+                    // SET NX answers nil while an earlier event's debounce key is still alive.
+                    if debounced.is_some() {
+                        self.record_transition(
+                            fix,
+                            &geofence_id,
+                            GeofenceTransition::Speeding,
+                            geofence.speed_limit,
+                            geofence.webhook_url.as_deref(),
+                        )
+                        .await?;
+                        recorded += 1;
+                    }
+                }
+            }
+
+            Ok(recorded)
+        }
+
+        /// Starts a visit at the entering fix. A visit still open, because the membership lapsed
+        /// while the user was silent, carries on instead.
+        async fn open_visit(&self, fix: &Location, geofence_id: &str) -> Result<(), MonitorError> {
+            sqlx::query(
+                "INSERT INTO geofence_visits (id, tenant_id, user_id, geofence_id, entered_at) VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (tenant_id, user_id, geofence_id) WHERE exited_at IS NULL DO NOTHING",
+            )
+            .bind(Uuid::new_v4())
+            .bind(&fix.tenant_id)
+            .bind(&fix.user_id)
+            .bind(Uuid::parse_str(geofence_id)?)
+            .bind(fix.timestamp)
+            .execute(&self.db_pool)
+            .await?;
+            Ok(())
+        }
+
+        /// Ends the user's open visit at the exiting fix, with the distance covered by the fixes
+        /// from the entering one up to it. Without an open visit, e.g. for a membership from
+        /// before visits were recorded, one is written closed from `entered_ms` when known.
+        async fn close_visit(&self, fix: &Location, geofence_id: &str, entered_ms: Option<i64>) -> Result<(), MonitorError> {
+            let geofence_id = Uuid::parse_str(geofence_id)?;
+            let open = sqlx::query_as::<_, (Uuid, DateTime<Utc>)>(
+                "SELECT id, entered_at FROM geofence_visits
+                 WHERE tenant_id = $1 AND user_id = $2 AND geofence_id = $3 AND exited_at IS NULL",
+            )
+            .bind(&fix.tenant_id)
+            .bind(&fix.user_id)
+            .bind(geofence_id)
+            .fetch_optional(&self.db_pool)
+            .await?;
+            let (id, entered_at) = match open {
+                Some((id, entered_at)) => (Some(id), entered_at),
+                None => match entered_ms.and_then(DateTime::from_timestamp_millis) {
+                    Some(entered_at) => (None, entered_at),
+                    None => return Ok(()),
+                },
+            };
+
+            let inside = sqlx::query_as::<_, Location>(
+                "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                 FROM locations WHERE tenant_id = $1 AND user_id = $2 AND timestamp >= $3 AND timestamp < $4
+                 ORDER BY timestamp, seq, id",
+            )
+            .bind(&fix.tenant_id)
+            .bind(&fix.user_id)
+            .bind(entered_at)
+            .bind(fix.timestamp)
+            .fetch_all(&self.db_pool)
+            .await?;
+            let distance_meters = track_distance_meters(&inside);
+            let dwell_secs = (fix.timestamp - entered_at).num_seconds();
+
+            match id {
+                Some(id) => {
+                    sqlx::query(
+                        "UPDATE geofence_visits SET exited_at = $2, dwell_secs = $3, distance_meters = $4 WHERE id = $1",
+                    )
+                    .bind(id)
+                    .bind(fix.timestamp)
+                    .bind(dwell_secs)
+                    .bind(distance_meters)
+                    .execute(&self.db_pool)
+                    .await?;
+                }
+                None => {
+                    sqlx::query(
+                        "INSERT INTO geofence_visits (id, tenant_id, user_id, geofence_id, entered_at, exited_at, dwell_secs, distance_meters)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                    )
+                    .bind(Uuid::new_v4())
+                    .bind(&fix.tenant_id)
+                    .bind(&fix.user_id)
+                    .bind(geofence_id)
+                    .bind(entered_at)
+                    .bind(fix.timestamp)
+                    .bind(dwell_secs)
+                    .bind(distance_meters)
+                    .execute(&self.db_pool)
+                    .await?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Visits of the geofence that overlap the query window, oldest entry first, or `None`
+        /// when the geofence does not exist in the tenant.
+        pub async fn visits(&self, tenant_id: &str, geofence_id: Uuid, query: &GeofenceVisitQuery) -> Result<Option<GeofenceVisits>, sqlx::Error> {
+            let exists = sqlx::query_scalar::<_, bool>(
+                "SELECT EXISTS (SELECT 1 FROM geofences WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL)",
+            )
+            .bind(geofence_id)
+            .bind(tenant_id)
+            .fetch_one(&self.db_pool)
+            .await?;
+            if !exists {
+                return Ok(None);
+            }
+
+            let mut visits = sqlx::query_as::<_, GeofenceVisit>(
+                "SELECT id, user_id, entered_at, exited_at, dwell_secs, distance_meters
+                 FROM geofence_visits
+                 WHERE tenant_id = $1 AND geofence_id = $2
+                   AND ($3::timestamptz IS NULL OR exited_at IS NULL OR exited_at >= $3)
+                   AND ($4::timestamptz IS NULL OR entered_at <= $4)
+                 ORDER BY entered_at, id
+                 LIMIT $5",
+            )
+            .bind(tenant_id)
+            .bind(geofence_id)
+            .bind(query.from)
+            .bind(query.to)
+            .bind(MAX_GEOFENCE_VISITS + 1)
+            .fetch_all(&self.db_pool)
+            .await?;
+            let truncated = visits.len() as i64 > MAX_GEOFENCE_VISITS;
+            visits.truncate(MAX_GEOFENCE_VISITS as usize);
+
+            Ok(Some(GeofenceVisits { geofence_id, visits, truncated }))
+        }
+
+        /// Persists the event, publishes it to WebSocket subscribers and hands it to the
+        /// geofence's webhook, if any. `speed_limit` is only given for SPEEDING events, which then
+        /// also carry the fix's speed.
+        async fn record_transition(
+            &self,
+            fix: &Location,
+            geofence_id: &str,
+            transition: GeofenceTransition,
+            speed_limit: Option<f64>,
+            webhook_url: Option<&str>,
+        ) -> Result<(), MonitorError> {
+            let mut event = GeofenceEvent {
+                id: Uuid::new_v4(),
+                geofence_id: Uuid::parse_str(geofence_id)?,
+                user_id: fix.user_id.clone(),
+                event_type: transition,
+                latitude: fix.latitude,
+                longitude: fix.longitude,
+                occurred_at: fix.timestamp,
+                observed_speed: speed_limit.and(fix.speed),
+                speed_limit,
+            };
+
+            sqlx::query(
+                "INSERT INTO geofence_events (id, tenant_id, user_id, geofence_id, event_type, latitude, longitude, occurred_at,
+                                              observed_speed, speed_limit)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+            )
+            .bind(event.id)
+            .bind(&fix.tenant_id)
+            .bind(&event.user_id)
+            .bind(event.geofence_id)
+            .bind(transition.as_str())
+            .bind(event.latitude)
+            .bind(event.longitude)
+            .bind(event.occurred_at)
+            .bind(event.observed_speed)
+            .bind(event.speed_limit)
+            .execute(&self.db_pool)
+            .await?;
+
+            self.config.coordinate_precision.apply(&mut event);
+            self.live_updates.publish_geofence_event(&event);
+            if let Some(url) = webhook_url {
+                self.webhooks.dispatch(url.to_string(), event);
+            }
+
+            self.metrics
+                .geofence_transitions_total
+                .with_label_values(&[transition.as_str()])
+                .inc();
+
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_support;
+
+        fn geofence(tenant_id: &str, name: &str, geometry: GeofenceColumns) -> Geofence {
+            let (geofence_type, center_latitude, center_longitude, radius_meters, polygon) = geometry;
+            Geofence {
+                id: Uuid::new_v4(),
+                tenant_id: tenant_id.to_string(),
+                name: name.to_string(),
+                geofence_type: geofence_type.to_string(),
+                center_latitude,
+                center_longitude,
+                radius_meters,
+                polygon,
+                dwell_threshold_secs: None,
+                webhook_url: None,
+                min_altitude: None,
+                max_altitude: None,
+                speed_limit: None,
+                created_at: Utc::now(),
+            }
+        }
+
+        fn circle(tenant_id: &str, name: &str, latitude: f64, longitude: f64, radius_meters: f64) -> Geofence {
+            geofence(tenant_id, name, ("circle", Some(latitude), Some(longitude), Some(radius_meters), None))
+        }
+
+        /// A square `half_side` degrees either side of the point.
+        fn square(tenant_id: &str, name: &str, latitude: f64, longitude: f64, half_side: f64) -> Geofence {
+            let ring = vec![
+                [longitude - half_side, latitude - half_side],
+                [longitude + half_side, latitude - half_side],
+                [longitude + half_side, latitude + half_side],
+                [longitude - half_side, latitude + half_side],
+            ];
+            geofence(tenant_id, name, ("polygon", None, None, None, Some(Json(vec![ring]))))
+        }
+
+        fn names(nearest: &[NearestGeofence]) -> Vec<&str> {
+            nearest.iter().map(|geofence| geofence.name.as_str()).collect()
+        }
+
+        #[test]
+        fn geofences_are_ranked_by_distance_to_their_boundary() {
+            // About 111 m per 0.001 degree at the equator.
+            let geofences = vec![
+                circle("acme", "far", 0.0, 0.01, 100.0),
+                circle("acme", "near", 0.0, 0.002, 100.0),
+                square("acme", "middle", 0.0, 0.006, 0.001),
+            ];
+            let nearest = rank_nearest(geofences, "acme", 0.0, 0.0, 10);
+
+            assert_eq!(names(&nearest), ["near", "middle", "far"]);
+            assert!((nearest[0].distance_meters - 122.4).abs() < 1.0, "{}", nearest[0].distance_meters);
+            assert!((nearest[1].distance_meters - 556.0).abs() < 1.0, "{}", nearest[1].distance_meters);
+            assert!(nearest.iter().all(|geofence| !geofence.inside));
+        }
+
+        #[test]
+        fn a_polygon_is_measured_to_its_nearest_edge_and_is_at_0_from_inside() {
+            let outside = rank_nearest(vec![square("acme", "yard", 0.0, 0.0, 0.001)], "acme", 0.0, 0.002, 1);
+            assert!((outside[0].distance_meters - 111.2).abs() < 1.0, "{}", outside[0].distance_meters);
+            assert!(!outside[0].inside);
+
+            for (latitude, longitude) in [(0.0, 0.0), (0.0005, -0.0009), (0.0, 0.001)] {
+                let inside = rank_nearest(vec![square("acme", "yard", 0.0, 0.0, 0.001)], "acme", latitude, longitude, 1);
+                assert_eq!(inside[0].distance_meters, 0.0);
+                assert!(inside[0].inside);
+            }
+        }
+
+        #[test]
+        fn geofences_at_the_same_distance_are_ordered_by_name() {
+            let geofences = vec![
+                circle("acme", "charlie", 0.0, 0.0, 500.0),
+                square("acme", "alpha", 0.0, 0.0, 0.01),
+                circle("acme", "bravo", 0.0, 0.0, 50.0),
+                circle("acme", "delta", 0.0, 0.01, 100.0),
+            ];
+            let nearest = rank_nearest(geofences, "acme", 0.0, 0.0, 10);
+
+            assert_eq!(names(&nearest), ["alpha", "bravo", "charlie", "delta"]);
+            assert!(nearest[..3].iter().all(|geofence| geofence.inside && geofence.distance_meters == 0.0));
+        }
+
+        #[test]
+        fn only_the_tenants_own_geofences_are_suggested_up_to_the_limit() {
+            let geofences = vec![
+                circle("other", "theirs", 0.0, 0.0, 100.0),
+                circle("acme", "second", 0.0, 0.005, 100.0),
+                circle("acme", "first", 0.0, 0.002, 100.0),
+                circle("acme", "third", 0.0, 0.009, 100.0),
+                geofence("acme", "broken", ("circle", None, None, None, None)),
+            ];
+            let nearest = rank_nearest(geofences, "acme", 0.0, 0.0, 2);
+
+            assert_eq!(names(&nearest), ["first", "second"]);
+        }
+
+        #[test]
+        fn a_point_in_overlapping_geofences_is_in_each_of_them() {
+            let geofences = [
+                // About 111 m per 0.001 degree at the equator.
+                circle("acme", "campus", 0.0, 0.0, 200.0),
+                square("acme", "building", 0.0, 0.002, 0.001),
+                circle("acme", "car park", 0.0, 0.004, 100.0),
+            ];
+            let inside = |latitude: f64, longitude: f64| -> Vec<&str> {
+                geofences
+                    .iter()
+                    .filter(|g| g.bounding_box().is_none_or(|bbox| bbox.contains(latitude, longitude)))
+                    .filter(|g| g.contains_fix(latitude, longitude, None, false))
+                    .map(|g| g.name.as_str())
+                    .collect()
+            };
+
+            assert_eq!(inside(0.0, 0.0015), ["campus", "building"]);
+            assert_eq!(inside(0.0, 0.0025), ["building"]);
+            assert_eq!(inside(0.0, 0.004), ["car park"]);
+            assert_eq!(inside(0.0, -0.001), ["campus"]);
+            assert!(inside(0.01, 0.0).is_empty());
+        }
+
+        #[test]
+        fn bounding_boxes_never_reject_a_contained_point() {
+            let geofences = [circle("acme", "round", 51.5, -0.12, 250.0), square("acme", "square", 51.5, -0.12, 0.002)];
+            for geofence in &geofences {
+                let bbox = geofence.bounding_box().unwrap();
+                for step in 0..=100 {
+                    let (latitude, longitude) = (51.497 + 0.00006 * step as f64, -0.123 + 0.00006 * step as f64);
+                    if geofence.contains(latitude, longitude) {
+                        assert!(bbox.contains(latitude, longitude), "{} at {},{}", geofence.name, latitude, longitude);
+                    }
+                }
+            }
+        }
+
+        /// Circles of 5 km and squares of about 11 km on a grid a degree apart, alternating.
+        fn dispersed(tenant_id: &str, side: usize) -> Vec<Geofence> {
+            (0..side * side)
+                .map(|i| {
+                    let (latitude, longitude) = ((i / side) as f64 - 25.0, (i % side) as f64 - 25.0);
+                    if i % 2 == 0 {
+                        circle(tenant_id, &format!("circle-{}", i), latitude, longitude, 5000.0)
+                    } else {
+                        square(tenant_id, &format!("square-{}", i), latitude, longitude, 0.05)
+                    }
+                })
+                .collect()
+        }
+
+        #[test]
+        fn the_index_offers_a_handful_of_thousands_of_dispersed_geofences() {
+            let mut geofences = dispersed("acme", 50);
+            geofences.extend(dispersed("other", 10));
+            let index = GeofenceIndex::new(&geofences);
+
+            for step in 0..500 {
+                let (latitude, longitude) = (-25.5 + step as f64 * 0.1, -25.5 + (step * 7 % 500) as f64 * 0.1);
+                let candidates: Vec<&Geofence> = index.candidates("acme", latitude, longitude).collect();
+                assert!(candidates.len() <= 1, "{} candidates at {},{}", candidates.len(), latitude, longitude);
+                assert!(candidates.iter().all(|g| g.tenant_id == "acme"));
+
+                // Nothing containing the point is left out.
+                let containing = geofences.iter().filter(|g| g.tenant_id == "acme" && g.contains(latitude, longitude));
+                for geofence in containing {
+                    let offered = candidates.iter().any(|c| c.id == geofence.id);
+                    assert!(offered, "{} missed at {},{}", geofence.name, latitude, longitude);
+                }
+            }
+        }
+
+        #[test]
+        fn geofences_without_a_box_are_always_candidates() {
+            let mut geofences = dispersed("acme", 10);
+            geofences.push(circle("acme", "polar", 89.99, 0.0, 5000.0));
+            let index = GeofenceIndex::new(&geofences);
+            let names: Vec<&str> = index.candidates("acme", -30.0, 120.0).map(|g| g.name.as_str()).collect();
+            assert_eq!(names, ["polar"]);
+        }
+
+        #[tokio::test]
+        #[ignore = "needs Postgres"]
+        async fn stored_boxes_narrow_the_candidates_to_the_geofences_near_the_point() {
+            let state = test_support::migrated_state().await;
+            let service = &state.geolocation_service;
+            let tenant = format!("bbox-{}", Uuid::new_v4());
+            let mut conn = service.db_pool.acquire().await.unwrap();
+            for latitude in -10..10 {
+                for longitude in -10..10 {
+                    let shape = GeofenceShape::Circle {
+                        center_latitude: latitude as f64,
+                        center_longitude: longitude as f64,
+                        radius_meters: 5000.0,
+                    };
+                    insert_geofence(&mut conn, &tenant, create_request("dispersed", shape)).await.unwrap();
+                }
+            }
+
+            let near = service.candidate_geofences(&tenant, 3.01, -4.02, &[]).await.unwrap();
+            assert_eq!(near.len(), 1);
+            assert_eq!((near[0].center_latitude, near[0].center_longitude), (Some(3.0), Some(-4.0)));
+            assert!(service.candidate_geofences(&tenant, 3.5, -4.5, &[]).await.unwrap().is_empty());
+        }
+
+        fn create_request(name: &str, shape: GeofenceShape) -> CreateGeofenceRequest {
+            CreateGeofenceRequest {
+                name: name.to_string(),
+                shape,
+                dwell_threshold_secs: None,
+                webhook_url: None,
+                min_altitude: None,
+                max_altitude: None,
+                speed_limit: None,
+            }
+        }
+
+        #[tokio::test]
+        #[ignore = "needs Postgres"]
+        async fn evaluation_lists_every_overlapping_geofence_of_the_tenant() {
+            let state = test_support::migrated_state().await;
+            let service = &state.geolocation_service;
+            let tenant = format!("evaluate-{}", Uuid::new_v4());
+            let circle = |latitude, longitude, radius_meters| GeofenceShape::Circle {
+                center_latitude: latitude,
+                center_longitude: longitude,
+                radius_meters,
+            };
+            let square = vec![vec![[0.001, -0.001], [0.003, -0.001], [0.003, 0.001], [0.001, 0.001]]];
+            for (name, shape) in [
+                ("campus", circle(0.0, 0.0, 500.0)),
+                ("building", GeofenceShape::Polygon { coordinates: square }),
+                ("elsewhere", circle(1.0, 1.0, 500.0)),
+            ] {
+                service.create_geofence(&tenant, create_request(name, shape)).await.unwrap();
+            }
+            service
+                .create_geofence(&format!("{}-other", tenant), create_request("theirs", circle(0.0, 0.0, 500.0)))
+                .await
+                .unwrap();
+
+            let request = EvaluateGeofencesRequest { latitude: 0.0, longitude: 0.0025, altitude: None, user_id: None };
+            let evaluation = service.evaluate_point(&tenant, request).await.unwrap();
+            let names: Vec<&str> = evaluation.geofences.iter().map(|g| g.name.as_str()).collect();
+            assert_eq!(names, ["campus", "building"]);
+            assert!(evaluation.geofences.iter().all(|g| g.currently_inside.is_none()));
+        }
+    }
+}
+
+pub mod webhooks {
     use std::sync::Arc;
+    use std::time::Duration;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use sqlx::{types::Json, Pool, Postgres};
+    use tracing::{error, warn};
+    use uuid::Uuid;
+    use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+    use warp::hyper::{client::HttpConnector, Body, Client, Method, Request};
+    use crate::config::Config;
+    use crate::models::GeofenceEvent;
+
+    pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// Delivers geofence events to their webhooks in the background, so a slow or failing
+    /// endpoint never holds up the monitoring loop.
+    #[derive(Debug)]
+    pub struct WebhookDispatcher {
+        db_pool: Pool<Postgres>,
+        config: Arc<Config>,
+        client: Client<HttpsConnector<HttpConnector>>,
+    }
+
+    /// `sha256=` followed by the hex HMAC-SHA256 of `body` under `secret`.
+    pub fn signature(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    impl WebhookDispatcher {
+        pub fn new(db_pool: Pool<Postgres>, config: Arc<Config>) -> Self {
+            // Webhooks may be plain HTTP or HTTPS, verified against the bundled Mozilla roots.
+            let connector = HttpsConnectorBuilder::new().with_webpki_roots().https_or_http().enable_http1().build();
+            Self {
+                db_pool,
+                config,
+                client: Client::builder().build(connector),
+            }
+        }
+
+        /// POSTs `event` as JSON to `url`, retrying with exponential backoff up to
+        /// `webhook_max_attempts` times. Each attempt is cut off after `webhook_timeout_ms`.
+        /// Deliveries that never succeed are written to `webhook_dead_letters`.
+        pub fn dispatch(self: &Arc<Self>, url: String, event: GeofenceEvent) {
+            let dispatcher = self.clone();
+            tokio::spawn(async move {
+                let payload = match serde_json::to_vec(&event) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        error!("Failed to encode geofence event {}: {}", event.id, e);
+                        return;
+                    }
+                };
+                let signature = signature(&dispatcher.config.webhook_secret, &payload);
+
+                let mut backoff = Duration::from_millis(dispatcher.config.webhook_initial_backoff_ms);
+                let mut last_error = String::new();
+                for attempt in 1..=dispatcher.config.webhook_max_attempts {
+                    match dispatcher.attempt(&url, &payload, &signature, &event).await {
+                        Ok(()) => return,
+                        Err(e) => {
+                            warn!("Webhook delivery {} of event {} to {} failed: {}", attempt, event.id, url, e);
+                            last_error = e;
+                        }
+                    }
+                    if attempt < dispatcher.config.webhook_max_attempts {
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+
+                if let Err(e) = dispatcher.dead_letter(&url, &event, &payload, &last_error).await {
+                    error!("Failed to dead-letter webhook for event {}: {}", event.id, e);
+                }
+            });
+        }
+
+        async fn attempt(&self, url: &str, payload: &[u8], signature: &str, event: &GeofenceEvent) -> Result<(), String> {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header("content-type", "application/json")
+                .header("x-webhook-event-id", event.id.to_string())
+                .header(SIGNATURE_HEADER, signature)
+                .body(Body::from(payload.to_vec()))
+                .map_err(|e| e.to_string())?;
+
+            let timeout = Duration::from_millis(self.config.webhook_timeout_ms);
+            match tokio::time::timeout(timeout, self.client.request(request)).await {
+                Ok(Ok(response)) if response.status().is_success() => Ok(()),
+                Ok(Ok(response)) => Err(format!("endpoint answered {}", response.status())),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err(format!("timed out after {}ms", timeout.as_millis())),
+            }
+        }
+
+        async fn dead_letter(&self, url: &str, event: &GeofenceEvent, payload: &[u8], last_error: &str) -> Result<(), sqlx::Error> {
+            let payload: serde_json::Value = serde_json::from_slice(payload).unwrap_or_default();
+            sqlx::query(
+                "INSERT INTO webhook_dead_letters (id, geofence_id, event_id, url, payload, attempts, last_error)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            )
+            .bind(Uuid::new_v4())
+            .bind(event.geofence_id)
+            .bind(event.id)
+            .bind(url)
+            .bind(Json(payload))
+            .bind(self.config.webhook_max_attempts as i32)
+            .bind(last_error)
+            .execute(&self.db_pool)
+            .await?;
+            Ok(())
+        }
+    }
+}
+
+pub mod route_optimization {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use chrono::{DateTime, Utc};
+    use futures_util::future::try_join_all;
+    use serde::Deserialize;
+    use sqlx::{types::Json, Pool, Postgres};
+    use tracing::warn;
+    use uuid::Uuid;
+    use warp::hyper::{body, client::HttpConnector, Client};
+    use crate::config::Config;
+    use crate::models::{
+        DistanceMetric, Location, MatchQuery, MatchedPoint, MatchedTrack, OptimizeRouteRequest, OptimizedRoute, Route,
+        MAX_MATCH_POINTS,
+    };
+
+    /// OSRM's default `max_matching_size`; longer tracks are matched in chunks of this many fixes.
+    const MATCH_CHUNK_SIZE: usize = 100;
+    /// Search radius bounds, in meters, derived from each fix's reported accuracy.
+    const MIN_MATCH_RADIUS: f64 = 5.0;
+    const MAX_MATCH_RADIUS: f64 = 50.0;
+    const DEFAULT_MATCH_RADIUS: f64 = 10.0;
+
+    #[derive(Debug, Deserialize)]
+    struct OsrmResponse {
+        code: String,
+        #[serde(default)]
+        message: Option<String>,
+        #[serde(default)]
+        matchings: Vec<OsrmMatching>,
+        #[serde(default)]
+        tracepoints: Vec<Option<OsrmTracepoint>>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct OsrmMatching {
+        confidence: f64,
+        geometry: OsrmGeometry,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct OsrmGeometry {
+        coordinates: Vec<[f64; 2]>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct OsrmTracepoint {
+        location: [f64; 2],
+        matchings_index: usize,
+    }
+
+    /// Matched geometry and points of one chunk of the track.
+    type MatchedChunk = (Vec<[f64; 2]>, Vec<MatchedPoint>);
+
+    const ROUTE_COLUMNS: &str =
+        "id, waypoints, waypoint_order, distance_metric, total_distance_meters, two_opt_iterations, created_at";
+
+    /// Above this many waypoints 2-opt is skipped and the nearest-neighbor tour is returned as-is.
+    const MAX_TWO_OPT_WAYPOINTS: usize = 200;
+
+    #[derive(Debug)]
+    pub struct RouteOptimizer {
+        db_pool: Pool<Postgres>,
+        config: Arc<Config>,
+        http: Client<HttpConnector>,
+    }
+
+    fn raw_point(location: &Location) -> MatchedPoint {
+        MatchedPoint {
+            timestamp: location.timestamp,
+            latitude: location.latitude,
+            longitude: location.longitude,
+            raw_latitude: location.latitude,
+            raw_longitude: location.longitude,
+            confidence: None,
+        }
+    }
+
+    impl RouteOptimizer {
+        pub fn new(db_pool: Pool<Postgres>, config: Arc<Config>) -> Self {
+            Self {
+                db_pool,
+                config,
+                http: Client::new(),
+            }
+        }
+
+        /// The user's fixes in the window snapped to roads by the configured OSRM backend. Any
+        /// backend problem, including running past `map_matching_timeout_ms`, yields the raw
+        /// track with `matched: false` instead of an error.
+        pub async fn matched_track(&self, tenant_id: &str, user_id: &str, query: &MatchQuery) -> Result<MatchedTrack, sqlx::Error> {
+            let mut points = sqlx::query_as::<_, Location>(
+                "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                 FROM locations WHERE tenant_id = $1 AND user_id = $2 AND timestamp BETWEEN $3 AND $4
+                 ORDER BY timestamp, seq, id LIMIT $5",
+            )
+            .bind(tenant_id)
+            .bind(user_id)
+            .bind(query.from)
+            .bind(query.to)
+            .bind(MAX_MATCH_POINTS + 1)
+            .fetch_all(&self.db_pool)
+            .await?;
+            let truncated = points.len() as i64 > MAX_MATCH_POINTS;
+            points.truncate(MAX_MATCH_POINTS as usize);
+
+            let matched = match &self.config.map_matching_url {
+                Some(base_url) if points.len() >= 2 => {
+                    let budget = Duration::from_millis(self.config.map_matching_timeout_ms);
+                    let chunks = points.chunks(MATCH_CHUNK_SIZE).map(|chunk| self.match_chunk(base_url, chunk));
+                    match tokio::time::timeout(budget, try_join_all(chunks)).await {
+                        Ok(Ok(chunks)) => Ok(chunks),
+                        Ok(Err(reason)) => Err(reason),
+                        Err(_) => Err(format!("map matching timed out after {}ms", budget.as_millis())),
+                    }
+                }
+                Some(_) => Err("at least two fixes are needed for map matching".to_string()),
+                None => Err("map matching is not configured".to_string()),
+            };
+
+            let (matched, fallback_reason, geometry, points) = match matched {
+                Ok(chunks) => {
+                    let (geometry, points): (Vec<_>, Vec<_>) = chunks.into_iter().unzip();
+                    (true, None, geometry.concat(), points.into_iter().flatten().collect())
+                }
+                Err(reason) => {
+                    if self.config.map_matching_url.is_some() {
+                        warn!("Returning the raw track of {}: {}", user_id, reason);
+                    }
+                    let geometry = points.iter().map(|p| [p.longitude, p.latitude]).collect();
+                    (false, Some(reason), geometry, points.iter().map(raw_point).collect())
+                }
+            };
+
+            Ok(MatchedTrack {
+                user_id: user_id.to_string(),
+                from: query.from,
+                to: query.to,
+                matched,
+                fallback_reason,
+                truncated,
+                geometry,
+                points,
+            })
+        }
+
+        /// Calls OSRM's `match` service for up to [`MATCH_CHUNK_SIZE`] fixes.
+        async fn match_chunk(&self, base_url: &str, chunk: &[Location]) -> Result<MatchedChunk, String> {
+            if chunk.len() < 2 {
+                let geometry = chunk.iter().map(|p| [p.longitude, p.latitude]).collect();
+                return Ok((geometry, chunk.iter().map(raw_point).collect()));
+            }
+
+            let join = |values: Vec<String>, separator: &str| values.join(separator);
+            let coordinates = join(chunk.iter().map(|p| format!("{:.6},{:.6}", p.longitude, p.latitude)).collect(), ";");
+            let timestamps = join(chunk.iter().map(|p| p.timestamp.timestamp().to_string()).collect(), ";");
+            let radiuses = join(
+                chunk
+                    .iter()
+                    .map(|p| {
+                        let radius = p.accuracy.map_or(DEFAULT_MATCH_RADIUS, |a| a.clamp(MIN_MATCH_RADIUS, MAX_MATCH_RADIUS));
+                        format!("{:.1}", radius)
+                    })
+                    .collect(),
+                ";",
+            );
+            let url = format!(
+                "{}/match/v1/{}/{}?timestamps={}&radiuses={}&geometries=geojson&overview=full&gaps=split",
+                base_url, self.config.map_matching_profile, coordinates, timestamps, radiuses
+            );
+            let uri = url.parse().map_err(|e| format!("invalid map matching URL: {}", e))?;
+
+            let response = self.http.get(uri).await.map_err(|e| format!("map matching backend unreachable: {}", e))?;
+            let status = response.status();
+            let bytes = body::to_bytes(response.into_body())
+                .await
+                .map_err(|e| format!("failed to read map matching response: {}", e))?;
+            let parsed: OsrmResponse = serde_json::from_slice(&bytes)
+                .map_err(|e| format!("unreadable map matching response ({}): {}", status, e))?;
+            if parsed.code != "Ok" {
+                return Err(format!(
+                    "map matching backend answered {}: {}",
+                    parsed.code,
+                    parsed.message.unwrap_or_default()
+                ));
+            }
+
+            let geometry = parsed.matchings.iter().flat_map(|m| m.geometry.coordinates.iter().copied()).collect();
+            let points = chunk
+                .iter()
+                .zip(parsed.tracepoints.iter().map(Some).chain(std::iter::repeat(None)))
+                .map(|(fix, tracepoint)| match tracepoint.and_then(Option::as_ref) {
+                    Some(tracepoint) => MatchedPoint {
+                        latitude: tracepoint.location[1],
+                        longitude: tracepoint.location[0],
+                        confidence: Some(parsed.matchings.get(tracepoint.matchings_index).map_or(0.0, |m| m.confidence)),
+                        ..raw_point(fix)
+                    },
+                    // Discarded by the backend as an outlier.
+                    None => MatchedPoint { confidence: Some(0.0), ..raw_point(fix) },
+                })
+                .collect();
+            Ok((geometry, points))
+        }
+
+        /// Stores an optimized route with its waypoints already arranged in visiting order.
+        pub async fn save_route(
+            &self,
+            tenant_id: &str,
+            waypoints: &[(f64, f64)],
+            route: &OptimizedRoute,
+        ) -> Result<Route, sqlx::Error> {
+            let ordered: Vec<(f64, f64)> = route.order.iter().map(|&i| waypoints[i]).collect();
+
+            sqlx::query_as::<_, Route>(&format!(
+                "INSERT INTO routes (id, tenant_id, waypoints, waypoint_order, distance_metric, total_distance_meters, two_opt_iterations)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)
+                 RETURNING {}",
+                ROUTE_COLUMNS
+            ))
+            .bind(Uuid::new_v4())
+            .bind(tenant_id)
+            .bind(Json(ordered))
+            .bind(Json(&route.order))
+            .bind(route.metric.as_str())
+            .bind(route.total_distance_meters)
+            .bind(route.two_opt_iterations as i32)
+            .fetch_one(&self.db_pool)
+            .await
+        }
+
+        /// `None` for unknown routes and those of other tenants.
+        pub async fn get_route(&self, tenant_id: &str, route_id: Uuid) -> Result<Option<Route>, sqlx::Error> {
+            sqlx::query_as::<_, Route>(&format!("SELECT {} FROM routes WHERE id = $1 AND tenant_id = $2", ROUTE_COLUMNS))
+                .bind(route_id)
+                .bind(tenant_id)
+                .fetch_optional(&self.db_pool)
+                .await
+        }
+
+        /// Orders `(latitude, longitude)` waypoints into an open path starting at the first one,
+        /// using nearest-neighbor construction followed by 2-opt improvement within the configured
+        /// time budget. Both phases measure legs with `metric`.
+        pub fn optimize(&self, waypoints: Vec<(f64, f64)>, metric: DistanceMetric) -> OptimizedRoute {
+            let distance = |a: usize, b: usize| metric.meters(waypoints[a], waypoints[b]);
+
+            let mut order = nearest_neighbor(waypoints.len(), &distance);
+            let mut iterations = 0;
+
+            if waypoints.len() <= MAX_TWO_OPT_WAYPOINTS {
+                let deadline = Instant::now() + Duration::from_millis(self.config.route_optimization_budget_ms);
+                iterations = two_opt(&mut order, &distance, deadline);
+            }
+
+            let total_distance_meters = order.windows(2).map(|leg| distance(leg[0], leg[1])).sum();
+
+            OptimizedRoute {
+                order,
+                metric,
+                total_distance_meters,
+                two_opt_iterations: iterations,
+            }
+        }
+
+        /// Arrival time at each waypoint of `route`, in visiting order, leaving the depot at the
+        /// requested departure time. Each leg is driven at its override speed when one is given
+        /// and at the average speed otherwise, over the leg length under the route's metric.
+        pub fn etas(&self, request: &OptimizeRouteRequest, route: &OptimizedRoute) -> Vec<DateTime<Utc>> {
+            let average_speed_kmh = request.average_speed_kmh.unwrap_or(self.config.default_route_speed_kmh);
+            let legs: Vec<(f64, f64)> = route
+                .order
+                .windows(2)
+                .map(|leg| {
+                    let speed_kmh = request
+                        .leg_speeds_kmh
+                        .as_ref()
+                        .and_then(|speeds| speeds[leg[1]])
+                        .unwrap_or(average_speed_kmh);
+                    (route.metric.meters(request.waypoints[leg[0]], request.waypoints[leg[1]]), speed_kmh)
+                })
+                .collect();
+
+            cumulative_etas(request.departure_time.unwrap_or_else(Utc::now), &legs)
+        }
+    }
+
+    /// Departure followed by the arrival after each `(distance_meters, speed_kmh)` leg.
+    fn cumulative_etas(departure: DateTime<Utc>, legs: &[(f64, f64)]) -> Vec<DateTime<Utc>> {
+        let mut etas = Vec::with_capacity(legs.len() + 1);
+        let mut elapsed_secs = 0.0;
+        etas.push(departure);
+        for &(distance_meters, speed_kmh) in legs {
+            elapsed_secs += distance_meters / (speed_kmh / 3.6);
+            etas.push(departure + chrono::Duration::milliseconds((elapsed_secs * 1000.0).round() as i64));
+        }
+        etas
+    }
+
+    fn nearest_neighbor(count: usize, distance: &impl Fn(usize, usize) -> f64) -> Vec<usize> {
+        if count == 0 {
+            return Vec::new();
+        }
+
+        let mut visited = vec![false; count];
+        let mut order = Vec::with_capacity(count);
+        let mut current = 0;
+        visited[0] = true;
+        order.push(0);
+
+        while order.len() < count {
+            let next = (0..count)
+                .filter(|&candidate| !visited[candidate])
+                .min_by(|&a, &b| distance(current, a).total_cmp(&distance(current, b)))
+                .expect("unvisited waypoint remains");
+            visited[next] = true;
+            order.push(next);
+            current = next;
+        }
+
+        order
+    }
+
+    /// Applies improving segment reversals until none remain or the deadline passes. The depot at
+    /// position 0 is never moved. Returns the number of reversals applied.
+    fn two_opt(order: &mut [usize], distance: &impl Fn(usize, usize) -> f64, deadline: Instant) -> u32 {
+        let n = order.len();
+        let mut iterations = 0;
+        let mut improved = true;
+
+        while improved {
+            improved = false;
+
+            for i in 1..n.saturating_sub(1) {
+                if Instant::now() >= deadline {
+                    return iterations;
+                }
+
+                for j in (i + 1)..n {
+                    let before = distance(order[i - 1], order[i])
+                        + if j + 1 < n { distance(order[j], order[j + 1]) } else { 0.0 };
+                    let after = distance(order[i - 1], order[j])
+                        + if j + 1 < n { distance(order[i], order[j + 1]) } else { 0.0 };
+
+                    if after + 1e-9 < before {
+                        order[i..=j].reverse();
+                        iterations += 1;
+                        improved = true;
+                    }
+                }
+            }
+        }
+
+        iterations
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_support;
+
+        /// `(latitude, longitude)` of a point `east` and `north` meters from the origin.
+        fn offset(east: f64, north: f64) -> (f64, f64) {
+            let meters_per_degree = crate::utils::EARTH_RADIUS_METERS.to_radians();
+            (north / meters_per_degree, east / meters_per_degree)
+        }
+
+        #[tokio::test]
+        async fn manhattan_distance_prefers_the_stop_along_the_grid() {
+            let optimizer = &test_support::state().route_optimizer;
+            // A block diagonally away is nearer as the crow flies than one 160 m down the street,
+            // but further when driving along the grid.
+            let waypoints = vec![offset(0.0, 0.0), offset(100.0, 100.0), offset(160.0, 0.0)];
+
+            let haversine = optimizer.optimize(waypoints.clone(), DistanceMetric::Haversine);
+            let manhattan = optimizer.optimize(waypoints, DistanceMetric::Manhattan);
+
+            assert_eq!(haversine.order, [0, 1, 2]);
+            assert_eq!(manhattan.order, [0, 2, 1]);
+            assert!((manhattan.total_distance_meters - 320.0).abs() < 0.5);
+        }
+
+        #[tokio::test]
+        async fn every_metric_orders_the_whole_grid_and_measures_it_with_itself() {
+            let optimizer = &test_support::state().route_optimizer;
+            let waypoints: Vec<(f64, f64)> =
+                (0..25).map(|i| offset((i * 7 % 5) as f64 * 150.0, (i * 3 % 5) as f64 * 150.0)).collect();
+
+            for metric in [DistanceMetric::Haversine, DistanceMetric::Euclidean, DistanceMetric::Manhattan] {
+                let route = optimizer.optimize(waypoints.clone(), metric);
+                assert_eq!(route.order[0], 0);
+                let mut visited = route.order.clone();
+                visited.sort_unstable();
+                assert_eq!(visited, (0..waypoints.len()).collect::<Vec<_>>());
+
+                assert_eq!(route.metric, metric);
+                let total: f64 = route.order.windows(2).map(|leg| metric.meters(waypoints[leg[0]], waypoints[leg[1]])).sum();
+                assert!((route.total_distance_meters - total).abs() < 1e-6);
+            }
+        }
+
+        #[test]
+        fn the_metric_defaults_to_haversine() {
+            let request: OptimizeRouteRequest = serde_json::from_str(r#"{"waypoints": [[0, 0], [0, 1]]}"#).unwrap();
+            assert_eq!(request.metric, DistanceMetric::Haversine);
+        }
+    }
+}
+
+pub mod analytics_service {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use chrono::{DateTime, NaiveDate, Utc};
+    use futures_util::TryStreamExt;
+    use redis::{AsyncCommands, Script};
     use sqlx::{Pool, Postgres};
-    use redis::Client as RedisClient;
+    use tokio::sync::broadcast::error::RecvError;
+    use tracing::{error, info, warn};
+    use uuid::Uuid;
     use crate::config::Config;
+    use crate::metrics::Metrics;
+    use crate::models::{
+        ActiveUsersResult, AnalyticsQuery, AnalyticsSummary, CellId, DistanceResult, Grid, HeatmapCell, HeatmapQuery, HeatmapResult,
+        Location, RollingStats, Stop, StopsResult, Trip, TripsResult, DEFAULT_ACTIVE_USERS_WINDOW_MINUTES, MAX_HEATMAP_CELLS,
+    };
+    use crate::redis_client::RedisClient;
+    use crate::utils::{
+        geohash, h3, haversine_meters, redis_keys, smoothing::kalman_smooth_with_accuracy, track_distance_meters,
+    };
+    use super::live_updates::LiveUpdates;
+
+    type FlushError = Box<dyn std::error::Error + Send + Sync>;
+
+    /// Makes a fix the user's newest folded one unless a later fix already is, and returns the
+    /// one it replaces: nil when the fix is not the newest, fields nil when there was none.
+    const SWAP_LAST_FIX_SCRIPT: &str = r#"
+        local previous = redis.call('HMGET', KEYS[1], 'latitude', 'longitude', 'timestamp')
+        local timestamp = tonumber(previous[3])
+        if timestamp and timestamp >= tonumber(ARGV[3]) then
+            return false
+        end
+        redis.call('HSET', KEYS[1], 'latitude', ARGV[1], 'longitude', ARGV[2], 'timestamp', ARGV[3])
+        redis.call('EXPIRE', KEYS[1], ARGV[4])
+        return previous
+    "#;
+
+    /// Adds a fix to its bucket and marks the bucket for the next flush. An empty speed leaves
+    /// the speed figures alone.
+    const ADD_TO_BUCKET_SCRIPT: &str = r#"
+        redis.call('HINCRBYFLOAT', KEYS[1], 'distance_meters', ARGV[1])
+        redis.call('HINCRBY', KEYS[1], 'point_count', 1)
+        if ARGV[2] ~= '' then
+            local speed = tonumber(ARGV[2])
+            redis.call('HINCRBYFLOAT', KEYS[1], 'speed_sum', speed)
+            redis.call('HINCRBY', KEYS[1], 'speed_count', 1)
+            local max = tonumber(redis.call('HGET', KEYS[1], 'max_speed'))
+            if not max or speed > max then
+                redis.call('HSET', KEYS[1], 'max_speed', ARGV[2])
+            end
+        end
+        redis.call('EXPIRE', KEYS[1], ARGV[3])
+        redis.call('SADD', KEYS[2], ARGV[4])
+        return 1
+    "#;
+
+    /// Running totals of one rolling-aggregate bucket, or of several added together.
+    #[derive(Debug, Default)]
+    struct BucketTotals {
+        distance_meters: f64,
+        speed_sum: f64,
+        speed_count: i64,
+        max_speed: Option<f64>,
+        point_count: i64,
+    }
+
+    impl BucketTotals {
+        fn from_hash(fields: &HashMap<String, f64>) -> Self {
+            Self {
+                distance_meters: fields.get("distance_meters").copied().unwrap_or_default(),
+                speed_sum: fields.get("speed_sum").copied().unwrap_or_default(),
+                speed_count: fields.get("speed_count").copied().unwrap_or_default() as i64,
+                max_speed: fields.get("max_speed").copied(),
+                point_count: fields.get("point_count").copied().unwrap_or_default() as i64,
+            }
+        }
+
+        fn add(&mut self, other: BucketTotals) {
+            self.distance_meters += other.distance_meters;
+            self.speed_sum += other.speed_sum;
+            self.speed_count += other.speed_count;
+            self.max_speed = match (self.max_speed, other.max_speed) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            };
+            self.point_count += other.point_count;
+        }
 
-    pub struct TrackingService {
-        _db_pool: Pool<Postgres>,
-        _redis_client: RedisClient,
-        _config: Arc<Config>,
+        fn into_stats(self, config: &Config) -> RollingStats {
+            RollingStats {
+                window_secs: config.rolling_window_secs,
+                bucket_secs: config.rolling_bucket_secs,
+                distance_meters: self.distance_meters,
+                average_speed: (self.speed_count > 0).then(|| self.speed_sum / self.speed_count as f64),
+                max_speed: self.max_speed,
+                point_count: self.point_count,
+            }
+        }
     }
 
-    impl TrackingService {
-        pub fn new(db_pool: Pool<Postgres>, redis_client: RedisClient, config: Arc<Config>) -> Self {
+    /// Adds `user_id` to the current minute's active-users buckets of its tenant and of all
+    /// tenants, which expire after `ttl_secs`.
+    pub async fn mark_active(redis_client: &RedisClient, tenant_id: &str, user_id: &str, ttl_secs: u64) -> redis::RedisResult<()> {
+        let minute = Utc::now().timestamp() / 60;
+        let key = redis_keys::active_users_bucket(tenant_id, minute);
+        let all_key = redis_keys::all_active_users_bucket(minute);
+        let mut conn = redis_client.get_multiplexed_async_connection().await?;
+        redis::pipe()
+            .sadd(&key, user_id)
+            .ignore()
+            .expire(&key, ttl_secs as usize)
+            .ignore()
+            .sadd(&all_key, redis_keys::presence_member(tenant_id, user_id))
+            .ignore()
+            .expire(&all_key, ttl_secs as usize)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+    }
+
+    /// Consecutive fixes allowed to land outside a stop's radius before the stop is considered
+    /// over, so a brief GPS glitch that jumps out and back does not split it.
+    const STOP_GLITCH_TOLERANCE: usize = 2;
+
+    /// Fixes gathered around a candidate stop, with a running centroid.
+    struct Cluster {
+        latitude: f64,
+        longitude: f64,
+        first: usize,
+        last: usize,
+        count: usize,
+    }
+
+    impl Cluster {
+        fn start(index: usize, point: &Location) -> Self {
             Self {
-                _db_pool: db_pool,
-                _redis_client: redis_client,
-                _config: config,
+                latitude: point.latitude,
+                longitude: point.longitude,
+                first: index,
+                last: index,
+                count: 1,
             }
         }
 
-        pub async fn start_data_aggregation(&self) {
-            // Placeholder implementation
+        fn add(&mut self, index: usize, point: &Location) {
+            self.count += 1;
+            self.latitude += (point.latitude - self.latitude) / self.count as f64;
+            self.longitude += (point.longitude - self.longitude) / self.count as f64;
+            self.last = index;
+        }
+
+        fn into_stop(self, points: &[Location], min_duration_secs: i64) -> Option<StopSpan> {
+            let started_at = points[self.first].timestamp;
+            let ended_at = points[self.last].timestamp;
+            let duration_secs = (ended_at - started_at).num_seconds();
+            (duration_secs >= min_duration_secs).then_some(StopSpan {
+                first: self.first,
+                last: self.last,
+                stop: Stop {
+                    latitude: self.latitude,
+                    longitude: self.longitude,
+                    started_at,
+                    ended_at,
+                    duration_secs,
+                    point_count: self.count,
+                },
+            })
         }
     }
-}
 
-pub mod geolocation_service {
-    use std::sync::Arc;
-    use sqlx::{Pool, Postgres};
-    use crate::config::Config;
+    /// A detected stop together with the indices of its first and last fix in the track.
+    struct StopSpan {
+        first: usize,
+        last: usize,
+        stop: Stop,
+    }
 
-    pub struct GeolocationService {
-        _db_pool: Pool<Postgres>,
-        _config: Arc<Config>,
+    /// Splits a time-ordered track into stops: runs of fixes within `radius_meters` of their
+    /// centroid lasting at least `min_duration_secs`.
+    fn detect_stops(points: &[Location], radius_meters: f64, min_duration_secs: i64) -> Vec<StopSpan> {
+        let mut stops = Vec::new();
+        let Some(first) = points.first() else {
+            return stops;
+        };
+
+        let mut cluster = Cluster::start(0, first);
+        let mut outliers = 0;
+        let mut index = 1;
+        while index < points.len() {
+            let point = &points[index];
+            if haversine_meters(cluster.latitude, cluster.longitude, point.latitude, point.longitude) <= radius_meters {
+                cluster.add(index, point);
+                outliers = 0;
+            } else if outliers < STOP_GLITCH_TOLERANCE {
+                outliers += 1;
+            } else {
+                // The user really left: close the cluster and restart from the first fix outside it.
+                let restart = cluster.last + 1;
+                stops.extend(cluster.into_stop(points, min_duration_secs));
+                cluster = Cluster::start(restart, &points[restart]);
+                outliers = 0;
+                index = restart;
+            }
+            index += 1;
+        }
+        stops.extend(cluster.into_stop(points, min_duration_secs));
+
+        stops
     }
 
-    impl GeolocationService {
-        pub fn new(db_pool: Pool<Postgres>, config: Arc<Config>) -> Self {
+    /// Splits a time-ordered track into trips: the movement between consecutive stops, further
+    /// cut wherever two fixes are more than `max_gap_secs` apart. A trip starts at the last fix
+    /// of the stop before it and ends at the first fix of the stop after it.
+    fn detect_trips(points: &[Location], stops: &[StopSpan], max_gap_secs: i64) -> Vec<Trip> {
+        let mut segments = Vec::with_capacity(stops.len() + 1);
+        let mut start = 0;
+        for span in stops {
+            segments.push(&points[start..=span.first]);
+            start = span.last;
+        }
+        if start < points.len() {
+            segments.push(&points[start..]);
+        }
+
+        segments
+            .into_iter()
+            .flat_map(|segment| {
+                segment.chunk_by(|a, b| (b.timestamp - a.timestamp).num_seconds() <= max_gap_secs)
+            })
+            .filter(|trip| trip.len() >= 2)
+            .map(|trip| {
+                let (first, last) = (&trip[0], &trip[trip.len() - 1]);
+                let distance_meters = track_distance_meters(trip);
+                let duration_secs = (last.timestamp - first.timestamp).num_seconds();
+                Trip {
+                    started_at: first.timestamp,
+                    ended_at: last.timestamp,
+                    start_latitude: first.latitude,
+                    start_longitude: first.longitude,
+                    end_latitude: last.latitude,
+                    end_longitude: last.longitude,
+                    distance_meters,
+                    duration_secs,
+                    average_speed: (duration_secs > 0).then(|| distance_meters / duration_secs as f64),
+                    point_count: trip.len(),
+                }
+            })
+            .collect()
+    }
+
+    #[derive(Debug)]
+    pub struct AnalyticsService {
+        db_pool: Pool<Postgres>,
+        redis_client: RedisClient,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+        live_updates: Arc<LiveUpdates>,
+    }
+
+    impl AnalyticsService {
+        pub fn new(
+            db_pool: Pool<Postgres>,
+            redis_client: RedisClient,
+            config: Arc<Config>,
+            metrics: Arc<Metrics>,
+            live_updates: Arc<LiveUpdates>,
+        ) -> Self {
             Self {
-                _db_pool: db_pool,
-                _config: config,
+                db_pool,
+                redis_client,
+                config,
+                metrics,
+                live_updates,
             }
         }
 
-        pub async fn start_geofence_monitoring(&self) {
-            // Placeholder implementation
+        /// Distinct users of the tenant that reported a fix in the current minute or the
+        /// `window_minutes - 1` before it, or of every tenant when `tenant_id` is `None`. Counted by
+        /// unioning the per-minute buckets into a short-lived key.
+        pub async fn active_users(&self, tenant_id: Option<&str>, window_minutes: u32) -> redis::RedisResult<ActiveUsersResult> {
+            let current_minute = Utc::now().timestamp() / 60;
+            let buckets: Vec<String> = (0..i64::from(window_minutes))
+                .map(|offset| match tenant_id {
+                    Some(tenant_id) => redis_keys::active_users_bucket(tenant_id, current_minute - offset),
+                    None => redis_keys::all_active_users_bucket(current_minute - offset),
+                })
+                .collect();
+            let union_key = redis_keys::active_users_union(&Uuid::new_v4().to_string());
+
+            let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+            let (active_users,): (u64,) = redis::pipe()
+                .atomic()
+                .sunionstore(&union_key, &buckets)
+                .ignore()
+                .scard(&union_key)
+                .del(&union_key)
+                .ignore()
+                .query_async(&mut conn)
+                .await?;
+
+            Ok(ActiveUsersResult { window_minutes, active_users })
+        }
+
+        /// Summarises a user's fixes within the query window. Distance for completed UTC days that
+        /// lie wholly inside the window comes from the `daily_stats` rollup when one exists; the
+        /// rest is computed from raw fixes the same way the rollup is, one day at a time.
+        pub async fn user_summary(&self, tenant_id: &str, query: &AnalyticsQuery) -> Result<AnalyticsSummary, sqlx::Error> {
+            let (point_count, average_speed, max_speed, first_fix, last_fix) =
+                sqlx::query_as::<_, (i64, Option<f64>, Option<f64>, Option<DateTime<Utc>>, Option<DateTime<Utc>>)>(
+                    "SELECT COUNT(*), AVG(speed), MAX(speed), MIN(timestamp), MAX(timestamp)
+                     FROM locations WHERE tenant_id = $1 AND user_id = $2 AND timestamp BETWEEN $3 AND $4",
+                )
+                .bind(tenant_id)
+                .bind(&query.user_id)
+                .bind(query.from)
+                .bind(query.to)
+                .fetch_one(&self.db_pool)
+                .await?;
+
+            let first_full_day = match query.from.date_naive() {
+                day if day.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc() == query.from => day,
+                day => day.succ_opt().unwrap_or(day),
+            };
+            let rollup_end = query.to.date_naive().min(Utc::now().date_naive());
+            let rollups = sqlx::query_as::<_, (NaiveDate, f64)>(
+                "SELECT date, distance_meters FROM daily_stats
+                 WHERE tenant_id = $1 AND user_id = $2 AND date >= $3 AND date < $4",
+            )
+            .bind(tenant_id)
+            .bind(&query.user_id)
+            .bind(first_full_day)
+            .bind(rollup_end)
+            .fetch_all(&self.db_pool)
+            .await?;
+            let rolled_up_days: Vec<NaiveDate> = rollups.iter().map(|(date, _)| *date).collect();
+
+            let points = sqlx::query_as::<_, Location>(
+                "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                 FROM locations
+                 WHERE tenant_id = $1 AND user_id = $2 AND timestamp BETWEEN $3 AND $4
+                   AND (timestamp AT TIME ZONE 'UTC')::date <> ALL($5)
+                 ORDER BY timestamp, seq, id",
+            )
+            .bind(tenant_id)
+            .bind(&query.user_id)
+            .bind(query.from)
+            .bind(query.to)
+            .bind(&rolled_up_days)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+            let raw_distance: f64 = points
+                .chunk_by(|a, b| a.timestamp.date_naive() == b.timestamp.date_naive())
+                .map(|day| {
+                    if self.config.smooth_tracks {
+                        track_distance_meters(&kalman_smooth_with_accuracy(day, self.config.smoothing_default_accuracy_meters))
+                    } else {
+                        track_distance_meters(day)
+                    }
+                })
+                .sum();
+            let rolled_up_distance: f64 = rollups.iter().map(|(_, distance)| distance).sum();
+            let rolling = self.rolling_stats(tenant_id, &query.user_id).await;
+
+            Ok(AnalyticsSummary {
+                user_id: query.user_id.clone(),
+                from: query.from,
+                to: query.to,
+                total_distance_meters: raw_distance + rolled_up_distance,
+                average_speed,
+                max_speed,
+                point_count,
+                active_duration_secs: match (first_fix, last_fix) {
+                    (Some(first), Some(last)) => (last - first).num_seconds(),
+                    _ => 0,
+                },
+                rolling,
+            })
+        }
+
+        /// Distance along every fix in the window, in order. Repeated positions add nothing but are
+        /// still counted as points.
+        pub async fn user_distance(&self, tenant_id: &str, query: &AnalyticsQuery, smooth: bool) -> Result<DistanceResult, sqlx::Error> {
+            let mut points = self.track(tenant_id, query).await?;
+            if smooth {
+                points = kalman_smooth_with_accuracy(&points, self.config.smoothing_default_accuracy_meters);
+            }
+
+            Ok(DistanceResult {
+                user_id: query.user_id.clone(),
+                from: query.from,
+                to: query.to,
+                smoothed: smooth,
+                distance_meters: track_distance_meters(&points),
+                point_count: points.len(),
+            })
+        }
+
+        /// Stops made by a user within the query window, oldest first.
+        pub async fn user_stops(&self, tenant_id: &str, query: &AnalyticsQuery) -> Result<StopsResult, sqlx::Error> {
+            let points = self.track(tenant_id, query).await?;
+            let precision = self.config.coordinate_precision;
+
+            Ok(StopsResult {
+                user_id: query.user_id.clone(),
+                from: query.from,
+                to: query.to,
+                stops: detect_stops(&points, self.config.stop_radius_meters, self.config.stop_min_duration_secs)
+                    .into_iter()
+                    .map(|span| {
+                        let mut stop = span.stop;
+                        precision.apply(&mut stop);
+                        stop
+                    })
+                    .collect(),
+            })
+        }
+
+        /// Trips made by a user within the query window, oldest first.
+        pub async fn user_trips(&self, tenant_id: &str, query: &AnalyticsQuery) -> Result<TripsResult, sqlx::Error> {
+            let points = self.track(tenant_id, query).await?;
+            let stops = detect_stops(&points, self.config.stop_radius_meters, self.config.stop_min_duration_secs);
+            let mut trips = detect_trips(&points, &stops, self.config.trip_max_gap_secs);
+            for trip in &mut trips {
+                self.config.coordinate_precision.apply(trip);
+            }
+
+            Ok(TripsResult {
+                user_id: query.user_id.clone(),
+                from: query.from,
+                to: query.to,
+                trips,
+            })
+        }
+
+        /// Every fix of the user within the query window, oldest first.
+        async fn track(&self, tenant_id: &str, query: &AnalyticsQuery) -> Result<Vec<Location>, sqlx::Error> {
+            sqlx::query_as::<_, Location>(
+                "SELECT id, tenant_id, user_id, latitude, longitude, altitude, accuracy, speed, heading, battery, seq, timestamp, timestamp_status
+                 FROM locations WHERE tenant_id = $1 AND user_id = $2 AND timestamp BETWEEN $3 AND $4
+                 ORDER BY timestamp, seq, id",
+            )
+            .bind(tenant_id)
+            .bind(&query.user_id)
+            .bind(query.from)
+            .bind(query.to)
+            .fetch_all(&self.db_pool)
+            .await
+        }
+
+        /// Counts every fix in the bounding box and time window per cell of the query's grid,
+        /// densest cells first. Each cell is placed at its center.
+        pub async fn heatmap(&self, tenant_id: &str, query: &HeatmapQuery) -> Result<HeatmapResult, sqlx::Error> {
+            let (cells, truncated) = match query.grid {
+                Grid::Geohash { precision } => self.geohash_heatmap(tenant_id, query, precision).await?,
+                Grid::H3 { resolution } => self.h3_heatmap(tenant_id, query, resolution).await?,
+            };
+
+            Ok(HeatmapResult {
+                grid: query.grid,
+                from: query.from,
+                to: query.to,
+                cells,
+                truncated,
+            })
+        }
+
+        /// Cells counted in Postgres by geohash prefix. Fixes stored before the geohash column
+        /// existed are not counted.
+        async fn geohash_heatmap(
+            &self,
+            tenant_id: &str,
+            query: &HeatmapQuery,
+            precision: usize,
+        ) -> Result<(Vec<HeatmapCell>, bool), sqlx::Error> {
+            let mut rows = sqlx::query_as::<_, (String, i64)>(
+                "SELECT LEFT(geohash, $1) AS cell, COUNT(*) AS count
+                 FROM locations
+                 WHERE tenant_id = $2
+                   AND geohash IS NOT NULL
+                   AND longitude BETWEEN $3 AND $4
+                   AND latitude BETWEEN $5 AND $6
+                   AND timestamp BETWEEN $7 AND $8
+                 GROUP BY cell
+                 ORDER BY count DESC, cell
+                 LIMIT $9",
+            )
+            .bind(precision as i32)
+            .bind(tenant_id)
+            .bind(query.bbox.min_longitude)
+            .bind(query.bbox.max_longitude)
+            .bind(query.bbox.min_latitude)
+            .bind(query.bbox.max_latitude)
+            .bind(query.from)
+            .bind(query.to)
+            .bind(MAX_HEATMAP_CELLS as i64 + 1)
+            .fetch_all(&self.db_pool)
+            .await?;
+
+            let truncated = rows.len() > MAX_HEATMAP_CELLS;
+            rows.truncate(MAX_HEATMAP_CELLS);
+
+            let cells = rows
+                .into_iter()
+                .filter_map(|(cell, count)| {
+                    let (latitude, longitude) = geohash::decode(&cell).ok()?;
+                    Some(HeatmapCell { cell: CellId::Geohash(cell), latitude, longitude, count })
+                })
+                .collect();
+
+            Ok((cells, truncated))
+        }
+
+        /// Cells counted here, as Postgres knows nothing of H3: the fixes are streamed rather than
+        /// loaded at once, but every one of them crosses the wire.
+        async fn h3_heatmap(
+            &self,
+            tenant_id: &str,
+            query: &HeatmapQuery,
+            resolution: u8,
+        ) -> Result<(Vec<HeatmapCell>, bool), sqlx::Error> {
+            let mut rows = sqlx::query_as::<_, (f64, f64)>(
+                "SELECT latitude, longitude
+                 FROM locations
+                 WHERE tenant_id = $1
+                   AND longitude BETWEEN $2 AND $3
+                   AND latitude BETWEEN $4 AND $5
+                   AND timestamp BETWEEN $6 AND $7",
+            )
+            .bind(tenant_id)
+            .bind(query.bbox.min_longitude)
+            .bind(query.bbox.max_longitude)
+            .bind(query.bbox.min_latitude)
+            .bind(query.bbox.max_latitude)
+            .bind(query.from)
+            .bind(query.to)
+            .fetch(&self.db_pool);
+
+            let mut counts: HashMap<h3::CellIndex, i64> = HashMap::new();
+            while let Some((latitude, longitude)) = rows.try_next().await? {
+                if let Some(cell) = h3::cell(latitude, longitude, resolution) {
+                    *counts.entry(cell).or_default() += 1;
+                }
+            }
+            let mut counts: Vec<_> = counts.into_iter().collect();
+            counts.sort_unstable_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+            let truncated = counts.len() > MAX_HEATMAP_CELLS;
+            counts.truncate(MAX_HEATMAP_CELLS);
+
+            let cells = counts
+                .into_iter()
+                .map(|(cell, count)| {
+                    let (latitude, longitude) = h3::center(cell);
+                    HeatmapCell { cell: CellId::H3(cell.to_string()), latitude, longitude, count }
+                })
+                .collect();
+
+            Ok((cells, truncated))
+        }
+
+        /// Folds every fix this instance stores into the rolling aggregates and, every
+        /// `analytics_processing_interval_secs`, refreshes the `active_users` gauge and flushes
+        /// changed buckets to `rolling_stats`.
+        pub async fn start_processing(&self) {
+            let mut fixes = self.live_updates.subscribe_ingest();
+            let mut interval = tokio::time::interval(Duration::from_secs(self.config.analytics_processing_interval_secs));
+            loop {
+                tokio::select! {
+                    fix = fixes.recv() => match fix {
+                        Ok(location) => {
+                            if let Err(e) = self.fold_into_rolling(&location).await {
+                                warn!("Failed to update rolling aggregates of {}: {}", location.user_id, e);
+                            }
+                        }
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Rolling aggregates fell behind and left out {} fixes", skipped);
+                        }
+                        Err(RecvError::Closed) => return,
+                    },
+                    _ = interval.tick() => {
+                        match self.active_users(None, DEFAULT_ACTIVE_USERS_WINDOW_MINUTES).await {
+                            Ok(result) => self.metrics.active_users.set(result.active_users as i64),
+                            Err(e) => warn!("Failed to count active users: {}", e),
+                        }
+                        match self.flush_rolling().await {
+                            Ok(0) => {}
+                            Ok(buckets) => info!("Flushed {} rolling aggregate buckets", buckets),
+                            Err(e) => error!("Rolling aggregate flush failed: {}", e),
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Bucket number of an instant, counted from the Unix epoch.
+        fn bucket_of(&self, timestamp: DateTime<Utc>) -> i64 {
+            timestamp.timestamp().div_euclid(self.config.rolling_bucket_secs as i64)
+        }
+
+        /// The oldest bucket still inside the rolling window.
+        fn first_rolling_bucket(&self) -> i64 {
+            let buckets = (self.config.rolling_window_secs / self.config.rolling_bucket_secs) as i64;
+            self.bucket_of(Utc::now()) - buckets + 1
+        }
+
+        /// Adds a fix to the bucket of its timestamp. Its distance is measured from the user's
+        /// previous fix; a fix older than that one adds no distance, and one from before the
+        /// window only moves the starting point of the next measurement.
+        async fn fold_into_rolling(&self, location: &Location) -> redis::RedisResult<()> {
+            let (tenant_id, user_id) = (&location.tenant_id, &location.user_id);
+            let window_secs = self.config.rolling_window_secs;
+            let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+            let previous: Option<(Option<f64>, Option<f64>, Option<i64>)> = Script::new(SWAP_LAST_FIX_SCRIPT)
+                .key(redis_keys::rolling_last_fix(tenant_id, user_id))
+                .arg(location.latitude)
+                .arg(location.longitude)
+                .arg(location.timestamp.timestamp_millis())
+                .arg(window_secs)
+                .invoke_async(&mut conn)
+                .await?;
+
+            let bucket = self.bucket_of(location.timestamp);
+            if bucket < self.first_rolling_bucket() {
+                return Ok(());
+            }
+
+            let mut distance_meters = 0.0;
+            let mut segment_speed = None;
+            if let Some((Some(latitude), Some(longitude), Some(timestamp_ms))) = previous {
+                distance_meters = haversine_meters(latitude, longitude, location.latitude, location.longitude);
+                let elapsed_ms = location.timestamp.timestamp_millis() - timestamp_ms;
+                segment_speed = (elapsed_ms > 0).then(|| distance_meters * 1000.0 / elapsed_ms as f64);
+            }
+            let speed = location.speed.or(segment_speed).map(|speed| speed.to_string()).unwrap_or_default();
+
+            Script::new(ADD_TO_BUCKET_SCRIPT)
+                .key(redis_keys::rolling_bucket(tenant_id, user_id, bucket))
+                .key(redis_keys::ROLLING_PENDING)
+                .arg(distance_meters)
+                .arg(speed)
+                .arg(window_secs + self.config.rolling_bucket_secs)
+                .arg(redis_keys::rolling_member(tenant_id, user_id, bucket))
+                .invoke_async(&mut conn)
+                .await
+        }
+
+        /// The user's figures over the rolling window, from Redis or, when Redis cannot be read,
+        /// from the last flush to `rolling_stats`.
+        async fn rolling_stats(&self, tenant_id: &str, user_id: &str) -> Option<RollingStats> {
+            let first = self.first_rolling_bucket();
+            let last = self.bucket_of(Utc::now());
+            let cached: redis::RedisResult<Vec<HashMap<String, f64>>> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                let mut pipe = redis::pipe();
+                for bucket in first..=last {
+                    pipe.hgetall(redis_keys::rolling_bucket(tenant_id, user_id, bucket));
+                }
+                pipe.query_async(&mut conn).await
+            }
+            .await;
+
+            let totals = match cached {
+                Ok(buckets) => buckets.iter().map(BucketTotals::from_hash).fold(BucketTotals::default(), |mut sum, bucket| {
+                    sum.add(bucket);
+                    sum
+                }),
+                Err(e) => {
+                    warn!("Redis lookup for rolling aggregates failed, reading rolling_stats: {}", e);
+                    let window_start = DateTime::from_timestamp(first * self.config.rolling_bucket_secs as i64, 0)?;
+                    let row = sqlx::query_as::<_, (f64, f64, i64, Option<f64>, i64)>(
+                        "SELECT COALESCE(SUM(distance_meters), 0), COALESCE(SUM(speed_sum), 0),
+                                COALESCE(SUM(speed_count), 0)::BIGINT, MAX(max_speed), COALESCE(SUM(point_count), 0)::BIGINT
+                         FROM rolling_stats WHERE tenant_id = $1 AND user_id = $2 AND bucket_start >= $3",
+                    )
+                    .bind(tenant_id)
+                    .bind(user_id)
+                    .bind(window_start)
+                    .fetch_one(&self.db_pool)
+                    .await;
+                    match row {
+                        Ok((distance_meters, speed_sum, speed_count, max_speed, point_count)) => {
+                            BucketTotals { distance_meters, speed_sum, speed_count, max_speed, point_count }
+                        }
+                        Err(e) => {
+                            warn!("Failed to read rolling_stats: {}", e);
+                            return None;
+                        }
+                    }
+                }
+            };
+            Some(totals.into_stats(&self.config))
+        }
+
+        /// Copies every changed bucket into `rolling_stats` and deletes rows that have left the
+        /// window. A bucket is unmarked before it is read, so a fix added meanwhile marks it again
+        /// for the next pass; one whose row could not be written is marked again. Returns the
+        /// number of buckets written.
+        async fn flush_rolling(&self) -> Result<u64, FlushError> {
+            let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+            let members: Vec<String> = conn.smembers(redis_keys::ROLLING_PENDING).await?;
+            let first = self.first_rolling_bucket();
+            let bucket_secs = self.config.rolling_bucket_secs as i64;
+
+            let mut flushed = 0;
+            for member in members {
+                let Some((tenant_id, user_id, bucket)) = redis_keys::parse_rolling_member(&member) else {
+                    warn!("Dropping unreadable rolling aggregate member {}", member);
+                    let _: redis::RedisResult<()> = conn.srem(redis_keys::ROLLING_PENDING, &member).await;
+                    continue;
+                };
+                let (fields,): (HashMap<String, f64>,) = redis::pipe()
+                    .atomic()
+                    .srem(redis_keys::ROLLING_PENDING, &member)
+                    .ignore()
+                    .hgetall(redis_keys::rolling_bucket(tenant_id, user_id, bucket))
+                    .query_async(&mut conn)
+                    .await?;
+                if fields.is_empty() || bucket < first {
+                    continue;
+                }
+
+                let totals = BucketTotals::from_hash(&fields);
+                let written = sqlx::query(
+                    "INSERT INTO rolling_stats
+                         (tenant_id, user_id, bucket_start, distance_meters, speed_sum, speed_count, max_speed, point_count, updated_at)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NOW())
+                     ON CONFLICT (tenant_id, user_id, bucket_start) DO UPDATE
+                     SET distance_meters = EXCLUDED.distance_meters, speed_sum = EXCLUDED.speed_sum,
+                         speed_count = EXCLUDED.speed_count, max_speed = EXCLUDED.max_speed,
+                         point_count = EXCLUDED.point_count, updated_at = EXCLUDED.updated_at",
+                )
+                .bind(tenant_id)
+                .bind(user_id)
+                .bind(DateTime::from_timestamp(bucket * bucket_secs, 0))
+                .bind(totals.distance_meters)
+                .bind(totals.speed_sum)
+                .bind(totals.speed_count)
+                .bind(totals.max_speed)
+                .bind(totals.point_count)
+                .execute(&self.db_pool)
+                .await;
+                if let Err(e) = written {
+                    let _: redis::RedisResult<()> = conn.sadd(redis_keys::ROLLING_PENDING, &member).await;
+                    return Err(e.into());
+                }
+                flushed += 1;
+            }
+
+            sqlx::query("DELETE FROM rolling_stats WHERE bucket_start < $1")
+                .bind(DateTime::from_timestamp(first * bucket_secs, 0))
+                .execute(&self.db_pool)
+                .await?;
+            Ok(flushed)
         }
     }
 }
 
-pub mod route_optimization {
+pub mod presence_service {
+    use std::collections::{HashMap, HashSet};
     use std::sync::Arc;
-    use sqlx::{Pool, Postgres};
+    use std::time::Duration;
+    use chrono::{DateTime, Utc};
+    use sqlx::{Pool, Postgres, QueryBuilder};
+    use tracing::{error, info};
+    use uuid::Uuid;
     use crate::config::Config;
+    use crate::models::{HistoryCursor, PageInfo, Paginated, PresenceEvent, PresenceQuery, PresenceTransition};
+    use crate::redis_client::RedisClient;
+    use crate::utils::redis_keys;
+    use super::live_updates::LiveUpdates;
 
-    pub struct RouteOptimizer {
-        _db_pool: Pool<Postgres>,
-        _config: Arc<Config>,
+    type MonitorError = Box<dyn std::error::Error + Send + Sync>;
+
+    /// Turns last-seen times into ONLINE/OFFLINE transitions. The last known state of each user
+    /// lives in `user_presence`, so a transition is recorded once even across restarts.
+    #[derive(Debug)]
+    pub struct PresenceService {
+        db_pool: Pool<Postgres>,
+        redis_client: RedisClient,
+        config: Arc<Config>,
+        live_updates: Arc<LiveUpdates>,
     }
 
-    impl RouteOptimizer {
-        pub fn new(db_pool: Pool<Postgres>, config: Arc<Config>) -> Self {
+    impl PresenceService {
+        pub fn new(db_pool: Pool<Postgres>, redis_client: RedisClient, config: Arc<Config>, live_updates: Arc<LiveUpdates>) -> Self {
             Self {
-                _db_pool: db_pool,
-                _config: config,
+                db_pool,
+                redis_client,
+                config,
+                live_updates,
+            }
+        }
+
+        /// Compares last-seen times against `presence_staleness_secs` every
+        /// `presence_check_interval_secs`.
+        pub async fn start_monitoring(&self) {
+            let period = Duration::from_secs(self.config.presence_check_interval_secs.max(1));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match self.check_presence().await {
+                    Ok(0) => {}
+                    Ok(transitions) => info!("Recorded {} presence transitions", transitions),
+                    Err(e) => error!("Presence check failed: {}", e),
+                }
+            }
+        }
+
+        /// Users who reported within the window but are not known to be online go ONLINE; users
+        /// known to be online who have not go OFFLINE. Users are tracked as
+        /// [`redis_keys::presence_member`]s, covering every tenant in one pass. Returns the number
+        /// of transitions.
+        async fn check_presence(&self) -> Result<usize, MonitorError> {
+            let cutoff_ms =
+                (Utc::now() - chrono::Duration::seconds(self.config.presence_staleness_secs as i64)).timestamp_millis();
+            let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+            let recent: Vec<(String, i64)> = redis::cmd("ZRANGEBYSCORE")
+                .arg(redis_keys::PRESENCE_LAST_SEEN)
+                .arg(format!("({}", cutoff_ms))
+                .arg("+inf")
+                .arg("WITHSCORES")
+                .query_async(&mut conn)
+                .await?;
+            let recent: HashMap<String, i64> = recent.into_iter().collect();
+
+            let online: Vec<(String, String, DateTime<Utc>)> =
+                sqlx::query_as("SELECT tenant_id, user_id, last_seen FROM user_presence WHERE online")
+                    .fetch_all(&self.db_pool)
+                    .await?;
+            let online_members: HashSet<String> = online
+                .iter()
+                .map(|(tenant_id, user_id, _)| redis_keys::presence_member(tenant_id, user_id))
+                .collect();
+
+            let mut transitions = Vec::new();
+            for (member, last_seen_ms) in &recent {
+                if !online_members.contains(member) {
+                    let Some((tenant_id, user_id)) = redis_keys::parse_presence_member(member) else { continue };
+                    if let Some(last_seen) = DateTime::from_timestamp_millis(*last_seen_ms) {
+                        transitions.push((tenant_id.to_string(), user_id.to_string(), PresenceTransition::Online, last_seen));
+                    }
+                }
+            }
+            let mut gone = Vec::new();
+            for (tenant_id, user_id, recorded_last_seen) in &online {
+                let member = redis_keys::presence_member(tenant_id, user_id);
+                if !recent.contains_key(&member) {
+                    gone.push((member, tenant_id.clone(), user_id.clone(), *recorded_last_seen));
+                }
             }
+            if !gone.is_empty() {
+                let mut pipe = redis::pipe();
+                for (member, ..) in &gone {
+                    pipe.zscore(redis_keys::PRESENCE_LAST_SEEN, member);
+                }
+                let scores: Vec<Option<i64>> = pipe.query_async(&mut conn).await?;
+                for ((_, tenant_id, user_id, recorded_last_seen), score) in gone.into_iter().zip(scores) {
+                    let last_seen = score.and_then(DateTime::from_timestamp_millis).unwrap_or(recorded_last_seen);
+                    transitions.push((tenant_id, user_id, PresenceTransition::Offline, last_seen));
+                }
+            }
+
+            let mut recorded = 0;
+            for (tenant_id, user_id, transition, last_seen) in transitions {
+                if let Some(event) = self.record_transition(&tenant_id, &user_id, transition, last_seen).await? {
+                    self.live_updates.publish_presence_event(&event);
+                    recorded += 1;
+                }
+            }
+            Ok(recorded)
+        }
+
+        /// Stores the new state and its event together, unless another pass already did.
+        async fn record_transition(
+            &self,
+            tenant_id: &str,
+            user_id: &str,
+            transition: PresenceTransition,
+            last_seen: DateTime<Utc>,
+        ) -> Result<Option<PresenceEvent>, sqlx::Error> {
+            let online = transition == PresenceTransition::Online;
+            let mut tx = self.db_pool.begin().await?;
+            let changed = sqlx::query(
+                "INSERT INTO user_presence (tenant_id, user_id, online, last_seen, changed_at) VALUES ($1, $2, $3, $4, NOW())
+                 ON CONFLICT (tenant_id, user_id) DO UPDATE
+                 SET online = EXCLUDED.online, last_seen = EXCLUDED.last_seen, changed_at = EXCLUDED.changed_at
+                 WHERE user_presence.online <> EXCLUDED.online",
+            )
+            .bind(tenant_id)
+            .bind(user_id)
+            .bind(online)
+            .bind(last_seen)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+            if changed == 0 {
+                return Ok(None);
+            }
+
+            let event = sqlx::query_as::<_, PresenceEvent>(
+                "INSERT INTO presence_events (id, tenant_id, user_id, event_type, last_seen) VALUES ($1, $2, $3, $4, $5)
+                 RETURNING id, tenant_id, user_id, event_type, last_seen, occurred_at",
+            )
+            .bind(Uuid::new_v4())
+            .bind(tenant_id)
+            .bind(user_id)
+            .bind(transition.as_str())
+            .bind(last_seen)
+            .fetch_one(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            Ok(Some(event))
+        }
+
+        /// One page of the tenant's presence events, newest first.
+        pub async fn events(&self, tenant_id: &str, query: &PresenceQuery) -> Result<Paginated<PresenceEvent>, sqlx::Error> {
+            let mut builder = QueryBuilder::<Postgres>::new(
+                "SELECT id, tenant_id, user_id, event_type, last_seen, occurred_at FROM presence_events WHERE tenant_id = ",
+            );
+            builder.push_bind(tenant_id);
+            if let Some(user_id) = &query.user_id {
+                builder.push(" AND user_id = ").push_bind(user_id);
+            }
+            if let Some(cursor) = &query.before {
+                builder
+                    .push(" AND (occurred_at, id) < (")
+                    .push_bind(cursor.timestamp)
+                    .push(", ")
+                    .push_bind(cursor.id)
+                    .push(")");
+            }
+            builder
+                .push(" ORDER BY occurred_at DESC, id DESC LIMIT ")
+                .push_bind(query.limit + 1);
+
+            let mut events = builder.build_query_as::<PresenceEvent>().fetch_all(&self.db_pool).await?;
+            let next_cursor = if events.len() as i64 > query.limit {
+                events.truncate(query.limit as usize);
+                events.last().map(|last| HistoryCursor { timestamp: last.occurred_at, seq: None, id: last.id }.encode())
+            } else {
+                None
+            };
+
+            Ok(Paginated {
+                data: events,
+                page: PageInfo {
+                    limit: query.limit,
+                    next_cursor,
+                    total: None,
+                },
+            })
         }
     }
 }
 
-pub mod analytics_service {
+pub mod usage_service {
     use std::sync::Arc;
+    use std::time::Duration;
+    use chrono::{NaiveDate, Utc};
+    use redis::{AsyncCommands, Script};
     use sqlx::{Pool, Postgres};
-    use redis::Client as RedisClient;
+    use tracing::{error, info, warn};
     use crate::config::Config;
+    use crate::models::Usage;
+    use crate::redis_client::RedisClient;
+    use crate::utils::redis_keys;
 
-    pub struct AnalyticsService {
-        _db_pool: Pool<Postgres>,
-        _redis_client: RedisClient,
-        _config: Arc<Config>,
+    type FlushError = Box<dyn std::error::Error + Send + Sync>;
+
+    /// Takes a month's pending count and drops it from the pending set in one step, so fixes
+    /// counted meanwhile start a fresh counter that the next flush picks up. Returns the count.
+    const TAKE_PENDING_SCRIPT: &str = r#"
+        local count = tonumber(redis.call('GET', KEYS[1])) or 0
+        redis.call('DEL', KEYS[1])
+        redis.call('SREM', KEYS[2], ARGV[1])
+        return count
+    "#;
+
+    /// Why a usage lookup failed.
+    #[derive(Debug)]
+    pub enum UsageError {
+        Storage(sqlx::Error),
+        /// The count still pending in Redis could not be read.
+        Cache(redis::RedisError),
     }
 
-    impl AnalyticsService {
+    fn month_key(month: NaiveDate) -> String {
+        month.format("%Y-%m").to_string()
+    }
+
+    /// Meters stored fixes per tenant and UTC calendar month for billing. Fixes are counted with
+    /// an atomic `INCRBY` on a Redis counter that is moved into the `usage` table every
+    /// `usage_flush_interval_secs`; while Redis is unreachable they are added to the table directly.
+    #[derive(Debug)]
+    pub struct UsageService {
+        db_pool: Pool<Postgres>,
+        redis_client: RedisClient,
+        config: Arc<Config>,
+    }
+
+    impl UsageService {
         pub fn new(db_pool: Pool<Postgres>, redis_client: RedisClient, config: Arc<Config>) -> Self {
+            Self { db_pool, redis_client, config }
+        }
+
+        /// Counts `fixes` newly stored fixes of the tenant against the current month.
+        pub async fn record(&self, tenant_id: &str, fixes: u64) {
+            if fixes == 0 {
+                return;
+            }
+            let month = month_key(Utc::now().date_naive());
+            let result: redis::RedisResult<()> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                redis::pipe()
+                    .atomic()
+                    .incr(redis_keys::usage(tenant_id, &month), fixes)
+                    .ignore()
+                    .sadd(redis_keys::USAGE_PENDING, redis_keys::usage_member(tenant_id, &month))
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await
+            }
+            .await;
+
+            if let Err(e) = result {
+                warn!("Failed to count usage in Redis, adding it to Postgres directly: {}", e);
+                if let Err(e) = self.add_to_table(tenant_id, &month, fixes).await {
+                    error!("Lost usage of {} fixes for tenant {} in {}: {}", fixes, tenant_id, month, e);
+                }
+            }
+        }
+
+        async fn add_to_table(&self, tenant_id: &str, month: &str, fixes: u64) -> Result<(), FlushError> {
+            let month = NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")?;
+            sqlx::query(
+                "INSERT INTO usage (tenant_id, month, fixes, updated_at) VALUES ($1, $2, $3, NOW())
+                 ON CONFLICT (tenant_id, month) DO UPDATE
+                 SET fixes = usage.fixes + EXCLUDED.fixes, updated_at = EXCLUDED.updated_at",
+            )
+            .bind(tenant_id)
+            .bind(month)
+            .bind(fixes as i64)
+            .execute(&self.db_pool)
+            .await?;
+            Ok(())
+        }
+
+        /// Moves pending counters into `usage` every `usage_flush_interval_secs`.
+        pub async fn start_flushing(&self) {
+            let period = Duration::from_secs(self.config.usage_flush_interval_secs.max(1));
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match self.flush().await {
+                    Ok(0) => {}
+                    Ok(fixes) => info!("Flushed usage of {} fixes", fixes),
+                    Err(e) => error!("Usage flush failed: {}", e),
+                }
+            }
+        }
+
+        /// Adds every pending counter to its `usage` row. A counter whose row could not be written
+        /// is put back for the next pass. Returns the number of fixes flushed.
+        async fn flush(&self) -> Result<u64, FlushError> {
+            let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+            let members: Vec<String> = conn.smembers(redis_keys::USAGE_PENDING).await?;
+
+            let mut flushed = 0;
+            for member in members {
+                let Some((tenant_id, month)) = redis_keys::parse_usage_member(&member) else {
+                    warn!("Dropping unreadable usage member {}", member);
+                    let _: redis::RedisResult<()> = conn.srem(redis_keys::USAGE_PENDING, &member).await;
+                    continue;
+                };
+                let key = redis_keys::usage(tenant_id, month);
+                let fixes: u64 = Script::new(TAKE_PENDING_SCRIPT)
+                    .key(&key)
+                    .key(redis_keys::USAGE_PENDING)
+                    .arg(&member)
+                    .invoke_async(&mut conn)
+                    .await?;
+                if fixes == 0 {
+                    continue;
+                }
+
+                if let Err(e) = self.add_to_table(tenant_id, month, fixes).await {
+                    let restored: redis::RedisResult<()> = redis::pipe()
+                        .atomic()
+                        .incr(&key, fixes)
+                        .ignore()
+                        .sadd(redis_keys::USAGE_PENDING, &member)
+                        .ignore()
+                        .query_async(&mut conn)
+                        .await;
+                    if let Err(restore) = restored {
+                        error!("Lost usage of {} fixes for tenant {} in {}: {}", fixes, tenant_id, month, restore);
+                    }
+                    return Err(e);
+                }
+                flushed += fixes;
+            }
+            Ok(flushed)
+        }
+
+        /// Fixes the tenant stored during the month starting on `month`: the flushed row plus the
+        /// count still pending in Redis. A flush finishing between the two reads can briefly leave
+        /// its share out.
+        pub async fn usage(&self, tenant_id: &str, month: NaiveDate) -> Result<Usage, UsageError> {
+            let flushed: Option<i64> = sqlx::query_scalar("SELECT fixes FROM usage WHERE tenant_id = $1 AND month = $2")
+                .bind(tenant_id)
+                .bind(month)
+                .fetch_optional(&self.db_pool)
+                .await
+                .map_err(UsageError::Storage)?;
+
+            let month = month_key(month);
+            let pending: Option<u64> = async {
+                let mut conn = self.redis_client.get_multiplexed_async_connection().await?;
+                conn.get(redis_keys::usage(tenant_id, &month)).await
+            }
+            .await
+            .map_err(UsageError::Cache)?;
+
+            Ok(Usage {
+                tenant_id: tenant_id.to_string(),
+                month,
+                fixes: flushed.unwrap_or_default().max(0) as u64 + pending.unwrap_or_default(),
+            })
+        }
+    }
+}
+
+pub mod live_updates {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, RwLock};
+    use serde::Serialize;
+    use tokio::sync::{broadcast, watch};
+    use uuid::Uuid;
+    use crate::config::Config;
+    use crate::metrics::Metrics;
+    use crate::models::{CoordinatePrecision, GeofenceEvent, GeofenceStreamMessage, Location, PresenceEvent};
+
+    const CHANNEL_CAPACITY: usize = 64;
+
+    /// Backlog of the channel carrying every stored fix to background consumers.
+    const INGEST_CHANNEL_CAPACITY: usize = 4096;
+
+    /// Channel of a user's fixes; user ids are only unique within a tenant.
+    fn user_channel(tenant_id: &str, user_id: &str) -> String {
+        format!("{}:{}", tenant_id, user_id)
+    }
+
+    /// Why a WebSocket was turned away.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ConnectionRefused {
+        UserLimit,
+        GlobalLimit,
+    }
+
+    impl ConnectionRefused {
+        fn as_str(self) -> &'static str {
+            match self {
+                ConnectionRefused::UserLimit => "user_limit",
+                ConnectionRefused::GlobalLimit => "global_limit",
+            }
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct ConnectionCounts {
+        total: usize,
+        per_user: HashMap<String, usize>,
+    }
+
+    impl ConnectionCounts {
+        fn max_per_user(&self) -> usize {
+            self.per_user.values().copied().max().unwrap_or(0)
+        }
+    }
+
+    /// Open WebSocket connections, overall and per authenticated user, against their limits. The
+    /// total and the highest per-user count are exported; every user's count would give the
+    /// metrics one series per user.
+    #[derive(Debug)]
+    struct Connections {
+        counts: Mutex<ConnectionCounts>,
+        max_per_user: usize,
+        max_total: usize,
+        metrics: Arc<Metrics>,
+    }
+
+    /// A reserved connection slot, released when dropped. Held by the WebSocket task for as long
+    /// as the socket is open.
+    #[derive(Debug)]
+    pub struct ConnectionPermit {
+        connections: Arc<Connections>,
+        user_id: Option<String>,
+    }
+
+    impl Drop for ConnectionPermit {
+        fn drop(&mut self) {
+            let mut counts = self.connections.counts.lock().expect("connection counts poisoned");
+            counts.total -= 1;
+            self.connections.metrics.websocket_connections_active.dec();
+
+            let Some(user_id) = &self.user_id else { return };
+            if let Some(count) = counts.per_user.get_mut(user_id) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.per_user.remove(user_id);
+                }
+            }
+            self.connections.metrics.websocket_connections_max_per_user.set(counts.max_per_user() as i64);
+        }
+    }
+
+    /// Broadcast channels created on first subscription and dropped with their last subscriber.
+    #[derive(Debug)]
+    struct Registry<T> {
+        channels: RwLock<HashMap<String, broadcast::Sender<T>>>,
+    }
+
+    impl<T: Clone> Registry<T> {
+        fn new() -> Self {
+            Self { channels: RwLock::default() }
+        }
+
+        fn subscribe(&self, key: &str) -> broadcast::Receiver<T> {
+            let mut channels = self.channels.write().expect("live update registry poisoned");
+            channels
+                .entry(key.to_string())
+                .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+                .subscribe()
+        }
+
+        fn publish(&self, key: &str, message: T) {
+            let channels = self.channels.read().expect("live update registry poisoned");
+            if let Some(sender) = channels.get(key) {
+                let _ = sender.send(message);
+            }
+        }
+
+        fn release(&self, key: &str) {
+            let mut channels = self.channels.write().expect("live update registry poisoned");
+            if channels.get(key).is_some_and(|sender| sender.receiver_count() == 0) {
+                channels.remove(key);
+            }
+        }
+
+        fn stats(&self) -> ChannelStats {
+            let channels = self.channels.read().expect("live update registry poisoned");
+            ChannelStats {
+                channels: channels.len(),
+                subscribers: channels.values().map(|sender| sender.receiver_count()).sum(),
+                max_backlog: channels.values().map(|sender| sender.len()).max().unwrap_or(0),
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct ChannelStats {
+        pub channels: usize,
+        pub subscribers: usize,
+        /// Most updates any one channel holds that a subscriber has yet to receive. A subscriber
+        /// more than [`CHANNEL_CAPACITY`] behind is disconnected.
+        pub max_backlog: usize,
+    }
+
+    /// A snapshot of the open WebSockets and the channels feeding them.
+    #[derive(Debug, Serialize)]
+    pub struct LiveUpdatesStats {
+        pub connections: usize,
+        pub max_connections: usize,
+        /// Authenticated users with at least one open WebSocket.
+        pub connected_users: usize,
+        pub channel_capacity: usize,
+        pub locations: ChannelStats,
+        pub geofence_events: ChannelStats,
+        pub presence_events: ChannelStats,
+    }
+
+    /// Fan-out of freshly stored fixes (per user), geofence transitions (per geofence) and
+    /// presence transitions (per tenant) to WebSocket subscribers, and of every stored fix to
+    /// in-process consumers such as the rolling aggregates.
+    #[derive(Debug)]
+    pub struct LiveUpdates {
+        locations: Registry<Location>,
+        ingest: broadcast::Sender<Location>,
+        /// What subscribers see; ingest consumers get fixes as stored.
+        coordinate_precision: CoordinatePrecision,
+        geofence_events: Registry<GeofenceStreamMessage>,
+        presence_events: Registry<PresenceEvent>,
+        shutdown: watch::Sender<bool>,
+        connections: Arc<Connections>,
+    }
+
+    impl LiveUpdates {
+        pub fn new(config: &Config, metrics: Arc<Metrics>) -> Self {
             Self {
-                _db_pool: db_pool,
-                _redis_client: redis_client,
-                _config: config,
+                locations: Registry::new(),
+                ingest: broadcast::channel(INGEST_CHANNEL_CAPACITY).0,
+                coordinate_precision: config.coordinate_precision,
+                geofence_events: Registry::new(),
+                presence_events: Registry::new(),
+                shutdown: watch::Sender::new(false),
+                connections: Arc::new(Connections {
+                    counts: Mutex::default(),
+                    max_per_user: config.ws_max_connections_per_user,
+                    max_total: config.ws_max_connections,
+                    metrics,
+                }),
             }
         }
 
-        pub async fn start_processing(&self) {
-            // Placeholder implementation
+        /// Reserves a slot for a new WebSocket, counted against `user_id` as well when the
+        /// connection is authenticated.
+        pub fn admit(&self, user_id: Option<&str>) -> Result<ConnectionPermit, ConnectionRefused> {
+            let connections = &self.connections;
+            let refuse = |refused: ConnectionRefused| {
+                connections.metrics.websocket_handshakes_rejected_total.with_label_values(&[refused.as_str()]).inc();
+                Err(refused)
+            };
+            let mut counts = connections.counts.lock().expect("connection counts poisoned");
+            if counts.total >= connections.max_total {
+                return refuse(ConnectionRefused::GlobalLimit);
+            }
+            if let Some(user_id) = user_id {
+                let count = counts.per_user.entry(user_id.to_string()).or_default();
+                if *count >= connections.max_per_user {
+                    return refuse(ConnectionRefused::UserLimit);
+                }
+                *count += 1;
+            }
+            counts.total += 1;
+            connections.metrics.websocket_connections_active.inc();
+            connections.metrics.websocket_connections_max_per_user.set(counts.max_per_user() as i64);
+
+            Ok(ConnectionPermit {
+                connections: connections.clone(),
+                user_id: user_id.map(str::to_string),
+            })
+        }
+
+        /// Returns a handle that flips to `true` once the service starts shutting down.
+        /// WebSocket tasks must hold it for as long as their socket is open.
+        pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+            self.shutdown.subscribe()
+        }
+
+        /// Asks every open WebSocket to close and waits until all of them have gone away.
+        pub async fn close_all(&self) {
+            self.shutdown.send_replace(true);
+            self.shutdown.closed().await;
+        }
+
+        pub fn subscribe(&self, tenant_id: &str, user_id: &str) -> broadcast::Receiver<Location> {
+            self.locations.subscribe(&user_channel(tenant_id, user_id))
+        }
+
+        /// Sends a fix to the user's subscribers, if any, at the configured coordinate precision,
+        /// and to ingest subscribers as it is. Never waits on slow receivers: once a receiver falls
+        /// more than the channel capacity behind, its next `recv` reports `Lagged`.
+        pub fn publish(&self, location: &Location) {
+            let mut reported = location.clone();
+            self.coordinate_precision.apply(&mut reported);
+            self.locations.publish(&user_channel(&location.tenant_id, &location.user_id), reported);
+            let _ = self.ingest.send(location.clone());
+        }
+
+        /// Every fix this instance stores from now on, of all users.
+        pub fn subscribe_ingest(&self) -> broadcast::Receiver<Location> {
+            self.ingest.subscribe()
+        }
+
+        /// Drops the user's channel once its last subscriber has gone away.
+        pub fn release(&self, tenant_id: &str, user_id: &str) {
+            self.locations.release(&user_channel(tenant_id, user_id));
+        }
+
+        pub fn subscribe_geofence(&self, geofence_id: Uuid) -> broadcast::Receiver<GeofenceStreamMessage> {
+            self.geofence_events.subscribe(&geofence_id.to_string())
+        }
+
+        pub fn publish_geofence_event(&self, event: &GeofenceEvent) {
+            self.geofence_events
+                .publish(&event.geofence_id.to_string(), GeofenceStreamMessage::Event(event.clone()));
+        }
+
+        pub fn release_geofence(&self, geofence_id: Uuid) {
+            self.geofence_events.release(&geofence_id.to_string());
+        }
+
+        pub fn subscribe_presence(&self, tenant_id: &str) -> broadcast::Receiver<PresenceEvent> {
+            self.presence_events.subscribe(tenant_id)
+        }
+
+        pub fn publish_presence_event(&self, event: &PresenceEvent) {
+            self.presence_events.publish(&event.tenant_id, event.clone());
+        }
+
+        pub fn release_presence(&self, tenant_id: &str) {
+            self.presence_events.release(tenant_id);
+        }
+
+        pub fn stats(&self) -> LiveUpdatesStats {
+            let (connections, connected_users) = {
+                let counts = self.connections.counts.lock().expect("connection counts poisoned");
+                (counts.total, counts.per_user.len())
+            };
+            LiveUpdatesStats {
+                connections,
+                max_connections: self.connections.max_total,
+                connected_users,
+                channel_capacity: CHANNEL_CAPACITY,
+                locations: self.locations.stats(),
+                geofence_events: self.geofence_events.stats(),
+                presence_events: self.presence_events.stats(),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_support;
+
+        fn live_updates(max_per_user: usize, max_total: usize) -> (LiveUpdates, Arc<Metrics>) {
+            let mut config = test_support::config();
+            config.ws_max_connections_per_user = max_per_user;
+            config.ws_max_connections = max_total;
+            let metrics = test_support::metrics();
+            (LiveUpdates::new(&config, metrics.clone()), metrics)
+        }
+
+        fn rejected(metrics: &Metrics, reason: &str) -> u64 {
+            metrics.websocket_handshakes_rejected_total.with_label_values(&[reason]).get()
+        }
+
+        #[test]
+        fn a_user_over_their_limit_is_refused_until_a_connection_closes() {
+            let (live, metrics) = live_updates(2, 10);
+            let first = live.admit(Some("alice")).unwrap();
+            let _second = live.admit(Some("alice")).unwrap();
+            assert_eq!(live.admit(Some("alice")).err(), Some(ConnectionRefused::UserLimit));
+            assert!(live.admit(Some("bob")).is_ok());
+            assert_eq!(rejected(&metrics, "user_limit"), 1);
+
+            drop(first);
+            assert!(live.admit(Some("alice")).is_ok());
+        }
+
+        #[test]
+        fn the_global_limit_counts_anonymous_connections() {
+            let (live, metrics) = live_updates(5, 2);
+            let _anonymous = live.admit(None).unwrap();
+            let _alice = live.admit(Some("alice")).unwrap();
+            assert_eq!(live.admit(Some("bob")).err(), Some(ConnectionRefused::GlobalLimit));
+            assert_eq!(live.admit(None).err(), Some(ConnectionRefused::GlobalLimit));
+            assert_eq!(rejected(&metrics, "global_limit"), 2);
+            assert_eq!(metrics.websocket_connections_active.get(), 2);
+        }
+
+        #[test]
+        fn closing_every_connection_forgets_the_user() {
+            let (live, metrics) = live_updates(5, 10);
+            let permits: Vec<_> = (0..3).map(|_| live.admit(Some("alice")).unwrap()).collect();
+            assert_eq!(metrics.websocket_connections_active.get(), 3);
+
+            drop(permits);
+            assert_eq!(metrics.websocket_connections_active.get(), 0);
+            let counts = live.connections.counts.lock().unwrap();
+            assert_eq!(counts.total, 0);
+            assert!(counts.per_user.is_empty());
+        }
+
+        #[test]
+        fn the_busiest_user_sets_the_per_user_gauge() {
+            let (live, metrics) = live_updates(5, 10);
+            let alice: Vec<_> = (0..3).map(|_| live.admit(Some("alice")).unwrap()).collect();
+            let bob = live.admit(Some("bob")).unwrap();
+            let _anonymous = live.admit(None).unwrap();
+            assert_eq!(metrics.websocket_connections_max_per_user.get(), 3);
+
+            drop(alice);
+            assert_eq!(metrics.websocket_connections_max_per_user.get(), 1);
+            drop(bob);
+            assert_eq!(metrics.websocket_connections_max_per_user.get(), 0);
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,130 @@
+//! Shared fixtures for unit tests.
+
+use std::sync::Arc;
+use std::time::Instant;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::json;
+use sqlx::postgres::PgPoolOptions;
+use crate::AppState;
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::models::{Location, TimestampStatus};
+use crate::redis_client::RedisClient;
+use crate::services::{
+    analytics_service::AnalyticsService, geolocation_service::GeolocationService, live_updates::LiveUpdates,
+    presence_service::PresenceService, route_optimization::RouteOptimizer, tracking_service::TrackingService,
+    usage_service::UsageService, webhooks::WebhookDispatcher,
+};
+
+/// The development defaults, as a locally started service would see them, whatever `NODE_ENV`
+/// the tests run under. Other variables, such as `DATABASE_URL`, still come from the environment.
+pub fn config() -> Config {
+    let lookup = |var: &str| match var {
+        "NODE_ENV" => Some("development".to_string()),
+        _ => std::env::var(var).ok(),
+    };
+    Config::from_vars(&lookup).expect("development configuration")
+}
+
+pub fn metrics() -> Arc<Metrics> {
+    Arc::new(Metrics::new().expect("metrics registry"))
+}
+
+/// State wired as in `main`, but connecting to Postgres and Redis only when first used, so
+/// requests answered before any storage is touched can be tested without either. Must be
+/// called within a runtime.
+pub fn state() -> AppState {
+    state_with(config())
+}
+
+/// [`state`] with a configuration other than the development defaults.
+pub fn state_with(config: Config) -> AppState {
+    let config = Arc::new(config);
+    let metrics = metrics();
+    let db_pool = PgPoolOptions::new().connect_lazy(&config.database_url).expect("database url");
+    let redis = redis::Client::open(config.redis_url.as_str()).expect("redis url");
+    let redis_client = RedisClient::new(redis, &config, metrics.clone());
+    let live_updates = Arc::new(LiveUpdates::new(&config, metrics.clone()));
+    let usage_service = Arc::new(UsageService::new(db_pool.clone(), redis_client.clone(), config.clone()));
+    let webhooks = Arc::new(WebhookDispatcher::new(db_pool.clone(), config.clone()));
+
+    AppState {
+        tracking_service: Arc::new(TrackingService::new(
+            db_pool.clone(),
+            redis_client.clone(),
+            config.clone(),
+            metrics.clone(),
+            usage_service.clone(),
+        )),
+        geolocation_service: Arc::new(GeolocationService::new(
+            db_pool.clone(),
+            redis_client.clone(),
+            config.clone(),
+            metrics.clone(),
+            live_updates.clone(),
+            webhooks,
+        )),
+        route_optimizer: Arc::new(RouteOptimizer::new(db_pool.clone(), config.clone())),
+        analytics_service: Arc::new(AnalyticsService::new(
+            db_pool.clone(),
+            redis_client.clone(),
+            config.clone(),
+            metrics.clone(),
+            live_updates.clone(),
+        )),
+        presence_service: Arc::new(PresenceService::new(
+            db_pool.clone(),
+            redis_client.clone(),
+            config.clone(),
+            live_updates.clone(),
+        )),
+        usage_service,
+        config,
+        db_pool,
+        redis_client,
+        metrics,
+        live_updates,
+        started_at: Instant::now(),
+    }
+}
+
+/// [`state`] against the Postgres and Redis named by `DATABASE_URL` and `REDIS_URL`, with the
+/// schema migrated. For tests that are `#[ignore]`d unless run with `--ignored` next to both.
+pub async fn migrated_state() -> AppState {
+    let state = state();
+    crate::database::run_migrations(&state.db_pool).await.expect("database migrations");
+    state
+}
+
+/// A fix of `alice` in tenant `acme` with only a position and a time.
+pub fn location(latitude: f64, longitude: f64, timestamp: DateTime<Utc>) -> Location {
+    Location {
+        id: uuid::Uuid::new_v4(),
+        tenant_id: "acme".to_string(),
+        user_id: "alice".to_string(),
+        latitude,
+        longitude,
+        altitude: None,
+        accuracy: None,
+        speed: None,
+        heading: None,
+        battery: None,
+        seq: None,
+        timestamp,
+        timestamp_status: TimestampStatus::Device,
+    }
+}
+
+/// An `Authorization` header value for a token signed with the development secret.
+pub fn bearer(sub: &str, tenant: Option<&str>, roles: &[&str]) -> String {
+    let claims = json!({
+        "sub": sub,
+        "tenant": tenant,
+        "roles": roles,
+        "exp": Utc::now().timestamp() + 3600,
+    });
+    let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(config().jwt_secret.as_bytes()))
+        .expect("token");
+    format!("Bearer {}", token)
+}
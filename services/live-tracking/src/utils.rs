@@ -1 +1,1813 @@
-// Placeholder for utility modules
\ No newline at end of file
+use crate::models::Location;
+
+pub const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+/// Great-circle distance in meters between two fixes.
+pub fn haversine_distance(a: &Location, b: &Location) -> f64 {
+    haversine_meters(a.latitude, a.longitude, b.latitude, b.longitude)
+}
+
+/// Total distance in meters along a time-ordered track.
+pub fn track_distance_meters(points: &[Location]) -> f64 {
+    points.windows(2).map(|pair| haversine_distance(&pair[0], &pair[1])).sum()
+}
+
+pub fn haversine_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let phi1 = lat1.to_radians();
+    let phi2 = lat2.to_radians();
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+
+    let h = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * h.sqrt().min(1.0).asin()
+}
+
+/// Initial bearing in degrees (0..360, clockwise from north) when travelling from the first point
+/// to the second.
+pub fn bearing_degrees(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let phi1 = lat1.to_radians();
+    let phi2 = lat2.to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+
+    let y = d_lambda.sin() * phi2.cos();
+    let x = phi1.cos() * phi2.sin() - phi1.sin() * phi2.cos() * d_lambda.cos();
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
+}
+
+/// East and north offsets in meters from the first point to the second, using an equirectangular
+/// projection around their mean latitude.
+fn equirectangular_offset_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> (f64, f64) {
+    let meters_per_degree = EARTH_RADIUS_METERS.to_radians();
+    let scale_x = meters_per_degree * ((lat1 + lat2) / 2.0).to_radians().cos();
+    ((lon2 - lon1) * scale_x, (lat2 - lat1) * meters_per_degree)
+}
+
+/// Straight-line distance in meters on a flat projection; close to Haversine over short hops.
+pub fn euclidean_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (dx, dy) = equirectangular_offset_meters(lat1, lon1, lat2, lon2);
+    dx.hypot(dy)
+}
+
+/// Grid distance in meters: the east-west and north-south offsets added together, as when
+/// driving along north-aligned city blocks.
+pub fn manhattan_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (dx, dy) = equirectangular_offset_meters(lat1, lon1, lat2, lon2);
+    dx.abs() + dy.abs()
+}
+
+/// Shortest distance in meters from a point to the segment `a`-`b`, all given as `[lon, lat]`.
+/// Uses an equirectangular projection around the point, which is accurate for short segments.
+pub fn distance_to_segment_meters(point: [f64; 2], a: [f64; 2], b: [f64; 2]) -> f64 {
+    let meters_per_degree = EARTH_RADIUS_METERS.to_radians();
+    let scale_x = meters_per_degree * point[1].to_radians().cos();
+    let project = |p: [f64; 2]| ((p[0] - point[0]) * scale_x, (p[1] - point[1]) * meters_per_degree);
+
+    let (ax, ay) = project(a);
+    let (bx, by) = project(b);
+    let (dx, dy) = (bx - ax, by - ay);
+    let length_squared = dx * dx + dy * dy;
+    let t = if length_squared == 0.0 {
+        0.0
+    } else {
+        (-(ax * dx + ay * dy) / length_squared).clamp(0.0, 1.0)
+    };
+    (ax + t * dx).hypot(ay + t * dy)
+}
+
+/// Ray-casting point-in-polygon test over a single `[lon, lat]` ring. Points lying exactly on an
+/// edge or vertex count as inside. Rings with fewer than three vertices never contain anything.
+pub fn point_in_polygon(point: [f64; 2], ring: &[[f64; 2]]) -> bool {
+    if ring.len() < 3 {
+        return false;
+    }
+
+    let [x, y] = point;
+    let mut inside = false;
+
+    for (i, &current) in ring.iter().enumerate() {
+        let previous = ring[(i + ring.len() - 1) % ring.len()];
+
+        if point_on_segment(point, previous, current) {
+            return true;
+        }
+
+        let [xi, yi] = current;
+        let [xj, yj] = previous;
+        if (yi > y) != (yj > y) {
+            let x_cross = (xj - xi) * (y - yi) / (yj - yi) + xi;
+            if x < x_cross {
+                inside = !inside;
+            }
+        }
+    }
+
+    inside
+}
+
+/// Signed distance in meters from a `[lon, lat]` point to the boundary of a polygon given as its
+/// outer ring followed by any holes: negative inside, positive outside. Each edge is measured with
+/// [`distance_to_segment_meters`], and containment follows [`point_in_polygon`], so points on an
+/// edge are inside at distance 0. `None` when there is no outer ring.
+pub fn polygon_distance(point: [f64; 2], rings: &[Vec<[f64; 2]>]) -> Option<f64> {
+    let (outer, holes) = rings.split_first()?;
+    let distance = rings
+        .iter()
+        .flat_map(|ring| ring.iter().zip(ring.iter().cycle().skip(1)))
+        .map(|(&a, &b)| distance_to_segment_meters(point, a, b))
+        .min_by(f64::total_cmp)?;
+    let inside = point_in_polygon(point, outer) && !holes.iter().any(|hole| point_in_polygon(point, hole));
+    Some(if inside { -distance } else { distance })
+}
+
+fn point_on_segment(point: [f64; 2], a: [f64; 2], b: [f64; 2]) -> bool {
+    const EPSILON: f64 = 1e-12;
+
+    let cross = (b[0] - a[0]) * (point[1] - a[1]) - (b[1] - a[1]) * (point[0] - a[0]);
+    if cross.abs() > EPSILON {
+        return false;
+    }
+
+    point[0] >= a[0].min(b[0]) - EPSILON
+        && point[0] <= a[0].max(b[0]) + EPSILON
+        && point[1] >= a[1].min(b[1]) - EPSILON
+        && point[1] <= a[1].max(b[1]) + EPSILON
+}
+
+/// Builders for every Redis key the service uses, so each format is defined once. Keys holding a
+/// user's data are scoped as `tenant:<tenant_id>:...`, so the same user id in two tenants never
+/// shares state; tenant ids contain no `:` or glob characters.
+pub mod redis_keys {
+    /// Set of geofence ids whose geometry changed since the last membership scan.
+    pub const GEOFENCE_RESCAN: &str = "geofence:rescan";
+
+    /// Present while fixes stored by instances that don't aggregate wait for a `daily_stats` pass
+    /// that scans `locations`.
+    pub const AGGREGATION_RESYNC: &str = "aggregation:resync";
+
+    /// Sorted set of [`presence_member`]s scored by the Unix milliseconds of their last report.
+    pub const PRESENCE_LAST_SEEN: &str = "presence:last_seen";
+
+    /// Set of [`usage_member`]s whose [`usage`] counter holds fixes not yet flushed to Postgres.
+    pub const USAGE_PENDING: &str = "usage:pending";
+
+    /// Set of [`rolling_member`]s whose [`rolling_bucket`] changed since the last flush to
+    /// `rolling_stats`.
+    pub const ROLLING_PENDING: &str = "rolling:pending";
+
+    fn scoped(tenant_id: &str, key: std::fmt::Arguments) -> String {
+        format!("tenant:{}:{}", tenant_id, key)
+    }
+
+    /// Latest fix of a user, as JSON.
+    pub fn current_location(tenant_id: &str, user_id: &str) -> String {
+        scoped(tenant_id, format_args!("location:current:{}", user_id))
+    }
+
+    /// Unix milliseconds of the fix held in [`current_location`], kept alongside it so the cache
+    /// can be compared and set atomically.
+    pub fn current_location_timestamp(tenant_id: &str, user_id: &str) -> String {
+        scoped(tenant_id, format_args!("location:current:{}:ts", user_id))
+    }
+
+    /// Outcome of a `track_location` call made with an `Idempotency-Key`.
+    pub fn track_idempotency(tenant_id: &str, user_id: &str, key: &str) -> String {
+        scoped(tenant_id, format_args!("idempotency:track:{}:{}", user_id, key))
+    }
+
+    /// Hash with `reported_at` (Unix milliseconds of the last accepted report) and the last
+    /// known `battery` level.
+    pub fn last_seen(tenant_id: &str, user_id: &str) -> String {
+        scoped(tenant_id, format_args!("last_seen:{}", user_id))
+    }
+
+    /// `SCAN MATCH` pattern for every idempotency record of a user.
+    pub fn track_idempotency_pattern(tenant_id: &str, user_id: &str) -> String {
+        let mut escaped = String::with_capacity(user_id.len());
+        for c in user_id.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        scoped(tenant_id, format_args!("idempotency:track:{}:*", escaped))
+    }
+
+    /// Set of geofence ids a user is currently inside.
+    pub fn geofence_membership(tenant_id: &str, user_id: &str) -> String {
+        scoped(tenant_id, format_args!("geofence:membership:{}", user_id))
+    }
+
+    /// Hash of geofence id to the millisecond timestamp of the fix that entered it.
+    pub fn geofence_entered(tenant_id: &str, user_id: &str) -> String {
+        scoped(tenant_id, format_args!("geofence:entered:{}", user_id))
+    }
+
+    /// Set of geofence ids a DWELL event already fired for during the current visit.
+    pub fn geofence_dwelled(tenant_id: &str, user_id: &str) -> String {
+        scoped(tenant_id, format_args!("geofence:dwelled:{}", user_id))
+    }
+
+    /// Present while SPEEDING events of the user in the geofence are being debounced.
+    pub fn geofence_speeding(tenant_id: &str, user_id: &str, geofence_id: &str) -> String {
+        scoped(tenant_id, format_args!("geofence:speeding:{}:{}", user_id, geofence_id))
+    }
+
+    /// Set of the tenant's users that reported a fix during the given minute since the Unix epoch.
+    pub fn active_users_bucket(tenant_id: &str, minute: i64) -> String {
+        scoped(tenant_id, format_args!("active_users:{}", minute))
+    }
+
+    /// Set of [`presence_member`]s of every tenant that reported during the given minute, behind
+    /// the `active_users` gauge.
+    pub fn all_active_users_bucket(minute: i64) -> String {
+        format!("active_users:{}", minute)
+    }
+
+    /// Scratch key holding the union of several active-user buckets.
+    pub fn active_users_union(token: &str) -> String {
+        format!("active_users:union:{}", token)
+    }
+
+    /// A user in sets shared by all tenants.
+    pub fn presence_member(tenant_id: &str, user_id: &str) -> String {
+        format!("{}:{}", tenant_id, user_id)
+    }
+
+    /// The tenant and user of a [`presence_member`].
+    pub fn parse_presence_member(member: &str) -> Option<(&str, &str)> {
+        member.split_once(':')
+    }
+
+    /// Fixes the tenant stored during the month (`YYYY-MM`) that are not yet in the `usage` table.
+    pub fn usage(tenant_id: &str, month: &str) -> String {
+        scoped(tenant_id, format_args!("usage:{}", month))
+    }
+
+    /// A tenant's month in [`USAGE_PENDING`].
+    pub fn usage_member(tenant_id: &str, month: &str) -> String {
+        format!("{}:{}", tenant_id, month)
+    }
+
+    /// The tenant and month of a [`usage_member`].
+    pub fn parse_usage_member(member: &str) -> Option<(&str, &str)> {
+        member.split_once(':')
+    }
+
+    /// Hash of a user's fixes stored during one rolling-aggregate bucket, numbered from the Unix
+    /// epoch: `distance_meters`, `speed_sum`, `speed_count`, `max_speed` and `point_count`.
+    pub fn rolling_bucket(tenant_id: &str, user_id: &str, bucket: i64) -> String {
+        scoped(tenant_id, format_args!("rolling:{}:{}", user_id, bucket))
+    }
+
+    /// Hash with the `latitude`, `longitude` and `timestamp` (Unix milliseconds) of the newest fix
+    /// folded into a user's rolling aggregates, which the next fix's distance is measured from.
+    pub fn rolling_last_fix(tenant_id: &str, user_id: &str) -> String {
+        scoped(tenant_id, format_args!("rolling_last:{}", user_id))
+    }
+
+    /// A user's bucket in [`ROLLING_PENDING`].
+    pub fn rolling_member(tenant_id: &str, user_id: &str, bucket: i64) -> String {
+        format!("{}:{}:{}", tenant_id, user_id, bucket)
+    }
+
+    /// The tenant, user and bucket of a [`rolling_member`].
+    pub fn parse_rolling_member(member: &str) -> Option<(&str, &str, i64)> {
+        let (tenant_id, rest) = member.split_once(':')?;
+        let (user_id, bucket) = rest.rsplit_once(':')?;
+        Some((tenant_id, user_id, bucket.parse().ok()?))
+    }
+
+    pub fn rate_limit_user(tenant_id: &str, subject: &str) -> String {
+        scoped(tenant_id, format_args!("ratelimit:user:{}", subject))
+    }
+
+    pub fn rate_limit_ip(ip: &str) -> String {
+        format!("ratelimit:ip:{}", ip)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn user_keys_are_scoped_to_the_tenant() {
+            assert_eq!(current_location("acme", "alice"), "tenant:acme:location:current:alice");
+            assert_eq!(current_location_timestamp("acme", "alice"), "tenant:acme:location:current:alice:ts");
+            assert_eq!(track_idempotency("acme", "alice", "k1"), "tenant:acme:idempotency:track:alice:k1");
+            assert_eq!(last_seen("acme", "alice"), "tenant:acme:last_seen:alice");
+            assert_eq!(geofence_membership("acme", "alice"), "tenant:acme:geofence:membership:alice");
+            assert_eq!(geofence_entered("acme", "alice"), "tenant:acme:geofence:entered:alice");
+            assert_eq!(geofence_dwelled("acme", "alice"), "tenant:acme:geofence:dwelled:alice");
+            assert_eq!(geofence_speeding("acme", "alice", "g1"), "tenant:acme:geofence:speeding:alice:g1");
+            assert_eq!(active_users_bucket("acme", 28_000_000), "tenant:acme:active_users:28000000");
+            assert_eq!(usage("acme", "2024-05"), "tenant:acme:usage:2024-05");
+            assert_eq!(rolling_bucket("acme", "alice", 42), "tenant:acme:rolling:alice:42");
+            assert_eq!(rolling_last_fix("acme", "alice"), "tenant:acme:rolling_last:alice");
+            assert_eq!(rate_limit_user("acme", "alice"), "tenant:acme:ratelimit:user:alice");
+            assert_ne!(current_location("acme", "alice"), current_location("globex", "alice"));
+        }
+
+        #[test]
+        fn shared_keys_are_not_scoped() {
+            assert_eq!(all_active_users_bucket(28_000_000), "active_users:28000000");
+            assert_eq!(active_users_union("t1"), "active_users:union:t1");
+            assert_eq!(rate_limit_ip("10.0.0.1"), "ratelimit:ip:10.0.0.1");
+        }
+
+        #[test]
+        fn the_idempotency_pattern_matches_only_the_users_records() {
+            assert_eq!(track_idempotency_pattern("acme", "alice"), "tenant:acme:idempotency:track:alice:*");
+            assert_eq!(track_idempotency_pattern("acme", "a*[b]?\\"), "tenant:acme:idempotency:track:a\\*\\[b\\]\\?\\\\:*");
+        }
+
+        #[test]
+        fn members_parse_back_into_their_parts() {
+            assert_eq!(parse_presence_member(&presence_member("acme", "alice")), Some(("acme", "alice")));
+            assert_eq!(parse_usage_member(&usage_member("acme", "2024-05")), Some(("acme", "2024-05")));
+            assert_eq!(parse_rolling_member(&rolling_member("acme", "al:ice", 42)), Some(("acme", "al:ice", 42)));
+            assert_eq!(parse_rolling_member("acme:alice:soon"), None);
+            assert_eq!(parse_presence_member("malformed"), None);
+        }
+    }
+}
+
+/// Pieces of a GPX 1.1 document holding one track, so it can be written out point by point, and
+/// a reader pulling the track points back out of a document as it arrives.
+pub mod gpx {
+    use std::fmt;
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use quick_xml::events::{BytesStart, Event};
+    use quick_xml::name::LocalName;
+    use quick_xml::Reader;
+    use crate::models::Location;
+
+    pub const CONTENT_TYPE: &str = "application/gpx+xml";
+
+    pub const FOOTER: &str = "</trkseg></trk></gpx>\n";
+
+    /// XML declaration, `<gpx>` root and the opening of a track named `name`.
+    pub fn header(name: &str) -> String {
+        format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<gpx version=\"1.1\" creator=\"suuupra-live-tracking\" ",
+                "xmlns=\"http://www.topografix.com/GPX/1/1\" ",
+                "xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" ",
+                "xsi:schemaLocation=\"http://www.topografix.com/GPX/1/1 http://www.topografix.com/GPX/1/1/gpx.xsd\">\n",
+                "<trk><name>{}</name><trkseg>\n",
+            ),
+            escape(name)
+        )
+    }
+
+    /// One `<trkpt>`. `<ele>` is left out when the fix has no altitude.
+    pub fn track_point(location: &Location) -> String {
+        let ele = location
+            .altitude
+            .map(|altitude| format!("<ele>{}</ele>", altitude))
+            .unwrap_or_default();
+        format!(
+            "<trkpt lat=\"{}\" lon=\"{}\">{}<time>{}</time></trkpt>\n",
+            location.latitude,
+            location.longitude,
+            ele,
+            location.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)
+        )
+    }
+
+    /// Longest `<trkpt>` element, or other markup, accepted, so a missing close tag can't make the
+    /// reader buffer the rest of the document.
+    const MAX_ELEMENT_BYTES: usize = 64 * 1024;
+
+    /// A `<trkpt>` as read: its position, plus `<ele>` and `<time>` when present. `None` stands
+    /// for a value that is missing or doesn't parse, which callers tell apart by `has_time`.
+    #[derive(Debug, Clone, Default)]
+    pub struct TrackPoint {
+        pub latitude: Option<f64>,
+        pub longitude: Option<f64>,
+        pub elevation: Option<f64>,
+        pub time: Option<DateTime<Utc>>,
+        /// Whether a `<time>` element was present at all.
+        pub has_time: bool,
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum ReadError {
+        /// A `<trkpt>` or other markup longer than [`MAX_ELEMENT_BYTES`], or never closed.
+        Unterminated,
+        /// The document is not well-formed XML.
+        Malformed(String),
+    }
+
+    impl fmt::Display for ReadError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                ReadError::Unterminated => write!(f, "a <trkpt> element or other markup is not closed within {} bytes", MAX_ELEMENT_BYTES),
+                ReadError::Malformed(reason) => write!(f, "the document is not well-formed XML: {}", reason),
+            }
+        }
+    }
+
+    /// Pulls `<trkpt>` elements, in any namespace prefix, out of a GPX document fed in arbitrary
+    /// chunks. Only the part of the document not yet matched is kept, so memory stays bounded by
+    /// one element and one chunk. Everything outside track points, such as waypoints and routes,
+    /// is ignored.
+    #[derive(Debug, Default)]
+    pub struct TrackPointReader {
+        pending: Vec<u8>,
+    }
+
+    impl TrackPointReader {
+        /// The track points completed by `chunk`, in document order.
+        pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<TrackPoint>, ReadError> {
+            self.pending.extend_from_slice(chunk);
+            self.read(false)
+        }
+
+        /// Checks that the document did not end inside a track point or other markup.
+        pub fn finish(mut self) -> Result<(), ReadError> {
+            self.read(true)?;
+            match self.pending.is_empty() {
+                true => Ok(()),
+                false => Err(ReadError::Unterminated),
+            }
+        }
+
+        /// Reads the pending bytes up to the last point outside a track point. Unless `at_end`,
+        /// markup cut off by the end of the chunk is kept for the next one.
+        fn read(&mut self, at_end: bool) -> Result<Vec<TrackPoint>, ReadError> {
+            let mut reader = Reader::from_reader(self.pending.as_slice());
+            // Reading resumes mid-document, after elements that are still open.
+            let config = reader.config_mut();
+            config.check_end_names = false;
+            config.allow_unmatched_ends = true;
+
+            let mut points = Vec::new();
+            let mut open: Option<PointBuilder> = None;
+            let mut consumed = 0;
+            loop {
+                let event = match reader.read_event() {
+                    Ok(Event::Eof) => break,
+                    Ok(event) => event,
+                    Err(quick_xml::Error::Syntax(_)) if !at_end => break,
+                    Err(e) => return Err(ReadError::Malformed(e.to_string())),
+                };
+                let closed = match (&mut open, event) {
+                    (None, Event::Start(tag)) if is_track_point(tag.local_name()) => {
+                        open = Some(PointBuilder::new(&tag));
+                        false
+                    }
+                    (None, Event::Empty(tag)) if is_track_point(tag.local_name()) => {
+                        points.push(PointBuilder::new(&tag).point);
+                        false
+                    }
+                    (Some(builder), event) => builder.closed_by(event),
+                    _ => false,
+                };
+                if closed {
+                    points.extend(open.take().map(|builder| builder.point));
+                }
+                if open.is_none() {
+                    consumed = reader.buffer_position() as usize;
+                }
+            }
+
+            if self.pending.len() - consumed > MAX_ELEMENT_BYTES {
+                return Err(ReadError::Unterminated);
+            }
+            self.pending.drain(..consumed);
+            Ok(points)
+        }
+    }
+
+    fn is_track_point(name: LocalName<'_>) -> bool {
+        name.as_ref() == b"trkpt"
+    }
+
+    /// The child of a `<trkpt>` whose text is being collected.
+    #[derive(Debug)]
+    enum Field {
+        Elevation,
+        Time,
+    }
+
+    /// A `<trkpt>` whose start tag has been read but not yet its end tag.
+    #[derive(Debug)]
+    struct PointBuilder {
+        point: TrackPoint,
+        /// Elements open inside the track point.
+        depth: usize,
+        field: Option<Field>,
+        /// Text of `field` so far; `None` once part of it could not be decoded.
+        text: Option<String>,
+    }
+
+    impl PointBuilder {
+        fn new(tag: &BytesStart<'_>) -> Self {
+            let mut point = TrackPoint::default();
+            for attribute in tag.attributes().flatten() {
+                let value = attribute.unescape_value().ok();
+                match attribute.key.local_name().as_ref() {
+                    b"lat" => point.latitude = value.as_deref().and_then(number),
+                    b"lon" => point.longitude = value.as_deref().and_then(number),
+                    _ => {}
+                }
+            }
+            Self { point, depth: 0, field: None, text: None }
+        }
+
+        /// Takes the next event inside the track point; true once it is the point's end tag.
+        fn closed_by(&mut self, event: Event<'_>) -> bool {
+            match event {
+                Event::Start(tag) => {
+                    self.depth += 1;
+                    if self.depth == 1 {
+                        self.field = match tag.local_name().as_ref() {
+                            b"ele" => Some(Field::Elevation),
+                            b"time" => Some(Field::Time),
+                            _ => None,
+                        };
+                        self.text = Some(String::new());
+                    }
+                }
+                Event::Empty(tag) if self.depth == 0 && tag.local_name().as_ref() == b"time" => self.point.has_time = true,
+                Event::Text(text) if self.field.is_some() => {
+                    let decoded = text.unescape().ok();
+                    self.append(decoded.as_deref());
+                }
+                Event::CData(text) if self.field.is_some() => {
+                    let bytes = text.into_inner();
+                    self.append(std::str::from_utf8(&bytes).ok());
+                }
+                Event::End(_) if self.depth == 0 => return true,
+                Event::End(_) => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        let text = self.text.take();
+                        match self.field.take() {
+                            Some(Field::Elevation) => self.point.elevation = text.as_deref().and_then(number),
+                            Some(Field::Time) => {
+                                self.point.has_time = true;
+                                self.point.time = text
+                                    .and_then(|time| DateTime::parse_from_rfc3339(time.trim()).ok())
+                                    .map(|time| time.with_timezone(&Utc));
+                            }
+                            None => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+            false
+        }
+
+        fn append(&mut self, decoded: Option<&str>) {
+            self.text = self.text.take().zip(decoded).map(|(text, decoded)| text + decoded);
+        }
+    }
+
+    fn number(text: &str) -> Option<f64> {
+        text.trim().parse::<f64>().ok().filter(|value| value.is_finite())
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&apos;")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn read(document: &str) -> Result<Vec<TrackPoint>, ReadError> {
+            let mut reader = TrackPointReader::default();
+            let points = reader.push(document.as_bytes())?;
+            reader.finish()?;
+            Ok(points)
+        }
+
+        fn time(text: &str) -> Option<DateTime<Utc>> {
+            Some(DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc))
+        }
+
+        const DOCUMENT: &str = concat!(
+            "<?xml version=\"1.0\"?>\n<gpx version=\"1.1\" xmlns=\"http://www.topografix.com/GPX/1/1\">",
+            "<wpt lat=\"9\" lon=\"9\"><time>2024-01-01T00:00:00Z</time></wpt>",
+            "<trk><name>Run &amp; back</name><trkseg>",
+            "<trkpt lat=\"51.5\" lon=\"-0.12\"><ele>11.5</ele><time>2024-01-01T10:00:00Z</time></trkpt>\n",
+            "<!-- <trkpt lat=\"0\" lon=\"0\"></trkpt> -->",
+            "<trkpt lon='-0.13' lat='51.6'><time>2024-01-01T10:01:00Z</time><extensions><time>x</time></extensions></trkpt>",
+            "</trkseg></trk></gpx>",
+        );
+
+        #[test]
+        fn track_points_are_read_and_everything_else_is_ignored() {
+            let points = read(DOCUMENT).unwrap();
+            assert_eq!(points.len(), 2);
+            assert_eq!((points[0].latitude, points[0].longitude, points[0].elevation), (Some(51.5), Some(-0.12), Some(11.5)));
+            assert_eq!(points[0].time, time("2024-01-01T10:00:00Z"));
+            assert_eq!((points[1].latitude, points[1].longitude, points[1].elevation), (Some(51.6), Some(-0.13), None));
+            assert_eq!(points[1].time, time("2024-01-01T10:01:00Z"));
+        }
+
+        #[test]
+        fn chunks_split_anywhere_give_the_same_points() {
+            let whole = read(DOCUMENT).unwrap();
+            for size in [1, 2, 7, 64] {
+                let mut reader = TrackPointReader::default();
+                let mut points = Vec::new();
+                for chunk in DOCUMENT.as_bytes().chunks(size) {
+                    points.extend(reader.push(chunk).unwrap());
+                }
+                reader.finish().unwrap();
+                assert_eq!(format!("{:?}", points), format!("{:?}", whole), "chunks of {} bytes", size);
+            }
+        }
+
+        #[test]
+        fn cdata_and_entities_are_decoded() {
+            let points = read(concat!(
+                "<gpx><trkpt lat=\"1&#46;5\" lon=\"&#x32;\">",
+                "<ele><![CDATA[ 7.25 ]]></ele><time>2024-01-01T10:00:00<![CDATA[Z]]></time>",
+                "</trkpt></gpx>",
+            ))
+            .unwrap();
+            assert_eq!((points[0].latitude, points[0].longitude, points[0].elevation), (Some(1.5), Some(2.0), Some(7.25)));
+            assert_eq!(points[0].time, time("2024-01-01T10:00:00Z"));
+        }
+
+        #[test]
+        fn an_unknown_entity_makes_the_value_unreadable() {
+            let points = read("<gpx><trkpt lat=\"1\" lon=\"2\"><time>&bogus;</time></trkpt></gpx>").unwrap();
+            assert!(points[0].has_time);
+            assert_eq!(points[0].time, None);
+        }
+
+        #[test]
+        fn namespaced_track_points_are_read() {
+            let points = read(concat!(
+                "<gpx:gpx xmlns:gpx=\"http://www.topografix.com/GPX/1/1\"><gpx:trk><gpx:trkseg>",
+                "<gpx:trkpt lat=\"1\" lon=\"2\"><gpx:ele>3</gpx:ele><gpx:time>2024-01-01T10:00:00Z</gpx:time></gpx:trkpt>",
+                "<gpx:trkpt lat=\"4\" lon=\"5\"/>",
+                "</gpx:trkseg></gpx:trk></gpx:gpx>",
+            ))
+            .unwrap();
+            assert_eq!(points.len(), 2);
+            assert_eq!((points[0].latitude, points[0].longitude, points[0].elevation), (Some(1.0), Some(2.0), Some(3.0)));
+            assert_eq!(points[0].time, time("2024-01-01T10:00:00Z"));
+            assert_eq!((points[1].latitude, points[1].longitude), (Some(4.0), Some(5.0)));
+        }
+
+        #[test]
+        fn a_missing_time_is_told_apart_from_an_unreadable_one() {
+            let points = read(concat!(
+                "<gpx><trkpt lat=\"1\" lon=\"2\"></trkpt>",
+                "<trkpt lat=\"1\" lon=\"2\"><time>yesterday</time></trkpt>",
+                "<trkpt lat=\"1\" lon=\"2\"><time/></trkpt></gpx>",
+            ))
+            .unwrap();
+            assert_eq!(points.iter().map(|point| (point.has_time, point.time)).collect::<Vec<_>>(), [(false, None), (true, None), (true, None)]);
+        }
+
+        #[test]
+        fn a_document_cut_inside_a_track_point_is_unterminated() {
+            let mut reader = TrackPointReader::default();
+            assert!(reader.push(b"<gpx><trkpt lat=\"1\" lon=\"2\"><time>2024-01-01").unwrap().is_empty());
+            assert_eq!(reader.finish(), Err(ReadError::Unterminated));
+        }
+
+        #[test]
+        fn a_document_cut_inside_a_tag_is_malformed() {
+            for cut in ["<gpx><trkpt lat=\"1\" lon=\"2\"/><!-- never closed", "<gpx><trkpt lat=\"1\" lon=\"2\"/><trkpt lat=\"1\" lon="] {
+                let mut reader = TrackPointReader::default();
+                assert_eq!(reader.push(cut.as_bytes()).unwrap().len(), 1, "{}", cut);
+                assert!(matches!(reader.finish(), Err(ReadError::Malformed(_))), "{}", cut);
+            }
+        }
+
+        /// A written document with a fix at altitude and one without.
+        fn written() -> String {
+            let mut high = crate::test_support::location(51.5, -0.12, time("2024-01-01T10:00:00Z").unwrap());
+            high.altitude = Some(11.5);
+            let low = crate::test_support::location(51.6, -0.13, time("2024-01-01T10:01:00.250Z").unwrap());
+            [header("alice & <bob>"), track_point(&high), track_point(&low), FOOTER.to_string()].concat()
+        }
+
+        #[test]
+        fn written_documents_follow_the_gpx_structure() {
+            let document = written();
+            let mut reader = Reader::from_str(&document);
+            let mut path: Vec<String> = Vec::new();
+            let mut track_points = Vec::new();
+            let mut elements = Vec::new();
+            loop {
+                match reader.read_event().unwrap() {
+                    Event::Start(start) | Event::Empty(start) if start.name().as_ref() == b"gpx" => {
+                        assert_eq!(start.try_get_attribute("version").unwrap().unwrap().value.as_ref(), b"1.1");
+                        assert_eq!(
+                            start.try_get_attribute("xmlns").unwrap().unwrap().value.as_ref(),
+                            b"http://www.topografix.com/GPX/1/1"
+                        );
+                        assert!(start.try_get_attribute("creator").unwrap().is_some());
+                        path.push("gpx".to_string());
+                    }
+                    Event::Start(start) => {
+                        let name = String::from_utf8(start.name().as_ref().to_vec()).unwrap();
+                        if name == "trkpt" {
+                            assert_eq!(path, ["gpx", "trk", "trkseg"]);
+                            track_points.push((
+                                start.try_get_attribute("lat").unwrap().unwrap().unescape_value().unwrap().into_owned(),
+                                start.try_get_attribute("lon").unwrap().unwrap().unescape_value().unwrap().into_owned(),
+                            ));
+                        }
+                        elements.push(path.join("/") + "/" + &name);
+                        path.push(name);
+                    }
+                    Event::End(_) => {
+                        path.pop();
+                    }
+                    Event::Eof => break,
+                    _ => {}
+                }
+            }
+
+            assert!(path.is_empty(), "unclosed elements: {:?}", path);
+            assert_eq!(track_points, [("51.5".to_string(), "-0.12".to_string()), ("51.6".to_string(), "-0.13".to_string())]);
+            assert_eq!(
+                elements,
+                [
+                    "gpx/trk",
+                    "gpx/trk/name",
+                    "gpx/trk/trkseg",
+                    "gpx/trk/trkseg/trkpt",
+                    "gpx/trk/trkseg/trkpt/ele",
+                    "gpx/trk/trkseg/trkpt/time",
+                    "gpx/trk/trkseg/trkpt",
+                    "gpx/trk/trkseg/trkpt/time",
+                ]
+            );
+            assert!(document.contains("<name>alice &amp; &lt;bob&gt;</name>"));
+            assert!(!document.contains("<ele></ele>"));
+        }
+
+        #[test]
+        fn written_documents_read_back_to_the_same_fixes() {
+            let points = read(&written()).unwrap();
+            assert_eq!(points.len(), 2);
+            assert_eq!((points[0].latitude, points[0].longitude, points[0].elevation), (Some(51.5), Some(-0.12), Some(11.5)));
+            assert_eq!(points[0].time, time("2024-01-01T10:00:00Z"));
+            assert_eq!(points[1].elevation, None);
+            assert_eq!(points[1].time, time("2024-01-01T10:01:00.250Z"));
+        }
+
+        #[test]
+        fn a_track_point_that_never_closes_is_refused_once_it_exceeds_the_limit() {
+            let mut reader = TrackPointReader::default();
+            reader.push(b"<gpx><trkpt lat=\"1\" lon=\"2\"><name>").unwrap();
+            let filler = vec![b'x'; MAX_ELEMENT_BYTES];
+            assert_eq!(reader.push(&filler).unwrap_err(), ReadError::Unterminated);
+        }
+    }
+}
+
+/// Shareable export links. The query string carries the export's parameters, the tenant and an
+/// expiry, all covered by an HMAC-SHA256 signature together with the user id and format, so the
+/// link grants exactly one download window without a JWT.
+pub mod signed_url {
+    use std::collections::HashMap;
+    use std::fmt;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    pub const TENANT: &str = "tenant";
+    /// Unix time in seconds after which the link is refused.
+    pub const EXPIRES: &str = "expires";
+    pub const SIGNATURE: &str = "signature";
+
+    /// Query parameters covered by the signature, in the order they are signed and emitted.
+    pub const SIGNED_PARAMS: [&str; 5] = [TENANT, "from", "to", "simplify", EXPIRES];
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SignatureError {
+        Invalid,
+        Expired,
+    }
+
+    impl fmt::Display for SignatureError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                SignatureError::Invalid => write!(f, "invalid export link signature"),
+                SignatureError::Expired => write!(f, "export link has expired"),
+            }
+        }
+    }
+
+    fn mac(secret: &str, user_id: &str, format: &str, params: &HashMap<String, String>) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+        let signed = SIGNED_PARAMS.iter().map(|name| params.get(*name).map_or("", String::as_str));
+        for value in [user_id, format].into_iter().chain(signed) {
+            // Length-prefixed, so no value can be shifted into its neighbour.
+            mac.update(&(value.len() as u64).to_be_bytes());
+            mac.update(value.as_bytes());
+        }
+        mac
+    }
+
+    /// Hex signature of the export of `user_id` in `format` with the [`SIGNED_PARAMS`] in `params`.
+    pub fn sign(secret: &str, user_id: &str, format: &str, params: &HashMap<String, String>) -> String {
+        hex::encode(mac(secret, user_id, format, params).finalize().into_bytes())
+    }
+
+    /// Checks the signature of a link and then its expiry against `now` (Unix seconds), returning
+    /// the tenant the link was minted in.
+    pub fn verify<'a>(
+        secret: &str,
+        user_id: &str,
+        format: &str,
+        params: &'a HashMap<String, String>,
+        now: i64,
+    ) -> Result<&'a str, SignatureError> {
+        let signature = params
+            .get(SIGNATURE)
+            .and_then(|signature| hex::decode(signature).ok())
+            .ok_or(SignatureError::Invalid)?;
+        mac(secret, user_id, format, params)
+            .verify_slice(&signature)
+            .map_err(|_| SignatureError::Invalid)?;
+
+        let expires = params
+            .get(EXPIRES)
+            .and_then(|expires| expires.parse::<i64>().ok())
+            .ok_or(SignatureError::Invalid)?;
+        if expires <= now {
+            return Err(SignatureError::Expired);
+        }
+        params.get(TENANT).map(String::as_str).ok_or(SignatureError::Invalid)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const SECRET: &str = "export-secret";
+        const NOW: i64 = 1_717_243_200;
+
+        /// Parameters of a link to alice's export in tenant `acme`, signed with [`SECRET`].
+        fn link(expires: i64) -> HashMap<String, String> {
+            let mut params = HashMap::from([
+                (TENANT.to_string(), "acme".to_string()),
+                ("from".to_string(), "2024-06-01T00:00:00.000Z".to_string()),
+                ("to".to_string(), "2024-06-01T12:00:00.000Z".to_string()),
+                (EXPIRES.to_string(), expires.to_string()),
+            ]);
+            params.insert(SIGNATURE.to_string(), sign(SECRET, "alice", "gpx", &params));
+            params
+        }
+
+        #[test]
+        fn a_valid_link_names_its_tenant() {
+            assert_eq!(verify(SECRET, "alice", "gpx", &link(NOW + 600), NOW), Ok("acme"));
+        }
+
+        #[test]
+        fn a_link_is_refused_from_its_expiry_on() {
+            assert_eq!(verify(SECRET, "alice", "gpx", &link(NOW), NOW), Err(SignatureError::Expired));
+            assert_eq!(verify(SECRET, "alice", "gpx", &link(NOW - 1), NOW), Err(SignatureError::Expired));
+        }
+
+        #[test]
+        fn a_tampered_link_is_invalid() {
+            let link = link(NOW + 600);
+            assert_eq!(verify(SECRET, "bob", "gpx", &link, NOW), Err(SignatureError::Invalid));
+            assert_eq!(verify(SECRET, "alice", "ndjson", &link, NOW), Err(SignatureError::Invalid));
+            assert_eq!(verify("another-secret", "alice", "gpx", &link, NOW), Err(SignatureError::Invalid));
+
+            for (name, value) in [
+                (TENANT, "globex"),
+                ("from", "2024-05-01T00:00:00.000Z"),
+                ("simplify", "5"),
+                (EXPIRES, "1999999999"),
+                (SIGNATURE, "not-hex"),
+                (SIGNATURE, "00"),
+            ] {
+                let mut tampered = link.clone();
+                tampered.insert(name.to_string(), value.to_string());
+                assert_eq!(verify(SECRET, "alice", "gpx", &tampered, NOW), Err(SignatureError::Invalid), "{}", name);
+            }
+            let mut unsigned = link.clone();
+            unsigned.remove(SIGNATURE);
+            assert_eq!(verify(SECRET, "alice", "gpx", &unsigned, NOW), Err(SignatureError::Invalid));
+        }
+
+        #[test]
+        fn values_cannot_be_shifted_between_fields() {
+            let mut params =
+                HashMap::from([(TENANT.to_string(), "acme".to_string()), (EXPIRES.to_string(), "1".to_string())]);
+            let signature = sign(SECRET, "alice", "gpx", &params);
+            params.insert(TENANT.to_string(), "gpxacme".to_string());
+            assert_ne!(sign(SECRET, "alice", "", &params), signature);
+        }
+    }
+}
+
+/// Google's encoded polyline format: each coordinate is the delta from the previous one, scaled
+/// by `10^precision`, zig-zag encoded and written as 5-bit chunks offset into printable ASCII.
+pub mod polyline {
+    /// Appends fixes to an encoded polyline one at a time, so a track never has to be held in memory.
+    #[derive(Debug)]
+    pub struct Encoder {
+        factor: f64,
+        previous: (i64, i64),
+        encoded: String,
+    }
+
+    impl Encoder {
+        /// `precision` is the number of decimal places kept, 5 in Google's own encoder.
+        pub fn new(precision: u32) -> Self {
+            Self { factor: 10f64.powi(precision as i32), previous: (0, 0), encoded: String::new() }
+        }
+
+        pub fn push(&mut self, latitude: f64, longitude: f64) {
+            let point = ((latitude * self.factor).round() as i64, (longitude * self.factor).round() as i64);
+            self.push_value(point.0 - self.previous.0);
+            self.push_value(point.1 - self.previous.1);
+            self.previous = point;
+        }
+
+        fn push_value(&mut self, delta: i64) {
+            let mut value = if delta < 0 { !(delta << 1) } else { delta << 1 };
+            while value >= 0x20 {
+                self.encoded.push(char::from((0x20 | (value & 0x1f)) as u8 + 63));
+                value >>= 5;
+            }
+            self.encoded.push(char::from(value as u8 + 63));
+        }
+
+        pub fn finish(self) -> String {
+            self.encoded
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use super::*;
+
+        fn encode(points: &[(f64, f64)], precision: u32) -> String {
+            let mut encoder = Encoder::new(precision);
+            for &(latitude, longitude) in points {
+                encoder.push(latitude, longitude);
+            }
+            encoder.finish()
+        }
+
+        /// The reverse of [`Encoder`], as a map client would run it.
+        fn decode(encoded: &str, precision: u32) -> Vec<(f64, f64)> {
+            let factor = 10f64.powi(precision as i32);
+            let mut values = Vec::new();
+            let (mut value, mut shift) = (0i64, 0);
+            for byte in encoded.bytes() {
+                let chunk = (byte - 63) as i64;
+                value |= (chunk & 0x1f) << shift;
+                shift += 5;
+                if chunk < 0x20 {
+                    values.push(if value & 1 == 1 { !(value >> 1) } else { value >> 1 });
+                    (value, shift) = (0, 0);
+                }
+            }
+            let mut point = (0i64, 0i64);
+            values
+                .chunks(2)
+                .map(|delta| {
+                    point = (point.0 + delta[0], point.1 + delta[1]);
+                    (point.0 as f64 / factor, point.1 as f64 / factor)
+                })
+                .collect()
+        }
+
+        #[test]
+        fn matches_the_reference_encoding() {
+            let points = [(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)];
+            assert_eq!(encode(&points, 5), "_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+        }
+
+        #[test]
+        fn decodes_back_within_the_precision() {
+            let mut rng = StdRng::seed_from_u64(87);
+            let points: Vec<(f64, f64)> =
+                (0..500).map(|_| (rng.gen_range(-90.0..=90.0), rng.gen_range(-180.0..=180.0))).collect();
+            for precision in [5, 6] {
+                let tolerance = 0.5 / 10f64.powi(precision as i32) + 1e-12;
+                let decoded = decode(&encode(&points, precision), precision);
+                assert_eq!(decoded.len(), points.len());
+                for (original, decoded) in points.iter().zip(&decoded) {
+                    assert!((original.0 - decoded.0).abs() <= tolerance, "{:?} != {:?}", original, decoded);
+                    assert!((original.1 - decoded.1).abs() <= tolerance, "{:?} != {:?}", original, decoded);
+                }
+            }
+        }
+
+        #[test]
+        fn an_empty_track_is_an_empty_string() {
+            assert_eq!(Encoder::new(5).finish(), "");
+        }
+
+        #[test]
+        fn repeated_points_cost_two_characters() {
+            let encoded = encode(&[(51.5, -0.12), (51.5, -0.12), (51.5, -0.12)], 6);
+            assert_eq!(encoded.len(), encode(&[(51.5, -0.12)], 6).len() + 4);
+        }
+    }
+}
+
+pub mod smoothing {
+    use super::EARTH_RADIUS_METERS;
+    use crate::models::Location;
+
+    /// Measurement noise assumed for fixes that report no accuracy, unless configured otherwise.
+    pub const DEFAULT_ACCURACY_METERS: f64 = 20.0;
+    /// Variance of the unmodelled acceleration, in (m/s²)².
+    const ACCELERATION_VARIANCE: f64 = 1.0;
+    /// Initial velocity variance, loose enough for the first few fixes to set the pace.
+    const INITIAL_VELOCITY_VARIANCE: f64 = 100.0;
+
+    /// Constant-velocity Kalman filter along one axis of a local tangent plane, in meters.
+    struct AxisFilter {
+        position: f64,
+        velocity: f64,
+        p00: f64,
+        p01: f64,
+        p11: f64,
+    }
+
+    impl AxisFilter {
+        fn new(position: f64, variance: f64) -> Self {
+            Self {
+                position,
+                velocity: 0.0,
+                p00: variance,
+                p01: 0.0,
+                p11: INITIAL_VELOCITY_VARIANCE,
+            }
+        }
+
+        fn predict(&mut self, dt: f64) {
+            self.position += self.velocity * dt;
+            self.p00 += 2.0 * dt * self.p01 + dt * dt * self.p11 + ACCELERATION_VARIANCE * dt.powi(4) / 4.0;
+            self.p01 += dt * self.p11 + ACCELERATION_VARIANCE * dt.powi(3) / 2.0;
+            self.p11 += ACCELERATION_VARIANCE * dt * dt;
+        }
+
+        fn update(&mut self, measurement: f64, variance: f64) {
+            let innovation = measurement - self.position;
+            let s = self.p00 + variance;
+            let k0 = self.p00 / s;
+            let k1 = self.p01 / s;
+
+            self.position += k0 * innovation;
+            self.velocity += k1 * innovation;
+            self.p11 -= k1 * self.p01;
+            self.p01 *= 1.0 - k0;
+            self.p00 *= 1.0 - k0;
+        }
+    }
+
+    /// Runs a constant-velocity Kalman filter over a time-ordered track, weighting each fix by its
+    /// reported accuracy. Only latitude and longitude are replaced; every other field is kept.
+    pub fn kalman_smooth_with_accuracy(points: &[Location], default_accuracy_meters: f64) -> Vec<Location> {
+        let Some(origin) = points.first() else {
+            return Vec::new();
+        };
+
+        // Equirectangular projection around the first fix; accurate enough over a single track.
+        let meters_per_degree = EARTH_RADIUS_METERS.to_radians();
+        let meters_per_degree_lon = meters_per_degree * origin.latitude.to_radians().cos().max(1e-6);
+        let variance = |point: &Location| {
+            let accuracy = point.accuracy.filter(|a| *a > 0.0).unwrap_or(default_accuracy_meters);
+            accuracy * accuracy
+        };
+
+        let mut east = AxisFilter::new(0.0, variance(origin));
+        let mut north = AxisFilter::new(0.0, variance(origin));
+        let mut previous = origin.timestamp;
+
+        points
+            .iter()
+            .enumerate()
+            .map(|(i, point)| {
+                if i > 0 {
+                    let dt = (point.timestamp - previous).num_milliseconds().max(0) as f64 / 1000.0;
+                    east.predict(dt);
+                    north.predict(dt);
+
+                    let r = variance(point);
+                    east.update((point.longitude - origin.longitude) * meters_per_degree_lon, r);
+                    north.update((point.latitude - origin.latitude) * meters_per_degree, r);
+                    previous = point.timestamp;
+                }
+
+                let mut smoothed = point.clone();
+                smoothed.latitude = origin.latitude + north.position / meters_per_degree;
+                smoothed.longitude = origin.longitude + east.position / meters_per_degree_lon;
+                smoothed
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use chrono::{Duration, TimeZone, Utc};
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use super::*;
+        use crate::test_support;
+        use crate::utils::track_distance_meters;
+
+        /// Five minutes due east at 10 m/s, one fix a second, each off by up to `noise_meters`
+        /// in both axes.
+        fn noisy_track(noise_meters: f64) -> Vec<Location> {
+            let mut rng = StdRng::seed_from_u64(7);
+            let meters_per_degree = EARTH_RADIUS_METERS.to_radians();
+            let start = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+            (0..300)
+                .map(|i| {
+                    let east = 10.0 * i as f64 + rng.gen_range(-noise_meters..=noise_meters);
+                    let north = rng.gen_range(-noise_meters..=noise_meters);
+                    test_support::location(north / meters_per_degree, east / meters_per_degree, start + Duration::seconds(i))
+                })
+                .collect()
+        }
+
+        #[test]
+        fn smoothing_removes_most_of_the_distance_noise_adds() {
+            let track = noisy_track(15.0);
+            let truth = 2_990.0;
+            let raw = track_distance_meters(&track);
+            let smoothed = kalman_smooth_with_accuracy(&track, DEFAULT_ACCURACY_METERS);
+
+            assert!(raw > truth * 1.5, "noise should inflate the raw distance, got {}", raw);
+            let distance = track_distance_meters(&smoothed);
+            assert!((distance - truth).abs() < truth * 0.1, "smoothed distance {} should be near {}", distance, truth);
+            assert_eq!(smoothed.len(), track.len());
+            assert!(smoothed.iter().zip(&track).all(|(s, t)| s.id == t.id && s.timestamp == t.timestamp));
+        }
+
+        #[test]
+        fn reported_accuracy_outweighs_the_default() {
+            let mut track = noisy_track(15.0);
+            let loose = kalman_smooth_with_accuracy(&track, DEFAULT_ACCURACY_METERS);
+            track.iter_mut().for_each(|point| point.accuracy = Some(0.01));
+            let exact = kalman_smooth_with_accuracy(&track, DEFAULT_ACCURACY_METERS);
+
+            assert!(track_distance_meters(&exact) > track_distance_meters(&loose));
+            let last = (exact.last().unwrap(), track.last().unwrap());
+            assert!((last.0.longitude - last.1.longitude).abs() < 1e-6);
+        }
+
+        #[test]
+        fn an_empty_track_stays_empty() {
+            assert!(kalman_smooth_with_accuracy(&[], DEFAULT_ACCURACY_METERS).is_empty());
+        }
+    }
+}
+
+pub mod simplify {
+    use super::EARTH_RADIUS_METERS;
+    use crate::models::Location;
+
+    /// Ramer-Douglas-Peucker simplification of a time-ordered track: keeps the fewest fixes such
+    /// that no dropped fix lies further than `epsilon_meters` from the simplified line. The first
+    /// and last fixes are always kept.
+    pub fn douglas_peucker(points: &[Location], epsilon_meters: f64) -> Vec<Location> {
+        if points.len() < 3 {
+            return points.to_vec();
+        }
+
+        // Equirectangular projection around the first fix, as for smoothing.
+        let meters_per_degree = EARTH_RADIUS_METERS.to_radians();
+        let meters_per_degree_lon = meters_per_degree * points[0].latitude.to_radians().cos().max(1e-6);
+        let projected: Vec<(f64, f64)> = points
+            .iter()
+            .map(|p| {
+                (
+                    (p.longitude - points[0].longitude) * meters_per_degree_lon,
+                    (p.latitude - points[0].latitude) * meters_per_degree,
+                )
+            })
+            .collect();
+
+        let mut keep = vec![false; points.len()];
+        keep[0] = true;
+        keep[points.len() - 1] = true;
+
+        // Explicit stack rather than recursion; long tracks would otherwise risk the call stack.
+        let mut segments = vec![(0, points.len() - 1)];
+        while let Some((start, end)) = segments.pop() {
+            let farthest = (start + 1..end)
+                .map(|i| (i, segment_distance(projected[i], projected[start], projected[end])))
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+            if let Some((index, distance)) = farthest {
+                if distance > epsilon_meters {
+                    keep[index] = true;
+                    segments.push((start, index));
+                    segments.push((index, end));
+                }
+            }
+        }
+
+        points.iter().zip(keep).filter(|(_, kept)| *kept).map(|(p, _)| p.clone()).collect()
+    }
+
+    /// Distance in meters from `p` to the segment `a`-`b`, all in projected coordinates.
+    fn segment_distance(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let length_squared = dx * dx + dy * dy;
+        let t = if length_squared == 0.0 {
+            0.0
+        } else {
+            (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length_squared).clamp(0.0, 1.0)
+        };
+        (p.0 - (a.0 + t * dx)).hypot(p.1 - (a.1 + t * dy))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use chrono::{Duration, Utc};
+        use super::*;
+        use crate::test_support;
+
+        /// Fixes a second apart through the given points.
+        fn track(points: &[(f64, f64)]) -> Vec<Location> {
+            let start = Utc::now();
+            points
+                .iter()
+                .enumerate()
+                .map(|(i, &(lat, lon))| test_support::location(lat, lon, start + Duration::seconds(i as i64)))
+                .collect()
+        }
+
+        fn positions(track: &[Location]) -> Vec<(f64, f64)> {
+            track.iter().map(|p| (p.latitude, p.longitude)).collect()
+        }
+
+        #[test]
+        fn a_straight_line_collapses_to_its_ends() {
+            let line: Vec<(f64, f64)> = (0..=100).map(|i| (51.5, -0.12 + i as f64 * 0.0001)).collect();
+            let simplified = douglas_peucker(&track(&line), 1.0);
+            assert_eq!(positions(&simplified), [line[0], line[100]]);
+        }
+
+        #[test]
+        fn a_sharp_corner_is_kept() {
+            // East for about 700 m, then north; small wobbles stay well under the tolerance.
+            let mut corner: Vec<(f64, f64)> =
+                (0..=10).map(|i| (51.5 + (i % 2) as f64 * 0.00001, -0.12 + i as f64 * 0.001)).collect();
+            corner.extend((1..=10).map(|i| (51.5 + i as f64 * 0.001, -0.11)));
+            let simplified = douglas_peucker(&track(&corner), 10.0);
+            assert_eq!(positions(&simplified), [corner[0], corner[10], corner[20]]);
+        }
+
+        #[test]
+        fn no_dropped_fix_strays_beyond_the_tolerance() {
+            let wiggly: Vec<(f64, f64)> =
+                (0..200).map(|i| (51.5 + (i as f64 / 7.0).sin() * 0.001, -0.12 + i as f64 * 0.0001)).collect();
+            let original = track(&wiggly);
+            for epsilon in [1.0, 10.0, 50.0] {
+                let simplified = douglas_peucker(&original, epsilon);
+                assert!(simplified.len() < original.len(), "{}", epsilon);
+                assert_eq!(simplified.first().unwrap().id, original[0].id);
+                assert_eq!(simplified.last().unwrap().id, original[199].id);
+                // Every fix lies within the tolerance of the kept fixes either side of it.
+                let mut kept = simplified.iter().map(|p| original.iter().position(|o| o.id == p.id).unwrap());
+                let mut start = kept.next().unwrap();
+                for end in kept {
+                    for p in &original[start + 1..end] {
+                        let projected = douglas_peucker(&[original[start].clone(), p.clone(), original[end].clone()], epsilon);
+                        assert_eq!(projected.len(), 2, "{} m", epsilon);
+                    }
+                    start = end;
+                }
+            }
+        }
+
+        #[test]
+        fn short_tracks_are_left_alone() {
+            let pair = track(&[(51.5, -0.12), (51.6, -0.12)]);
+            assert_eq!(douglas_peucker(&pair, 10.0).len(), 2);
+            assert!(douglas_peucker(&[], 10.0).is_empty());
+        }
+    }
+}
+
+pub mod interpolate {
+    use chrono::{DateTime, Utc};
+    use uuid::Uuid;
+    use super::bearing_degrees;
+    use crate::models::Location;
+
+    /// Estimated fix at `t` between two fixes of one user: the position moves along the great
+    /// circle joining them (spherical linear interpolation) and altitude, accuracy, speed and
+    /// battery change linearly with time. `t` is clamped to the pair, and fixes sharing a
+    /// timestamp yield `before`. The result has a nil id and no `seq`, as it was never stored.
+    pub fn at_time(before: &Location, after: &Location, t: DateTime<Utc>) -> Location {
+        let span_ms = (after.timestamp - before.timestamp).num_milliseconds();
+        let fraction = if span_ms <= 0 {
+            0.0
+        } else {
+            ((t - before.timestamp).num_milliseconds() as f64 / span_ms as f64).clamp(0.0, 1.0)
+        };
+
+        let (latitude, longitude) = slerp(
+            (before.latitude, before.longitude),
+            (after.latitude, after.longitude),
+            fraction,
+        );
+        let heading = if fraction < 1.0 && (latitude, longitude) != (after.latitude, after.longitude) {
+            Some(bearing_degrees(latitude, longitude, after.latitude, after.longitude))
+        } else {
+            before.heading.or(after.heading)
+        };
+
+        Location {
+            id: Uuid::nil(),
+            tenant_id: before.tenant_id.clone(),
+            user_id: before.user_id.clone(),
+            latitude,
+            longitude,
+            altitude: lerp(before.altitude, after.altitude, fraction),
+            accuracy: lerp(before.accuracy, after.accuracy, fraction),
+            speed: lerp(before.speed, after.speed, fraction),
+            heading,
+            battery: lerp(before.battery.map(f64::from), after.battery.map(f64::from), fraction).map(|b| b as f32),
+            seq: None,
+            timestamp: if fraction == 0.0 { before.timestamp } else { t.clamp(before.timestamp, after.timestamp) },
+            timestamp_status: before.timestamp_status,
+        }
+    }
+
+    /// A value known at only one end is held rather than invented.
+    fn lerp(a: Option<f64>, b: Option<f64>, fraction: f64) -> Option<f64> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a + (b - a) * fraction),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Point `fraction` of the way from `a` to `b` along the great circle through them, in degrees.
+    fn slerp((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64), fraction: f64) -> (f64, f64) {
+        let to_vector = |lat: f64, lon: f64| {
+            let (phi, lambda) = (lat.to_radians(), lon.to_radians());
+            [phi.cos() * lambda.cos(), phi.cos() * lambda.sin(), phi.sin()]
+        };
+        let a = to_vector(lat1, lon1);
+        let b = to_vector(lat2, lon2);
+
+        let dot = (a[0] * b[0] + a[1] * b[1] + a[2] * b[2]).clamp(-1.0, 1.0);
+        let omega = dot.acos();
+        // Nearly coincident points: the linear blend is exact enough and avoids dividing by ~0.
+        let (wa, wb) = if omega.sin().abs() < 1e-12 {
+            (1.0 - fraction, fraction)
+        } else {
+            (((1.0 - fraction) * omega).sin() / omega.sin(), (fraction * omega).sin() / omega.sin())
+        };
+        let [x, y, z] = [wa * a[0] + wb * b[0], wa * a[1] + wb * b[1], wa * a[2] + wb * b[2]];
+
+        (z.atan2(x.hypot(y)).to_degrees(), y.atan2(x).to_degrees())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use chrono::Duration;
+        use super::*;
+        use crate::test_support;
+        use crate::utils::haversine_meters;
+
+        fn fix(latitude: f64, longitude: f64, timestamp: DateTime<Utc>, altitude: f64, speed: f64) -> Location {
+            Location {
+                altitude: Some(altitude),
+                speed: Some(speed),
+                ..test_support::location(latitude, longitude, timestamp)
+            }
+        }
+
+        fn assert_close(actual: f64, expected: f64) {
+            assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+        }
+
+        #[test]
+        fn the_midpoint_in_time_is_halfway_along_the_great_circle() {
+            let t0 = Utc::now();
+            let before = fix(0.0, 0.0, t0, 100.0, 2.0);
+            let after = fix(0.0, 2.0, t0 + Duration::seconds(60), 200.0, 4.0);
+            let middle = at_time(&before, &after, t0 + Duration::seconds(30));
+            assert_close(middle.latitude, 0.0);
+            assert_close(middle.longitude, 1.0);
+            assert_close(middle.altitude.unwrap(), 150.0);
+            assert_close(middle.speed.unwrap(), 3.0);
+            assert_eq!(middle.timestamp, t0 + Duration::seconds(30));
+            assert_eq!((middle.id, middle.seq), (Uuid::nil(), None));
+
+            // Off the equator the great circle bows towards the pole, unlike a straight average.
+            let london = fix(51.5, -0.12, t0, 0.0, 0.0);
+            let new_york = fix(40.7, -74.0, t0 + Duration::hours(8), 0.0, 0.0);
+            let middle = at_time(&london, &new_york, t0 + Duration::hours(4));
+            assert!(middle.latitude > 52.0, "{}", middle.latitude);
+            let to_london = haversine_meters(middle.latitude, middle.longitude, 51.5, -0.12);
+            let to_new_york = haversine_meters(middle.latitude, middle.longitude, 40.7, -74.0);
+            assert!((to_london - to_new_york).abs() < 1.0, "{} != {}", to_london, to_new_york);
+        }
+
+        #[test]
+        fn the_endpoints_and_beyond_are_the_fixes_themselves() {
+            let t0 = Utc::now();
+            let before = fix(51.5, -0.12, t0, 10.0, 1.0);
+            let after = fix(51.6, -0.10, t0 + Duration::seconds(60), 20.0, 5.0);
+            for (t, expected) in [
+                (t0, &before),
+                (t0 - Duration::seconds(30), &before),
+                (t0 + Duration::seconds(60), &after),
+                (t0 + Duration::seconds(90), &after),
+            ] {
+                let estimate = at_time(&before, &after, t);
+                assert_close(estimate.latitude, expected.latitude);
+                assert_close(estimate.longitude, expected.longitude);
+                assert_close(estimate.altitude.unwrap(), expected.altitude.unwrap());
+                assert_eq!(estimate.timestamp, expected.timestamp);
+            }
+        }
+
+        #[test]
+        fn fixes_sharing_a_timestamp_yield_the_earlier_one() {
+            let t0 = Utc::now();
+            let before = fix(51.5, -0.12, t0, 10.0, 1.0);
+            let after = fix(51.6, -0.10, t0, 20.0, 5.0);
+            for t in [t0, t0 + Duration::seconds(30)] {
+                let estimate = at_time(&before, &after, t);
+                assert_close(estimate.latitude, 51.5);
+                assert_close(estimate.longitude, -0.12);
+                assert_eq!((estimate.altitude, estimate.timestamp), (Some(10.0), t0));
+            }
+        }
+
+        #[test]
+        fn crossing_the_antimeridian_takes_the_short_way() {
+            let t0 = Utc::now();
+            let before = fix(0.0, 179.0, t0, 0.0, 0.0);
+            let after = fix(0.0, -179.0, t0 + Duration::seconds(60), 0.0, 0.0);
+            let middle = at_time(&before, &after, t0 + Duration::seconds(30));
+            assert_close(middle.longitude.abs(), 180.0);
+        }
+
+        #[test]
+        fn values_known_at_one_end_are_held() {
+            let t0 = Utc::now();
+            let before = fix(0.0, 0.0, t0, 100.0, 2.0);
+            let after = Location { altitude: None, ..fix(0.0, 1.0, t0 + Duration::seconds(10), 0.0, 4.0) };
+            assert_eq!(at_time(&before, &after, t0 + Duration::seconds(5)).altitude, Some(100.0));
+        }
+    }
+}
+
+pub mod geohash {
+    use std::fmt;
+
+    const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+    pub const MAX_PRECISION: usize = 12;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum GeohashError {
+        InvalidPrecision(usize),
+        InvalidCharacter(char),
+    }
+
+    impl fmt::Display for GeohashError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                GeohashError::InvalidPrecision(precision) => {
+                    write!(f, "precision {} is outside 1..={}", precision, MAX_PRECISION)
+                }
+                GeohashError::InvalidCharacter(c) => write!(f, "'{}' is not a geohash character", c),
+            }
+        }
+    }
+
+    impl std::error::Error for GeohashError {}
+
+    /// Latitude and longitude ranges covered by a geohash cell.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Bounds {
+        pub min_latitude: f64,
+        pub max_latitude: f64,
+        pub min_longitude: f64,
+        pub max_longitude: f64,
+    }
+
+    /// Encodes a point as a geohash of `precision` characters (1..=12).
+    pub fn encode(latitude: f64, longitude: f64, precision: usize) -> Result<String, GeohashError> {
+        if !(1..=MAX_PRECISION).contains(&precision) {
+            return Err(GeohashError::InvalidPrecision(precision));
+        }
+
+        let mut lat_range = (-90.0, 90.0);
+        let mut lon_range = (-180.0, 180.0);
+        let mut hash = String::with_capacity(precision);
+        let mut even_bit = true;
+
+        while hash.len() < precision {
+            let mut index = 0;
+            for _ in 0..5 {
+                let (range, value) = if even_bit {
+                    (&mut lon_range, longitude)
+                } else {
+                    (&mut lat_range, latitude)
+                };
+                let mid = (range.0 + range.1) / 2.0;
+                index <<= 1;
+                if value >= mid {
+                    index |= 1;
+                    range.0 = mid;
+                } else {
+                    range.1 = mid;
+                }
+                even_bit = !even_bit;
+            }
+            hash.push(BASE32[index] as char);
+        }
+
+        Ok(hash)
+    }
+
+    pub fn bounds(hash: &str) -> Result<Bounds, GeohashError> {
+        if !(1..=MAX_PRECISION).contains(&hash.len()) {
+            return Err(GeohashError::InvalidPrecision(hash.len()));
+        }
+
+        let mut lat_range = (-90.0, 90.0);
+        let mut lon_range = (-180.0, 180.0);
+        let mut even_bit = true;
+
+        for c in hash.chars() {
+            let index = BASE32
+                .iter()
+                .position(|&b| b as char == c.to_ascii_lowercase())
+                .ok_or(GeohashError::InvalidCharacter(c))?;
+            for shift in (0..5).rev() {
+                let range = if even_bit { &mut lon_range } else { &mut lat_range };
+                let mid = (range.0 + range.1) / 2.0;
+                if (index >> shift) & 1 == 1 {
+                    range.0 = mid;
+                } else {
+                    range.1 = mid;
+                }
+                even_bit = !even_bit;
+            }
+        }
+
+        Ok(Bounds {
+            min_latitude: lat_range.0,
+            max_latitude: lat_range.1,
+            min_longitude: lon_range.0,
+            max_longitude: lon_range.1,
+        })
+    }
+
+    /// Cell size in meters (north-south, east-west) at the given latitude.
+    fn cell_size_meters(precision: usize, latitude: f64) -> (f64, f64) {
+        let bits = 5 * precision as i32;
+        let lat_bits = bits / 2;
+        let lon_bits = bits - lat_bits;
+        let meters_per_degree = super::EARTH_RADIUS_METERS.to_radians();
+        (
+            180.0 / 2f64.powi(lat_bits) * meters_per_degree,
+            360.0 / 2f64.powi(lon_bits) * meters_per_degree * latitude.to_radians().cos(),
+        )
+    }
+
+    /// Geohash prefixes whose cells together cover every point within `radius_meters` of the
+    /// centre. Picks the finest precision whose cells are at least as large as the search circle's
+    /// bounding box, so that box touches at most 2x2 cells and its corners hit all of them.
+    pub fn covering(latitude: f64, longitude: f64, radius_meters: f64) -> Vec<String> {
+        let precision = (1..=MAX_PRECISION)
+            .rev()
+            .find(|&precision| {
+                let (height, width) = cell_size_meters(precision, latitude);
+                height >= 2.0 * radius_meters && width >= 2.0 * radius_meters
+            })
+            .unwrap_or(1);
+
+        let meters_per_degree = super::EARTH_RADIUS_METERS.to_radians();
+        let d_lat = radius_meters / meters_per_degree;
+        let d_lon = radius_meters / (meters_per_degree * latitude.to_radians().cos().max(1e-6));
+
+        let mut prefixes = Vec::with_capacity(5);
+        for (lat, lon) in [
+            (latitude, longitude),
+            (latitude - d_lat, longitude - d_lon),
+            (latitude - d_lat, longitude + d_lon),
+            (latitude + d_lat, longitude - d_lon),
+            (latitude + d_lat, longitude + d_lon),
+        ] {
+            let lat = lat.clamp(-90.0, 90.0);
+            let lon = (lon + 180.0).rem_euclid(360.0) - 180.0;
+            if let Ok(prefix) = encode(lat, lon, precision) {
+                if !prefixes.contains(&prefix) {
+                    prefixes.push(prefix);
+                }
+            }
+        }
+        prefixes
+    }
+
+    /// Centre of the geohash cell as `(latitude, longitude)`.
+    pub fn decode(hash: &str) -> Result<(f64, f64), GeohashError> {
+        let cell = bounds(hash)?;
+        Ok((
+            (cell.min_latitude + cell.max_latitude) / 2.0,
+            (cell.min_longitude + cell.max_longitude) / 2.0,
+        ))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn encodes_the_reference_point() {
+            assert_eq!(encode(57.64911, 10.40744, 11).unwrap(), "u4pruydqqvj");
+            assert_eq!(encode(57.64911, 10.40744, 6).unwrap(), "u4pruy");
+        }
+
+        #[test]
+        fn decoding_lands_within_the_encoded_cell() {
+            let points = [(57.64911, 10.40744), (-33.8688, 151.2093), (40.7128, -74.006), (0.0, 0.0), (-89.9, 179.9)];
+            for (latitude, longitude) in points {
+                for precision in 1..=MAX_PRECISION {
+                    let hash = encode(latitude, longitude, precision).unwrap();
+                    let cell = bounds(&hash).unwrap();
+                    assert!((cell.min_latitude..=cell.max_latitude).contains(&latitude), "{}", hash);
+                    assert!((cell.min_longitude..=cell.max_longitude).contains(&longitude), "{}", hash);
+
+                    let (lat, lon) = decode(&hash).unwrap();
+                    assert!((lat - latitude).abs() <= (cell.max_latitude - cell.min_latitude) / 2.0);
+                    assert!((lon - longitude).abs() <= (cell.max_longitude - cell.min_longitude) / 2.0);
+                    assert_eq!(encode(lat, lon, precision).unwrap(), hash);
+                }
+            }
+        }
+
+        #[test]
+        fn precision_must_be_between_1_and_12() {
+            assert_eq!(encode(0.0, 0.0, 0), Err(GeohashError::InvalidPrecision(0)));
+            assert_eq!(encode(0.0, 0.0, 13), Err(GeohashError::InvalidPrecision(13)));
+            assert_eq!(decode(""), Err(GeohashError::InvalidPrecision(0)));
+            assert_eq!(decode("u4pruydqqvjxx"), Err(GeohashError::InvalidPrecision(13)));
+        }
+
+        #[test]
+        fn rejects_characters_outside_the_alphabet() {
+            assert_eq!(decode("u4pa"), Err(GeohashError::InvalidCharacter('a')));
+            assert_eq!(decode("U4PRUY"), decode("u4pruy"));
+        }
+    }
+}
+
+/// H3 hexagonal cells, for aggregations that must line up with datasets indexed on Uber's grid.
+pub mod h3 {
+    pub use h3o::CellIndex;
+    use h3o::{LatLng, Resolution};
+
+    pub const MAX_RESOLUTION: u8 = 15;
+
+    /// Cell containing the point at `resolution`; `None` for non-finite coordinates or a
+    /// resolution above [`MAX_RESOLUTION`].
+    pub fn cell(latitude: f64, longitude: f64, resolution: u8) -> Option<CellIndex> {
+        let resolution = Resolution::try_from(resolution).ok()?;
+        Some(LatLng::new(latitude, longitude).ok()?.to_cell(resolution))
+    }
+
+    /// Centre of the cell as `(latitude, longitude)`.
+    pub fn center(cell: CellIndex) -> (f64, f64) {
+        let center = LatLng::from(cell);
+        (center.lat(), center.lng())
+    }
+
+    /// Resolution for a map zoom level, giving cells about as wide as the geohash cells
+    /// `cluster_precision` picks for the same zoom:
+    ///
+    /// | zoom  | resolution | mean edge |
+    /// |-------|------------|-----------|
+    /// | 0–1   | 0          | ~1280 km  |
+    /// | 2–4   | 1          | ~483 km   |
+    /// | 5–6   | 3          | ~69 km    |
+    /// | 7–9   | 4          | ~26 km    |
+    /// | 10–11 | 6          | ~3.7 km   |
+    /// | 12–14 | 7          | ~1.4 km   |
+    /// | 15–16 | 9          | ~200 m    |
+    /// | 17+   | 10         | ~76 m     |
+    pub fn resolution_for_zoom(zoom: u8) -> u8 {
+        match zoom {
+            0..=1 => 0,
+            2..=4 => 1,
+            5..=6 => 3,
+            7..=9 => 4,
+            10..=11 => 6,
+            12..=14 => 7,
+            15..=16 => 9,
+            _ => 10,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use super::*;
+    use crate::test_support;
+
+    const LONDON: (f64, f64) = (51.5074, -0.1278);
+    const PARIS: (f64, f64) = (48.8566, 2.3522);
+    const NEW_YORK: (f64, f64) = (40.7128, -74.0060);
+    const LOS_ANGELES: (f64, f64) = (34.0522, -118.2437);
+
+    fn assert_within_half_percent(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() <= expected * 0.005, "{} is not within 0.5% of {}", actual, expected);
+    }
+
+    #[test]
+    fn haversine_matches_known_city_pairs() {
+        let london = test_support::location(LONDON.0, LONDON.1, Utc::now());
+        let paris = test_support::location(PARIS.0, PARIS.1, Utc::now());
+        assert_within_half_percent(haversine_distance(&london, &paris), 343_500.0);
+        assert_within_half_percent(
+            haversine_meters(NEW_YORK.0, NEW_YORK.1, LOS_ANGELES.0, LOS_ANGELES.1),
+            3_936_000.0,
+        );
+        assert_eq!(haversine_distance(&paris, &london), haversine_distance(&london, &paris));
+        assert_eq!(haversine_distance(&london, &london), 0.0);
+    }
+
+    #[test]
+    fn bearing_is_clockwise_from_north() {
+        assert_eq!(bearing_degrees(0.0, 0.0, 1.0, 0.0), 0.0);
+        assert!((bearing_degrees(0.0, 0.0, 0.0, 1.0) - 90.0).abs() < 1e-9);
+        assert!((bearing_degrees(0.0, 0.0, -1.0, 0.0) - 180.0).abs() < 1e-9);
+        assert!((bearing_degrees(0.0, 0.0, 0.0, -1.0) - 270.0).abs() < 1e-9);
+        assert!((bearing_degrees(LONDON.0, LONDON.1, PARIS.0, PARIS.1) - 148.1).abs() < 0.1);
+    }
+
+    /// Meters spanned by `degrees` of latitude, or of longitude at the equator.
+    fn meters(degrees: f64) -> f64 {
+        degrees * EARTH_RADIUS_METERS.to_radians()
+    }
+
+    fn assert_distance(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("a distance");
+        assert!((actual - expected).abs() < 0.01, "{} != {}", actual, expected);
+    }
+
+    /// A square 0.002 degrees across, centred on the origin.
+    fn square() -> Vec<Vec<[f64; 2]>> {
+        vec![vec![[-0.001, -0.001], [0.001, -0.001], [0.001, 0.001], [-0.001, 0.001]]]
+    }
+
+    /// An L: the square from (0, 0) to (0.004, 0.004) without its north-east quarter.
+    fn ell() -> Vec<Vec<[f64; 2]>> {
+        vec![vec![[0.0, 0.0], [0.004, 0.0], [0.004, 0.002], [0.002, 0.002], [0.002, 0.004], [0.0, 0.004]]]
+    }
+
+    #[test]
+    fn distance_to_a_convex_polygon_is_signed_by_containment() {
+        assert_distance(polygon_distance([0.0, 0.0], &square()), -meters(0.001));
+        assert_distance(polygon_distance([0.0005, 0.0], &square()), -meters(0.0005));
+        assert_distance(polygon_distance([0.003, 0.0], &square()), meters(0.002));
+        assert_distance(polygon_distance([0.0, -0.0015], &square()), meters(0.0005));
+        // Beyond a corner the corner itself is nearest.
+        assert_distance(polygon_distance([0.002, 0.002], &square()), meters(0.001) * 2f64.sqrt());
+        // Points on an edge are inside, at 0.
+        assert_distance(polygon_distance([0.001, 0.0], &square()), 0.0);
+    }
+
+    #[test]
+    fn distance_to_a_concave_polygon_measures_the_nearest_edge() {
+        // Inside an arm of the L, nearer its inner edge than any outer one.
+        assert_distance(polygon_distance([0.0015, 0.003], &ell()), -meters(0.0005));
+        // In the missing quarter: outside, measured to the inner edges rather than the outer ones.
+        assert_distance(polygon_distance([0.003, 0.003], &ell()), meters(0.001));
+        assert_distance(polygon_distance([0.0025, 0.0035], &ell()), meters(0.0005));
+        // Past the arms, a tip of either one is nearest.
+        assert_distance(polygon_distance([0.0045, 0.0045], &ell()), meters(0.0025f64.hypot(0.0005)));
+        // Inside near the reflex corner, which is closer than the outer edges.
+        assert_distance(polygon_distance([0.0019, 0.0019], &ell()), -meters(0.0001) * 2f64.sqrt());
+    }
+
+    #[test]
+    fn holes_are_outside_and_have_edges_of_their_own() {
+        let mut rings = square();
+        rings.push(vec![[-0.0005, -0.0005], [0.0005, -0.0005], [0.0005, 0.0005], [-0.0005, 0.0005]]);
+        assert_distance(polygon_distance([0.0, 0.0], &rings), meters(0.0005));
+        assert_distance(polygon_distance([0.0, 0.0008], &rings), -meters(0.0002));
+        assert!(polygon_distance([0.0, 0.0], &[]).is_none());
+    }
+}